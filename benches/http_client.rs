@@ -0,0 +1,122 @@
+//! Measures what an upstream call costs the gateway with a client built per
+//! request, as providers used to do, and with one pooled client, as they do now.
+//!
+//! Start `tests/python/openai_mock.py` over TLS (see its docstring), then run
+//!
+//!     SSL_CERT_FILE=cert.pem cargo bench --bench http_client
+//!
+//! `MOCK_URL` (default `https://127.0.0.1:8443/v1/chat/completions`) and
+//! `REQUESTS` (default 300) change the upstream and the number of sequential
+//! requests per mode. Prints a markdown table of milliseconds.
+
+use futures::StreamExt;
+use serde_json::json;
+use std::time::{Duration, Instant};
+
+struct Timings {
+    chat: Vec<Duration>,
+    stream_ttfb: Vec<Duration>,
+    stream_total: Vec<Duration>,
+}
+
+async fn chat(client: &reqwest::Client, url: &str) -> Duration {
+    let started = Instant::now();
+    let response = client
+        .post(url)
+        .json(&json!({"model": "gpt-4o", "messages": [{"role": "user", "content": "hi"}]}))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .expect("chat request failed");
+    response.bytes().await.expect("chat body failed");
+    started.elapsed()
+}
+
+async fn stream(client: &reqwest::Client, url: &str) -> (Duration, Duration) {
+    let started = Instant::now();
+    let response = client
+        .post(url)
+        .json(&json!({"model": "gpt-4o", "stream": true, "messages": [{"role": "user", "content": "hi"}]}))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .expect("stream request failed");
+    let mut chunks = response.bytes_stream();
+    let mut ttfb = None;
+    while let Some(chunk) = chunks.next().await {
+        chunk.expect("stream body failed");
+        ttfb.get_or_insert_with(|| started.elapsed());
+    }
+    (ttfb.unwrap_or_default(), started.elapsed())
+}
+
+async fn measure(url: &str, requests: usize, pooled: Option<&reqwest::Client>) -> Timings {
+    let mut timings = Timings {
+        chat: Vec::with_capacity(requests),
+        stream_ttfb: Vec::with_capacity(requests),
+        stream_total: Vec::with_capacity(requests),
+    };
+    for _ in 0..requests {
+        let client = pooled.cloned().unwrap_or_default();
+        timings.chat.push(chat(&client, url).await);
+        let client = pooled.cloned().unwrap_or_default();
+        let (ttfb, total) = stream(&client, url).await;
+        timings.stream_ttfb.push(ttfb);
+        timings.stream_total.push(total);
+    }
+    timings
+}
+
+fn avg(samples: &[Duration]) -> f64 {
+    samples.iter().map(Duration::as_secs_f64).sum::<f64>() * 1000.0 / samples.len() as f64
+}
+
+fn p95(samples: &[Duration]) -> f64 {
+    let mut sorted = samples.to_vec();
+    sorted.sort();
+    let index = (sorted.len() * 95).div_ceil(100).saturating_sub(1);
+    sorted[index].as_secs_f64() * 1000.0
+}
+
+#[tokio::main]
+async fn main() {
+    let url = std::env::var("MOCK_URL")
+        .unwrap_or_else(|_| "https://127.0.0.1:8443/v1/chat/completions".to_string());
+    let requests = std::env::var("REQUESTS")
+        .ok()
+        .and_then(|requests| requests.parse().ok())
+        .unwrap_or(300);
+
+    let per_request = measure(&url, requests, None).await;
+    let client = reqwest::Client::new();
+    // the first call opens the pooled connection, like the gateway's first request
+    chat(&client, &url).await;
+    let pooled = measure(&url, requests, Some(&client)).await;
+
+    println!("{requests} sequential requests per mode against {url}, in milliseconds.\n");
+    println!("| Metric             | Client per request | Pooled client |");
+    println!("|--------------------|--------------------|---------------|");
+    let rows = [
+        ("Chat", &per_request.chat, &pooled.chat),
+        ("Stream ttfb", &per_request.stream_ttfb, &pooled.stream_ttfb),
+        (
+            "Stream total",
+            &per_request.stream_total,
+            &pooled.stream_total,
+        ),
+    ];
+    for (name, before, after) in rows {
+        println!(
+            "| {:<18} | {:<18.2} | {:<13.2} |",
+            format!("{name} (avg)"),
+            avg(before),
+            avg(after)
+        );
+        println!(
+            "| {:<18} | {:<18.2} | {:<13.2} |",
+            format!("{name} (p95)"),
+            p95(before),
+            p95(after)
+        );
+    }
+}
@@ -0,0 +1,162 @@
+use crate::types::{OaiChatCompletionRequest, OaiChatCompletionResponse};
+use lru::LruCache;
+use sha2::{Digest, Sha256};
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Request header that opts a request into the cache (`true`) or skips the
+/// lookup (`bypass`). Also set on translate responses to `hit` or `miss`.
+pub const CACHE_HEADER: &str = "x-felafax-cache";
+
+struct CachedResponse {
+    response: OaiChatCompletionResponse,
+    expires_at: Instant,
+}
+
+/// Size-bounded in-memory LRU of chat completion responses.
+pub struct ResponseCache {
+    entries: Mutex<LruCache<String, CachedResponse>>,
+    ttl: Duration,
+}
+
+impl ResponseCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            entries: Mutex::new(LruCache::new(
+                NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN),
+            )),
+            ttl,
+        }
+    }
+
+    /// Reads `RESPONSE_CACHE_CAPACITY` (entries, default 1000) and
+    /// `RESPONSE_CACHE_TTL_SECS` (default one hour).
+    pub fn from_env() -> Self {
+        let capacity = std::env::var("RESPONSE_CACHE_CAPACITY")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(1000);
+        let ttl = std::env::var("RESPONSE_CACHE_TTL_SECS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(3600);
+        Self::new(capacity, Duration::from_secs(ttl))
+    }
+
+    pub fn get(&self, key: &str) -> Option<OaiChatCompletionResponse> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some(entry) if entry.expires_at > Instant::now() => Some(entry.response.clone()),
+            Some(_) => {
+                entries.pop(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, key: String, response: OaiChatCompletionResponse) {
+        let entry = CachedResponse {
+            response,
+            expires_at: Instant::now() + self.ttl,
+        };
+        self.entries.lock().unwrap().put(key, entry);
+    }
+}
+
+/// Hash of a request as sent to an LLM, ignoring whether it streams so both
+/// kinds of request share entries.
+pub fn cache_key(
+    customer_id: &str,
+    llm_name: &str,
+    model: &str,
+    request: &OaiChatCompletionRequest,
+) -> String {
+    let mut request = request.clone();
+    request.model = model.to_string();
+    request.stream = None;
+    request.stream_options = None;
+
+    // serde_json sorts object keys, so maps hash the same in any order
+    let normalized = serde_json::to_value(&request)
+        .map(|value| value.to_string())
+        .unwrap_or_default();
+
+    let mut hasher = Sha256::new();
+    for part in [customer_id, llm_name, model, &normalized] {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn request(value: serde_json::Value) -> OaiChatCompletionRequest {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn response(id: &str) -> OaiChatCompletionResponse {
+        OaiChatCompletionResponse {
+            id: id.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn streaming_and_plain_requests_share_keys() {
+        let plain = request(json!({
+            "model": "gpt-4o",
+            "messages": [{"role": "user", "content": "Hi"}],
+            "logit_bias": {"50256": -100, "1": 5}
+        }));
+        let streaming = request(json!({
+            "model": "gpt-4o",
+            "stream": true,
+            "stream_options": {"include_usage": "true"},
+            "messages": [{"role": "user", "content": "Hi"}],
+            "logit_bias": {"1": 5, "50256": -100}
+        }));
+        assert_eq!(
+            cache_key("customer", "openai", "gpt-4o", &plain),
+            cache_key("customer", "openai", "gpt-4o", &streaming)
+        );
+    }
+
+    #[test]
+    fn keys_differ_by_customer_llm_model_and_prompt() {
+        let hi =
+            request(json!({"model": "gpt-4o", "messages": [{"role": "user", "content": "Hi"}]}));
+        let hello =
+            request(json!({"model": "gpt-4o", "messages": [{"role": "user", "content": "Hello"}]}));
+        let key = cache_key("customer", "openai", "gpt-4o", &hi);
+        assert_ne!(key, cache_key("other", "openai", "gpt-4o", &hi));
+        assert_ne!(key, cache_key("customer", "azure", "gpt-4o", &hi));
+        assert_ne!(key, cache_key("customer", "openai", "gpt-4o-mini", &hi));
+        assert_ne!(key, cache_key("customer", "openai", "gpt-4o", &hello));
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_entry() {
+        let cache = ResponseCache::new(2, Duration::from_secs(60));
+        cache.insert("a".to_string(), response("a"));
+        cache.insert("b".to_string(), response("b"));
+        assert!(cache.get("a").is_some());
+        cache.insert("c".to_string(), response("c"));
+
+        assert_eq!(cache.get("a").unwrap().id, "a");
+        assert!(cache.get("b").is_none());
+        assert_eq!(cache.get("c").unwrap().id, "c");
+    }
+
+    #[test]
+    fn expires_entries_after_the_ttl() {
+        let cache = ResponseCache::new(10, Duration::ZERO);
+        cache.insert("a".to_string(), response("a"));
+        assert!(cache.get("a").is_none());
+    }
+}
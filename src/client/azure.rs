@@ -0,0 +1,159 @@
+use super::traits::{start_stream, ChatTrait};
+use crate::firestore::AzureConfig;
+use crate::retry::{no_backoff, Retrier};
+use crate::types::config::LLMConfig;
+use crate::types::*;
+use anyhow::Result;
+use async_openai;
+use async_trait::async_trait;
+use futures::StreamExt;
+
+/// Azure OpenAI. Requests go to the deployment serving the requested model,
+/// the request and response bodies are OpenAI's.
+pub struct Azure {
+    api_key: String,
+    azure: AzureConfig,
+    retrier: Retrier,
+    http_client: reqwest::Client,
+}
+
+impl LLMConfig for Azure {
+    fn get_api_key(&self) -> String {
+        self.api_key.clone()
+    }
+
+    fn set_api_key(&mut self, api_key: &str) {
+        self.api_key = api_key.to_string();
+    }
+
+    fn get_base_url(&self) -> String {
+        self.azure.endpoint.clone()
+    }
+
+    fn get_name(&self) -> String {
+        "Azure".to_string()
+    }
+
+    fn get_default_model(&self) -> String {
+        "gpt-4o".to_string()
+    }
+
+    // Deployments are per customer, models are routed here explicitly.
+    // Models with a deployment entry; others are deployed under their own
+    // name and only known once requested.
+    fn get_models(&self) -> Vec<String> {
+        let mut models: Vec<String> = self.azure.deployments.keys().cloned().collect();
+        models.sort();
+        models
+    }
+
+    fn get_model_prefixes(&self) -> Vec<String> {
+        vec![]
+    }
+}
+
+impl Azure {
+    /// Sends every request with the pooled `http_client`.
+    pub fn new(http_client: reqwest::Client) -> Self {
+        Self {
+            api_key: "".to_string(),
+            azure: AzureConfig::default(),
+            retrier: Retrier::default(),
+            http_client,
+        }
+    }
+
+    pub fn with_api_key(mut self, api_key: &str) -> Self {
+        self.api_key = api_key.to_string();
+        self
+    }
+
+    pub fn with_azure(mut self, azure: AzureConfig) -> Self {
+        self.azure = azure;
+        self
+    }
+
+    pub fn with_retrier(mut self, retrier: Retrier) -> Self {
+        self.retrier = retrier;
+        self
+    }
+
+    fn client(&self, model: &str) -> async_openai::Client<async_openai::config::AzureConfig> {
+        let config = async_openai::config::AzureConfig::new()
+            .with_api_base(self.azure.endpoint.trim_end_matches('/'))
+            .with_api_version(&self.azure.api_version)
+            .with_deployment_id(self.azure.deployment(model))
+            .with_api_key(self.api_key.clone());
+        async_openai::Client::with_config(config)
+            .with_http_client(self.http_client.clone())
+            .with_backoff(no_backoff())
+    }
+
+    fn request(
+        &self,
+        request: OaiChatCompletionRequest,
+    ) -> Result<async_openai::types::CreateChatCompletionRequest> {
+        let mut azure_request =
+            async_openai::types::CreateChatCompletionRequest::try_from(request)?;
+        if azure_request.model.is_empty() {
+            azure_request.model = self.get_default_model();
+        }
+        Ok(azure_request)
+    }
+}
+
+#[async_trait]
+impl ChatTrait for Azure {
+    async fn chat(&self, request: OaiChatCompletionRequest) -> Result<OaiChatCompletionResponse> {
+        let mut azure_request = self.request(request)?;
+        azure_request.stream = None;
+        azure_request.stream_options = None;
+        let client = &self.client(&azure_request.model);
+        let azure_request = &azure_request;
+        let response = self
+            .retrier
+            .retry(move || async move { Ok(client.chat().create(azure_request.clone()).await?) })
+            .await?;
+        Ok(response.into())
+    }
+
+    async fn chat_stream(
+        &self,
+        request: OaiChatCompletionRequest,
+    ) -> Result<OaiChatCompletionStream> {
+        let mut azure_request = self.request(request)?;
+        // always ask for usage so the final chunk can be logged
+        azure_request.stream_options = Some(async_openai::types::ChatCompletionStreamOptions {
+            include_usage: true,
+        });
+        let client = &self.client(&azure_request.model);
+        let azure_request = &azure_request;
+        self.retrier
+            .retry(move || async move {
+                let stream = client.chat().create_stream(azure_request.clone()).await?;
+                start_stream(Box::pin(stream.map(|chunk| Ok(chunk?.into())))).await
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn lists_models_with_a_deployment() {
+        let azure = Azure::new(reqwest::Client::new()).with_azure(AzureConfig {
+            endpoint: "https://my-resource.openai.azure.com".to_string(),
+            deployments: HashMap::from([
+                ("gpt-4o-mini".to_string(), "mini".to_string()),
+                ("gpt-4o".to_string(), "prod-gpt4o".to_string()),
+            ]),
+            ..Default::default()
+        });
+        assert_eq!(azure.get_models(), ["gpt-4o", "gpt-4o-mini"]);
+        assert_eq!(azure.azure.deployment("gpt-4o"), "prod-gpt4o");
+        assert_eq!(azure.azure.deployment("o1"), "o1");
+    }
+}
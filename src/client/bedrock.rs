@@ -0,0 +1,905 @@
+use super::claude::{
+    self, ClaudeCompletionRequest, ClaudeCompletionResponse, ClaudeStreamDelta, ClaudeStreamEvent,
+    ClaudeStreamState, ContentBlock, Usage,
+};
+use super::event_stream::{event_stream_messages, EventStreamMessage};
+use super::sigv4;
+use super::traits::ChatTrait;
+use crate::error::Error;
+use crate::firestore::AwsConfig;
+use crate::retry::Retrier;
+use crate::types::LLMConfig;
+use crate::types::*;
+use anyhow::Result;
+use async_trait::async_trait;
+use base64::Engine;
+use chrono::Utc;
+use futures::StreamExt;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::{json, Value};
+use url::Url;
+use uuid::Uuid;
+
+const SERVICE: &str = "bedrock";
+
+// Converse API types, see
+// https://docs.aws.amazon.com/bedrock/latest/APIReference/API_runtime_Converse.html
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ConverseRequest {
+    messages: Vec<ConverseMessage>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    system: Vec<ConverseBlock>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    inference_config: Option<InferenceConfig>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_config: Option<ConverseToolConfig>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct ConverseMessage {
+    role: String,
+    content: Vec<ConverseBlock>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct ConverseBlock {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image: Option<ConverseImage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_use: Option<ConverseToolUse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_result: Option<ConverseToolResult>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct ConverseImage {
+    // png, jpeg, gif or webp
+    format: String,
+    source: ConverseImageSource,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct ConverseImageSource {
+    bytes: String,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct ConverseToolUse {
+    tool_use_id: String,
+    name: String,
+    input: Value,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ConverseToolResult {
+    tool_use_id: String,
+    content: Vec<ConverseBlock>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InferenceConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop_sequences: Option<Vec<String>>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ConverseToolConfig {
+    tools: Vec<ConverseTool>,
+
+    // {"auto": {}}, {"any": {}} or {"tool": {"name": ...}}
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_choice: Option<Value>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ConverseTool {
+    tool_spec: ToolSpec,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ToolSpec {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    input_schema: ToolInputSchema,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct ToolInputSchema {
+    json: Value,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct ConverseResponse {
+    output: ConverseOutput,
+    stop_reason: String,
+    usage: ConverseUsage,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct ConverseOutput {
+    message: ConverseMessage,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct ConverseUsage {
+    input_tokens: u32,
+    output_tokens: u32,
+}
+
+// ConverseStream events, named by the `:event-type` header
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct ConverseStreamEvent {
+    role: Option<String>,
+    content_block_index: Option<u32>,
+    start: Option<ConverseBlock>,
+    delta: Option<ConverseStreamDelta>,
+    stop_reason: Option<String>,
+    usage: Option<ConverseUsage>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct ConverseStreamDelta {
+    text: Option<String>,
+    tool_use: Option<ConverseToolUseDelta>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct ConverseToolUseDelta {
+    input: String,
+}
+
+impl ConverseBlock {
+    fn text(text: String) -> Self {
+        Self {
+            text: Some(text),
+            ..Default::default()
+        }
+    }
+
+    fn from_claude(block: ContentBlock) -> Result<Self, Error> {
+        match block.content_type.as_str() {
+            "text" => Ok(Self::text(block.text.unwrap_or_default())),
+            "image" => {
+                let source = block.source.unwrap_or_default();
+                let (Some(media_type), Some(data)) = (source.media_type, source.data) else {
+                    return Err(Error::InvalidArgument(
+                        "Bedrock only accepts images as base64 data urls".to_string(),
+                    ));
+                };
+                Ok(Self {
+                    image: Some(ConverseImage {
+                        format: media_type.trim_start_matches("image/").to_string(),
+                        source: ConverseImageSource { bytes: data },
+                    }),
+                    ..Default::default()
+                })
+            }
+            "tool_use" => Ok(Self {
+                tool_use: Some(ConverseToolUse {
+                    tool_use_id: block.id.unwrap_or_default(),
+                    name: block.name.unwrap_or_default(),
+                    input: block.input.unwrap_or(json!({})),
+                }),
+                ..Default::default()
+            }),
+            "tool_result" => Ok(Self {
+                tool_result: Some(ConverseToolResult {
+                    tool_use_id: block.tool_use_id.unwrap_or_default(),
+                    content: vec![Self::text(block.content.unwrap_or_default())],
+                }),
+                ..Default::default()
+            }),
+            content_type => Err(Error::InvalidArgument(format!(
+                "content of type '{}' is not supported by Bedrock",
+                content_type
+            ))),
+        }
+    }
+
+    fn into_claude(self) -> Option<ContentBlock> {
+        if let Some(tool_use) = self.tool_use {
+            return Some(ContentBlock {
+                content_type: "tool_use".to_string(),
+                id: Some(tool_use.tool_use_id),
+                name: Some(tool_use.name),
+                input: Some(tool_use.input),
+                ..Default::default()
+            });
+        }
+        Some(ContentBlock {
+            content_type: "text".to_string(),
+            text: Some(self.text?),
+            ..Default::default()
+        })
+    }
+}
+
+impl ConverseRequest {
+    /// Converse speaks Claude's dialect of messages, so the Claude request is
+    /// converted block by block. `max_tokens` is only sent when the caller set
+    /// it, since Claude's default is above some models' limits.
+    fn from_claude(request: ClaudeCompletionRequest, max_tokens: Option<u32>) -> Result<Self> {
+        let messages = request
+            .messages
+            .into_iter()
+            .map(|message| {
+                Ok(ConverseMessage {
+                    role: message.role,
+                    content: message
+                        .content
+                        .into_iter()
+                        .map(ConverseBlock::from_claude)
+                        .collect::<Result<_, Error>>()?,
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+
+        let inference_config = InferenceConfig {
+            max_tokens,
+            temperature: request.temperature,
+            top_p: request.top_p,
+            stop_sequences: request.stop_sequences,
+        };
+
+        // Converse has no "none" choice, tools are still needed to read
+        // earlier tool calls
+        let tool_choice =
+            request
+                .tool_choice
+                .and_then(|tool_choice| match tool_choice.choice_type.as_str() {
+                    "auto" => Some(json!({ "auto": {} })),
+                    "any" => Some(json!({ "any": {} })),
+                    "tool" => Some(json!({ "tool": { "name": tool_choice.name } })),
+                    _ => None,
+                });
+        let tool_config = request.tools.map(|tools| ConverseToolConfig {
+            tools: tools
+                .into_iter()
+                .map(|tool| ConverseTool {
+                    tool_spec: ToolSpec {
+                        name: tool.name,
+                        description: tool.description,
+                        input_schema: ToolInputSchema {
+                            json: tool.input_schema,
+                        },
+                    },
+                })
+                .collect(),
+            tool_choice,
+        });
+
+        Ok(Self {
+            messages,
+            system: request
+                .system
+                .into_iter()
+                .map(ConverseBlock::text)
+                .collect(),
+            inference_config: (inference_config != InferenceConfig::default())
+                .then_some(inference_config),
+            tool_config,
+        })
+    }
+}
+
+impl ConverseResponse {
+    fn into_claude(self, model: String) -> ClaudeCompletionResponse {
+        ClaudeCompletionResponse {
+            id: Uuid::new_v4().to_string(),
+            role: "assistant".to_string(),
+            content: self
+                .output
+                .message
+                .content
+                .into_iter()
+                .filter_map(ConverseBlock::into_claude)
+                .collect(),
+            model,
+            stop_reason: Some(self.stop_reason).filter(|reason| !reason.is_empty()),
+            usage: Usage {
+                input_tokens: self.usage.input_tokens,
+                output_tokens: self.usage.output_tokens,
+            },
+            ..Default::default()
+        }
+    }
+}
+
+/// The body of an InvokeModel request for an Anthropic model: Claude's own
+/// request, with the model in the url and the version in the body.
+fn invoke_body(claude_request: &ClaudeCompletionRequest) -> Result<Value> {
+    let mut body = claude::platform_body(claude_request, "bedrock-2023-05-31")?;
+    if let Some(body) = body.as_object_mut() {
+        body.remove("stream");
+    }
+    Ok(body)
+}
+
+/// Anthropic models, including cross-region inference profiles, are called
+/// with InvokeModel, every other family with Converse.
+fn is_anthropic(model: &str) -> bool {
+    model.contains("anthropic.")
+}
+
+/// Turns both streaming APIs into Claude stream events, so Claude's stream
+/// state can build the chunks.
+struct BedrockStreamState {
+    claude: ClaudeStreamState,
+    model: String,
+    converse: bool,
+    // ConverseStream reports usage after the stop reason
+    stop_reason: Option<String>,
+}
+
+impl BedrockStreamState {
+    fn handle(&mut self, message: EventStreamMessage) -> Option<Result<OaiChatCompletionChunk>> {
+        if message.header(":message-type") == Some("exception") {
+            return Some(Err(stream_exception(&message)));
+        }
+        let event_type = message.header(":event-type").unwrap_or_default();
+        let event = if self.converse {
+            self.converse_event(event_type, &message.payload)
+        } else {
+            invoke_event(event_type, &message.payload)
+        };
+        match event {
+            Ok(Some(event)) => self.claude.handle(event),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+
+    fn converse_event(
+        &mut self,
+        event_type: &str,
+        payload: &[u8],
+    ) -> Result<Option<ClaudeStreamEvent>> {
+        let event = serde_json::from_slice::<ConverseStreamEvent>(payload)?;
+        let claude_event = match event_type {
+            "messageStart" => ClaudeStreamEvent {
+                event_type: "message_start".to_string(),
+                message: Some(ClaudeCompletionResponse {
+                    id: Uuid::new_v4().to_string(),
+                    role: event.role.unwrap_or_default(),
+                    model: self.model.clone(),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            "contentBlockStart" => {
+                let Some(tool_use) = event.start.and_then(|start| start.tool_use) else {
+                    return Ok(None);
+                };
+                ClaudeStreamEvent {
+                    event_type: "content_block_start".to_string(),
+                    index: event.content_block_index,
+                    content_block: Some(ContentBlock {
+                        content_type: "tool_use".to_string(),
+                        id: Some(tool_use.tool_use_id),
+                        name: Some(tool_use.name),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }
+            }
+            "contentBlockDelta" => {
+                let delta = event.delta.unwrap_or_default();
+                ClaudeStreamEvent {
+                    event_type: "content_block_delta".to_string(),
+                    index: event.content_block_index,
+                    delta: Some(match delta.tool_use {
+                        Some(tool_use) => ClaudeStreamDelta {
+                            delta_type: Some("input_json_delta".to_string()),
+                            partial_json: Some(tool_use.input),
+                            ..Default::default()
+                        },
+                        None => ClaudeStreamDelta {
+                            delta_type: Some("text_delta".to_string()),
+                            text: Some(delta.text.unwrap_or_default()),
+                            ..Default::default()
+                        },
+                    }),
+                    ..Default::default()
+                }
+            }
+            "messageStop" => {
+                self.stop_reason = event.stop_reason;
+                return Ok(None);
+            }
+            "metadata" => {
+                let usage = event.usage.unwrap_or_default();
+                ClaudeStreamEvent {
+                    event_type: "message_delta".to_string(),
+                    delta: Some(ClaudeStreamDelta {
+                        stop_reason: self.stop_reason.take(),
+                        ..Default::default()
+                    }),
+                    usage: Some(Usage {
+                        input_tokens: usage.input_tokens,
+                        output_tokens: usage.output_tokens,
+                    }),
+                    ..Default::default()
+                }
+            }
+            // contentBlockStop
+            _ => return Ok(None),
+        };
+        Ok(Some(claude_event))
+    }
+}
+
+/// InvokeModelWithResponseStream wraps each of Claude's events in a `chunk`.
+fn invoke_event(event_type: &str, payload: &[u8]) -> Result<Option<ClaudeStreamEvent>> {
+    #[derive(Deserialize)]
+    struct Chunk {
+        bytes: String,
+    }
+
+    if event_type != "chunk" {
+        return Ok(None);
+    }
+    let chunk = serde_json::from_slice::<Chunk>(payload)?;
+    let event = base64::engine::general_purpose::STANDARD.decode(chunk.bytes)?;
+    Ok(Some(serde_json::from_slice::<ClaudeStreamEvent>(&event)?))
+}
+
+fn stream_exception(message: &EventStreamMessage) -> anyhow::Error {
+    let exception_type = message.header(":exception-type").unwrap_or_default();
+    let status = match exception_type {
+        "throttlingException" => 429,
+        "validationException" => 400,
+        "modelTimeoutException" => 408,
+        "serviceUnavailableException" => 503,
+        _ => 500,
+    };
+    Error::UpstreamError {
+        status,
+        message: format!(
+            "Bedrock stream error: {} {}",
+            exception_type,
+            String::from_utf8_lossy(&message.payload)
+        ),
+    }
+    .into()
+}
+
+pub struct Bedrock {
+    aws: AwsConfig,
+    base_url: Option<String>,
+    retrier: Retrier,
+    http_client: reqwest::Client,
+}
+
+impl LLMConfig for Bedrock {
+    fn get_api_key(&self) -> String {
+        self.aws.access_key_id.clone()
+    }
+
+    fn set_api_key(&mut self, api_key: &str) {
+        self.aws.access_key_id = api_key.to_string();
+    }
+
+    fn get_base_url(&self) -> String {
+        self.base_url
+            .clone()
+            .unwrap_or_else(|| format!("https://bedrock-runtime.{}.amazonaws.com", self.aws.region))
+    }
+
+    fn get_name(&self) -> String {
+        "Bedrock".to_string()
+    }
+
+    fn get_default_model(&self) -> String {
+        "anthropic.claude-3-5-sonnet-20240620-v1:0".to_string()
+    }
+
+    fn get_models(&self) -> Vec<String> {
+        vec![
+            "anthropic.claude-3-5-sonnet-20240620-v1:0".to_string(),
+            "anthropic.claude-3-haiku-20240307-v1:0".to_string(),
+            "meta.llama3-1-70b-instruct-v1:0".to_string(),
+            "mistral.mistral-large-2407-v1:0".to_string(),
+            "amazon.titan-text-premier-v1:0".to_string(),
+            "cohere.command-r-plus-v1:0".to_string(),
+        ]
+    }
+
+    fn get_model_prefixes(&self) -> Vec<String> {
+        vec![
+            "anthropic.".to_string(),
+            "meta.".to_string(),
+            "mistral.".to_string(),
+            "amazon.".to_string(),
+            "cohere.".to_string(),
+            "ai21.".to_string(),
+        ]
+    }
+}
+
+impl Bedrock {
+    /// Sends every request with the pooled `http_client`.
+    pub fn new(http_client: reqwest::Client) -> Self {
+        Self {
+            aws: AwsConfig::default(),
+            base_url: None,
+            retrier: Retrier::default(),
+            http_client,
+        }
+    }
+
+    pub fn with_aws(mut self, aws: AwsConfig) -> Self {
+        self.aws = aws;
+        self
+    }
+
+    /// Overrides the regional endpoint, e.g. to test against a local mock.
+    pub fn with_base_url(mut self, base_url: Option<String>) -> Self {
+        self.base_url = base_url;
+        self
+    }
+
+    pub fn with_retrier(mut self, retrier: Retrier) -> Self {
+        self.retrier = retrier;
+        self
+    }
+
+    /// Signs and sends `body` to one of a model's actions, e.g. `invoke`.
+    async fn send<T: Serialize>(
+        &self,
+        model: &str,
+        action: &str,
+        body: &T,
+    ) -> Result<reqwest::Response> {
+        let url = Url::parse(&format!(
+            "{url}/model/{model}/{action}",
+            url = self.get_base_url().trim_end_matches('/'),
+            model = sigv4::uri_encode(model),
+        ))?;
+        let body = serde_json::to_vec(body)?;
+        let accept = if action.ends_with("stream") {
+            "application/vnd.amazon.eventstream"
+        } else {
+            "application/json"
+        };
+
+        let http_client = &self.http_client;
+        let http_response = self
+            .retrier
+            .execute(|| {
+                let mut request = http_client
+                    .post(url.clone())
+                    .header("content-type", "application/json")
+                    .header("accept", accept);
+                // signed per attempt, signatures expire
+                for (name, value) in
+                    sigv4::sign(&self.aws, SERVICE, "POST", &url, &body, Utc::now())
+                {
+                    request = request.header(name, value);
+                }
+                request.body(body.clone()).send()
+            })
+            .await?;
+
+        if !http_response.status().is_success() {
+            let status = http_response.status().as_u16();
+            let error_text = http_response
+                .text()
+                .await
+                .unwrap_or_else(|_| "Failed to read response text".to_string());
+
+            tracing::error!(
+                status = status,
+                error = error_text,
+                "Failed to make completion request to Bedrock",
+            );
+            return Err(Error::UpstreamError {
+                status,
+                message: format!(
+                    "Failed to make completion request to Bedrock: {}",
+                    error_text
+                ),
+            }
+            .into());
+        }
+        Ok(http_response)
+    }
+
+    /// Converts the request through Claude's conversion, which both APIs
+    /// build on.
+    fn claude_request(&self, request: OaiChatCompletionRequest) -> Result<ClaudeCompletionRequest> {
+        let mut claude_request = ClaudeCompletionRequest::try_from(request)?;
+        if claude_request.model.is_empty() {
+            claude_request.model = self.get_default_model();
+        }
+        Ok(claude_request)
+    }
+}
+
+#[async_trait]
+impl ChatTrait for Bedrock {
+    async fn chat(&self, request: OaiChatCompletionRequest) -> Result<OaiChatCompletionResponse> {
+        let max_tokens = request.max_tokens;
+        let claude_request = self.claude_request(request)?;
+        let model = claude_request.model.clone();
+
+        let result = if is_anthropic(&model) {
+            let http_response = self
+                .send(&model, "invoke", &invoke_body(&claude_request)?)
+                .await?;
+            let mut result = http_response.json::<ClaudeCompletionResponse>().await?;
+            result.model = model;
+            result
+        } else {
+            let converse_request = ConverseRequest::from_claude(claude_request, max_tokens)?;
+            let http_response = self.send(&model, "converse", &converse_request).await?;
+            http_response
+                .json::<ConverseResponse>()
+                .await?
+                .into_claude(model)
+        };
+        Ok(result.into())
+    }
+
+    async fn chat_stream(
+        &self,
+        request: OaiChatCompletionRequest,
+    ) -> Result<OaiChatCompletionStream> {
+        let max_tokens = request.max_tokens;
+        let claude_request = self.claude_request(request)?;
+        let model = claude_request.model.clone();
+        let converse = !is_anthropic(&model);
+
+        let http_response = if converse {
+            let converse_request = ConverseRequest::from_claude(claude_request, max_tokens)?;
+            self.send(&model, "converse-stream", &converse_request)
+                .await?
+        } else {
+            self.send(
+                &model,
+                "invoke-with-response-stream",
+                &invoke_body(&claude_request)?,
+            )
+            .await?
+        };
+
+        let mut state = BedrockStreamState {
+            claude: ClaudeStreamState::new(),
+            model,
+            converse,
+            stop_reason: None,
+        };
+        let stream = event_stream_messages(http_response).filter_map(move |message| {
+            let chunk = message.map_or_else(|e| Some(Err(e)), |message| state.handle(message));
+            futures::future::ready(chunk)
+        });
+        Ok(Box::pin(stream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn claude_request(request: Value) -> ClaudeCompletionRequest {
+        let request: OaiChatCompletionRequest = serde_json::from_value(request).unwrap();
+        ClaudeCompletionRequest::try_from(request).unwrap()
+    }
+
+    fn message(headers: &[(&str, &str)], payload: Value) -> EventStreamMessage {
+        EventStreamMessage {
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<HashMap<_, _>>(),
+            payload: payload.to_string().into(),
+        }
+    }
+
+    fn converse_event(event_type: &str, payload: Value) -> EventStreamMessage {
+        message(
+            &[(":message-type", "event"), (":event-type", event_type)],
+            payload,
+        )
+    }
+
+    #[test]
+    fn invokes_anthropic_models_with_claudes_body() {
+        assert!(is_anthropic("anthropic.claude-3-haiku-20240307-v1:0"));
+        assert!(is_anthropic("us.anthropic.claude-3-5-sonnet-20240620-v1:0"));
+        assert!(!is_anthropic("meta.llama3-1-70b-instruct-v1:0"));
+
+        let body = invoke_body(&claude_request(json!({
+            "model": "anthropic.claude-3-haiku-20240307-v1:0",
+            "stream": true,
+            "messages": [{"role": "user", "content": "Hi"}],
+        })))
+        .unwrap();
+        assert_eq!(body["anthropic_version"], "bedrock-2023-05-31");
+        assert!(body.get("model").is_none());
+        assert!(body.get("stream").is_none());
+        assert_eq!(
+            body["messages"],
+            json!([{"role": "user", "content": [{"type": "text", "text": "Hi"}]}])
+        );
+    }
+
+    #[test]
+    fn converts_requests_for_converse() {
+        let request = claude_request(json!({
+            "model": "meta.llama3-1-70b-instruct-v1:0",
+            "temperature": 0.5,
+            "messages": [
+                {"role": "system", "content": "Be brief."},
+                {"role": "user", "content": [
+                    {"type": "text", "text": "What's the weather here?"},
+                    {"type": "image_url", "image_url": {"url": "data:image/png;base64,iVBORw0"}},
+                ]},
+                {"role": "assistant", "content": "", "tool_calls": [{
+                    "id": "call_1",
+                    "type": "function",
+                    "function": {"name": "get_weather", "arguments": "{\"city\":\"Paris\"}"},
+                }]},
+                {"role": "tool", "tool_call_id": "call_1", "content": "Sunny"},
+            ],
+            "tools": [{"type": "function", "function": {
+                "name": "get_weather",
+                "parameters": {"type": "object", "properties": {"city": {"type": "string"}}},
+            }}],
+            "tool_choice": "required",
+        }));
+        let converse =
+            serde_json::to_value(ConverseRequest::from_claude(request, None).unwrap()).unwrap();
+        assert_eq!(
+            converse,
+            json!({
+                "messages": [
+                    {"role": "user", "content": [
+                        {"text": "What's the weather here?"},
+                        {"image": {"format": "png", "source": {"bytes": "iVBORw0"}}},
+                    ]},
+                    {"role": "assistant", "content": [
+                        {"toolUse": {"toolUseId": "call_1", "name": "get_weather", "input": {"city": "Paris"}}},
+                    ]},
+                    {"role": "user", "content": [
+                        {"toolResult": {"toolUseId": "call_1", "content": [{"text": "Sunny"}]}},
+                    ]},
+                ],
+                "system": [{"text": "Be brief."}],
+                // Claude's default max_tokens isn't sent
+                "inferenceConfig": {"temperature": 0.5},
+                "toolConfig": {
+                    "tools": [{"toolSpec": {
+                        "name": "get_weather",
+                        "inputSchema": {"json": {"type": "object", "properties": {"city": {"type": "string"}}}},
+                    }}],
+                    "toolChoice": {"any": {}},
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn converts_converse_responses() {
+        let response: ConverseResponse = serde_json::from_value(json!({
+            "output": {"message": {"role": "assistant", "content": [
+                {"text": "Checking."},
+                {"toolUse": {"toolUseId": "tooluse_1", "name": "get_weather", "input": {"city": "Paris"}}},
+            ]}},
+            "stopReason": "tool_use",
+            "usage": {"inputTokens": 10, "outputTokens": 5, "totalTokens": 15},
+        }))
+        .unwrap();
+        let response =
+            OaiChatCompletionResponse::from(response.into_claude("meta.llama3".to_string()));
+        assert_eq!(response.model, "meta.llama3");
+        let choice = &response.choices[0];
+        assert_eq!(choice.message.content.text(), "Checking.");
+        assert_eq!(choice.finish_reason.as_deref(), Some("tool_calls"));
+        let tool_call = &choice.message.tool_calls.as_ref().unwrap()[0];
+        assert_eq!(tool_call.id, "tooluse_1");
+        assert_eq!(tool_call.function.arguments, r#"{"city":"Paris"}"#);
+        assert_eq!(response.usage.unwrap().total_tokens, 15);
+    }
+
+    #[test]
+    fn streams_converse_events() {
+        let mut state = BedrockStreamState {
+            claude: ClaudeStreamState::new(),
+            model: "meta.llama3".to_string(),
+            converse: true,
+            stop_reason: None,
+        };
+        let mut chunks = [
+            converse_event("messageStart", json!({"role": "assistant"})),
+            converse_event(
+                "contentBlockDelta",
+                json!({"contentBlockIndex": 0, "delta": {"text": "Hi"}}),
+            ),
+            converse_event("contentBlockStop", json!({"contentBlockIndex": 0})),
+            converse_event("messageStop", json!({"stopReason": "end_turn"})),
+            converse_event(
+                "metadata",
+                json!({"usage": {"inputTokens": 10, "outputTokens": 5}}),
+            ),
+        ]
+        .into_iter()
+        .filter_map(|message| state.handle(message))
+        .map(Result::unwrap);
+
+        let first = chunks.next().unwrap();
+        assert_eq!(first.model, "meta.llama3");
+        assert_eq!(first.choices[0].delta.role.as_deref(), Some("assistant"));
+        let text = chunks.next().unwrap();
+        assert_eq!(text.choices[0].delta.content.as_deref(), Some("Hi"));
+        // the stop reason waits for the usage that follows it
+        let last = chunks.next().unwrap();
+        assert_eq!(last.choices[0].finish_reason.as_deref(), Some("stop"));
+        assert_eq!(last.usage.unwrap().total_tokens, 15);
+        assert!(chunks.next().is_none());
+    }
+
+    #[test]
+    fn unwraps_invoke_chunks_and_exceptions() {
+        let mut state = BedrockStreamState {
+            claude: ClaudeStreamState::new(),
+            model: "anthropic.claude-3-haiku-20240307-v1:0".to_string(),
+            converse: false,
+            stop_reason: None,
+        };
+        let event = json!({
+            "type": "content_block_delta",
+            "index": 0,
+            "delta": {"type": "text_delta", "text": "Hi"},
+        });
+        let bytes = base64::engine::general_purpose::STANDARD.encode(event.to_string());
+        let chunk = state
+            .handle(message(
+                &[(":message-type", "event"), (":event-type", "chunk")],
+                json!({ "bytes": bytes }),
+            ))
+            .unwrap()
+            .unwrap();
+        assert_eq!(chunk.choices[0].delta.content.as_deref(), Some("Hi"));
+
+        let error = state
+            .handle(message(
+                &[
+                    (":message-type", "exception"),
+                    (":exception-type", "throttlingException"),
+                ],
+                json!({"message": "Too many requests"}),
+            ))
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(crate::error::upstream_status(&error), Some(429));
+    }
+}
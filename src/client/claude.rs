@@ -1,11 +1,16 @@
+use super::sse::sse_events;
 use super::traits::ChatTrait;
+use crate::error::Error;
+use crate::retry::Retrier;
 use crate::types::LLMConfig;
 use crate::types::*;
-use anyhow::bail;
 use anyhow::Result;
+use async_trait::async_trait;
+use futures::StreamExt;
 use serde::Deserialize;
 use serde::Serialize;
-use serde_json::Value;
+use serde_json::{json, Value};
+use std::collections::HashMap;
 use std::time::{SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 
@@ -25,22 +30,63 @@ struct ModelListResponse {
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-struct MessageRequest {
-    role: String,
-    content: String,
+pub(super) struct MessageRequest {
+    pub(super) role: String,
+    pub(super) content: Vec<ContentBlock>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(super) struct ClaudeCompletionRequest {
+    pub(super) model: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) max_tokens: Option<u32>,
+
+    pub(super) messages: Vec<MessageRequest>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) system: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) temperature: Option<f32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) top_p: Option<f32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) stop_sequences: Option<Vec<String>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) stream: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) tools: Option<Vec<ClaudeTool>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) tool_choice: Option<ClaudeToolChoice>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-struct ClaudeCompletionRequest {
-    model: String,
+pub(super) struct ClaudeTool {
+    pub(super) name: String,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    max_tokens: Option<u32>,
+    pub(super) description: Option<String>,
+
+    pub(super) input_schema: Value,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct ClaudeToolChoice {
+    // one of auto, any, tool, none
+    #[serde(rename = "type")]
+    pub(super) choice_type: String,
 
-    messages: Vec<MessageRequest>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) name: Option<String>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    stream: Option<bool>,
+    pub(super) disable_parallel_tool_use: Option<bool>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -66,7 +112,7 @@ struct Healthcheck {
 pub struct ClaudeCompletionResponse {
     pub id: String,
     #[serde(rename = "type")]
-    _type: String,
+    pub(super) _type: String,
     pub role: String,
     pub content: Vec<ContentBlock>,
     pub model: String,
@@ -83,18 +129,330 @@ pub struct ContentBlock {
     pub content_type: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub text: Option<String>,
+
+    // tool_use
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub tool_use: Option<Value>, // use Value to capture dynamic fields if needed
+    pub input: Option<Value>,
+
+    // tool_result
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_use_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+
+    // image
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<ImageSource>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageSource {
+    // base64 or url
+    #[serde(rename = "type")]
+    pub source_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+impl ContentBlock {
+    fn text(text: String) -> Self {
+        Self {
+            content_type: "text".to_string(),
+            text: Some(text),
+            ..Default::default()
+        }
+    }
+
+    /// Accepts `data:<media type>;base64,<data>` and http(s) urls.
+    fn image(image_url: OaiImageUrl) -> Result<Self, Error> {
+        let source = if let Some(data_url) = image_url.url.strip_prefix("data:") {
+            let (media_type, data) = data_url.split_once(";base64,").ok_or_else(|| {
+                Error::InvalidArgument("image data urls must be base64 encoded".to_string())
+            })?;
+            ImageSource {
+                source_type: "base64".to_string(),
+                media_type: Some(media_type.to_string()),
+                data: Some(data.to_string()),
+                url: None,
+            }
+        } else if image_url.url.starts_with("https://") || image_url.url.starts_with("http://") {
+            ImageSource {
+                source_type: "url".to_string(),
+                url: Some(image_url.url),
+                ..Default::default()
+            }
+        } else {
+            return Err(Error::InvalidArgument(
+                "image_url must be an http(s) url or a base64 data url".to_string(),
+            ));
+        };
+        Ok(Self {
+            content_type: "image".to_string(),
+            source: Some(source),
+            ..Default::default()
+        })
+    }
+
+    fn from_content(content: OaiMessageContent) -> Result<Vec<Self>, Error> {
+        content.check_parts()?;
+        match content {
+            OaiMessageContent::Text(text) => Ok(vec![Self::text(text)]),
+            OaiMessageContent::Parts(parts) => parts
+                .into_iter()
+                .map(|part| match (part.text, part.image_url) {
+                    (_, Some(image_url)) => Self::image(image_url),
+                    (text, None) => Ok(Self::text(text.unwrap_or_default())),
+                })
+                .collect(),
+        }
+    }
+
+    fn tool_use(tool_call: OaiToolCall) -> Result<Self, Error> {
+        let input = match tool_call.function.arguments.trim() {
+            "" => json!({}),
+            arguments => serde_json::from_str::<Value>(arguments)
+                .ok()
+                .filter(Value::is_object)
+                .ok_or_else(|| {
+                    Error::InvalidArgument(format!(
+                        "arguments of tool call {} must be a JSON object",
+                        tool_call.id
+                    ))
+                })?,
+        };
+        Ok(Self {
+            content_type: "tool_use".to_string(),
+            id: Some(tool_call.id),
+            name: Some(tool_call.function.name),
+            input: Some(input),
+            ..Default::default()
+        })
+    }
+
+    fn tool_result(tool_use_id: String, content: String) -> Self {
+        Self {
+            content_type: "tool_result".to_string(),
+            tool_use_id: Some(tool_use_id),
+            content: Some(content),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Usage {
     pub input_tokens: u32,
     pub output_tokens: u32,
 }
 
+// Streaming events, see https://docs.anthropic.com/en/api/messages-streaming
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClaudeStreamEvent {
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub index: Option<u32>,
+    pub message: Option<ClaudeCompletionResponse>,
+    pub content_block: Option<ContentBlock>,
+    pub delta: Option<ClaudeStreamDelta>,
+    pub usage: Option<Usage>,
+    pub error: Option<Value>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClaudeStreamDelta {
+    #[serde(rename = "type")]
+    pub delta_type: Option<String>,
+    pub text: Option<String>,
+    pub partial_json: Option<String>,
+    pub stop_reason: Option<String>,
+    pub stop_sequence: Option<String>,
+}
+
+/// Carries message level fields from `message_start` into later chunks.
+#[derive(Default, Debug, Clone)]
+pub(super) struct ClaudeStreamState {
+    id: String,
+    model: String,
+    created: u64,
+    input_tokens: u32,
+    // content block index -> OpenAI tool call index
+    tool_indices: HashMap<u32, u32>,
+}
+
+impl ClaudeStreamState {
+    pub(super) fn new() -> Self {
+        Self {
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            ..Default::default()
+        }
+    }
+
+    fn chunk(&self, delta: OaiDelta, finish_reason: Option<String>) -> OaiChatCompletionChunk {
+        OaiChatCompletionChunkBuilder::default()
+            .id(self.id.clone())
+            .object("chat.completion.chunk")
+            .created(self.created)
+            .model(self.model.clone())
+            .choices(vec![OaiChunkChoice {
+                index: 0,
+                delta,
+                logprobs: None,
+                finish_reason,
+            }])
+            .build()
+            .unwrap()
+    }
+
+    pub(super) fn handle(
+        &mut self,
+        event: ClaudeStreamEvent,
+    ) -> Option<Result<OaiChatCompletionChunk>> {
+        match event.event_type.as_str() {
+            "message_start" => {
+                let message = event.message.unwrap_or_default();
+                self.id = message.id;
+                self.model = message.model;
+                self.input_tokens = message.usage.input_tokens;
+                Some(Ok(self.chunk(
+                    OaiDelta {
+                        role: Some("assistant".to_string()),
+                        content: Some("".to_string()),
+                        ..Default::default()
+                    },
+                    None,
+                )))
+            }
+            "content_block_start" => {
+                let block = event.content_block?;
+                if block.content_type != "tool_use" {
+                    return None;
+                }
+                let tool_index = self.tool_indices.len() as u32;
+                self.tool_indices
+                    .insert(event.index.unwrap_or_default(), tool_index);
+                Some(Ok(self.chunk(
+                    OaiDelta {
+                        tool_calls: Some(vec![OaiToolCallDelta {
+                            index: tool_index,
+                            id: block.id,
+                            tool_type: Some("function".to_string()),
+                            function: Some(OaiFunctionCallDelta {
+                                name: block.name,
+                                arguments: Some("".to_string()),
+                            }),
+                        }]),
+                        ..Default::default()
+                    },
+                    None,
+                )))
+            }
+            "content_block_delta" => {
+                let delta = event.delta?;
+                match delta.delta_type.as_deref() {
+                    Some("input_json_delta") => {
+                        let tool_index =
+                            *self.tool_indices.get(&event.index.unwrap_or_default())?;
+                        Some(Ok(self.chunk(
+                            OaiDelta {
+                                tool_calls: Some(vec![OaiToolCallDelta {
+                                    index: tool_index,
+                                    function: Some(OaiFunctionCallDelta {
+                                        name: None,
+                                        arguments: delta.partial_json,
+                                    }),
+                                    ..Default::default()
+                                }]),
+                                ..Default::default()
+                            },
+                            None,
+                        )))
+                    }
+                    _ => Some(Ok(self.chunk(
+                        OaiDelta {
+                            content: Some(delta.text?),
+                            ..Default::default()
+                        },
+                        None,
+                    ))),
+                }
+            }
+            "message_delta" => {
+                let stop_reason = event.delta.and_then(|delta| delta.stop_reason);
+                let mut chunk = self.chunk(OaiDelta::default(), stop_reason.map(map_stop_reason));
+                // usage here is cumulative and may repeat the input tokens
+                let usage = event.usage.unwrap_or_default();
+                let input_tokens = usage.input_tokens.max(self.input_tokens);
+                chunk.usage = Some(OaiUsage {
+                    prompt_tokens: input_tokens,
+                    completion_tokens: usage.output_tokens,
+                    total_tokens: input_tokens + usage.output_tokens,
+                });
+                Some(Ok(chunk))
+            }
+            "error" => {
+                let error = event.error.unwrap_or_default();
+                let status = match error["type"].as_str() {
+                    Some("overloaded_error") => 529,
+                    Some("rate_limit_error") => 429,
+                    Some("api_error") => 500,
+                    _ => 400,
+                };
+                Some(Err(Error::UpstreamError {
+                    status,
+                    message: format!("Claude stream error: {}", error),
+                }
+                .into()))
+            }
+            // ping, content_block_stop, message_stop
+            _ => None,
+        }
+    }
+}
+
+/// Claude's request for the cloud platforms hosting it, Bedrock and Vertex
+/// AI, which take the model in the url and the API version in the body.
+pub(super) fn platform_body(
+    claude_request: &ClaudeCompletionRequest,
+    anthropic_version: &str,
+) -> Result<Value> {
+    let mut body = serde_json::to_value(claude_request)?;
+    if let Some(body) = body.as_object_mut() {
+        body.remove("model");
+        body.insert("anthropic_version".to_string(), json!(anthropic_version));
+    }
+    Ok(body)
+}
+
+/// Maps Claude stop reasons onto OpenAI finish reasons.
+fn map_stop_reason(stop_reason: String) -> String {
+    match stop_reason.as_str() {
+        "end_turn" | "stop_sequence" => "stop".to_string(),
+        "max_tokens" => "length".to_string(),
+        "tool_use" => "tool_calls".to_string(),
+        // Bedrock's Converse API
+        "guardrail_intervened" | "content_filtered" => "content_filter".to_string(),
+        _ => stop_reason,
+    }
+}
+
 pub struct Claude {
     api_key: String,
+    retrier: Retrier,
+    http_client: reqwest::Client,
 }
 
 impl LLMConfig for Claude {
@@ -117,12 +475,28 @@ impl LLMConfig for Claude {
     fn get_default_model(&self) -> String {
         "claude-3-5-sonnet-20240620".to_string()
     }
+
+    fn get_models(&self) -> Vec<String> {
+        vec![
+            "claude-3-5-sonnet-20240620".to_string(),
+            "claude-3-opus-20240229".to_string(),
+            "claude-3-sonnet-20240229".to_string(),
+            "claude-3-haiku-20240307".to_string(),
+        ]
+    }
+
+    fn get_model_prefixes(&self) -> Vec<String> {
+        vec!["claude-".to_string()]
+    }
 }
 
 impl Claude {
-    pub fn new() -> Self {
+    /// Sends every request with the pooled `http_client`.
+    pub fn new(http_client: reqwest::Client) -> Self {
         Self {
             api_key: "".to_string(),
+            retrier: Retrier::default(),
+            http_client,
         }
     }
 
@@ -130,24 +504,26 @@ impl Claude {
         self.api_key = api_key.to_string();
         self
     }
-}
 
-impl ChatTrait for Claude {
-    async fn chat(&self, request: OaiChatCompletionRequest) -> Result<OaiChatCompletionResponse> {
-        //convert request to Claude request
-        let mut claude_request: ClaudeCompletionRequest = request.into();
-        claude_request.model = self.get_default_model();
-
-        println!("CLAUDE REQUEST: {:?}", claude_request);
-
-        let http_response = reqwest::Client::new()
-            .post(format!("{url}/v1/messages", url = self.get_base_url()))
-            .header("x-api-key", &self.get_api_key())
-            .header("content-type", "application/json")
-            .header("anthropic-version", "2023-06-01")
-            .header("anthropic-beta", "messages-2023-12-15")
-            .json(&claude_request)
-            .send()
+    pub fn with_retrier(mut self, retrier: Retrier) -> Self {
+        self.retrier = retrier;
+        self
+    }
+
+    async fn send(&self, claude_request: &ClaudeCompletionRequest) -> Result<reqwest::Response> {
+        let http_client = &self.http_client;
+        let http_response = self
+            .retrier
+            .execute(|| {
+                http_client
+                    .post(format!("{url}/v1/messages", url = self.get_base_url()))
+                    .header("x-api-key", &self.get_api_key())
+                    .header("content-type", "application/json")
+                    .header("anthropic-version", "2023-06-01")
+                    .header("anthropic-beta", "messages-2023-12-15")
+                    .json(claude_request)
+                    .send()
+            })
             .await?;
 
         if !http_response.status().is_success() {
@@ -157,25 +533,67 @@ impl ChatTrait for Claude {
                 .await
                 .unwrap_or_else(|_| "Failed to read response text".to_string());
 
-            println!("CLAUDE ERROR: {:?}", error_text);
             tracing::error!(
                 status = status,
                 error = error_text.as_str(),
                 "Failed to make completion request to Claude"
             );
-            bail!(
-                "Failed to make completion request to Claude: {}",
-                error_text
-            );
+            return Err(Error::UpstreamError {
+                status,
+                message: format!(
+                    "Failed to make completion request to Claude: {}",
+                    error_text
+                ),
+            }
+            .into());
+        }
+        Ok(http_response)
+    }
+}
+
+#[async_trait]
+impl ChatTrait for Claude {
+    async fn chat(&self, request: OaiChatCompletionRequest) -> Result<OaiChatCompletionResponse> {
+        //convert request to Claude request
+        let mut claude_request = ClaudeCompletionRequest::try_from(request)?;
+        if claude_request.model.is_empty() {
+            claude_request.model = self.get_default_model();
         }
+        claude_request.stream = None;
+
+        let http_response = self.send(&claude_request).await?;
         let result = http_response.json::<ClaudeCompletionResponse>().await?;
         //convert to ChatCompletionResponse
         Ok(result.into())
     }
+
+    async fn chat_stream(
+        &self,
+        request: OaiChatCompletionRequest,
+    ) -> Result<OaiChatCompletionStream> {
+        let mut claude_request = ClaudeCompletionRequest::try_from(request)?;
+        if claude_request.model.is_empty() {
+            claude_request.model = self.get_default_model();
+        }
+        claude_request.stream = Some(true);
+
+        let http_response = self.send(&claude_request).await?;
+
+        let mut state = ClaudeStreamState::new();
+        let stream = sse_events(http_response).filter_map(move |event| {
+            let chunk = event
+                .and_then(|event| Ok(serde_json::from_str::<ClaudeStreamEvent>(&event.data)?))
+                .map_or_else(|e| Some(Err(e)), |event| state.handle(event));
+            futures::future::ready(chunk)
+        });
+        Ok(Box::pin(stream))
+    }
 }
 
-impl From<OaiChatCompletionRequest> for ClaudeCompletionRequest {
-    fn from(value: OaiChatCompletionRequest) -> Self {
+impl TryFrom<OaiChatCompletionRequest> for ClaudeCompletionRequest {
+    type Error = Error;
+
+    fn try_from(value: OaiChatCompletionRequest) -> Result<Self, Self::Error> {
         let mut request_builder = ClaudeCompletionRequest::default();
 
         //TODO: take users model
@@ -183,42 +601,173 @@ impl From<OaiChatCompletionRequest> for ClaudeCompletionRequest {
 
         // TODO: Claude expects max_tokens always
         request_builder.max_tokens = Some(value.max_tokens.unwrap_or(4096));
-        request_builder.messages = value
-            .messages
-            .into_iter()
-            .map(|msg| MessageRequest {
-                role: msg.role,
-                content: msg.content,
-            })
-            .collect();
+
+        // Claude takes the system prompt as a top level field
+        let mut system_prompts = vec![];
+        let mut messages: Vec<MessageRequest> = vec![];
+        for msg in value.messages {
+            let (role, content) = match msg.role.as_str() {
+                "system" | "developer" => {
+                    system_prompts.push(msg.content.text_only()?);
+                    continue;
+                }
+                "tool" => {
+                    let tool_call_id = msg.tool_call_id.ok_or_else(|| {
+                        Error::InvalidArgument("tool messages must set tool_call_id".to_string())
+                    })?;
+                    (
+                        "user".to_string(),
+                        vec![ContentBlock::tool_result(
+                            tool_call_id,
+                            msg.content.text_only()?,
+                        )],
+                    )
+                }
+                "user" | "assistant" => {
+                    let mut content = ContentBlock::from_content(msg.content)?;
+                    for tool_call in msg.tool_calls.into_iter().flatten() {
+                        content.push(ContentBlock::tool_use(tool_call)?);
+                    }
+                    (msg.role, content)
+                }
+                role => {
+                    return Err(Error::InvalidArgument(format!(
+                        "role '{}' is not supported by Claude",
+                        role
+                    )))
+                }
+            };
+            // Claude rejects empty text blocks
+            let content = content
+                .into_iter()
+                .filter(|block| block.content_type != "text" || block.text.as_deref() != Some(""));
+
+            // turns must alternate, so adjacent turns of the same role are merged
+            match messages.last_mut() {
+                Some(last) if last.role == role => last.content.extend(content),
+                _ => messages.push(MessageRequest {
+                    role,
+                    content: content.collect(),
+                }),
+            }
+        }
+
+        match messages.first() {
+            None => {
+                return Err(Error::InvalidArgument(
+                    "messages must contain at least one user message".to_string(),
+                ))
+            }
+            Some(first) if first.role != "user" => {
+                return Err(Error::InvalidArgument(
+                    "the first non-system message must have role 'user'".to_string(),
+                ))
+            }
+            _ => {}
+        }
+        if let Some(empty) = messages.iter().find(|msg| msg.content.is_empty()) {
+            return Err(Error::InvalidArgument(format!(
+                "{} messages must have non-empty content",
+                empty.role
+            )));
+        }
+
+        // a trailing assistant turn is a prefill, which may not end in whitespace
+        if let Some(last) = messages.last_mut().filter(|msg| msg.role == "assistant") {
+            if let Some(text) = last
+                .content
+                .last_mut()
+                .and_then(|block| block.text.as_mut())
+            {
+                text.truncate(text.trim_end().len());
+            }
+        }
+
+        request_builder.messages = messages;
+        if !system_prompts.is_empty() {
+            request_builder.system = Some(system_prompts.join("\n\n"));
+        }
+        // Claude's temperature tops out at 1, OpenAI's at 2
+        request_builder.temperature = value.temperature.map(|temperature| temperature.min(1.0));
+        request_builder.top_p = value.top_p;
+        request_builder.stop_sequences = value.stop;
         request_builder.stream = value.stream;
 
-        request_builder
+        request_builder.tools = value.tools.map(|tools| {
+            tools
+                .into_iter()
+                .map(|tool| ClaudeTool {
+                    name: tool.function.name,
+                    description: tool.function.description,
+                    input_schema: tool
+                        .function
+                        .parameters
+                        .unwrap_or(json!({ "type": "object", "properties": {} })),
+                })
+                .collect()
+        });
+
+        let disable_parallel_tool_use = value.parallel_tool_calls.map(|parallel| !parallel);
+        request_builder.tool_choice = match value.tool_choice {
+            Some(OaiToolChoice::Mode(mode)) => Some(ClaudeToolChoice {
+                choice_type: match mode.as_str() {
+                    "required" => "any".to_string(),
+                    _ => mode,
+                },
+                name: None,
+                disable_parallel_tool_use,
+            }),
+            Some(OaiToolChoice::Named(named)) => Some(ClaudeToolChoice {
+                choice_type: "tool".to_string(),
+                name: Some(named.function.name),
+                disable_parallel_tool_use,
+            }),
+            None => disable_parallel_tool_use.map(|disable| ClaudeToolChoice {
+                choice_type: "auto".to_string(),
+                name: None,
+                disable_parallel_tool_use: Some(disable),
+            }),
+        };
+
+        Ok(request_builder)
     }
 }
 
 impl From<ClaudeCompletionResponse> for OaiChatCompletionResponse {
     fn from(value: ClaudeCompletionResponse) -> Self {
-        let choices: Vec<OaiChoice> = value
-            .content
-            .into_iter()
-            .enumerate()
-            .map(|(index, block)| {
-                let content = block.text.unwrap_or_default(); // Assuming text content is what you need for Message
-                OaiChoice {
-                    index: index as u32,
-                    message: OaiMessage {
-                        role: value.role.clone(),
-                        content,
+        let mut message = OaiMessage {
+            role: value.role,
+            ..Default::default()
+        };
+        let mut tool_calls = vec![];
+        for block in value.content {
+            match block.content_type.as_str() {
+                "text" => message.content.push_str(&block.text.unwrap_or_default()),
+                "tool_use" => tool_calls.push(OaiToolCall {
+                    id: block.id.unwrap_or_default(),
+                    tool_type: "function".to_string(),
+                    function: OaiFunctionCall {
+                        name: block.name.unwrap_or_default(),
+                        arguments: block.input.unwrap_or(json!({})).to_string(),
                     },
-                    logprobs: None, // Set to None or map accordingly if you have Logprobs
-                    finish_reason: value.stop_reason.clone(), // You can use stop_reason or stop_sequence
-                }
-            })
-            .collect();
+                }),
+                _ => {}
+            }
+        }
+        if !tool_calls.is_empty() {
+            message.tool_calls = Some(tool_calls);
+        }
+
+        let choices = vec![OaiChoice {
+            index: 0,
+            message,
+            logprobs: None,
+            finish_reason: value.stop_reason.map(map_stop_reason),
+        }];
 
         OaiChatCompletionResponseBuilder::default()
             .id(Uuid::new_v4().to_string())
+            .object("chat.completion")
             .model(value.model)
             .created(
                 SystemTime::now()
@@ -236,3 +785,103 @@ impl From<ClaudeCompletionResponse> for OaiChatCompletionResponse {
             .unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn convert(request: Value) -> Result<Value, Error> {
+        let request: OaiChatCompletionRequest = serde_json::from_value(request).unwrap();
+        let claude_request = ClaudeCompletionRequest::try_from(request)?;
+        Ok(serde_json::to_value(claude_request).unwrap())
+    }
+
+    #[test]
+    fn lifts_system_prompts() {
+        let request = convert(json!({
+            "model": "claude-3-5-sonnet-20240620",
+            "messages": [
+                {"role": "system", "content": "Be brief."},
+                {"role": "user", "content": "Hi"},
+                {"role": "developer", "content": "Answer in French."},
+            ],
+        }))
+        .unwrap();
+        assert_eq!(request["system"], "Be brief.\n\nAnswer in French.");
+        assert_eq!(
+            request["messages"],
+            json!([{"role": "user", "content": [{"type": "text", "text": "Hi"}]}])
+        );
+        assert_eq!(request["max_tokens"], 4096);
+    }
+
+    #[test]
+    fn merges_turns_of_the_same_role() {
+        let request = convert(json!({
+            "model": "claude-3-5-sonnet-20240620",
+            "messages": [
+                {"role": "user", "content": "Hi"},
+                {"role": "user", "content": ""},
+                {"role": "user", "content": "What's the weather in Paris?"},
+                {"role": "assistant", "content": "", "tool_calls": [{
+                    "id": "call_1",
+                    "type": "function",
+                    "function": {"name": "get_weather", "arguments": "{\"city\":\"Paris\"}"},
+                }]},
+                {"role": "tool", "tool_call_id": "call_1", "content": "Sunny"},
+                {"role": "user", "content": "Thanks"},
+                {"role": "assistant", "content": "You're welcome.  "},
+            ],
+        }))
+        .unwrap();
+        assert_eq!(
+            request["messages"],
+            json!([
+                {"role": "user", "content": [
+                    {"type": "text", "text": "Hi"},
+                    {"type": "text", "text": "What's the weather in Paris?"},
+                ]},
+                {"role": "assistant", "content": [
+                    {"type": "tool_use", "id": "call_1", "name": "get_weather", "input": {"city": "Paris"}},
+                ]},
+                {"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": "call_1", "content": "Sunny"},
+                    {"type": "text", "text": "Thanks"},
+                ]},
+                // a prefill may not end in whitespace
+                {"role": "assistant", "content": [{"type": "text", "text": "You're welcome."}]},
+            ])
+        );
+    }
+
+    #[test]
+    fn rejects_conversations_claude_cannot_take() {
+        let starts_with_assistant = convert(json!({
+            "model": "claude-3-5-sonnet-20240620",
+            "messages": [
+                {"role": "system", "content": "Be brief."},
+                {"role": "assistant", "content": "Hello"},
+            ],
+        }));
+        assert!(matches!(
+            starts_with_assistant,
+            Err(Error::InvalidArgument(_))
+        ));
+
+        let only_system = convert(json!({
+            "model": "claude-3-5-sonnet-20240620",
+            "messages": [{"role": "system", "content": "Be brief."}],
+        }));
+        assert!(matches!(only_system, Err(Error::InvalidArgument(_))));
+
+        let tool_without_id = convert(json!({
+            "model": "claude-3-5-sonnet-20240620",
+            "messages": [
+                {"role": "user", "content": "Hi"},
+                {"role": "tool", "content": "Sunny"},
+            ],
+        }));
+        assert!(matches!(tool_without_id, Err(Error::InvalidArgument(_))));
+    }
+}
@@ -0,0 +1,875 @@
+use super::ndjson::ndjson_lines;
+use super::traits::{ChatTrait, EmbeddingsTrait};
+use crate::error::Error;
+use crate::retry::Retrier;
+use crate::types::LLMConfig;
+use crate::types::*;
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::StreamExt;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Chat requests without a model use this, `get_default_model` is for
+/// embeddings.
+const DEFAULT_CHAT_MODEL: &str = "command-r-plus";
+
+pub struct Cohere {
+    api_key: String,
+    retrier: Retrier,
+    http_client: reqwest::Client,
+}
+
+impl LLMConfig for Cohere {
+    fn get_api_key(&self) -> String {
+        self.api_key.clone()
+    }
+
+    fn set_api_key(&mut self, api_key: &str) {
+        self.api_key = api_key.to_string();
+    }
+
+    fn get_base_url(&self) -> String {
+        "https://api.cohere.com".to_string()
+    }
+
+    fn get_name(&self) -> String {
+        "Cohere".to_string()
+    }
+
+    fn get_default_model(&self) -> String {
+        "embed-english-v3.0".to_string()
+    }
+
+    fn get_models(&self) -> Vec<String> {
+        vec![
+            "command-r-plus".to_string(),
+            "command-r".to_string(),
+            "command-r7b-12-2024".to_string(),
+            "command-light".to_string(),
+        ]
+    }
+
+    fn get_model_prefixes(&self) -> Vec<String> {
+        vec!["command".to_string()]
+    }
+
+    fn get_embedding_models(&self) -> Vec<String> {
+        vec![
+            "embed-english-v3.0".to_string(),
+            "embed-english-light-v3.0".to_string(),
+            "embed-multilingual-v3.0".to_string(),
+            "embed-multilingual-light-v3.0".to_string(),
+        ]
+    }
+
+    fn get_embedding_model_prefixes(&self) -> Vec<String> {
+        vec!["embed-".to_string()]
+    }
+}
+
+impl Cohere {
+    /// Sends every request with the pooled `http_client`.
+    pub fn new(http_client: reqwest::Client) -> Self {
+        Self {
+            api_key: "".to_string(),
+            retrier: Retrier::default(),
+            http_client,
+        }
+    }
+
+    pub fn with_api_key(mut self, api_key: &str) -> Self {
+        self.api_key = api_key.to_string();
+        self
+    }
+
+    pub fn with_retrier(mut self, retrier: Retrier) -> Self {
+        self.retrier = retrier;
+        self
+    }
+
+    async fn post<T: Serialize>(
+        &self,
+        path: &str,
+        cohere_request: &T,
+    ) -> Result<reqwest::Response> {
+        let http_client = &self.http_client;
+        let http_response = self
+            .retrier
+            .execute(|| {
+                http_client
+                    .post(format!("{url}/{path}", url = self.get_base_url()))
+                    .header("Authorization", format!("Bearer {}", self.get_api_key()))
+                    .json(cohere_request)
+                    .send()
+            })
+            .await?;
+
+        if !http_response.status().is_success() {
+            let status = http_response.status().as_u16();
+            let error_text = http_response
+                .text()
+                .await
+                .unwrap_or_else(|_| "Failed to read response text".to_string());
+
+            tracing::error!(
+                status = status,
+                error = error_text,
+                "Failed to make request to Cohere",
+            );
+            return Err(Error::UpstreamError {
+                status,
+                message: format!("Failed to make request to Cohere: {}", error_text),
+            }
+            .into());
+        }
+        Ok(http_response)
+    }
+}
+
+impl EmbeddingsTrait for Cohere {
+    async fn embeddings(&self, request: OaiEmbeddingRequest) -> Result<OaiEmbeddingResponse> {
+        let texts = request.input.texts().ok_or_else(|| {
+            Error::InvalidArgument("Cohere embeddings only accept text input".to_string())
+        })?;
+        let mut model = request.model;
+        if model.is_empty() {
+            model = self.get_default_model();
+        }
+
+        let embed_request = EmbedRequest {
+            model: model.clone(),
+            texts,
+            input_type: request
+                .input_type
+                .unwrap_or_else(|| "search_query".to_string()),
+            embedding_types: vec!["float".to_string()],
+            output_dimension: request.dimensions,
+        };
+        let http_response = self.post("v2/embed", &embed_request).await?;
+        let response = http_response.json::<EmbedResponse>().await?;
+
+        Ok(OaiEmbeddingResponse::from_vectors(
+            &model,
+            response.embeddings.float,
+            response.meta.billed_units.input_tokens,
+        ))
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct EmbedRequest {
+    model: String,
+    texts: Vec<String>,
+    input_type: String,
+    embedding_types: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output_dimension: Option<u32>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct EmbedResponse {
+    id: String,
+    embeddings: Embeddings,
+    meta: Meta,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct Embeddings {
+    float: Vec<Vec<f32>>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct Meta {
+    billed_units: BilledUnits,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct BilledUnits {
+    input_tokens: u32,
+    output_tokens: u32,
+}
+
+// Chat types, see https://docs.cohere.com/v1/reference/chat
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CohereChatRequest {
+    pub model: String,
+    /// The latest user message, empty when answering tool results.
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preamble: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub chat_history: Vec<CohereMessage>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<CohereTool>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tool_results: Vec<CohereToolResult>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub documents: Vec<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_sequences: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<Value>,
+    pub stream: bool,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CohereMessage {
+    /// USER, CHATBOT, SYSTEM or TOOL
+    pub role: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<CohereToolCall>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_results: Vec<CohereToolResult>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CohereToolCall {
+    pub name: String,
+    #[serde(default)]
+    pub parameters: Value,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CohereToolResult {
+    pub call: CohereToolCall,
+    pub outputs: Vec<Value>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CohereTool {
+    pub name: String,
+    pub description: String,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub parameter_definitions: HashMap<String, CohereParameter>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CohereParameter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// A Python type name, e.g. `str` or `List[int]`.
+    #[serde(rename = "type")]
+    pub parameter_type: String,
+    pub required: bool,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CohereChatResponse {
+    pub response_id: String,
+    pub generation_id: Option<String>,
+    pub text: String,
+    pub finish_reason: Option<String>,
+    pub tool_calls: Vec<CohereToolCall>,
+    pub meta: CohereChatMeta,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CohereChatMeta {
+    pub billed_units: CohereBilledUnits,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CohereBilledUnits {
+    pub input_tokens: u32,
+    pub output_tokens: u32,
+}
+
+impl From<CohereBilledUnits> for OaiUsage {
+    fn from(value: CohereBilledUnits) -> Self {
+        OaiUsage {
+            prompt_tokens: value.input_tokens,
+            completion_tokens: value.output_tokens,
+            total_tokens: value.input_tokens + value.output_tokens,
+        }
+    }
+}
+
+/// A line of a streamed chat response.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CohereStreamEvent {
+    pub event_type: String,
+    pub generation_id: Option<String>,
+    pub text: Option<String>,
+    pub tool_calls: Vec<CohereToolCall>,
+    pub finish_reason: Option<String>,
+    pub response: Option<CohereChatResponse>,
+}
+
+fn map_finish_reason(finish_reason: &str, has_tool_calls: bool) -> String {
+    match finish_reason {
+        "MAX_TOKENS" | "ERROR_LIMIT" => "length".to_string(),
+        "ERROR_TOXIC" => "content_filter".to_string(),
+        _ if has_tool_calls => "tool_calls".to_string(),
+        _ => "stop".to_string(),
+    }
+}
+
+impl From<CohereToolCall> for OaiToolCall {
+    fn from(value: CohereToolCall) -> Self {
+        // Cohere doesn't id its calls, results are matched by name
+        OaiToolCall {
+            id: format!("call_{}", Uuid::new_v4().simple()),
+            tool_type: "function".to_string(),
+            function: OaiFunctionCall {
+                name: value.name,
+                arguments: value.parameters.to_string(),
+            },
+        }
+    }
+}
+
+/// Maps a JSON schema type to the Python type name Cohere expects.
+fn parameter_type(schema: &Value) -> String {
+    match schema["type"].as_str() {
+        Some("string") => "str".to_string(),
+        Some("integer") => "int".to_string(),
+        Some("number") => "float".to_string(),
+        Some("boolean") => "bool".to_string(),
+        Some("array") => match schema.get("items") {
+            Some(items) => format!("List[{}]", parameter_type(items)),
+            None => "list".to_string(),
+        },
+        _ => "dict".to_string(),
+    }
+}
+
+impl From<OaiTool> for CohereTool {
+    fn from(tool: OaiTool) -> Self {
+        let parameters = tool.function.parameters.unwrap_or_default();
+        let required: Vec<&str> = parameters["required"]
+            .as_array()
+            .map(|required| required.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+        let parameter_definitions = parameters["properties"]
+            .as_object()
+            .map(|properties| {
+                properties
+                    .iter()
+                    .map(|(name, schema)| {
+                        let parameter = CohereParameter {
+                            description: schema["description"].as_str().map(str::to_string),
+                            parameter_type: parameter_type(schema),
+                            required: required.contains(&name.as_str()),
+                        };
+                        (name.clone(), parameter)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        CohereTool {
+            name: tool.function.name,
+            description: tool.function.description.unwrap_or_default(),
+            parameter_definitions,
+        }
+    }
+}
+
+/// Tool outputs must be objects, anything else is wrapped.
+fn tool_outputs(content: &str) -> Vec<Value> {
+    match serde_json::from_str::<Value>(content) {
+        Ok(Value::Object(output)) => vec![Value::Object(output)],
+        Ok(Value::Array(outputs)) if outputs.iter().all(Value::is_object) => outputs,
+        _ => vec![json!({ "result": content })],
+    }
+}
+
+impl TryFrom<OaiChatCompletionRequest> for CohereChatRequest {
+    type Error = Error;
+
+    fn try_from(value: OaiChatCompletionRequest) -> Result<Self, Self::Error> {
+        if value.messages.iter().any(|msg| msg.content.has_images()) {
+            return Err(Error::InvalidArgument(
+                "Cohere chat does not accept images".to_string(),
+            ));
+        }
+
+        let mut preambles = vec![];
+        // tool results are matched to calls by id, Cohere needs the call itself
+        let mut tool_calls: HashMap<String, CohereToolCall> = HashMap::new();
+        let mut history: Vec<CohereMessage> = vec![];
+        for msg in value.messages {
+            let message = match msg.role.as_str() {
+                "system" | "developer" => {
+                    preambles.push(msg.content.text_only()?);
+                    continue;
+                }
+                "user" => CohereMessage {
+                    role: "USER".to_string(),
+                    message: Some(msg.content.text_only()?),
+                    ..Default::default()
+                },
+                "assistant" => {
+                    let mut calls = vec![];
+                    for tool_call in msg.tool_calls.into_iter().flatten() {
+                        let parameters = serde_json::from_str(&tool_call.function.arguments)
+                            .map_err(|_| {
+                                Error::InvalidArgument(format!(
+                                    "arguments of tool call {} are not valid JSON",
+                                    tool_call.id
+                                ))
+                            })?;
+                        let call = CohereToolCall {
+                            name: tool_call.function.name,
+                            parameters,
+                        };
+                        tool_calls.insert(tool_call.id, call.clone());
+                        calls.push(call);
+                    }
+                    CohereMessage {
+                        role: "CHATBOT".to_string(),
+                        message: Some(msg.content.text_only()?),
+                        tool_calls: calls,
+                        ..Default::default()
+                    }
+                }
+                "tool" => {
+                    let tool_call_id = msg.tool_call_id.unwrap_or_default();
+                    let call = tool_calls.get(&tool_call_id).cloned().ok_or_else(|| {
+                        Error::InvalidArgument(format!(
+                            "tool message {} does not answer a known tool call",
+                            tool_call_id
+                        ))
+                    })?;
+                    let result = CohereToolResult {
+                        call,
+                        outputs: tool_outputs(&msg.content.text_only()?),
+                    };
+                    // results of the same turn go together
+                    match history.last_mut() {
+                        Some(last) if last.role == "TOOL" => {
+                            last.tool_results.push(result);
+                            continue;
+                        }
+                        _ => CohereMessage {
+                            role: "TOOL".to_string(),
+                            tool_results: vec![result],
+                            ..Default::default()
+                        },
+                    }
+                }
+                role => {
+                    return Err(Error::InvalidArgument(format!(
+                        "role '{}' is not supported by Cohere",
+                        role
+                    )))
+                }
+            };
+            history.push(message);
+        }
+
+        // the last turn is sent apart from the history
+        let (message, tool_results) = match history.pop() {
+            Some(last) if last.role == "USER" => (last.message.unwrap_or_default(), vec![]),
+            Some(last) if last.role == "TOOL" => ("".to_string(), last.tool_results),
+            _ => {
+                return Err(Error::InvalidArgument(
+                    "the last message must be from the user or a tool".to_string(),
+                ))
+            }
+        };
+
+        // Cohere can't be told not to call tools, so they aren't sent at all
+        let tools_disabled = matches!(
+            &value.tool_choice,
+            Some(OaiToolChoice::Mode(mode)) if mode == "none"
+        );
+        let tools = value
+            .tools
+            .filter(|_| !tools_disabled)
+            .unwrap_or_default()
+            .into_iter()
+            .map(CohereTool::from)
+            .collect();
+
+        let response_format = value
+            .response_format
+            .as_ref()
+            .and_then(|format| format.get("type"))
+            .filter(|format_type| *format_type == "json_object")
+            .map(|_| json!({ "type": "json_object" }));
+
+        Ok(CohereChatRequest {
+            model: value.model,
+            message,
+            preamble: (!preambles.is_empty()).then(|| preambles.join("\n\n")),
+            chat_history: history,
+            tools,
+            tool_results,
+            documents: value.documents.unwrap_or_default(),
+            temperature: value.temperature,
+            p: value.top_p,
+            max_tokens: value.max_tokens,
+            stop_sequences: value.stop,
+            seed: value.seed,
+            frequency_penalty: value.frequency_penalty,
+            presence_penalty: value.presence_penalty,
+            response_format,
+            stream: false,
+        })
+    }
+}
+
+impl From<CohereChatResponse> for OaiChatCompletionResponse {
+    fn from(value: CohereChatResponse) -> Self {
+        let tool_calls: Vec<OaiToolCall> = value
+            .tool_calls
+            .into_iter()
+            .map(OaiToolCall::from)
+            .collect();
+        let finish_reason = value
+            .finish_reason
+            .map(|finish_reason| map_finish_reason(&finish_reason, !tool_calls.is_empty()));
+
+        OaiChatCompletionResponse {
+            id: value.generation_id.unwrap_or(value.response_id),
+            object: "chat.completion".to_string(),
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            model: "".to_string(),
+            system_fingerprint: None,
+            choices: vec![OaiChoice {
+                index: 0,
+                message: OaiMessage {
+                    role: "assistant".to_string(),
+                    content: value.text.into(),
+                    tool_calls: (!tool_calls.is_empty()).then_some(tool_calls),
+                    ..Default::default()
+                },
+                logprobs: None,
+                finish_reason,
+            }],
+            usage: Some(value.meta.billed_units.into()),
+        }
+    }
+}
+
+/// Carries the id and tool call count across stream events.
+#[derive(Default, Debug, Clone)]
+struct CohereStreamState {
+    id: String,
+    model: String,
+    created: u64,
+    tool_calls: u32,
+}
+
+impl CohereStreamState {
+    fn chunk(&self, delta: OaiDelta, finish_reason: Option<String>) -> OaiChatCompletionChunk {
+        OaiChatCompletionChunkBuilder::default()
+            .id(self.id.clone())
+            .object("chat.completion.chunk")
+            .created(self.created)
+            .model(self.model.clone())
+            .choices(vec![OaiChunkChoice {
+                index: 0,
+                delta,
+                logprobs: None,
+                finish_reason,
+            }])
+            .build()
+            .unwrap()
+    }
+
+    fn handle(&mut self, event: CohereStreamEvent) -> Option<OaiChatCompletionChunk> {
+        match event.event_type.as_str() {
+            "stream-start" => {
+                if let Some(generation_id) = event.generation_id {
+                    self.id = generation_id;
+                }
+                Some(self.chunk(
+                    OaiDelta {
+                        role: Some("assistant".to_string()),
+                        content: Some("".to_string()),
+                        ..Default::default()
+                    },
+                    None,
+                ))
+            }
+            "text-generation" => Some(self.chunk(
+                OaiDelta {
+                    content: event.text,
+                    ..Default::default()
+                },
+                None,
+            )),
+            // sent once the calls are complete, the partial tool-calls-chunk
+            // events before it are skipped
+            "tool-calls-generation" => {
+                let tool_calls = event
+                    .tool_calls
+                    .into_iter()
+                    .map(|tool_call| {
+                        let tool_call = OaiToolCall::from(tool_call);
+                        let delta = OaiToolCallDelta {
+                            index: self.tool_calls,
+                            id: Some(tool_call.id),
+                            tool_type: Some(tool_call.tool_type),
+                            function: Some(OaiFunctionCallDelta {
+                                name: Some(tool_call.function.name),
+                                arguments: Some(tool_call.function.arguments),
+                            }),
+                        };
+                        self.tool_calls += 1;
+                        delta
+                    })
+                    .collect();
+                Some(self.chunk(
+                    OaiDelta {
+                        tool_calls: Some(tool_calls),
+                        ..Default::default()
+                    },
+                    None,
+                ))
+            }
+            "stream-end" => {
+                let finish_reason = event
+                    .finish_reason
+                    .map(|finish_reason| map_finish_reason(&finish_reason, self.tool_calls > 0));
+                let mut chunk = self.chunk(OaiDelta::default(), finish_reason);
+                chunk.usage = event
+                    .response
+                    .map(|response| response.meta.billed_units.into());
+                Some(chunk)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[async_trait]
+impl ChatTrait for Cohere {
+    async fn chat(&self, request: OaiChatCompletionRequest) -> Result<OaiChatCompletionResponse> {
+        let mut cohere_request = CohereChatRequest::try_from(request)?;
+        if cohere_request.model.is_empty() {
+            cohere_request.model = DEFAULT_CHAT_MODEL.to_string();
+        }
+
+        let http_response = self.post("v1/chat", &cohere_request).await?;
+        let result = http_response.json::<CohereChatResponse>().await?;
+        let mut response = OaiChatCompletionResponse::from(result);
+        response.model = cohere_request.model;
+        Ok(response)
+    }
+
+    async fn chat_stream(
+        &self,
+        request: OaiChatCompletionRequest,
+    ) -> Result<OaiChatCompletionStream> {
+        let mut cohere_request = CohereChatRequest::try_from(request)?;
+        if cohere_request.model.is_empty() {
+            cohere_request.model = DEFAULT_CHAT_MODEL.to_string();
+        }
+        cohere_request.stream = true;
+
+        let http_response = self.post("v1/chat", &cohere_request).await?;
+        let mut state = CohereStreamState {
+            id: format!("chatcmpl-{}", Uuid::new_v4()),
+            model: cohere_request.model,
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            ..Default::default()
+        };
+        let stream = ndjson_lines(http_response).filter_map(move |line| {
+            let chunk = line
+                .and_then(|line| Ok(serde_json::from_str::<CohereStreamEvent>(&line)?))
+                .map(|event| state.handle(event))
+                .transpose();
+            futures::future::ready(chunk)
+        });
+        Ok(Box::pin(stream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn convert(request: Value) -> Result<Value, Error> {
+        let request: OaiChatCompletionRequest = serde_json::from_value(request).unwrap();
+        let cohere_request = CohereChatRequest::try_from(request)?;
+        Ok(serde_json::to_value(cohere_request).unwrap())
+    }
+
+    #[test]
+    fn sends_the_last_user_turn_apart_from_the_history() {
+        let request = convert(json!({
+            "model": "command-r-plus",
+            "top_p": 0.5,
+            "documents": [{"title": "Paris", "snippet": "Paris is in France."}],
+            "messages": [
+                {"role": "system", "content": "Be brief."},
+                {"role": "user", "content": "Hi"},
+                {"role": "assistant", "content": "Hello!"},
+                {"role": "user", "content": "Where is Paris?"},
+            ],
+        }))
+        .unwrap();
+        assert_eq!(
+            request,
+            json!({
+                "model": "command-r-plus",
+                "message": "Where is Paris?",
+                "preamble": "Be brief.",
+                "chat_history": [
+                    {"role": "USER", "message": "Hi"},
+                    {"role": "CHATBOT", "message": "Hello!"},
+                ],
+                "documents": [{"title": "Paris", "snippet": "Paris is in France."}],
+                "p": 0.5,
+                "stream": false,
+            })
+        );
+    }
+
+    #[test]
+    fn answers_tool_calls_with_their_call() {
+        let request = convert(json!({
+            "model": "command-r-plus",
+            "messages": [
+                {"role": "user", "content": "Weather in Paris and Rome?"},
+                {"role": "assistant", "content": "", "tool_calls": [
+                    {"id": "call_1", "type": "function", "function": {"name": "get_weather", "arguments": "{\"city\":\"Paris\"}"}},
+                    {"id": "call_2", "type": "function", "function": {"name": "get_weather", "arguments": "{\"city\":\"Rome\"}"}},
+                ]},
+                {"role": "tool", "tool_call_id": "call_1", "content": "Sunny"},
+                {"role": "tool", "tool_call_id": "call_2", "content": "{\"sky\":\"cloudy\"}"},
+            ],
+            "tools": [{"type": "function", "function": {
+                "name": "get_weather",
+                "description": "Current weather",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "city": {"type": "string", "description": "City name"},
+                        "days": {"type": "array", "items": {"type": "integer"}},
+                    },
+                    "required": ["city"],
+                },
+            }}],
+        }))
+        .unwrap();
+        assert_eq!(request["message"], "");
+        assert_eq!(
+            request["tool_results"],
+            json!([
+                {"call": {"name": "get_weather", "parameters": {"city": "Paris"}}, "outputs": [{"result": "Sunny"}]},
+                {"call": {"name": "get_weather", "parameters": {"city": "Rome"}}, "outputs": [{"sky": "cloudy"}]},
+            ])
+        );
+        assert_eq!(request["chat_history"][1]["role"], "CHATBOT");
+        assert_eq!(
+            request["tools"],
+            json!([{
+                "name": "get_weather",
+                "description": "Current weather",
+                "parameter_definitions": {
+                    "city": {"description": "City name", "type": "str", "required": true},
+                    "days": {"type": "List[int]", "required": false},
+                },
+            }])
+        );
+    }
+
+    #[test]
+    fn rejects_conversations_cohere_cannot_take() {
+        let ends_with_assistant = convert(json!({
+            "model": "command-r-plus",
+            "messages": [
+                {"role": "user", "content": "Hi"},
+                {"role": "assistant", "content": "Hello!"},
+            ],
+        }));
+        assert!(matches!(
+            ends_with_assistant,
+            Err(Error::InvalidArgument(_))
+        ));
+
+        let unknown_tool_call = convert(json!({
+            "model": "command-r-plus",
+            "messages": [
+                {"role": "user", "content": "Hi"},
+                {"role": "tool", "tool_call_id": "call_1", "content": "Sunny"},
+            ],
+        }));
+        assert!(matches!(unknown_tool_call, Err(Error::InvalidArgument(_))));
+
+        let with_image = convert(json!({
+            "model": "command-r-plus",
+            "messages": [{"role": "user", "content": [
+                {"type": "image_url", "image_url": {"url": "data:image/png;base64,iVBORw0"}},
+            ]}],
+        }));
+        assert!(matches!(with_image, Err(Error::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn converts_responses_and_stream_events() {
+        let response: CohereChatResponse = serde_json::from_value(json!({
+            "response_id": "r-1",
+            "generation_id": "g-1",
+            "text": "",
+            "finish_reason": "COMPLETE",
+            "tool_calls": [{"name": "get_weather", "parameters": {"city": "Paris"}}],
+            "meta": {"billed_units": {"input_tokens": 10, "output_tokens": 5}},
+        }))
+        .unwrap();
+        let response = OaiChatCompletionResponse::from(response);
+        assert_eq!(response.id, "g-1");
+        assert_eq!(
+            response.choices[0].finish_reason.as_deref(),
+            Some("tool_calls")
+        );
+        assert_eq!(response.usage.unwrap().total_tokens, 15);
+
+        let mut state = CohereStreamState::default();
+        let chunks: Vec<_> = [
+            json!({"event_type": "stream-start", "generation_id": "g-2"}),
+            json!({"event_type": "text-generation", "text": "Hi"}),
+            json!({"event_type": "tool-calls-chunk", "tool_call_delta": {"name": "get_weather"}}),
+            json!({"event_type": "stream-end", "finish_reason": "MAX_TOKENS", "response": {
+                "meta": {"billed_units": {"input_tokens": 10, "output_tokens": 5}},
+            }}),
+        ]
+        .into_iter()
+        .filter_map(|event| state.handle(serde_json::from_value(event).unwrap()))
+        .collect();
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks.iter().all(|chunk| chunk.id == "g-2"));
+        assert_eq!(chunks[1].choices[0].delta.content.as_deref(), Some("Hi"));
+        assert_eq!(
+            chunks[2].choices[0].finish_reason.as_deref(),
+            Some("length")
+        );
+        assert_eq!(chunks[2].usage.as_ref().unwrap().total_tokens, 15);
+    }
+}
@@ -0,0 +1,184 @@
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use futures::stream::{BoxStream, StreamExt};
+use futures::Stream;
+use std::collections::HashMap;
+
+/// A single message of an AWS `application/vnd.amazon.eventstream` response,
+/// as sent by Bedrock's streaming APIs.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct EventStreamMessage {
+    /// String headers, such as `:event-type` and `:message-type`.
+    pub headers: HashMap<String, String>,
+    pub payload: Bytes,
+}
+
+impl EventStreamMessage {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+}
+
+/// Splits an upstream event stream response into messages.
+pub fn event_stream_messages(
+    response: reqwest::Response,
+) -> impl Stream<Item = Result<EventStreamMessage>> + Send {
+    let bytes: BoxStream<'static, Result<Bytes>> = response
+        .bytes_stream()
+        .map(|chunk| chunk.map_err(Into::into))
+        .boxed();
+
+    futures::stream::unfold(
+        (bytes, Vec::<u8>::new(), false),
+        |(mut bytes, mut buffer, done)| async move {
+            if done {
+                return None;
+            }
+            loop {
+                match take_message(&mut buffer) {
+                    Ok(Some(message)) => return Some((Ok(message), (bytes, buffer, false))),
+                    Ok(None) => {}
+                    Err(e) => return Some((Err(e), (bytes, Vec::new(), true))),
+                }
+                match bytes.next().await {
+                    Some(Ok(chunk)) => buffer.extend_from_slice(&chunk),
+                    Some(Err(e)) => return Some((Err(e), (bytes, Vec::new(), true))),
+                    None if buffer.is_empty() => return None,
+                    None => {
+                        return Some((
+                            Err(anyhow!("event stream ended in the middle of a message")),
+                            (bytes, Vec::new(), true),
+                        ))
+                    }
+                }
+            }
+        },
+    )
+}
+
+/// Pops the next complete message from `buffer`. Messages are a prelude
+/// (total length, headers length, prelude CRC), headers, payload and a
+/// message CRC. CRCs aren't checked, TLS already guards the stream.
+fn take_message(buffer: &mut Vec<u8>) -> Result<Option<EventStreamMessage>> {
+    if buffer.len() < 12 {
+        return Ok(None);
+    }
+    let total_length = u32::from_be_bytes(buffer[0..4].try_into()?) as usize;
+    let headers_length = u32::from_be_bytes(buffer[4..8].try_into()?) as usize;
+    if total_length < 16 + headers_length {
+        return Err(anyhow!("malformed event stream message"));
+    }
+    if buffer.len() < total_length {
+        return Ok(None);
+    }
+
+    let message: Vec<u8> = buffer.drain(..total_length).collect();
+    let headers = parse_headers(&message[12..12 + headers_length])?;
+    let payload = Bytes::copy_from_slice(&message[12 + headers_length..total_length - 4]);
+    Ok(Some(EventStreamMessage { headers, payload }))
+}
+
+/// Keeps string headers and skips over the other value types.
+fn parse_headers(mut raw: &[u8]) -> Result<HashMap<String, String>> {
+    let malformed = || anyhow!("malformed event stream headers");
+    let mut headers = HashMap::new();
+    while !raw.is_empty() {
+        let name_length = raw[0] as usize;
+        let name = raw.get(1..1 + name_length).ok_or_else(malformed)?;
+        let name = String::from_utf8_lossy(name).to_string();
+        let value_type = *raw.get(1 + name_length).ok_or_else(malformed)?;
+        raw = &raw[2 + name_length..];
+
+        let value_length = match value_type {
+            // bool true, bool false
+            0 | 1 => 0,
+            // byte, short, int, long, timestamp, uuid
+            2 => 1,
+            3 => 2,
+            4 => 4,
+            5 | 8 => 8,
+            9 => 16,
+            // bytes, string
+            6 | 7 => {
+                let length = raw.get(..2).ok_or_else(malformed)?;
+                raw = &raw[2..];
+                u16::from_be_bytes([length[0], length[1]]) as usize
+            }
+            _ => return Err(malformed()),
+        };
+        let value = raw.get(..value_length).ok_or_else(malformed)?;
+        if value_type == 7 {
+            headers.insert(name, String::from_utf8_lossy(value).to_string());
+        }
+        raw = &raw[value_length..];
+    }
+    Ok(headers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string_header(name: &str, value: &str) -> Vec<u8> {
+        let mut header = vec![name.len() as u8];
+        header.extend_from_slice(name.as_bytes());
+        header.push(7);
+        header.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        header.extend_from_slice(value.as_bytes());
+        header
+    }
+
+    /// A message with zeroed CRCs, which aren't checked.
+    fn message(headers: &[u8], payload: &[u8]) -> Vec<u8> {
+        let total_length = 12 + headers.len() + payload.len() + 4;
+        let mut message = Vec::new();
+        message.extend_from_slice(&(total_length as u32).to_be_bytes());
+        message.extend_from_slice(&(headers.len() as u32).to_be_bytes());
+        message.extend_from_slice(&[0; 4]);
+        message.extend_from_slice(headers);
+        message.extend_from_slice(payload);
+        message.extend_from_slice(&[0; 4]);
+        message
+    }
+
+    #[test]
+    fn takes_message_from_prelude_and_headers() {
+        let mut headers = string_header(":event-type", "chunk");
+        // a bool and an int header, skipped over
+        headers.extend_from_slice(b"\x04flag\x00");
+        headers.extend_from_slice(b"\x05count\x04\x00\x00\x00\x2a");
+        headers.extend(string_header(":message-type", "event"));
+        let mut buffer = message(&headers, b"{\"bytes\":\"e30=\"}");
+        buffer.extend_from_slice(&[0, 0]);
+
+        let message = take_message(&mut buffer).unwrap().unwrap();
+        assert_eq!(message.header(":event-type"), Some("chunk"));
+        assert_eq!(message.header(":message-type"), Some("event"));
+        assert_eq!(message.headers.len(), 2);
+        assert_eq!(&message.payload[..], b"{\"bytes\":\"e30=\"}");
+        // the start of the next message stays buffered
+        assert_eq!(buffer, [0, 0]);
+    }
+
+    #[test]
+    fn waits_for_complete_message() {
+        let full = message(&string_header(":event-type", "chunk"), b"{}");
+        let mut buffer = full[..full.len() - 1].to_vec();
+        assert_eq!(take_message(&mut buffer).unwrap(), None);
+        assert_eq!(buffer.len(), full.len() - 1);
+    }
+
+    #[test]
+    fn rejects_malformed_messages() {
+        // total length too short for the headers it declares
+        let mut buffer = message(&string_header(":event-type", "chunk"), b"");
+        buffer[0..4].copy_from_slice(&16u32.to_be_bytes());
+        assert!(take_message(&mut buffer).is_err());
+
+        // header value running past the headers
+        let mut headers = string_header(":event-type", "chunk");
+        headers.truncate(headers.len() - 2);
+        let mut buffer = message(&headers, b"");
+        assert!(take_message(&mut buffer).is_err());
+    }
+}
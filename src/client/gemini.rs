@@ -0,0 +1,1044 @@
+use super::sse::sse_events;
+use super::traits::ChatTrait;
+use crate::error::Error;
+use crate::retry::Retrier;
+use crate::types::LLMConfig;
+use crate::types::*;
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::StreamExt;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+// Request types, see https://ai.google.dev/api/generate-content
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiRequest {
+    // sent in the url, not the body
+    #[serde(skip)]
+    pub model: String,
+
+    pub contents: Vec<Content>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_instruction: Option<Content>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generation_config: Option<GenerationConfig>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<GeminiTool>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_config: Option<ToolConfig>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Content {
+    // user or model, absent for the system instruction
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    pub parts: Vec<Part>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Part {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inline_data: Option<InlineData>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_data: Option<FileData>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub function_call: Option<FunctionCall>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub function_response: Option<FunctionResponse>,
+    // set on thinking summaries, which are not part of the answer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thought: Option<bool>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InlineData {
+    pub mime_type: String,
+    pub data: String,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileData {
+    pub mime_type: String,
+    pub file_uri: String,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FunctionCall {
+    pub name: String,
+    pub args: Value,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FunctionResponse {
+    pub name: String,
+    pub response: Value,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerationConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_output_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_sequences: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub candidate_count: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_mime_type: Option<String>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiTool {
+    pub function_declarations: Vec<FunctionDeclaration>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FunctionDeclaration {
+    pub name: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<Value>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ToolConfig {
+    pub function_calling_config: FunctionCallingConfig,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FunctionCallingConfig {
+    // one of AUTO, ANY, NONE
+    pub mode: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed_function_names: Option<Vec<String>>,
+}
+
+// Response types. Streamed chunks have the same shape.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct GeminiResponse {
+    pub candidates: Vec<Candidate>,
+    pub prompt_feedback: Option<PromptFeedback>,
+    pub usage_metadata: Option<UsageMetadata>,
+    pub model_version: Option<String>,
+    pub error: Option<GeminiError>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Candidate {
+    pub index: u32,
+    pub content: Content,
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct PromptFeedback {
+    pub block_reason: Option<String>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct UsageMetadata {
+    pub prompt_token_count: u32,
+    pub candidates_token_count: u32,
+    pub thoughts_token_count: u32,
+    pub total_token_count: u32,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GeminiError {
+    pub code: u16,
+    pub message: String,
+    pub status: String,
+}
+
+impl Part {
+    fn text(text: String) -> Self {
+        Self {
+            text: Some(text),
+            ..Default::default()
+        }
+    }
+
+    /// Accepts `data:<media type>;base64,<data>` and http(s) urls. Gemini only
+    /// fetches urls it can reach, such as uploaded files.
+    fn image(image_url: OaiImageUrl) -> Result<Self, Error> {
+        if let Some(data_url) = image_url.url.strip_prefix("data:") {
+            let (mime_type, data) = data_url.split_once(";base64,").ok_or_else(|| {
+                Error::InvalidArgument("image data urls must be base64 encoded".to_string())
+            })?;
+            Ok(Self {
+                inline_data: Some(InlineData {
+                    mime_type: mime_type.to_string(),
+                    data: data.to_string(),
+                }),
+                ..Default::default()
+            })
+        } else if image_url.url.starts_with("https://") || image_url.url.starts_with("http://") {
+            Ok(Self {
+                file_data: Some(FileData {
+                    mime_type: image_mime_type(&image_url.url).to_string(),
+                    file_uri: image_url.url,
+                }),
+                ..Default::default()
+            })
+        } else {
+            Err(Error::InvalidArgument(
+                "image_url must be an http(s) url or a base64 data url".to_string(),
+            ))
+        }
+    }
+
+    fn from_content(content: OaiMessageContent) -> Result<Vec<Self>, Error> {
+        content.check_parts()?;
+        match content {
+            OaiMessageContent::Text(text) => Ok(vec![Self::text(text)]),
+            OaiMessageContent::Parts(parts) => parts
+                .into_iter()
+                .map(|part| match (part.text, part.image_url) {
+                    (_, Some(image_url)) => Self::image(image_url),
+                    (text, None) => Ok(Self::text(text.unwrap_or_default())),
+                })
+                .collect(),
+        }
+    }
+
+    fn function_call(tool_call: OaiToolCall) -> Result<Self, Error> {
+        let args = match tool_call.function.arguments.trim() {
+            "" => json!({}),
+            arguments => serde_json::from_str::<Value>(arguments)
+                .ok()
+                .filter(Value::is_object)
+                .ok_or_else(|| {
+                    Error::InvalidArgument(format!(
+                        "arguments of tool call {} must be a JSON object",
+                        tool_call.id
+                    ))
+                })?,
+        };
+        Ok(Self {
+            function_call: Some(FunctionCall {
+                name: tool_call.function.name,
+                args,
+            }),
+            ..Default::default()
+        })
+    }
+
+    /// Gemini wants tool results as JSON objects, anything else is wrapped.
+    fn function_response(name: String, content: String) -> Self {
+        let response = serde_json::from_str::<Value>(&content)
+            .ok()
+            .filter(Value::is_object)
+            .unwrap_or_else(|| json!({ "content": content }));
+        Self {
+            function_response: Some(FunctionResponse { name, response }),
+            ..Default::default()
+        }
+    }
+}
+
+impl Content {
+    /// The answer text and function calls, without thoughts.
+    fn into_answer(self) -> (String, Vec<FunctionCall>) {
+        let mut text = String::new();
+        let mut function_calls = vec![];
+        for part in self.parts {
+            if part.thought == Some(true) {
+                continue;
+            }
+            if let Some(part_text) = part.text {
+                text.push_str(&part_text);
+            }
+            if let Some(function_call) = part.function_call {
+                function_calls.push(function_call);
+            }
+        }
+        (text, function_calls)
+    }
+}
+
+impl From<FunctionCall> for OaiToolCall {
+    fn from(value: FunctionCall) -> Self {
+        // Gemini doesn't id its calls, tool results are matched by name
+        OaiToolCall {
+            id: format!("call_{}", Uuid::new_v4().simple()),
+            tool_type: "function".to_string(),
+            function: OaiFunctionCall {
+                name: value.name,
+                arguments: value.args.to_string(),
+            },
+        }
+    }
+}
+
+impl From<UsageMetadata> for OaiUsage {
+    fn from(value: UsageMetadata) -> Self {
+        let completion_tokens = value.candidates_token_count + value.thoughts_token_count;
+        OaiUsage {
+            prompt_tokens: value.prompt_token_count,
+            completion_tokens,
+            total_tokens: value
+                .total_token_count
+                .max(value.prompt_token_count + completion_tokens),
+        }
+    }
+}
+
+impl GeminiError {
+    pub(super) fn into_error(self) -> anyhow::Error {
+        Error::UpstreamError {
+            status: if self.code == 0 { 500 } else { self.code },
+            message: format!("Gemini error: {} {}", self.status, self.message),
+        }
+        .into()
+    }
+}
+
+/// Maps Gemini finish reasons onto OpenAI finish reasons.
+fn map_finish_reason(finish_reason: String, has_tool_calls: bool) -> String {
+    match finish_reason.as_str() {
+        "STOP" if has_tool_calls => "tool_calls".to_string(),
+        "STOP" => "stop".to_string(),
+        "MAX_TOKENS" => "length".to_string(),
+        "SAFETY" | "RECITATION" | "BLOCKLIST" | "PROHIBITED_CONTENT" | "SPII" | "IMAGE_SAFETY" => {
+            "content_filter".to_string()
+        }
+        _ => finish_reason.to_lowercase(),
+    }
+}
+
+/// Guesses an image's mime type from its url, Gemini requires one.
+fn image_mime_type(url: &str) -> &'static str {
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    match path.rsplit('.').next().map(str::to_lowercase).as_deref() {
+        Some("png") => "image/png",
+        Some("webp") => "image/webp",
+        Some("gif") => "image/gif",
+        Some("heic") => "image/heic",
+        _ => "image/jpeg",
+    }
+}
+
+/// Drops JSON schema keywords that Gemini's OpenAPI subset rejects.
+fn strip_unsupported_schema(schema: &mut Value) {
+    match schema {
+        Value::Object(object) => {
+            object.remove("$schema");
+            object.remove("additionalProperties");
+            object.remove("strict");
+            object.values_mut().for_each(strip_unsupported_schema);
+        }
+        Value::Array(values) => values.iter_mut().for_each(strip_unsupported_schema),
+        _ => {}
+    }
+}
+
+/// Carries the id and per candidate tool call counts across chunks.
+#[derive(Default, Debug, Clone)]
+pub(super) struct GeminiStreamState {
+    id: String,
+    model: String,
+    created: u64,
+    started: bool,
+    // candidate index -> tool calls emitted so far
+    tool_calls: HashMap<u32, u32>,
+}
+
+impl GeminiStreamState {
+    pub(super) fn new(model: String) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            model,
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            ..Default::default()
+        }
+    }
+
+    pub(super) fn handle(
+        &mut self,
+        response: GeminiResponse,
+    ) -> Option<Result<OaiChatCompletionChunk>> {
+        if let Some(error) = response.error {
+            return Some(Err(error.into_error()));
+        }
+        if let Some(model_version) = response.model_version {
+            self.model = model_version;
+        }
+
+        let role = (!self.started).then(|| "assistant".to_string());
+        let mut finished = false;
+        let mut choices = vec![];
+        for candidate in response.candidates {
+            let (text, function_calls) = candidate.content.into_answer();
+            // Gemini sends each function call whole, in a single chunk
+            let tool_calls = self.tool_calls.entry(candidate.index).or_default();
+            let tool_call_deltas: Vec<OaiToolCallDelta> = function_calls
+                .into_iter()
+                .map(|function_call| {
+                    let tool_call = OaiToolCall::from(function_call);
+                    let delta = OaiToolCallDelta {
+                        index: *tool_calls,
+                        id: Some(tool_call.id),
+                        tool_type: Some(tool_call.tool_type),
+                        function: Some(OaiFunctionCallDelta {
+                            name: Some(tool_call.function.name),
+                            arguments: Some(tool_call.function.arguments),
+                        }),
+                    };
+                    *tool_calls += 1;
+                    delta
+                })
+                .collect();
+            let has_tool_calls = *tool_calls > 0;
+            let finish_reason = candidate
+                .finish_reason
+                .map(|finish_reason| map_finish_reason(finish_reason, has_tool_calls));
+            finished |= finish_reason.is_some();
+
+            choices.push(OaiChunkChoice {
+                index: candidate.index,
+                delta: OaiDelta {
+                    role: role.clone(),
+                    content: (!text.is_empty() || !self.started).then_some(text),
+                    tool_calls: (!tool_call_deltas.is_empty()).then_some(tool_call_deltas),
+                },
+                logprobs: None,
+                finish_reason,
+            });
+        }
+        // a blocked prompt comes back without candidates
+        if choices.is_empty() {
+            response
+                .prompt_feedback
+                .and_then(|feedback| feedback.block_reason)?;
+            finished = true;
+            choices.push(OaiChunkChoice {
+                index: 0,
+                delta: OaiDelta {
+                    role,
+                    ..Default::default()
+                },
+                logprobs: None,
+                finish_reason: Some("content_filter".to_string()),
+            });
+        }
+        self.started = true;
+
+        let mut chunk = OaiChatCompletionChunkBuilder::default()
+            .id(self.id.clone())
+            .object("chat.completion.chunk")
+            .created(self.created)
+            .model(self.model.clone())
+            .choices(choices)
+            .build()
+            .unwrap();
+        // usage is cumulative, so it's only reported once
+        if finished {
+            chunk.usage = response.usage_metadata.map(OaiUsage::from);
+        }
+        Some(Ok(chunk))
+    }
+}
+
+pub struct Gemini {
+    api_key: String,
+    retrier: Retrier,
+    http_client: reqwest::Client,
+}
+
+impl LLMConfig for Gemini {
+    fn get_api_key(&self) -> String {
+        self.api_key.clone()
+    }
+
+    fn set_api_key(&mut self, api_key: &str) {
+        self.api_key = api_key.to_string();
+    }
+
+    fn get_base_url(&self) -> String {
+        "https://generativelanguage.googleapis.com".to_string()
+    }
+
+    fn get_name(&self) -> String {
+        "Gemini".to_string()
+    }
+
+    fn get_default_model(&self) -> String {
+        "gemini-1.5-pro".to_string()
+    }
+
+    fn get_models(&self) -> Vec<String> {
+        vec![
+            "gemini-1.5-pro".to_string(),
+            "gemini-1.5-flash".to_string(),
+            "gemini-1.5-flash-8b".to_string(),
+            "gemini-2.0-flash".to_string(),
+        ]
+    }
+
+    fn get_model_prefixes(&self) -> Vec<String> {
+        vec!["gemini-".to_string()]
+    }
+}
+
+impl Gemini {
+    /// Sends every request with the pooled `http_client`.
+    pub fn new(http_client: reqwest::Client) -> Self {
+        Self {
+            api_key: "".to_string(),
+            retrier: Retrier::default(),
+            http_client,
+        }
+    }
+
+    pub fn with_api_key(mut self, api_key: &str) -> Self {
+        self.api_key = api_key.to_string();
+        self
+    }
+
+    pub fn with_retrier(mut self, retrier: Retrier) -> Self {
+        self.retrier = retrier;
+        self
+    }
+
+    /// Calls `method` (generateContent or streamGenerateContent) on the
+    /// request's model.
+    async fn send(
+        &self,
+        method: &str,
+        gemini_request: &GeminiRequest,
+    ) -> Result<reqwest::Response> {
+        let model = gemini_request.model.trim_start_matches("models/");
+        let http_client = &self.http_client;
+        let http_response = self
+            .retrier
+            .execute(|| {
+                let request = http_client
+                    .post(format!(
+                        "{url}/v1beta/models/{model}:{method}",
+                        url = self.get_base_url()
+                    ))
+                    .header("x-goog-api-key", self.get_api_key())
+                    .json(gemini_request);
+                if method == "streamGenerateContent" {
+                    request.query(&[("alt", "sse")]).send()
+                } else {
+                    request.send()
+                }
+            })
+            .await?;
+
+        if !http_response.status().is_success() {
+            let status = http_response.status().as_u16();
+            let error_text = http_response
+                .text()
+                .await
+                .unwrap_or_else(|_| "Failed to read response text".to_string());
+
+            tracing::error!(
+                status = status,
+                error = error_text,
+                "Failed to make completion request to Gemini",
+            );
+            return Err(Error::UpstreamError {
+                status,
+                message: format!(
+                    "Failed to make completion request to Gemini: {}",
+                    error_text
+                ),
+            }
+            .into());
+        }
+        Ok(http_response)
+    }
+}
+
+#[async_trait]
+impl ChatTrait for Gemini {
+    async fn chat(&self, request: OaiChatCompletionRequest) -> Result<OaiChatCompletionResponse> {
+        let mut gemini_request = GeminiRequest::try_from(request)?;
+        if gemini_request.model.is_empty() {
+            gemini_request.model = self.get_default_model();
+        }
+
+        let http_response = self.send("generateContent", &gemini_request).await?;
+        let result = http_response.json::<GeminiResponse>().await?;
+        if let Some(error) = result.error {
+            return Err(error.into_error());
+        }
+
+        let mut response = OaiChatCompletionResponse::from(result);
+        if response.model.is_empty() {
+            response.model = gemini_request.model;
+        }
+        Ok(response)
+    }
+
+    async fn chat_stream(
+        &self,
+        request: OaiChatCompletionRequest,
+    ) -> Result<OaiChatCompletionStream> {
+        let mut gemini_request = GeminiRequest::try_from(request)?;
+        if gemini_request.model.is_empty() {
+            gemini_request.model = self.get_default_model();
+        }
+
+        let http_response = self.send("streamGenerateContent", &gemini_request).await?;
+
+        let mut state = GeminiStreamState::new(gemini_request.model);
+        let stream = sse_events(http_response).filter_map(move |event| {
+            let chunk = event
+                .and_then(|event| Ok(serde_json::from_str::<GeminiResponse>(&event.data)?))
+                .map_or_else(|e| Some(Err(e)), |response| state.handle(response));
+            futures::future::ready(chunk)
+        });
+        Ok(Box::pin(stream))
+    }
+}
+
+impl TryFrom<OaiChatCompletionRequest> for GeminiRequest {
+    type Error = Error;
+
+    fn try_from(value: OaiChatCompletionRequest) -> Result<Self, Self::Error> {
+        let mut request_builder = GeminiRequest {
+            model: value.model,
+            ..Default::default()
+        };
+
+        // Gemini takes the system prompt as a top level field
+        let mut system_prompts = vec![];
+        // tool results are matched to calls by function name, not id
+        let mut tool_names: HashMap<String, String> = HashMap::new();
+        let mut contents: Vec<Content> = vec![];
+        for msg in value.messages {
+            let (role, parts) = match msg.role.as_str() {
+                "system" | "developer" => {
+                    system_prompts.push(msg.content.text_only()?);
+                    continue;
+                }
+                "tool" => {
+                    let tool_call_id = msg.tool_call_id.ok_or_else(|| {
+                        Error::InvalidArgument("tool messages must set tool_call_id".to_string())
+                    })?;
+                    let name = msg
+                        .name
+                        .or_else(|| tool_names.get(&tool_call_id).cloned())
+                        .ok_or_else(|| {
+                            Error::InvalidArgument(format!(
+                                "tool message {} does not answer a known tool call",
+                                tool_call_id
+                            ))
+                        })?;
+                    (
+                        "user",
+                        vec![Part::function_response(name, msg.content.text_only()?)],
+                    )
+                }
+                "user" | "assistant" => {
+                    let mut parts = Part::from_content(msg.content)?;
+                    for tool_call in msg.tool_calls.into_iter().flatten() {
+                        tool_names.insert(tool_call.id.clone(), tool_call.function.name.clone());
+                        parts.push(Part::function_call(tool_call)?);
+                    }
+                    (if msg.role == "user" { "user" } else { "model" }, parts)
+                }
+                role => {
+                    return Err(Error::InvalidArgument(format!(
+                        "role '{}' is not supported by Gemini",
+                        role
+                    )))
+                }
+            };
+            // Gemini rejects empty text parts
+            let parts = parts
+                .into_iter()
+                .filter(|part| part.text.as_deref() != Some(""));
+
+            // adjacent turns of the same role are merged into one
+            match contents.last_mut() {
+                Some(last) if last.role.as_deref() == Some(role) => last.parts.extend(parts),
+                _ => contents.push(Content {
+                    role: Some(role.to_string()),
+                    parts: parts.collect(),
+                }),
+            }
+        }
+
+        contents.retain(|content| !content.parts.is_empty());
+        if contents.is_empty() {
+            return Err(Error::InvalidArgument(
+                "messages must contain at least one non-empty message".to_string(),
+            ));
+        }
+        request_builder.contents = contents;
+        if !system_prompts.is_empty() {
+            request_builder.system_instruction = Some(Content {
+                role: None,
+                parts: vec![Part::text(system_prompts.join("\n\n"))],
+            });
+        }
+
+        let json_mode = value
+            .response_format
+            .as_ref()
+            .and_then(|format| format.get("type"))
+            .is_some_and(|format_type| format_type == "json_object");
+        let generation_config = GenerationConfig {
+            temperature: value.temperature,
+            top_p: value.top_p,
+            max_output_tokens: value.max_tokens,
+            stop_sequences: value.stop,
+            candidate_count: value.n,
+            presence_penalty: value.presence_penalty,
+            frequency_penalty: value.frequency_penalty,
+            seed: value.seed,
+            response_mime_type: json_mode.then(|| "application/json".to_string()),
+        };
+        if generation_config != GenerationConfig::default() {
+            request_builder.generation_config = Some(generation_config);
+        }
+
+        request_builder.tools = value.tools.map(|tools| {
+            vec![GeminiTool {
+                function_declarations: tools
+                    .into_iter()
+                    .map(|tool| FunctionDeclaration {
+                        name: tool.function.name,
+                        description: tool.function.description,
+                        // Gemini rejects objects without properties
+                        parameters: tool
+                            .function
+                            .parameters
+                            .filter(|parameters| {
+                                parameters["properties"]
+                                    .as_object()
+                                    .is_some_and(|properties| !properties.is_empty())
+                            })
+                            .map(|mut parameters| {
+                                strip_unsupported_schema(&mut parameters);
+                                parameters
+                            }),
+                    })
+                    .collect(),
+            }]
+        });
+
+        request_builder.tool_config = value.tool_choice.map(|tool_choice| ToolConfig {
+            function_calling_config: match tool_choice {
+                OaiToolChoice::Mode(mode) => FunctionCallingConfig {
+                    mode: match mode.as_str() {
+                        "required" => "ANY".to_string(),
+                        _ => mode.to_uppercase(),
+                    },
+                    allowed_function_names: None,
+                },
+                OaiToolChoice::Named(named) => FunctionCallingConfig {
+                    mode: "ANY".to_string(),
+                    allowed_function_names: Some(vec![named.function.name]),
+                },
+            },
+        });
+
+        Ok(request_builder)
+    }
+}
+
+impl From<GeminiResponse> for OaiChatCompletionResponse {
+    fn from(value: GeminiResponse) -> Self {
+        let mut choices: Vec<OaiChoice> = value
+            .candidates
+            .into_iter()
+            .map(|candidate| {
+                let (text, function_calls) = candidate.content.into_answer();
+                let mut message = OaiMessage {
+                    role: "assistant".to_string(),
+                    content: text.into(),
+                    ..Default::default()
+                };
+                if !function_calls.is_empty() {
+                    message.tool_calls =
+                        Some(function_calls.into_iter().map(OaiToolCall::from).collect());
+                }
+                let has_tool_calls = message.tool_calls.is_some();
+                OaiChoice {
+                    index: candidate.index,
+                    message,
+                    logprobs: None,
+                    finish_reason: candidate
+                        .finish_reason
+                        .map(|finish_reason| map_finish_reason(finish_reason, has_tool_calls)),
+                }
+            })
+            .collect();
+        // a blocked prompt comes back without candidates
+        if choices.is_empty()
+            && value
+                .prompt_feedback
+                .is_some_and(|feedback| feedback.block_reason.is_some())
+        {
+            choices.push(OaiChoice {
+                index: 0,
+                message: OaiMessage {
+                    role: "assistant".to_string(),
+                    ..Default::default()
+                },
+                logprobs: None,
+                finish_reason: Some("content_filter".to_string()),
+            });
+        }
+
+        let mut response = OaiChatCompletionResponseBuilder::default()
+            .id(Uuid::new_v4().to_string())
+            .object("chat.completion")
+            .model(value.model_version.unwrap_or_default())
+            .created(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs(),
+            )
+            .choices(choices)
+            .build()
+            .unwrap();
+        response.usage = value.usage_metadata.map(OaiUsage::from);
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn convert(request: Value) -> Result<Value, Error> {
+        let request: OaiChatCompletionRequest = serde_json::from_value(request).unwrap();
+        let gemini_request = GeminiRequest::try_from(request)?;
+        Ok(serde_json::to_value(gemini_request).unwrap())
+    }
+
+    #[test]
+    fn converts_messages_and_parameters() {
+        let request = convert(json!({
+            "model": "gemini-1.5-pro",
+            "temperature": 0.5,
+            "max_tokens": 100,
+            "response_format": {"type": "json_object"},
+            "messages": [
+                {"role": "system", "content": "Be brief."},
+                {"role": "user", "content": [
+                    {"type": "text", "text": "What is this?"},
+                    {"type": "image_url", "image_url": {"url": "data:image/png;base64,iVBORw0"}},
+                    {"type": "image_url", "image_url": {"url": "https://example.com/cat.webp?size=2"}},
+                ]},
+                {"role": "assistant", "content": "A cat."},
+                {"role": "user", "content": ""},
+            ],
+        }))
+        .unwrap();
+        assert_eq!(
+            request,
+            json!({
+                "contents": [
+                    {"role": "user", "parts": [
+                        {"text": "What is this?"},
+                        {"inlineData": {"mimeType": "image/png", "data": "iVBORw0"}},
+                        {"fileData": {"mimeType": "image/webp", "fileUri": "https://example.com/cat.webp?size=2"}},
+                    ]},
+                    {"role": "model", "parts": [{"text": "A cat."}]},
+                ],
+                "systemInstruction": {"parts": [{"text": "Be brief."}]},
+                "generationConfig": {
+                    "temperature": 0.5,
+                    "maxOutputTokens": 100,
+                    "responseMimeType": "application/json",
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn answers_tool_calls_by_function_name() {
+        let request = convert(json!({
+            "model": "gemini-1.5-pro",
+            "messages": [
+                {"role": "user", "content": "What's the weather in Paris?"},
+                {"role": "assistant", "content": "", "tool_calls": [{
+                    "id": "call_1",
+                    "type": "function",
+                    "function": {"name": "get_weather", "arguments": "{\"city\":\"Paris\"}"},
+                }]},
+                {"role": "tool", "tool_call_id": "call_1", "content": "Sunny"},
+            ],
+            "tools": [{"type": "function", "function": {
+                "name": "get_weather",
+                "parameters": {
+                    "type": "object",
+                    "additionalProperties": false,
+                    "properties": {"city": {"type": "string"}},
+                },
+            }}],
+            "tool_choice": "required",
+        }))
+        .unwrap();
+        assert_eq!(
+            request["contents"],
+            json!([
+                {"role": "user", "parts": [{"text": "What's the weather in Paris?"}]},
+                {"role": "model", "parts": [
+                    {"functionCall": {"name": "get_weather", "args": {"city": "Paris"}}},
+                ]},
+                {"role": "user", "parts": [
+                    {"functionResponse": {"name": "get_weather", "response": {"content": "Sunny"}}},
+                ]},
+            ])
+        );
+        assert_eq!(
+            request["tools"],
+            json!([{"functionDeclarations": [{
+                "name": "get_weather",
+                "parameters": {"type": "object", "properties": {"city": {"type": "string"}}},
+            }]}])
+        );
+        assert_eq!(
+            request["toolConfig"],
+            json!({"functionCallingConfig": {"mode": "ANY"}})
+        );
+    }
+
+    #[test]
+    fn rejects_conversations_gemini_cannot_take() {
+        let unknown_tool_call = convert(json!({
+            "model": "gemini-1.5-pro",
+            "messages": [
+                {"role": "user", "content": "Hi"},
+                {"role": "tool", "tool_call_id": "call_1", "content": "Sunny"},
+            ],
+        }));
+        assert!(matches!(unknown_tool_call, Err(Error::InvalidArgument(_))));
+
+        let only_system = convert(json!({
+            "model": "gemini-1.5-pro",
+            "messages": [{"role": "system", "content": "Be brief."}],
+        }));
+        assert!(matches!(only_system, Err(Error::InvalidArgument(_))));
+
+        let bad_image = convert(json!({
+            "model": "gemini-1.5-pro",
+            "messages": [{"role": "user", "content": [
+                {"type": "image_url", "image_url": {"url": "file:///cat.png"}},
+            ]}],
+        }));
+        assert!(matches!(bad_image, Err(Error::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn converts_responses_without_thoughts() {
+        let response: GeminiResponse = serde_json::from_value(json!({
+            "candidates": [{
+                "index": 0,
+                "content": {"role": "model", "parts": [
+                    {"text": "Let me check.", "thought": true},
+                    {"text": "Checking the weather."},
+                    {"functionCall": {"name": "get_weather", "args": {"city": "Paris"}}},
+                ]},
+                "finishReason": "STOP",
+            }],
+            "usageMetadata": {
+                "promptTokenCount": 10,
+                "candidatesTokenCount": 5,
+                "thoughtsTokenCount": 3,
+                "totalTokenCount": 18,
+            },
+            "modelVersion": "gemini-1.5-pro-002",
+        }))
+        .unwrap();
+        let response = OaiChatCompletionResponse::from(response);
+        assert_eq!(response.model, "gemini-1.5-pro-002");
+        let choice = &response.choices[0];
+        assert_eq!(choice.message.content.text(), "Checking the weather.");
+        assert_eq!(choice.finish_reason.as_deref(), Some("tool_calls"));
+        let tool_calls = choice.message.tool_calls.as_ref().unwrap();
+        assert_eq!(tool_calls[0].function.name, "get_weather");
+        assert_eq!(tool_calls[0].function.arguments, r#"{"city":"Paris"}"#);
+        let usage = response.usage.unwrap();
+        assert_eq!(
+            (
+                usage.prompt_tokens,
+                usage.completion_tokens,
+                usage.total_tokens
+            ),
+            (10, 8, 18)
+        );
+    }
+
+    #[test]
+    fn streams_tool_calls_with_increasing_indices() {
+        let mut state = GeminiStreamState::new("gemini-1.5-pro".to_string());
+        let chunk = |finish_reason: Option<&str>| -> GeminiResponse {
+            serde_json::from_value(json!({
+                "candidates": [{
+                    "index": 0,
+                    "content": {"role": "model", "parts": [
+                        {"functionCall": {"name": "get_weather", "args": {}}},
+                    ]},
+                    "finishReason": finish_reason,
+                }],
+                "usageMetadata": {"promptTokenCount": 10, "candidatesTokenCount": 5, "totalTokenCount": 15},
+            }))
+            .unwrap()
+        };
+
+        let first = state.handle(chunk(None)).unwrap().unwrap();
+        let delta = &first.choices[0].delta;
+        assert_eq!(delta.role.as_deref(), Some("assistant"));
+        assert_eq!(delta.tool_calls.as_ref().unwrap()[0].index, 0);
+        assert!(first.usage.is_none());
+
+        let last = state.handle(chunk(Some("STOP"))).unwrap().unwrap();
+        let choice = &last.choices[0];
+        assert_eq!(choice.delta.role, None);
+        assert_eq!(choice.delta.tool_calls.as_ref().unwrap()[0].index, 1);
+        assert_eq!(choice.finish_reason.as_deref(), Some("tool_calls"));
+        assert_eq!(last.usage.unwrap().total_tokens, 15);
+    }
+}
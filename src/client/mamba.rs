@@ -1,14 +1,21 @@
-use super::traits::ChatTrait;
+use super::sse::sse_events;
+use super::traits::{ChatTrait, EmbeddingsTrait};
+use crate::error::Error;
+use crate::retry::Retrier;
 use crate::types::LLMConfig;
 use crate::types::*;
-use anyhow::bail;
 use anyhow::Result;
+use async_trait::async_trait;
+use futures::StreamExt;
 use serde::Deserialize;
 use serde::Serialize;
 use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub struct Mamba {
     api_key: String,
+    retrier: Retrier,
+    http_client: reqwest::Client,
 }
 
 impl LLMConfig for Mamba {
@@ -31,12 +38,31 @@ impl LLMConfig for Mamba {
     fn get_default_model(&self) -> String {
         "jamba-instruct-preview".to_string()
     }
+
+    fn get_models(&self) -> Vec<String> {
+        vec![
+            "jamba-instruct-preview".to_string(),
+            "jamba-1.5-mini".to_string(),
+            "jamba-1.5-large".to_string(),
+        ]
+    }
+
+    fn get_model_prefixes(&self) -> Vec<String> {
+        vec!["jamba-".to_string()]
+    }
+
+    fn get_embedding_models(&self) -> Vec<String> {
+        vec!["ai21-embed".to_string()]
+    }
 }
 
 impl Mamba {
-    pub fn new() -> Self {
+    /// Sends every request with the pooled `http_client`.
+    pub fn new(http_client: reqwest::Client) -> Self {
         Self {
             api_key: "".to_string(),
+            retrier: Retrier::default(),
+            http_client,
         }
     }
 
@@ -44,25 +70,28 @@ impl Mamba {
         self.api_key = api_key.to_string();
         self
     }
-}
 
-impl ChatTrait for Mamba {
-    async fn chat(&self, request: OaiChatCompletionRequest) -> Result<OaiChatCompletionResponse> {
-        //convert request
-        let mut mamba_request: ChatRequest = request.into();
-        mamba_request.model = self.get_default_model();
-
-        let http_request = reqwest::Client::new()
-            .post(format!(
-                "{url}/v1/chat/completions",
-                url = self.get_base_url()
-            ))
-            .header("Authorization", format!("Bearer {}", self.get_api_key()))
-            .json(&mamba_request);
+    pub fn with_retrier(mut self, retrier: Retrier) -> Self {
+        self.retrier = retrier;
+        self
+    }
 
-        println!("MAMBA REQUEST: {:?}", http_request);
+    async fn send(&self, mamba_request: &ChatRequest) -> Result<reqwest::Response> {
+        self.post("v1/chat/completions", mamba_request).await
+    }
 
-        let http_response = http_request.send().await?;
+    async fn post<T: Serialize>(&self, path: &str, mamba_request: &T) -> Result<reqwest::Response> {
+        let http_client = &self.http_client;
+        let http_response = self
+            .retrier
+            .execute(|| {
+                http_client
+                    .post(format!("{url}/{path}", url = self.get_base_url()))
+                    .header("Authorization", format!("Bearer {}", self.get_api_key()))
+                    .json(mamba_request)
+                    .send()
+            })
+            .await?;
 
         if !http_response.status().is_success() {
             let status = http_response.status().as_u16();
@@ -74,28 +103,127 @@ impl ChatTrait for Mamba {
             tracing::error!(
                 status = status,
                 error = error_text,
-                "Failed to make completion request to Mamba",
+                "Failed to make request to Mamba",
             );
-            bail!("Failed to make completion request to Mamba: {}", error_text);
+            return Err(Error::UpstreamError {
+                status,
+                message: format!("Failed to make request to Mamba: {}", error_text),
+            }
+            .into());
         }
+        Ok(http_response)
+    }
+}
+
+#[async_trait]
+impl ChatTrait for Mamba {
+    async fn chat(&self, request: OaiChatCompletionRequest) -> Result<OaiChatCompletionResponse> {
+        //convert request
+        let mut mamba_request = ChatRequest::try_from(request)?;
+        if mamba_request.model.is_empty() {
+            mamba_request.model = self.get_default_model();
+        }
+        mamba_request.stream = None;
+
+        let http_response = self.send(&mamba_request).await?;
         let response = http_response.json::<ChatResponse>().await?;
         //convert to ChatCompletionResponse
         Ok(response.into())
     }
+
+    async fn chat_stream(
+        &self,
+        request: OaiChatCompletionRequest,
+    ) -> Result<OaiChatCompletionStream> {
+        let mut mamba_request = ChatRequest::try_from(request)?;
+        if mamba_request.model.is_empty() {
+            mamba_request.model = self.get_default_model();
+        }
+        mamba_request.stream = Some(true);
+
+        let http_response = self.send(&mamba_request).await?;
+
+        // AI21 chunks carry neither model nor creation time
+        let model = mamba_request.model.clone();
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let stream = sse_events(http_response)
+            .take_while(|event| {
+                let done = matches!(event, Ok(event) if event.data.trim() == "[DONE]");
+                futures::future::ready(!done)
+            })
+            .map(move |event| {
+                let response = serde_json::from_str::<ChatStreamResponse>(&event?.data)?;
+                let mut chunk: OaiChatCompletionChunk = response.into();
+                chunk.model = model.clone();
+                chunk.created = created;
+                Ok(chunk)
+            });
+        Ok(Box::pin(stream))
+    }
 }
 
-impl From<OaiChatCompletionRequest> for ChatRequest {
-    fn from(value: OaiChatCompletionRequest) -> Self {
+impl EmbeddingsTrait for Mamba {
+    async fn embeddings(&self, request: OaiEmbeddingRequest) -> Result<OaiEmbeddingResponse> {
+        let texts = request.input.texts().ok_or_else(|| {
+            Error::InvalidArgument("AI21 embeddings only accept text input".to_string())
+        })?;
+        if request.dimensions.is_some() {
+            return Err(Error::InvalidArgument(
+                "AI21 embeddings do not support `dimensions`".to_string(),
+            )
+            .into());
+        }
+
+        // AI21 calls documents segments and everything else queries
+        let embed_type = match request.input_type.as_deref() {
+            Some("search_document") => "segment",
+            _ => "query",
+        };
+        let embed_request = EmbedRequest {
+            texts,
+            embed_type: embed_type.to_string(),
+        };
+        let http_response = self.post("v1/embed", &embed_request).await?;
+        let response = http_response.json::<EmbedResponse>().await?;
+
+        // AI21 does not report token usage for embeddings
+        Ok(OaiEmbeddingResponse::from_vectors(
+            &request.model,
+            response
+                .results
+                .into_iter()
+                .map(|result| result.embedding)
+                .collect(),
+            0,
+        ))
+    }
+}
+
+impl TryFrom<OaiChatCompletionRequest> for ChatRequest {
+    type Error = Error;
+
+    fn try_from(value: OaiChatCompletionRequest) -> Result<Self, Self::Error> {
+        if value.messages.iter().any(|msg| msg.content.has_images()) {
+            return Err(Error::InvalidArgument(
+                "Jamba does not support image content".to_string(),
+            ));
+        }
+
         let mut request_builder = ChatRequest::default();
         request_builder.model = value.model;
         request_builder.messages = value
             .messages
             .into_iter()
-            .map(|msg| ChatMessage {
-                role: msg.role,
-                content: msg.content,
+            .map(|msg| {
+                Ok(ChatMessage {
+                    content: msg.content.text_only()?,
+                    role: msg.role,
+                })
             })
-            .collect();
+            .collect::<Result<_, Error>>()?;
         request_builder.max_tokens = value.max_tokens;
         request_builder.temperature = value.temperature;
         request_builder.top_p = value.top_p;
@@ -105,7 +233,7 @@ impl From<OaiChatCompletionRequest> for ChatRequest {
         request_builder.presence_penalty = value.presence_penalty;
         request_builder.stream = value.stream;
 
-        request_builder
+        Ok(request_builder)
     }
 }
 
@@ -120,7 +248,8 @@ impl From<ChatResponse> for OaiChatCompletionResponse {
                     index: choice.index,
                     message: OaiMessage {
                         role: choice.message.role,
-                        content: choice.message.content,
+                        content: choice.message.content.into(),
+                        ..Default::default()
                     },
                     finish_reason: choice.finish_reason,
                     logprobs: None,
@@ -141,6 +270,41 @@ impl From<ChatResponse> for OaiChatCompletionResponse {
     }
 }
 
+impl From<ChatStreamResponse> for OaiChatCompletionChunk {
+    fn from(value: ChatStreamResponse) -> Self {
+        let mut chunk_builder = OaiChatCompletionChunkBuilder::default();
+        chunk_builder
+            .id(value.id)
+            .object("chat.completion.chunk")
+            .choices(
+                value
+                    .choices
+                    .into_iter()
+                    .map(|choice| OaiChunkChoice {
+                        index: choice.index,
+                        delta: OaiDelta {
+                            role: choice.delta.role,
+                            content: choice.delta.content,
+                            ..Default::default()
+                        },
+                        finish_reason: choice.finish_reason,
+                        logprobs: None,
+                    })
+                    .collect::<Vec<OaiChunkChoice>>(),
+            );
+
+        if let Some(usage) = value.usage {
+            chunk_builder.usage(OaiUsage {
+                prompt_tokens: usage.prompt_tokens,
+                completion_tokens: usage.completion_tokens,
+                total_tokens: usage.total_tokens,
+            });
+        }
+
+        chunk_builder.build().unwrap()
+    }
+}
+
 // Request
 #[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 struct ChatMessage {
@@ -200,9 +364,53 @@ struct ChatResponse {
     additional_fields: HashMap<String, serde_json::Value>,
 }
 
+// Streaming response
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+struct ChatStreamDelta {
+    role: Option<String>,
+    content: Option<String>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct ChatStreamChoice {
+    index: u32,
+    delta: ChatStreamDelta,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    finish_reason: Option<String>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct ChatStreamResponse {
+    id: String,
+    choices: Vec<ChatStreamChoice>,
+    usage: Option<Usage>,
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 struct Usage {
     prompt_tokens: u32,
     completion_tokens: u32,
     total_tokens: u32,
 }
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct EmbedRequest {
+    texts: Vec<String>,
+    /// `segment` for documents, `query` for search queries.
+    #[serde(rename = "type")]
+    embed_type: String,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct EmbedResponse {
+    id: String,
+    results: Vec<EmbedResult>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct EmbedResult {
+    embedding: Vec<f32>,
+}
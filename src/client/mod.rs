@@ -1,9 +1,34 @@
+pub mod azure;
+pub mod bedrock;
 pub mod claude;
+pub mod cohere;
+pub mod event_stream;
+pub mod gemini;
 pub mod mamba;
+pub mod models;
+pub mod ndjson;
+pub mod ollama;
 pub mod openai;
+pub mod openai_compatible;
+pub mod providers;
+pub mod sigv4;
+pub mod sse;
 pub mod traits;
+pub mod vertex;
 
+pub use azure::*;
+pub use bedrock::*;
 pub use claude::*;
+pub use cohere::*;
+pub use event_stream::*;
+pub use gemini::*;
 pub use mamba::*;
+pub use models::*;
+pub use ndjson::*;
+pub use ollama::*;
 pub use openai::*;
+pub use openai_compatible::*;
+pub use providers::*;
+pub use sse::*;
 pub use traits::*;
+pub use vertex::*;
@@ -0,0 +1,404 @@
+use crate::client::{bedrock, claude, cohere, gemini, mamba, openai};
+use crate::client::{ProviderContext, ProviderRegistry};
+use crate::firestore::CustomerConfig;
+use crate::google_auth::GoogleTokenCache;
+use crate::retry::{Retrier, RetryPolicy};
+use crate::types::LLMConfig;
+use lru::LruCache;
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Virtual model that resolves to the customer's selected LLM.
+pub const HOT_SWAP_MODEL: &str = "hot-swap";
+
+/// Model ids and model id prefixes mapped to the LLM that serves them.
+#[derive(Debug, Clone, Default)]
+struct ModelLookup {
+    models: HashMap<String, String>,
+    prefixes: Vec<(String, String)>,
+}
+
+impl ModelLookup {
+    fn insert(&mut self, llm_name: &str, models: Vec<String>, prefixes: Vec<String>) {
+        for model in models {
+            self.models.insert(model, llm_name.to_string());
+        }
+        for prefix in prefixes {
+            self.prefixes.push((prefix, llm_name.to_string()));
+        }
+        // longest prefix wins
+        self.prefixes
+            .sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+    }
+
+    fn get(&self, model: &str) -> Option<String> {
+        if let Some(llm_name) = self.models.get(model) {
+            return Some(llm_name.clone());
+        }
+        self.prefixes
+            .iter()
+            .find(|(prefix, _)| model.starts_with(prefix.as_str()))
+            .map(|(_, llm_name)| llm_name.clone())
+    }
+}
+
+/// Maps model ids and model id prefixes to the LLM that serves them. Chat and
+/// embedding models are looked up separately.
+#[derive(Debug, Clone, Default)]
+pub struct ModelRegistry {
+    chat: ModelLookup,
+    embeddings: ModelLookup,
+    /// When the registry was built, reported as the models' creation time.
+    pub created: u64,
+}
+
+impl ModelRegistry {
+    /// Registers the models of the hosted LLMs. Their clients are only built
+    /// to read model lists, with the pooled `http_client`.
+    pub fn new(http_client: &reqwest::Client) -> Self {
+        let mut registry = Self {
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            ..Default::default()
+        };
+        registry.register("openai", &openai::OpenAI::new(http_client.clone()));
+        registry.register("claude", &claude::Claude::new(http_client.clone()));
+        registry.register("jamba", &mamba::Mamba::new(http_client.clone()));
+        registry.register("cohere", &cohere::Cohere::new(http_client.clone()));
+        registry.register("gemini", &gemini::Gemini::new(http_client.clone()));
+        registry.register("bedrock", &bedrock::Bedrock::new(http_client.clone()));
+        registry
+    }
+
+    pub fn register(&mut self, llm_name: &str, llm_config: &impl LLMConfig) {
+        let models = llm_config.get_models();
+        let embedding_models = llm_config.get_embedding_models();
+        self.chat
+            .insert(llm_name, models, llm_config.get_model_prefixes());
+        self.embeddings.insert(
+            llm_name,
+            embedding_models,
+            llm_config.get_embedding_model_prefixes(),
+        );
+    }
+
+    /// The LLM that serves a chat model.
+    pub fn get_llm_name(&self, model: &str) -> Option<String> {
+        self.chat.get(model)
+    }
+
+    /// The LLM that serves an embedding model.
+    pub fn get_embedding_llm_name(&self, model: &str) -> Option<String> {
+        self.embeddings.get(model)
+    }
+
+    /// Like `get_llm_name`, but models on the allowlist of one of the
+    /// customer's LLM configs, such as an OpenAI-compatible endpoint, go there.
+    pub fn get_customer_llm_name(
+        &self,
+        model: &str,
+        customer_config: &CustomerConfig,
+    ) -> Option<String> {
+        customer_llm_name(model, customer_config).or_else(|| self.get_llm_name(model))
+    }
+
+    /// Like `get_customer_llm_name`, for embedding models.
+    pub fn get_customer_embedding_llm_name(
+        &self,
+        model: &str,
+        customer_config: &CustomerConfig,
+    ) -> Option<String> {
+        customer_llm_name(model, customer_config).or_else(|| self.get_embedding_llm_name(model))
+    }
+
+    /// Returns the LLM name and model to send upstream for a requested model.
+    /// `hot-swap` (or no model at all) uses the customer's selected LLM, an
+    /// empty model means the LLM's default.
+    pub fn resolve(
+        &self,
+        model: &str,
+        customer_config: &CustomerConfig,
+    ) -> Option<(String, String)> {
+        if model.is_empty() || model == HOT_SWAP_MODEL {
+            return Some((
+                customer_config.selected_llm_name.clone(),
+                customer_config.selected_llm_model.clone(),
+            ));
+        }
+        self.get_customer_llm_name(model, customer_config)
+            .map(|llm_name| (llm_name, model.to_string()))
+    }
+}
+
+/// The customer's LLM config that allows `model`, if any.
+fn customer_llm_name(model: &str, customer_config: &CustomerConfig) -> Option<String> {
+    let mut llm_names: Vec<&String> = customer_config
+        .llm_configs
+        .iter()
+        .filter(|(_, llm_config)| llm_config.models.iter().any(|m| m == model))
+        .map(|(llm_name, _)| llm_name)
+        .collect();
+    // configs are a map, pick the same one every time
+    llm_names.sort();
+    llm_names.first().map(|llm_name| llm_name.to_string())
+}
+
+/// Models the customer's configs can reach, as (llm name, model): for each
+/// config, the models of the client its provider builds and the config's
+/// allowlist, then the selected and fallback models. Configs whose client
+/// can't be built, such as Bedrock without credentials, list their allowlist.
+pub fn customer_models(
+    customer_config: &CustomerConfig,
+    providers: &ProviderRegistry,
+    google_tokens: &Arc<GoogleTokenCache>,
+    http_client: &reqwest::Client,
+) -> Vec<(String, String)> {
+    let mut models: Vec<(String, String)> = vec![];
+    let mut add = |llm_name: &str, model: &str| {
+        let entry = (llm_name.to_string(), model.to_string());
+        if !model.is_empty() && !models.contains(&entry) {
+            models.push(entry);
+        }
+    };
+
+    // only read for model lists, nothing is sent
+    let retrier = Retrier::new(RetryPolicy::default());
+    let mut llm_names: Vec<&String> = customer_config.llm_configs.keys().collect();
+    llm_names.sort();
+    for llm_name in llm_names {
+        let llm_config = &customer_config.llm_configs[llm_name];
+        let ctx = ProviderContext {
+            api_key: &llm_config.api_key,
+            llm_config,
+            retrier: &retrier,
+            google_tokens,
+            http_client,
+        };
+        if let Ok(provider) = providers.build(llm_config.provider(llm_name), &ctx) {
+            for model in provider
+                .get_models()
+                .iter()
+                .chain(&provider.get_embedding_models())
+            {
+                add(llm_name, model);
+            }
+        }
+        for model in &llm_config.models {
+            add(llm_name, model);
+        }
+    }
+
+    add(
+        &customer_config.selected_llm_name,
+        &customer_config.selected_llm_model,
+    );
+    for fallback in &customer_config.fallbacks {
+        if let Some(model) = &fallback.model {
+            add(&fallback.llm_name, model);
+        }
+    }
+    models
+}
+
+/// A model list and the config it was built from.
+type CachedModels = (Arc<CustomerConfig>, Arc<Vec<(String, String)>>);
+
+/// Model lists by felafax token. An entry holds the config it was built
+/// from and is rebuilt once the store hands out a new one, so edits to a
+/// config show up as soon as the store sees them.
+pub struct CustomerModelsCache {
+    entries: Mutex<LruCache<String, CachedModels>>,
+}
+
+impl CustomerModelsCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Mutex::new(LruCache::new(
+                NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN),
+            )),
+        }
+    }
+
+    /// The cached list for `felafax_token` if it was built from
+    /// `customer_config`, otherwise the one `build` returns.
+    pub fn get_or_insert_with(
+        &self,
+        felafax_token: &str,
+        customer_config: &Arc<CustomerConfig>,
+        build: impl FnOnce() -> Vec<(String, String)>,
+    ) -> Arc<Vec<(String, String)>> {
+        if let Some((config, models)) = self.entries.lock().unwrap().get(felafax_token) {
+            // holding the config keeps its address from being reused
+            if Arc::ptr_eq(config, customer_config) {
+                return models.clone();
+            }
+        }
+        let models = Arc::new(build());
+        self.entries.lock().unwrap().put(
+            felafax_token.to_string(),
+            (customer_config.clone(), models.clone()),
+        );
+        models
+    }
+}
+
+impl Default for CustomerModelsCache {
+    /// Room for the lists of 1000 tokens.
+    fn default() -> Self {
+        Self::new(1000)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn customer_config() -> CustomerConfig {
+        serde_json::from_value(serde_json::json!({
+            "selected_llm_name": "ollama",
+            "selected_llm_model": "llama3.1",
+            "llm_configs": {
+                "azure": {
+                    "api_key": "azure-key",
+                    "azure": {
+                        "endpoint": "https://my-resource.openai.azure.com",
+                        "deployments": {"gpt-4o": "prod-4o", "gpt-4o-mini": "prod-mini"}
+                    }
+                },
+                "bedrock": {"models": ["anthropic.claude-3-haiku-20240307-v1:0"]},
+                "my-vllm": {
+                    "provider": "openai_compatible",
+                    "base_url": "http://localhost:8000/v1",
+                    "models": ["meta-llama/Llama-3.1-8B-Instruct"]
+                },
+                "ollama": {}
+            },
+            "fallbacks": [{"llm_name": "azure", "model": "gpt-4"}]
+        }))
+        .unwrap()
+    }
+
+    fn models(customer_config: &CustomerConfig) -> Vec<(String, String)> {
+        let http_client = reqwest::Client::new();
+        customer_models(
+            customer_config,
+            &ProviderRegistry::new(),
+            &Arc::new(GoogleTokenCache::new(http_client.clone())),
+            &http_client,
+        )
+    }
+
+    fn entry(llm_name: &str, model: &str) -> (String, String) {
+        (llm_name.to_string(), model.to_string())
+    }
+
+    #[test]
+    fn routes_models_by_id_and_prefix() {
+        let registry = ModelRegistry::new(&reqwest::Client::new());
+        let llm_name = |model| registry.get_llm_name(model);
+        assert_eq!(llm_name("gpt-4o-mini").as_deref(), Some("openai"));
+        assert_eq!(
+            llm_name("claude-3-5-sonnet-20240620").as_deref(),
+            Some("claude")
+        );
+        assert_eq!(llm_name("jamba-1.5-large").as_deref(), Some("jamba"));
+        assert_eq!(llm_name("gemini-1.5-pro").as_deref(), Some("gemini"));
+        assert_eq!(llm_name("command-r-plus").as_deref(), Some("cohere"));
+        assert_eq!(
+            llm_name("anthropic.claude-3-haiku-20240307-v1:0").as_deref(),
+            Some("bedrock")
+        );
+        assert_eq!(llm_name("llama3.1"), None);
+    }
+
+    #[test]
+    fn keeps_embedding_models_apart_from_chat() {
+        let registry = ModelRegistry::new(&reqwest::Client::new());
+        assert_eq!(registry.get_llm_name("text-embedding-3-small"), None);
+        assert_eq!(
+            registry
+                .get_embedding_llm_name("text-embedding-3-small")
+                .as_deref(),
+            Some("openai")
+        );
+        assert_eq!(
+            registry
+                .get_embedding_llm_name("embed-english-v3.0")
+                .as_deref(),
+            Some("cohere")
+        );
+        assert_eq!(registry.get_embedding_llm_name("gpt-4o"), None);
+    }
+
+    #[test]
+    fn resolves_hot_swap_and_allowlisted_models() {
+        let registry = ModelRegistry::new(&reqwest::Client::new());
+        let customer_config = customer_config();
+        let resolve = |model| registry.resolve(model, &customer_config);
+        assert_eq!(resolve(HOT_SWAP_MODEL), Some(entry("ollama", "llama3.1")));
+        assert_eq!(resolve(""), Some(entry("ollama", "llama3.1")));
+        assert_eq!(
+            resolve("meta-llama/Llama-3.1-8B-Instruct"),
+            Some(entry("my-vllm", "meta-llama/Llama-3.1-8B-Instruct"))
+        );
+        assert_eq!(resolve("gpt-4o"), Some(entry("openai", "gpt-4o")));
+        assert_eq!(resolve("unknown-model"), None);
+    }
+
+    #[test]
+    fn lists_the_models_of_every_configured_provider() {
+        assert_eq!(
+            models(&customer_config()),
+            vec![
+                entry("azure", "gpt-4o"),
+                entry("azure", "gpt-4o-mini"),
+                // no credentials to build a client with, the allowlist still counts
+                entry("bedrock", "anthropic.claude-3-haiku-20240307-v1:0"),
+                entry("my-vllm", "meta-llama/Llama-3.1-8B-Instruct"),
+                entry("ollama", "llama3.1"),
+                entry("azure", "gpt-4"),
+            ]
+        );
+    }
+
+    #[test]
+    fn lists_hosted_models_without_duplicates() {
+        let mut customer_config = customer_config();
+        customer_config.selected_llm_name = "openai".to_string();
+        customer_config.selected_llm_model = "gpt-4o".to_string();
+        customer_config.llm_configs = HashMap::from([(
+            "openai".to_string(),
+            serde_json::from_value(serde_json::json!({"api_key": "sk-1", "models": ["gpt-4o"]}))
+                .unwrap(),
+        )]);
+        customer_config.fallbacks.clear();
+
+        let models = models(&customer_config);
+        let gpt_4o = models
+            .iter()
+            .filter(|listed| **listed == entry("openai", "gpt-4o"))
+            .count();
+        assert_eq!(gpt_4o, 1);
+        assert!(models.contains(&entry("openai", "text-embedding-3-small")));
+    }
+
+    #[test]
+    fn rebuilds_lists_when_the_config_changes() {
+        let cache = CustomerModelsCache::new(10);
+        let config = Arc::new(customer_config());
+        let built = || vec![entry("ollama", "llama3.1")];
+
+        let first = cache.get_or_insert_with("tok-1", &config, built);
+        let second = cache.get_or_insert_with("tok-1", &config, || unreachable!());
+        assert!(Arc::ptr_eq(&first, &second));
+
+        // a reloaded config is a new Arc, even with the same contents
+        let reloaded = Arc::new(customer_config());
+        let third = cache.get_or_insert_with("tok-1", &reloaded, Vec::new);
+        assert!(third.is_empty());
+    }
+}
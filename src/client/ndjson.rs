@@ -0,0 +1,79 @@
+use anyhow::Result;
+use bytes::Bytes;
+use futures::stream::{BoxStream, StreamExt};
+use futures::Stream;
+
+/// Splits an upstream newline delimited JSON response into its non-empty
+/// lines, as streamed by Ollama and Cohere.
+pub fn ndjson_lines(response: reqwest::Response) -> impl Stream<Item = Result<String>> + Send {
+    let bytes: BoxStream<'static, Result<Bytes>> = response
+        .bytes_stream()
+        .map(|chunk| chunk.map_err(Into::into))
+        .boxed();
+
+    futures::stream::unfold(
+        (bytes, Vec::<u8>::new(), false),
+        |(mut bytes, mut buffer, mut done)| async move {
+            loop {
+                if let Some(line) = take_line(&mut buffer, done) {
+                    return Some((Ok(line), (bytes, buffer, done)));
+                }
+                if done {
+                    return None;
+                }
+                match bytes.next().await {
+                    Some(Ok(chunk)) => buffer.extend_from_slice(&chunk),
+                    Some(Err(e)) => return Some((Err(e), (bytes, Vec::new(), true))),
+                    None => done = true,
+                }
+            }
+        },
+    )
+}
+
+/// Pops the next non-empty line from `buffer`. When `flush` is set the
+/// remainder of the buffer is treated as a final line.
+fn take_line(buffer: &mut Vec<u8>, flush: bool) -> Option<String> {
+    loop {
+        let raw: Vec<u8> = match buffer.iter().position(|b| *b == b'\n') {
+            Some(end_pos) => buffer.drain(..end_pos + 1).collect(),
+            None if flush && !buffer.is_empty() => std::mem::take(buffer),
+            None => return None,
+        };
+        let line = String::from_utf8_lossy(&raw).trim().to_string();
+        if !line.is_empty() {
+            return Some(line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(body: &'static str) -> Vec<String> {
+        let response = reqwest::Response::from(axum::http::Response::new(body));
+        futures::executor::block_on(ndjson_lines(response).collect::<Vec<_>>())
+            .into_iter()
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    #[test]
+    fn splits_lines_and_skips_blank_ones() {
+        assert_eq!(
+            lines("{\"a\":1}\r\n\n  \n{\"b\":2}\n{\"c\":3}"),
+            ["{\"a\":1}", "{\"b\":2}", "{\"c\":3}"]
+        );
+    }
+
+    #[test]
+    fn keeps_partial_lines_until_flushed() {
+        let mut buffer = b"{\"a\":1}\n{\"b\"".to_vec();
+        assert_eq!(take_line(&mut buffer, false).as_deref(), Some("{\"a\":1}"));
+        assert_eq!(take_line(&mut buffer, false), None);
+        buffer.extend_from_slice(b":2}");
+        assert_eq!(take_line(&mut buffer, true).as_deref(), Some("{\"b\":2}"));
+        assert!(buffer.is_empty());
+    }
+}
@@ -0,0 +1,652 @@
+use super::ndjson::ndjson_lines;
+use super::traits::ChatTrait;
+use crate::error::Error;
+use crate::retry::Retrier;
+use crate::types::LLMConfig;
+use crate::types::*;
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::DateTime;
+use futures::StreamExt;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+// Request types, see https://github.com/ollama/ollama/blob/main/docs/api.md#generate-a-chat-completion
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OllamaRequest {
+    pub model: String,
+    pub messages: Vec<OllamaMessage>,
+    /// Same shape as OpenAI's.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<OaiTool>>,
+    /// `json`, or a JSON schema the answer must follow.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub options: Option<OllamaOptions>,
+    pub stream: bool,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OllamaMessage {
+    pub role: String,
+    #[serde(default)]
+    pub content: String,
+    /// Base64 encoded images, without a data url prefix.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<OllamaToolCall>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OllamaToolCall {
+    pub function: OllamaFunctionCall,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OllamaFunctionCall {
+    pub name: String,
+    /// An object, unlike OpenAI's JSON encoded string.
+    pub arguments: Value,
+}
+
+/// Sampling parameters, named after llama.cpp's.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OllamaOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub num_predict: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+}
+
+// Response types, a streamed response is a line of this per chunk
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OllamaResponse {
+    pub model: String,
+    pub created_at: String,
+    pub message: Option<OllamaMessage>,
+    pub done: bool,
+    pub done_reason: Option<String>,
+    /// Prompt tokens, left out when the prompt was cached.
+    pub prompt_eval_count: Option<u32>,
+    /// Completion tokens.
+    pub eval_count: Option<u32>,
+    pub error: Option<String>,
+}
+
+impl OllamaResponse {
+    fn created(&self) -> u64 {
+        DateTime::parse_from_rfc3339(&self.created_at)
+            .map(|created_at| created_at.timestamp() as u64)
+            .unwrap_or_else(|_| {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs()
+            })
+    }
+
+    fn usage(&self) -> OaiUsage {
+        let prompt_tokens = self.prompt_eval_count.unwrap_or_default();
+        let completion_tokens = self.eval_count.unwrap_or_default();
+        OaiUsage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        }
+    }
+}
+
+fn map_done_reason(done_reason: Option<&str>, has_tool_calls: bool) -> String {
+    match done_reason {
+        Some("length") => "length".to_string(),
+        _ if has_tool_calls => "tool_calls".to_string(),
+        _ => "stop".to_string(),
+    }
+}
+
+impl From<OllamaToolCall> for OaiToolCall {
+    fn from(value: OllamaToolCall) -> Self {
+        // Ollama doesn't id its calls, results follow calls in order
+        OaiToolCall {
+            id: format!("call_{}", Uuid::new_v4().simple()),
+            tool_type: "function".to_string(),
+            function: OaiFunctionCall {
+                name: value.function.name,
+                arguments: value.function.arguments.to_string(),
+            },
+        }
+    }
+}
+
+/// Carries the id and tool call count across chunks.
+#[derive(Default, Debug, Clone)]
+struct OllamaStreamState {
+    id: String,
+    started: bool,
+    tool_calls: u32,
+}
+
+impl OllamaStreamState {
+    fn handle(&mut self, response: OllamaResponse) -> Result<OaiChatCompletionChunk> {
+        if let Some(error) = response.error {
+            return Err(Error::UpstreamError {
+                status: 500,
+                message: format!("Ollama stream failed: {}", error),
+            }
+            .into());
+        }
+
+        let message = response.message.clone().unwrap_or_default();
+        // Ollama sends each tool call whole, in a single chunk
+        let tool_call_deltas: Vec<OaiToolCallDelta> = message
+            .tool_calls
+            .into_iter()
+            .map(|tool_call| {
+                let tool_call = OaiToolCall::from(tool_call);
+                let delta = OaiToolCallDelta {
+                    index: self.tool_calls,
+                    id: Some(tool_call.id),
+                    tool_type: Some(tool_call.tool_type),
+                    function: Some(OaiFunctionCallDelta {
+                        name: Some(tool_call.function.name),
+                        arguments: Some(tool_call.function.arguments),
+                    }),
+                };
+                self.tool_calls += 1;
+                delta
+            })
+            .collect();
+
+        let content = &message.content;
+        let choice = OaiChunkChoice {
+            index: 0,
+            delta: OaiDelta {
+                role: (!self.started).then(|| "assistant".to_string()),
+                content: (!content.is_empty() || !self.started).then(|| content.clone()),
+                tool_calls: (!tool_call_deltas.is_empty()).then_some(tool_call_deltas),
+            },
+            logprobs: None,
+            finish_reason: response
+                .done
+                .then(|| map_done_reason(response.done_reason.as_deref(), self.tool_calls > 0)),
+        };
+        self.started = true;
+
+        let mut chunk = OaiChatCompletionChunkBuilder::default()
+            .id(self.id.clone())
+            .object("chat.completion.chunk")
+            .created(response.created())
+            .model(response.model.clone())
+            .choices(vec![choice])
+            .build()
+            .unwrap();
+        // counts only come with the final chunk
+        if response.done {
+            chunk.usage = Some(response.usage());
+        }
+        Ok(chunk)
+    }
+}
+
+pub struct Ollama {
+    api_key: String,
+    base_url: Option<String>,
+    retrier: Retrier,
+    http_client: reqwest::Client,
+}
+
+impl LLMConfig for Ollama {
+    fn get_api_key(&self) -> String {
+        self.api_key.clone()
+    }
+
+    fn set_api_key(&mut self, api_key: &str) {
+        self.api_key = api_key.to_string();
+    }
+
+    fn get_base_url(&self) -> String {
+        self.base_url
+            .clone()
+            .unwrap_or_else(|| "http://localhost:11434".to_string())
+    }
+
+    fn get_name(&self) -> String {
+        "Ollama".to_string()
+    }
+
+    fn get_default_model(&self) -> String {
+        "llama3.2".to_string()
+    }
+
+    // Models are whatever was pulled locally, list them in the config's
+    // `models` to route them here.
+    fn get_models(&self) -> Vec<String> {
+        vec![]
+    }
+
+    fn get_model_prefixes(&self) -> Vec<String> {
+        vec![]
+    }
+}
+
+impl Ollama {
+    /// Sends every request with the pooled `http_client`.
+    pub fn new(http_client: reqwest::Client) -> Self {
+        Self {
+            api_key: "".to_string(),
+            base_url: None,
+            retrier: Retrier::default(),
+            http_client,
+        }
+    }
+
+    /// Only needed when Ollama sits behind an authenticating proxy.
+    pub fn with_api_key(mut self, api_key: &str) -> Self {
+        self.api_key = api_key.to_string();
+        self
+    }
+
+    /// e.g. `http://gpu-box:11434`, defaults to a local Ollama.
+    pub fn with_base_url(mut self, base_url: Option<String>) -> Self {
+        self.base_url = base_url;
+        self
+    }
+
+    pub fn with_retrier(mut self, retrier: Retrier) -> Self {
+        self.retrier = retrier;
+        self
+    }
+
+    async fn send(&self, ollama_request: &OllamaRequest) -> Result<reqwest::Response> {
+        let url = format!("{}/api/chat", self.get_base_url().trim_end_matches('/'));
+        let http_client = &self.http_client;
+        let http_response = self
+            .retrier
+            .execute(|| {
+                let request = http_client.post(&url).json(ollama_request);
+                if self.api_key.is_empty() {
+                    request.send()
+                } else {
+                    request.bearer_auth(&self.api_key).send()
+                }
+            })
+            .await?;
+
+        if !http_response.status().is_success() {
+            let status = http_response.status().as_u16();
+            let error_text = http_response
+                .text()
+                .await
+                .unwrap_or_else(|_| "Failed to read response text".to_string());
+
+            tracing::error!(
+                status = status,
+                error = error_text,
+                "Failed to make completion request to Ollama",
+            );
+            return Err(Error::UpstreamError {
+                status,
+                message: format!(
+                    "Failed to make completion request to Ollama: {}",
+                    error_text
+                ),
+            }
+            .into());
+        }
+        Ok(http_response)
+    }
+
+    fn request(&self, request: OaiChatCompletionRequest, stream: bool) -> Result<OllamaRequest> {
+        let mut ollama_request = OllamaRequest::try_from(request)?;
+        if ollama_request.model.is_empty() {
+            ollama_request.model = self.get_default_model();
+        }
+        ollama_request.stream = stream;
+        Ok(ollama_request)
+    }
+}
+
+#[async_trait]
+impl ChatTrait for Ollama {
+    async fn chat(&self, request: OaiChatCompletionRequest) -> Result<OaiChatCompletionResponse> {
+        let ollama_request = self.request(request, false)?;
+        let http_response = self.send(&ollama_request).await?;
+        let result = http_response.json::<OllamaResponse>().await?;
+        if let Some(error) = result.error {
+            return Err(Error::UpstreamError {
+                status: 500,
+                message: format!("Failed to make completion request to Ollama: {}", error),
+            }
+            .into());
+        }
+        Ok(result.into())
+    }
+
+    async fn chat_stream(
+        &self,
+        request: OaiChatCompletionRequest,
+    ) -> Result<OaiChatCompletionStream> {
+        let ollama_request = self.request(request, true)?;
+        let http_response = self.send(&ollama_request).await?;
+
+        let mut state = OllamaStreamState {
+            id: format!("chatcmpl-{}", Uuid::new_v4()),
+            ..Default::default()
+        };
+        let stream = ndjson_lines(http_response).map(move |line| {
+            line.and_then(|line| Ok(serde_json::from_str::<OllamaResponse>(&line)?))
+                .and_then(|response| state.handle(response))
+        });
+        Ok(Box::pin(stream))
+    }
+}
+
+impl TryFrom<OaiChatCompletionRequest> for OllamaRequest {
+    type Error = Error;
+
+    fn try_from(value: OaiChatCompletionRequest) -> Result<Self, Self::Error> {
+        if value.n.is_some_and(|n| n > 1) {
+            return Err(Error::InvalidArgument(
+                "Ollama returns a single choice, n must be 1".to_string(),
+            ));
+        }
+
+        let mut messages = vec![];
+        for msg in value.messages {
+            let role = match msg.role.as_str() {
+                "developer" => "system".to_string(),
+                "system" | "user" | "assistant" | "tool" => msg.role.clone(),
+                role => {
+                    return Err(Error::InvalidArgument(format!(
+                        "role '{}' is not supported by Ollama",
+                        role
+                    )))
+                }
+            };
+            msg.content.check_parts()?;
+            // images only go with user turns
+            let content = if role == "user" {
+                msg.content.text()
+            } else {
+                msg.content.text_only()?
+            };
+            let images = match &msg.content {
+                OaiMessageContent::Text(_) => vec![],
+                OaiMessageContent::Parts(parts) => parts
+                    .iter()
+                    .filter_map(|part| part.image_url.as_ref())
+                    .map(|image_url| image(&image_url.url))
+                    .collect::<Result<_, _>>()?,
+            };
+            let tool_calls = msg
+                .tool_calls
+                .into_iter()
+                .flatten()
+                .map(|tool_call| {
+                    let arguments =
+                        serde_json::from_str(&tool_call.function.arguments).map_err(|_| {
+                            Error::InvalidArgument(format!(
+                                "arguments of tool call {} are not valid JSON",
+                                tool_call.id
+                            ))
+                        })?;
+                    Ok(OllamaToolCall {
+                        function: OllamaFunctionCall {
+                            name: tool_call.function.name,
+                            arguments,
+                        },
+                    })
+                })
+                .collect::<Result<_, Error>>()?;
+            messages.push(OllamaMessage {
+                role,
+                content,
+                images,
+                tool_calls,
+            });
+        }
+
+        // Ollama can't be told not to call tools, so they aren't sent at all
+        let tools_disabled = matches!(
+            &value.tool_choice,
+            Some(OaiToolChoice::Mode(mode)) if mode == "none"
+        );
+        let tools = value.tools.filter(|_| !tools_disabled);
+
+        // Ollama takes "json", or the schema itself for structured outputs
+        let format = match value.response_format.as_ref() {
+            Some(format) => match format.get("type").and_then(Value::as_str) {
+                Some("json_object") => Some(Value::String("json".to_string())),
+                Some("json_schema") => Some(
+                    format
+                        .pointer("/json_schema/schema")
+                        .cloned()
+                        .ok_or_else(|| {
+                            Error::InvalidArgument(
+                                "response_format json_schema must set `json_schema.schema`"
+                                    .to_string(),
+                            )
+                        })?,
+                ),
+                _ => None,
+            },
+            None => None,
+        };
+
+        let options = OllamaOptions {
+            temperature: value.temperature,
+            top_p: value.top_p,
+            num_predict: value.max_tokens,
+            stop: value.stop,
+            seed: value.seed,
+            presence_penalty: value.presence_penalty,
+            frequency_penalty: value.frequency_penalty,
+        };
+
+        Ok(OllamaRequest {
+            model: value.model,
+            messages,
+            tools,
+            format,
+            options: (options != OllamaOptions::default()).then_some(options),
+            stream: false,
+        })
+    }
+}
+
+/// Ollama only takes inline images, as bare base64.
+fn image(url: &str) -> Result<String, Error> {
+    url.strip_prefix("data:")
+        .and_then(|data_url| data_url.split_once(";base64,"))
+        .map(|(_, data)| data.to_string())
+        .ok_or_else(|| {
+            Error::InvalidArgument(
+                "Ollama only supports base64 data url images, not links".to_string(),
+            )
+        })
+}
+
+impl From<OllamaResponse> for OaiChatCompletionResponse {
+    fn from(value: OllamaResponse) -> Self {
+        let created = value.created();
+        let usage = value.usage();
+        let message = value.message.unwrap_or_default();
+        let tool_calls: Vec<OaiToolCall> = message
+            .tool_calls
+            .into_iter()
+            .map(OaiToolCall::from)
+            .collect();
+        let finish_reason = map_done_reason(value.done_reason.as_deref(), !tool_calls.is_empty());
+
+        OaiChatCompletionResponse {
+            id: format!("chatcmpl-{}", Uuid::new_v4()),
+            object: "chat.completion".to_string(),
+            created,
+            model: value.model,
+            system_fingerprint: None,
+            choices: vec![OaiChoice {
+                index: 0,
+                message: OaiMessage {
+                    role: "assistant".to_string(),
+                    content: message.content.into(),
+                    tool_calls: (!tool_calls.is_empty()).then_some(tool_calls),
+                    ..Default::default()
+                },
+                logprobs: None,
+                finish_reason: Some(finish_reason),
+            }],
+            usage: Some(usage),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn convert(request: Value) -> Result<Value, Error> {
+        let request: OaiChatCompletionRequest = serde_json::from_value(request).unwrap();
+        let ollama_request = OllamaRequest::try_from(request)?;
+        Ok(serde_json::to_value(ollama_request).unwrap())
+    }
+
+    fn response(value: Value) -> OllamaResponse {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn converts_messages_and_options() {
+        let request = convert(json!({
+            "model": "llama3.1",
+            "temperature": 0.5,
+            "max_tokens": 100,
+            "response_format": {"type": "json_object"},
+            "tool_choice": "none",
+            "tools": [{"type": "function", "function": {"name": "get_weather"}}],
+            "messages": [
+                {"role": "developer", "content": "Be brief."},
+                {"role": "user", "content": [
+                    {"type": "text", "text": "What is this?"},
+                    {"type": "image_url", "image_url": {"url": "data:image/png;base64,iVBORw0"}},
+                ]},
+                {"role": "assistant", "content": "", "tool_calls": [{
+                    "id": "call_1",
+                    "type": "function",
+                    "function": {"name": "get_weather", "arguments": "{\"city\":\"Paris\"}"},
+                }]},
+                {"role": "tool", "tool_call_id": "call_1", "content": "Sunny"},
+            ],
+        }))
+        .unwrap();
+        assert_eq!(
+            request,
+            json!({
+                "model": "llama3.1",
+                "messages": [
+                    {"role": "system", "content": "Be brief."},
+                    {"role": "user", "content": "What is this?", "images": ["iVBORw0"]},
+                    {"role": "assistant", "content": "", "tool_calls": [
+                        {"function": {"name": "get_weather", "arguments": {"city": "Paris"}}},
+                    ]},
+                    {"role": "tool", "content": "Sunny"},
+                ],
+                // tools can't be turned off, so they aren't sent
+                "format": "json",
+                "options": {"temperature": 0.5, "num_predict": 100},
+                "stream": false,
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_requests_ollama_cannot_take() {
+        let linked_image = convert(json!({
+            "model": "llama3.1",
+            "messages": [{"role": "user", "content": [
+                {"type": "image_url", "image_url": {"url": "https://example.com/cat.png"}},
+            ]}],
+        }));
+        assert!(matches!(linked_image, Err(Error::InvalidArgument(_))));
+
+        let several_choices = convert(json!({
+            "model": "llama3.1",
+            "n": 2,
+            "messages": [{"role": "user", "content": "Hi"}],
+        }));
+        assert!(matches!(several_choices, Err(Error::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn converts_responses() {
+        let response = OaiChatCompletionResponse::from(response(json!({
+            "model": "llama3.1",
+            "created_at": "2024-07-25T12:00:00Z",
+            "message": {"role": "assistant", "content": "", "tool_calls": [
+                {"function": {"name": "get_weather", "arguments": {"city": "Paris"}}},
+            ]},
+            "done": true,
+            "done_reason": "stop",
+            "prompt_eval_count": 10,
+            "eval_count": 5,
+        })));
+        assert_eq!(response.created, 1721908800);
+        let choice = &response.choices[0];
+        assert_eq!(choice.finish_reason.as_deref(), Some("tool_calls"));
+        let tool_call = &choice.message.tool_calls.as_ref().unwrap()[0];
+        assert_eq!(tool_call.function.arguments, r#"{"city":"Paris"}"#);
+        assert_eq!(response.usage.unwrap().total_tokens, 15);
+    }
+
+    #[test]
+    fn streams_chunks_with_usage_on_the_last() {
+        let mut state = OllamaStreamState::default();
+        let first = state
+            .handle(response(json!({
+                "model": "llama3.1",
+                "message": {"role": "assistant", "content": "Hi"},
+            })))
+            .unwrap();
+        assert_eq!(first.choices[0].delta.role.as_deref(), Some("assistant"));
+        assert_eq!(first.choices[0].delta.content.as_deref(), Some("Hi"));
+        assert!(first.usage.is_none());
+
+        let last = state
+            .handle(response(json!({
+                "model": "llama3.1",
+                "message": {"role": "assistant", "content": ""},
+                "done": true,
+                "done_reason": "length",
+                "eval_count": 5,
+            })))
+            .unwrap();
+        let choice = &last.choices[0];
+        assert_eq!(
+            (choice.delta.role.as_ref(), choice.delta.content.as_ref()),
+            (None, None)
+        );
+        assert_eq!(choice.finish_reason.as_deref(), Some("length"));
+        assert_eq!(last.usage.unwrap().completion_tokens, 5);
+
+        let error = state
+            .handle(response(json!({"error": "model not found"})))
+            .unwrap_err();
+        assert_eq!(crate::error::upstream_status(&error), Some(500));
+    }
+}
@@ -1,11 +1,18 @@
-use super::traits::ChatTrait;
+use super::traits::{start_stream, ChatTrait, EmbeddingsTrait};
+use crate::error::Error;
+use crate::retry::{no_backoff, Retrier};
 use crate::types::config::LLMConfig;
 use crate::types::*;
 use anyhow::Result;
 use async_openai;
+use async_trait::async_trait;
+use futures::StreamExt;
 
 pub struct OpenAI {
     api_key: String,
+    base_url: Option<String>,
+    retrier: Retrier,
+    http_client: reqwest::Client,
 }
 
 impl LLMConfig for OpenAI {
@@ -28,12 +35,48 @@ impl LLMConfig for OpenAI {
     fn get_default_model(&self) -> String {
         "gpt-4o".to_string()
     }
+
+    fn get_models(&self) -> Vec<String> {
+        vec![
+            "gpt-4o".to_string(),
+            "gpt-4o-mini".to_string(),
+            "gpt-4-turbo".to_string(),
+            "gpt-4".to_string(),
+            "gpt-3.5-turbo".to_string(),
+            "o1-preview".to_string(),
+            "o1-mini".to_string(),
+        ]
+    }
+
+    fn get_model_prefixes(&self) -> Vec<String> {
+        vec![
+            "gpt-".to_string(),
+            "chatgpt-".to_string(),
+            "o1-".to_string(),
+        ]
+    }
+
+    fn get_embedding_models(&self) -> Vec<String> {
+        vec![
+            "text-embedding-3-small".to_string(),
+            "text-embedding-3-large".to_string(),
+            "text-embedding-ada-002".to_string(),
+        ]
+    }
+
+    fn get_embedding_model_prefixes(&self) -> Vec<String> {
+        vec!["text-embedding-".to_string()]
+    }
 }
 
 impl OpenAI {
-    pub fn new() -> Self {
+    /// Sends every request with the pooled `http_client`.
+    pub fn new(http_client: reqwest::Client) -> Self {
         Self {
             api_key: "".to_string(),
+            base_url: None,
+            retrier: Retrier::default(),
+            http_client,
         }
     }
 
@@ -41,22 +84,134 @@ impl OpenAI {
         self.api_key = api_key.to_string();
         self
     }
+
+    /// Sends requests to an OpenAI-compatible API instead, e.g.
+    /// `http://localhost:8080/v1`.
+    pub fn with_base_url(mut self, base_url: Option<String>) -> Self {
+        self.base_url = base_url;
+        self
+    }
+
+    pub fn with_retrier(mut self, retrier: Retrier) -> Self {
+        self.retrier = retrier;
+        self
+    }
+
+    fn client(&self) -> async_openai::Client<async_openai::config::OpenAIConfig> {
+        let mut config =
+            async_openai::config::OpenAIConfig::new().with_api_key(self.api_key.clone());
+        if let Some(base_url) = &self.base_url {
+            config = config.with_api_base(base_url.trim_end_matches('/'));
+        }
+        async_openai::Client::with_config(config)
+            .with_http_client(self.http_client.clone())
+            .with_backoff(no_backoff())
+    }
+}
+
+impl EmbeddingsTrait for OpenAI {
+    async fn embeddings(&self, request: OaiEmbeddingRequest) -> Result<OaiEmbeddingResponse> {
+        let input = match request.input {
+            OaiEmbeddingInput::String(text) => async_openai::types::EmbeddingInput::String(text),
+            OaiEmbeddingInput::StringArray(texts) => {
+                async_openai::types::EmbeddingInput::StringArray(texts)
+            }
+            OaiEmbeddingInput::Tokens(tokens) => {
+                async_openai::types::EmbeddingInput::IntegerArray(tokens)
+            }
+            OaiEmbeddingInput::TokensArray(tokens) => {
+                async_openai::types::EmbeddingInput::ArrayOfIntegerArray(tokens)
+            }
+        };
+        // always fetch floats, base64 is encoded by the gateway for every provider
+        let openai_request = async_openai::types::CreateEmbeddingRequest {
+            model: request.model,
+            input,
+            encoding_format: Some(async_openai::types::EncodingFormat::Float),
+            user: request.user,
+            dimensions: request.dimensions,
+        };
+        let client = &self.client();
+        let openai_request = &openai_request;
+        let response =
+            self.retrier
+                .retry(move || async move {
+                    Ok(client.embeddings().create(openai_request.clone()).await?)
+                })
+                .await?;
+
+        let mut data: Vec<_> = response.data;
+        data.sort_by_key(|embedding| embedding.index);
+        Ok(OaiEmbeddingResponse::from_vectors(
+            &response.model,
+            data.into_iter()
+                .map(|embedding| embedding.embedding)
+                .collect(),
+            response.usage.prompt_tokens,
+        ))
+    }
 }
 
+#[async_trait]
 impl ChatTrait for OpenAI {
     async fn chat(&self, request: OaiChatCompletionRequest) -> Result<OaiChatCompletionResponse> {
-        println!("OPENAI REQUEST: {:?}", request);
-        let config = async_openai::config::OpenAIConfig::new().with_api_key(self.api_key.clone());
-        let client = async_openai::Client::with_config(config);
-        let mut openai_request: async_openai::types::CreateChatCompletionRequest = request.into();
-        openai_request.model = self.get_default_model();
-        let response = client.chat().create(openai_request).await?;
+        let client = &self.client();
+        let mut openai_request =
+            async_openai::types::CreateChatCompletionRequest::try_from(request)?;
+        if openai_request.model.is_empty() {
+            openai_request.model = self.get_default_model();
+        }
+        openai_request.stream = None;
+        openai_request.stream_options = None;
+        let openai_request = &openai_request;
+        let response = self
+            .retrier
+            .retry(move || async move { Ok(client.chat().create(openai_request.clone()).await?) })
+            .await?;
         Ok(response.into())
     }
+
+    async fn chat_stream(
+        &self,
+        request: OaiChatCompletionRequest,
+    ) -> Result<OaiChatCompletionStream> {
+        let client = &self.client();
+        let mut openai_request =
+            async_openai::types::CreateChatCompletionRequest::try_from(request)?;
+        if openai_request.model.is_empty() {
+            openai_request.model = self.get_default_model();
+        }
+        // always ask for usage so the final chunk can be logged
+        openai_request.stream_options = Some(async_openai::types::ChatCompletionStreamOptions {
+            include_usage: true,
+        });
+        let openai_request = &openai_request;
+        self.retrier
+            .retry(move || async move {
+                let stream = client.chat().create_stream(openai_request.clone()).await?;
+                start_stream(Box::pin(stream.map(|chunk| Ok(chunk?.into())))).await
+            })
+            .await
+    }
+}
+
+fn map_finish_reason(finish_reason: Option<async_openai::types::FinishReason>) -> Option<String> {
+    match finish_reason {
+        Some(async_openai::types::FinishReason::Stop) => Some("stop".to_string()),
+        Some(async_openai::types::FinishReason::Length) => Some("length".to_string()),
+        Some(async_openai::types::FinishReason::ToolCalls) => Some("tool_calls".to_string()),
+        Some(async_openai::types::FinishReason::ContentFilter) => {
+            Some("content_filter".to_string())
+        }
+        Some(async_openai::types::FinishReason::FunctionCall) => Some("function_call".to_string()),
+        _ => None,
+    }
 }
 
-impl From<OaiChatCompletionRequest> for async_openai::types::CreateChatCompletionRequest {
-    fn from(value: OaiChatCompletionRequest) -> Self {
+impl TryFrom<OaiChatCompletionRequest> for async_openai::types::CreateChatCompletionRequest {
+    type Error = Error;
+
+    fn try_from(value: OaiChatCompletionRequest) -> Result<Self, Self::Error> {
         let mut request_builder = async_openai::types::CreateChatCompletionRequestArgs::default();
 
         request_builder.model(value.model);
@@ -64,46 +219,67 @@ impl From<OaiChatCompletionRequest> for async_openai::types::CreateChatCompletio
             .messages
             .into_iter()
             .map(|msg| {
-                match msg.role.as_str() {
-                    "system" => async_openai::types::ChatCompletionRequestMessage::System(
+                Ok(match msg.role.as_str() {
+                    // newer OpenAI models take instructions as `developer`
+                    "system" | "developer" => async_openai::types::ChatCompletionRequestMessage::System(
                         async_openai::types::ChatCompletionRequestSystemMessage {
-                            content: msg.content,
+                            content: msg.content.text_only()?,
                             name: None, // or set appropriately
                         },
                     ),
                     "user" => async_openai::types::ChatCompletionRequestMessage::User(
                         async_openai::types::ChatCompletionRequestUserMessage {
-                            content:
-                                async_openai::types::ChatCompletionRequestUserMessageContent::Text(
-                                    msg.content,
-                                ),
+                            content: msg.content.into(),
                             name: None, // or set appropriately
                         },
                     ),
                     "assistant" => async_openai::types::ChatCompletionRequestMessage::Assistant(
                         async_openai::types::ChatCompletionRequestAssistantMessage {
-                            content: Some(msg.content),
-                            name: None, // or set appropriately
-                            tool_calls: None,
+                            content: match (msg.content.is_empty(), &msg.tool_calls) {
+                                (true, Some(_)) => None,
+                                _ => Some(msg.content.text_only()?),
+                            },
+                            name: msg.name,
+                            tool_calls: msg.tool_calls.map(|tool_calls| {
+                                tool_calls
+                                    .into_iter()
+                                    .map(|tool_call| {
+                                        async_openai::types::ChatCompletionMessageToolCall {
+                                            id: tool_call.id,
+                                            r#type:
+                                                async_openai::types::ChatCompletionToolType::Function,
+                                            function: async_openai::types::FunctionCall {
+                                                name: tool_call.function.name,
+                                                arguments: tool_call.function.arguments,
+                                            },
+                                        }
+                                    })
+                                    .collect()
+                            }),
                             function_call: None,
                         },
                     ),
                     "tool" => async_openai::types::ChatCompletionRequestMessage::Tool(
                         async_openai::types::ChatCompletionRequestToolMessage {
-                            content: msg.content,
-                            tool_call_id: "".to_string(), // or set appropriately
+                            content: msg.content.text_only()?,
+                            tool_call_id: msg.tool_call_id.unwrap_or_default(),
                         },
                     ),
                     "function" => async_openai::types::ChatCompletionRequestMessage::Function(
                         async_openai::types::ChatCompletionRequestFunctionMessage {
-                            content: Some(msg.content),
+                            content: Some(msg.content.text_only()?),
                             name: "".to_string(), // or set appropriately
                         },
                     ),
-                    _ => panic!("Unknown role: {}", msg.role),
-                }
+                    role => {
+                        return Err(Error::InvalidArgument(format!(
+                            "role '{}' is not supported by OpenAI",
+                            role
+                        )))
+                    }
+                })
             })
-            .collect();
+            .collect::<Result<_, Error>>()?;
         request_builder.messages(messages);
 
         if let Some(max_tokens) = value.max_tokens {
@@ -146,7 +322,46 @@ impl From<OaiChatCompletionRequest> for async_openai::types::CreateChatCompletio
         if let Some(seed) = value.seed {
             request_builder.seed(seed);
         }
-        request_builder.build().unwrap()
+        if let Some(tools) = value.tools {
+            let tools: Vec<async_openai::types::ChatCompletionTool> = tools
+                .into_iter()
+                .map(|tool| async_openai::types::ChatCompletionTool {
+                    r#type: async_openai::types::ChatCompletionToolType::Function,
+                    function: async_openai::types::FunctionObject {
+                        name: tool.function.name,
+                        description: tool.function.description,
+                        parameters: tool.function.parameters,
+                    },
+                })
+                .collect();
+            request_builder.tools(tools);
+        }
+        if let Some(tool_choice) = value.tool_choice {
+            let tool_choice = match tool_choice {
+                OaiToolChoice::Mode(mode) => match mode.as_str() {
+                    "none" => async_openai::types::ChatCompletionToolChoiceOption::None,
+                    "required" => async_openai::types::ChatCompletionToolChoiceOption::Required,
+                    _ => async_openai::types::ChatCompletionToolChoiceOption::Auto,
+                },
+                OaiToolChoice::Named(named) => {
+                    async_openai::types::ChatCompletionToolChoiceOption::Named(
+                        async_openai::types::ChatCompletionNamedToolChoice {
+                            r#type: async_openai::types::ChatCompletionToolType::Function,
+                            function: async_openai::types::FunctionName {
+                                name: named.function.name,
+                            },
+                        },
+                    )
+                }
+            };
+            request_builder.tool_choice(tool_choice);
+        }
+        if let Some(parallel_tool_calls) = value.parallel_tool_calls {
+            request_builder.parallel_tool_calls(parallel_tool_calls);
+        }
+        request_builder
+            .build()
+            .map_err(|e| Error::InvalidArgument(e.to_string()))
     }
 }
 
@@ -156,23 +371,24 @@ impl From<async_openai::types::CreateChatCompletionResponse> for OaiChatCompleti
         for choice in response.choices {
             let message = OaiMessage {
                 role: choice.message.role.to_string(),
-                content: choice.message.content.unwrap_or_default(),
+                content: choice.message.content.unwrap_or_default().into(),
+                tool_calls: choice.message.tool_calls.map(|tool_calls| {
+                    tool_calls
+                        .into_iter()
+                        .map(|tool_call| OaiToolCall {
+                            id: tool_call.id,
+                            tool_type: "function".to_string(),
+                            function: OaiFunctionCall {
+                                name: tool_call.function.name,
+                                arguments: tool_call.function.arguments,
+                            },
+                        })
+                        .collect()
+                }),
+                ..Default::default()
             };
 
-            let finish_reason = match choice.finish_reason {
-                Some(async_openai::types::FinishReason::Stop) => Some("stop".to_string()),
-                Some(async_openai::types::FinishReason::Length) => Some("length".to_string()),
-                Some(async_openai::types::FinishReason::ToolCalls) => {
-                    Some("tool_calls".to_string())
-                }
-                Some(async_openai::types::FinishReason::ContentFilter) => {
-                    Some("content_filter".to_string())
-                }
-                Some(async_openai::types::FinishReason::FunctionCall) => {
-                    Some("function_call".to_string())
-                }
-                _ => None,
-            };
+            let finish_reason = map_finish_reason(choice.finish_reason);
             // TODO: add logpobs
             choices.push(OaiChoice {
                 index: choice.index,
@@ -201,3 +417,134 @@ impl From<async_openai::types::CreateChatCompletionResponse> for OaiChatCompleti
         }
     }
 }
+
+impl From<async_openai::types::CreateChatCompletionStreamResponse> for OaiChatCompletionChunk {
+    fn from(response: async_openai::types::CreateChatCompletionStreamResponse) -> Self {
+        let choices = response
+            .choices
+            .into_iter()
+            .map(|choice| OaiChunkChoice {
+                index: choice.index,
+                delta: OaiDelta {
+                    role: choice.delta.role.map(|role| role.to_string()),
+                    content: choice.delta.content,
+                    tool_calls: choice.delta.tool_calls.map(|tool_calls| {
+                        tool_calls
+                            .into_iter()
+                            .map(|tool_call| OaiToolCallDelta {
+                                index: tool_call.index as u32,
+                                id: tool_call.id,
+                                tool_type: tool_call.r#type.map(|_| "function".to_string()),
+                                function: tool_call.function.map(|function| OaiFunctionCallDelta {
+                                    name: function.name,
+                                    arguments: function.arguments,
+                                }),
+                            })
+                            .collect()
+                    }),
+                },
+                logprobs: None,
+                finish_reason: map_finish_reason(choice.finish_reason),
+            })
+            .collect();
+        let usage = response.usage.map(|usage| OaiUsage {
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            total_tokens: usage.total_tokens,
+        });
+        OaiChatCompletionChunk {
+            id: response.id,
+            object: response.object,
+            created: response.created.into(),
+            model: response.model,
+            system_fingerprint: response.system_fingerprint,
+            choices,
+            usage,
+        }
+    }
+}
+
+impl From<OaiMessageContent> for async_openai::types::ChatCompletionRequestUserMessageContent {
+    fn from(content: OaiMessageContent) -> Self {
+        match content {
+            OaiMessageContent::Text(text) => {
+                async_openai::types::ChatCompletionRequestUserMessageContent::Text(text)
+            }
+            OaiMessageContent::Parts(parts) => {
+                async_openai::types::ChatCompletionRequestUserMessageContent::Array(
+                    parts
+                        .into_iter()
+                        .map(|part| match part.image_url {
+                            Some(image_url) => {
+                                async_openai::types::ChatCompletionRequestMessageContentPart::ImageUrl(
+                                    async_openai::types::ChatCompletionRequestMessageContentPartImage {
+                                        image_url: async_openai::types::ImageUrl {
+                                            url: image_url.url,
+                                            detail: Some(match image_url.detail.as_deref() {
+                                            Some("low") => async_openai::types::ImageDetail::Low,
+                                            Some("high") => async_openai::types::ImageDetail::High,
+                                            _ => async_openai::types::ImageDetail::Auto,
+                                        }),
+                                        },
+                                    },
+                                )
+                            }
+                            None => async_openai::types::ChatCompletionRequestMessageContentPart::Text(
+                                async_openai::types::ChatCompletionRequestMessageContentPartText {
+                                    text: part.text.unwrap_or_default(),
+                                },
+                            ),
+                        })
+                        .collect(),
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::types::{ChatCompletionRequestMessage, CreateChatCompletionRequest};
+    use serde_json::json;
+
+    fn request(messages: serde_json::Value) -> OaiChatCompletionRequest {
+        serde_json::from_value(json!({"model": "gpt-4o", "messages": messages})).unwrap()
+    }
+
+    #[test]
+    fn developer_messages_become_system_messages() {
+        let request = request(json!([
+            {"role": "developer", "content": "Answer in French."},
+            {"role": "user", "content": "Hi"},
+        ]));
+        let openai_request = CreateChatCompletionRequest::try_from(request).unwrap();
+        match &openai_request.messages[0] {
+            ChatCompletionRequestMessage::System(system) => {
+                assert_eq!(system.content, "Answer in French.")
+            }
+            message => panic!("expected a system message, got {:?}", message),
+        }
+    }
+
+    #[test]
+    fn rejects_images_outside_user_messages() {
+        let image =
+            json!({"type": "image_url", "image_url": {"url": "https://example.com/cat.png"}});
+        for role in ["system", "assistant", "tool"] {
+            let request = request(json!([{"role": role, "content": [image.clone()]}]));
+            let error = CreateChatCompletionRequest::try_from(request).unwrap_err();
+            assert!(matches!(error, Error::InvalidArgument(_)), "{}", role);
+        }
+
+        let request = request(json!([{"role": "user", "content": [image]}]));
+        assert!(CreateChatCompletionRequest::try_from(request).is_ok());
+    }
+
+    #[test]
+    fn rejects_unknown_roles() {
+        let request = request(json!([{"role": "narrator", "content": "Once upon a time"}]));
+        let error = CreateChatCompletionRequest::try_from(request).unwrap_err();
+        assert!(matches!(error, Error::InvalidArgument(_)));
+    }
+}
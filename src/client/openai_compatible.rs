@@ -0,0 +1,313 @@
+use super::traits::{start_stream, ChatTrait, EmbeddingsTrait};
+use crate::error::Error;
+use crate::retry::{no_backoff, Retrier};
+use crate::types::config::LLMConfig;
+use crate::types::*;
+use anyhow::Result;
+use async_openai;
+use async_openai::config::{Config, OpenAIConfig};
+use async_trait::async_trait;
+use futures::StreamExt;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use secrecy::Secret;
+use std::collections::HashMap;
+
+/// Any server speaking the OpenAI protocol at a customer supplied URL, e.g.
+/// vLLM, llama.cpp server, LM Studio, Groq, Together or Fireworks.
+pub struct OpenAICompatible {
+    api_key: String,
+    base_url: String,
+    headers: HashMap<String, String>,
+    models: Vec<String>,
+    retrier: Retrier,
+    http_client: reqwest::Client,
+}
+
+impl LLMConfig for OpenAICompatible {
+    fn get_api_key(&self) -> String {
+        self.api_key.clone()
+    }
+
+    fn set_api_key(&mut self, api_key: &str) {
+        self.api_key = api_key.to_string();
+    }
+
+    fn get_base_url(&self) -> String {
+        self.base_url.clone()
+    }
+
+    fn get_name(&self) -> String {
+        "OpenAI-compatible".to_string()
+    }
+
+    /// The first allowed model, servers that serve a single model usually
+    /// ignore it anyway.
+    fn get_default_model(&self) -> String {
+        self.models.first().cloned().unwrap_or_default()
+    }
+
+    fn get_models(&self) -> Vec<String> {
+        self.models.clone()
+    }
+
+    fn get_model_prefixes(&self) -> Vec<String> {
+        vec![]
+    }
+}
+
+impl OpenAICompatible {
+    /// Sends every request with the pooled `http_client`.
+    pub fn new(http_client: reqwest::Client) -> Self {
+        Self {
+            api_key: "".to_string(),
+            base_url: "".to_string(),
+            headers: HashMap::new(),
+            models: vec![],
+            retrier: Retrier::default(),
+            http_client,
+        }
+    }
+
+    pub fn with_api_key(mut self, api_key: &str) -> Self {
+        self.api_key = api_key.to_string();
+        self
+    }
+
+    /// e.g. `http://localhost:8000/v1` or `https://api.groq.com/openai/v1`
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        self.base_url = base_url.to_string();
+        self
+    }
+
+    pub fn with_headers(mut self, headers: HashMap<String, String>) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Restricts requests to these models, any model is allowed when empty.
+    pub fn with_models(mut self, models: Vec<String>) -> Self {
+        self.models = models;
+        self
+    }
+
+    pub fn with_retrier(mut self, retrier: Retrier) -> Self {
+        self.retrier = retrier;
+        self
+    }
+
+    fn client(&self) -> Result<async_openai::Client<CompatibleConfig>> {
+        if self.base_url.is_empty() {
+            return Err(Error::InvalidArgument(
+                "OpenAI-compatible configs must set a `base_url`".to_string(),
+            )
+            .into());
+        }
+        let openai = OpenAIConfig::new()
+            .with_api_key(self.api_key.clone())
+            .with_api_base(self.base_url.trim_end_matches('/'));
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            let invalid = || Error::InvalidArgument(format!("Invalid header '{}'", name));
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid())?,
+                HeaderValue::from_str(value).map_err(|_| invalid())?,
+            );
+        }
+        let config = CompatibleConfig { openai, headers };
+        Ok(async_openai::Client::with_config(config)
+            .with_http_client(self.http_client.clone())
+            .with_backoff(no_backoff()))
+    }
+
+    /// The model to send upstream, checked against the allowlist.
+    fn model(&self, model: &str) -> Result<String> {
+        if model.is_empty() {
+            return Ok(self.get_default_model());
+        }
+        if !self.models.is_empty() && !self.models.iter().any(|allowed| allowed == model) {
+            return Err(Error::InvalidArgument(format!(
+                "Model '{}' is not allowed. Allowed models are: {}",
+                model,
+                self.models.join(", ")
+            ))
+            .into());
+        }
+        Ok(model.to_string())
+    }
+}
+
+/// OpenAI's config plus the customer's headers, sent with every request.
+#[derive(Debug, Clone)]
+struct CompatibleConfig {
+    openai: OpenAIConfig,
+    headers: HeaderMap,
+}
+
+impl Config for CompatibleConfig {
+    fn headers(&self) -> HeaderMap {
+        let mut headers = self.openai.headers();
+        headers.extend(self.headers.clone());
+        headers
+    }
+
+    fn url(&self, path: &str) -> String {
+        self.openai.url(path)
+    }
+
+    fn query(&self) -> Vec<(&str, &str)> {
+        self.openai.query()
+    }
+
+    fn api_base(&self) -> &str {
+        self.openai.api_base()
+    }
+
+    fn api_key(&self) -> &Secret<String> {
+        self.openai.api_key()
+    }
+}
+
+impl EmbeddingsTrait for OpenAICompatible {
+    async fn embeddings(&self, request: OaiEmbeddingRequest) -> Result<OaiEmbeddingResponse> {
+        let model = self.model(&request.model)?;
+        let input = match request.input {
+            OaiEmbeddingInput::String(text) => async_openai::types::EmbeddingInput::String(text),
+            OaiEmbeddingInput::StringArray(texts) => {
+                async_openai::types::EmbeddingInput::StringArray(texts)
+            }
+            OaiEmbeddingInput::Tokens(tokens) => {
+                async_openai::types::EmbeddingInput::IntegerArray(tokens)
+            }
+            OaiEmbeddingInput::TokensArray(tokens) => {
+                async_openai::types::EmbeddingInput::ArrayOfIntegerArray(tokens)
+            }
+        };
+        let request = async_openai::types::CreateEmbeddingRequest {
+            model,
+            input,
+            encoding_format: Some(async_openai::types::EncodingFormat::Float),
+            user: request.user,
+            dimensions: request.dimensions,
+        };
+        let client = &self.client()?;
+        let request = &request;
+        let response = self
+            .retrier
+            .retry(move || async move { Ok(client.embeddings().create(request.clone()).await?) })
+            .await?;
+
+        let mut data: Vec<_> = response.data;
+        data.sort_by_key(|embedding| embedding.index);
+        Ok(OaiEmbeddingResponse::from_vectors(
+            &response.model,
+            data.into_iter()
+                .map(|embedding| embedding.embedding)
+                .collect(),
+            response.usage.prompt_tokens,
+        ))
+    }
+}
+
+#[async_trait]
+impl ChatTrait for OpenAICompatible {
+    async fn chat(&self, request: OaiChatCompletionRequest) -> Result<OaiChatCompletionResponse> {
+        let client = &self.client()?;
+        let mut openai_request =
+            async_openai::types::CreateChatCompletionRequest::try_from(request)?;
+        openai_request.model = self.model(&openai_request.model)?;
+        openai_request.stream = None;
+        openai_request.stream_options = None;
+        let openai_request = &openai_request;
+        let response = self
+            .retrier
+            .retry(move || async move { Ok(client.chat().create(openai_request.clone()).await?) })
+            .await?;
+        Ok(response.into())
+    }
+
+    async fn chat_stream(
+        &self,
+        request: OaiChatCompletionRequest,
+    ) -> Result<OaiChatCompletionStream> {
+        let client = &self.client()?;
+        let mut openai_request =
+            async_openai::types::CreateChatCompletionRequest::try_from(request)?;
+        openai_request.model = self.model(&openai_request.model)?;
+        // most servers send usage on the final chunk when asked
+        openai_request.stream_options = Some(async_openai::types::ChatCompletionStreamOptions {
+            include_usage: true,
+        });
+        let openai_request = &openai_request;
+        self.retrier
+            .retry(move || async move {
+                let stream = client.chat().create_stream(openai_request.clone()).await?;
+                start_stream(Box::pin(stream.map(|chunk| Ok(chunk?.into())))).await
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint() -> OpenAICompatible {
+        OpenAICompatible::new(reqwest::Client::new())
+            .with_api_key("sk-1")
+            .with_base_url("http://localhost:8000/v1/")
+            .with_headers(HashMap::from([(
+                "x-tenant".to_string(),
+                "acme".to_string(),
+            )]))
+            .with_models(vec![
+                "meta-llama/Llama-3.1-8B-Instruct".to_string(),
+                "Qwen/Qwen2.5-7B-Instruct".to_string(),
+            ])
+    }
+
+    #[test]
+    fn only_sends_allowed_models() {
+        let vllm = endpoint();
+        assert_eq!(vllm.model("").unwrap(), "meta-llama/Llama-3.1-8B-Instruct");
+        assert_eq!(
+            vllm.model("Qwen/Qwen2.5-7B-Instruct").unwrap(),
+            "Qwen/Qwen2.5-7B-Instruct"
+        );
+        let error = vllm.model("gpt-4o").unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<Error>(),
+            Some(Error::InvalidArgument(_))
+        ));
+
+        // without an allowlist any model goes
+        let open = endpoint().with_models(vec![]);
+        assert_eq!(open.model("gpt-4o").unwrap(), "gpt-4o");
+    }
+
+    #[test]
+    fn sends_the_customers_headers_to_the_base_url() {
+        let vllm = endpoint();
+        let client = vllm.client().unwrap();
+        let config = client.config();
+        assert_eq!(
+            config.url("/chat/completions"),
+            "http://localhost:8000/v1/chat/completions"
+        );
+        let headers = config.headers();
+        assert_eq!(headers["x-tenant"], "acme");
+        assert_eq!(headers["authorization"], "Bearer sk-1");
+    }
+
+    #[test]
+    fn rejects_configs_it_cannot_call() {
+        let without_url = endpoint().with_base_url("");
+        assert!(without_url.client().is_err());
+
+        let bad_header = endpoint().with_headers(HashMap::from([(
+            "x tenant".to_string(),
+            "acme".to_string(),
+        )]));
+        assert!(bad_header.client().is_err());
+    }
+}
@@ -0,0 +1,253 @@
+use super::traits::ChatTrait;
+use super::{
+    azure, bedrock, claude, cohere, gemini, mamba, ollama, openai, openai_compatible, vertex,
+};
+use crate::error::Error;
+use crate::firestore::{CustomerLLMConfig, OPENAI_COMPATIBLE};
+use crate::google_auth::GoogleTokenCache;
+use crate::retry::Retrier;
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+pub type Provider = Box<dyn ChatTrait + Send + Sync>;
+
+/// Everything a provider factory gets to build a client for one request.
+pub struct ProviderContext<'a> {
+    pub api_key: &'a str,
+    pub llm_config: &'a CustomerLLMConfig,
+    pub retrier: &'a Retrier,
+    pub google_tokens: &'a Arc<GoogleTokenCache>,
+    /// The pooled client every provider sends requests with.
+    pub http_client: &'a reqwest::Client,
+}
+
+/// Builds a client, or fails when the config is missing what it needs.
+pub type ProviderFactory = fn(&ProviderContext) -> Result<Provider>;
+
+/// Maps provider names, as used in `selected_llm_name` and fallbacks, to the
+/// factories building their clients.
+#[derive(Debug, Clone, Default)]
+pub struct ProviderRegistry {
+    factories: HashMap<String, ProviderFactory>,
+}
+
+impl ProviderRegistry {
+    pub fn new() -> Self {
+        let mut registry = Self::default();
+        registry.register("openai", |ctx| {
+            Ok(Box::new(
+                openai::OpenAI::new(ctx.http_client.clone())
+                    .with_api_key(ctx.api_key)
+                    .with_base_url(ctx.llm_config.base_url.clone())
+                    .with_retrier(ctx.retrier.clone()),
+            ))
+        });
+        registry.register("claude", |ctx| {
+            Ok(Box::new(
+                claude::Claude::new(ctx.http_client.clone())
+                    .with_api_key(ctx.api_key)
+                    .with_retrier(ctx.retrier.clone()),
+            ))
+        });
+        registry.register("jamba", |ctx| {
+            Ok(Box::new(
+                mamba::Mamba::new(ctx.http_client.clone())
+                    .with_api_key(ctx.api_key)
+                    .with_retrier(ctx.retrier.clone()),
+            ))
+        });
+        registry.register("gemini", |ctx| {
+            Ok(Box::new(
+                gemini::Gemini::new(ctx.http_client.clone())
+                    .with_api_key(ctx.api_key)
+                    .with_retrier(ctx.retrier.clone()),
+            ))
+        });
+        registry.register("cohere", |ctx| {
+            Ok(Box::new(
+                cohere::Cohere::new(ctx.http_client.clone())
+                    .with_api_key(ctx.api_key)
+                    .with_retrier(ctx.retrier.clone()),
+            ))
+        });
+        // Bedrock signs with the config's AWS credentials rather than the api key
+        registry.register("bedrock", |ctx| {
+            let aws = ctx.llm_config.aws.clone().ok_or_else(|| {
+                Error::InvalidArgument("The bedrock config must set `aws` credentials".to_string())
+            })?;
+            Ok(Box::new(
+                bedrock::Bedrock::new(ctx.http_client.clone())
+                    .with_aws(aws)
+                    .with_base_url(ctx.llm_config.base_url.clone())
+                    .with_retrier(ctx.retrier.clone()),
+            ))
+        });
+        // Azure needs the resource endpoint and deployments on top of the api key
+        registry.register("azure", |ctx| {
+            let azure = ctx.llm_config.azure.clone().ok_or_else(|| {
+                Error::InvalidArgument("The azure config must set the `azure` resource".to_string())
+            })?;
+            Ok(Box::new(
+                azure::Azure::new(ctx.http_client.clone())
+                    .with_api_key(ctx.api_key)
+                    .with_azure(azure)
+                    .with_retrier(ctx.retrier.clone()),
+            ))
+        });
+        // Vertex AI authenticates with the config's service account
+        registry.register("vertex", |ctx| {
+            let vertex = ctx.llm_config.vertex.clone().ok_or_else(|| {
+                Error::InvalidArgument("The vertex config must set a `service_account`".to_string())
+            })?;
+            Ok(Box::new(
+                vertex::Vertex::new(ctx.http_client.clone(), ctx.google_tokens.clone())
+                    .with_vertex(vertex)
+                    .with_retrier(ctx.retrier.clone()),
+            ))
+        });
+        registry.register("ollama", |ctx| {
+            Ok(Box::new(
+                ollama::Ollama::new(ctx.http_client.clone())
+                    .with_api_key(ctx.api_key)
+                    .with_base_url(ctx.llm_config.base_url.clone())
+                    .with_retrier(ctx.retrier.clone()),
+            ))
+        });
+        registry.register(OPENAI_COMPATIBLE, |ctx| {
+            Ok(Box::new(
+                openai_compatible::OpenAICompatible::new(ctx.http_client.clone())
+                    .with_api_key(ctx.api_key)
+                    .with_base_url(ctx.llm_config.base_url.as_deref().unwrap_or_default())
+                    .with_headers(ctx.llm_config.headers.clone())
+                    .with_models(ctx.llm_config.models.clone())
+                    .with_retrier(ctx.retrier.clone()),
+            ))
+        });
+        registry
+    }
+
+    /// Adds a provider, replacing any registered under the same name.
+    pub fn register(&mut self, provider: &str, factory: ProviderFactory) {
+        self.factories.insert(provider.to_string(), factory);
+    }
+
+    pub fn contains(&self, provider: &str) -> bool {
+        self.factories.contains_key(provider)
+    }
+
+    /// Registered provider names, sorted.
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.factories.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Builds a client for `provider`, a 400 when it isn't registered.
+    pub fn build(&self, provider: &str, ctx: &ProviderContext) -> Result<Provider> {
+        let factory = self.factories.get(provider).ok_or_else(|| {
+            Error::InvalidArgument(format!(
+                "Unknown provider '{}'. Supported providers are: {}",
+                provider,
+                self.names().join(", ")
+            ))
+        })?;
+        factory(ctx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn build(provider: &str, llm_config: serde_json::Value) -> Result<Provider> {
+        build_with(&ProviderRegistry::new(), provider, llm_config)
+    }
+
+    fn build_with(
+        registry: &ProviderRegistry,
+        provider: &str,
+        llm_config: serde_json::Value,
+    ) -> Result<Provider> {
+        let llm_config: CustomerLLMConfig = serde_json::from_value(llm_config).unwrap();
+        let http_client = reqwest::Client::new();
+        let ctx = ProviderContext {
+            api_key: "sk-1",
+            llm_config: &llm_config,
+            retrier: &Retrier::default(),
+            google_tokens: &Arc::new(GoogleTokenCache::new(http_client.clone())),
+            http_client: &http_client,
+        };
+        registry.build(provider, &ctx)
+    }
+
+    fn invalid_argument(result: Result<Provider>) -> String {
+        match result.map_err(|e| e.downcast::<Error>()) {
+            Err(Ok(Error::InvalidArgument(message))) => message,
+            Err(e) => panic!("expected an invalid argument, got {:?}", e),
+            Ok(provider) => panic!("expected an error, built {}", provider.get_name()),
+        }
+    }
+
+    #[test]
+    fn builds_every_provider() {
+        let registry = ProviderRegistry::new();
+        let names = registry.names();
+        assert_eq!(
+            names,
+            [
+                "azure",
+                "bedrock",
+                "claude",
+                "cohere",
+                "gemini",
+                "jamba",
+                "ollama",
+                "openai",
+                "openai_compatible",
+                "vertex",
+            ]
+        );
+
+        let llm_config = json!({
+            "base_url": "http://localhost:8000/v1",
+            "aws": {"access_key_id": "AKIA", "secret_access_key": "secret", "region": "us-east-1"},
+            "azure": {"endpoint": "https://my-resource.openai.azure.com"},
+            "vertex": {"service_account": {"client_email": "a@b.com", "private_key": "pem"}},
+        });
+        for name in names {
+            let provider = build(&name, llm_config.clone()).unwrap();
+            assert!(!provider.get_name().is_empty());
+        }
+        let provider = build("openai_compatible", llm_config).unwrap();
+        assert_eq!(provider.get_base_url(), "http://localhost:8000/v1");
+        assert_eq!(provider.get_api_key(), "sk-1");
+    }
+
+    #[test]
+    fn rejects_configs_missing_what_the_provider_needs() {
+        for provider in ["bedrock", "azure", "vertex"] {
+            let message = invalid_argument(build(provider, json!({})));
+            assert!(message.contains(provider), "{}", message);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_providers() {
+        let message = invalid_argument(build("mistral", json!({})));
+        assert!(message.starts_with("Unknown provider 'mistral'"));
+        assert!(message.contains("openai_compatible"));
+    }
+
+    #[test]
+    fn registered_providers_replace_builtin_ones() {
+        let mut registry = ProviderRegistry::new();
+        registry.register("openai", |_| {
+            Err(Error::InvalidArgument("disabled".to_string()).into())
+        });
+        assert!(registry.contains("openai"));
+        let message = invalid_argument(build_with(&registry, "openai", json!({})));
+        assert_eq!(message, "disabled");
+    }
+}
@@ -0,0 +1,162 @@
+use anyhow::Result;
+use bytes::Bytes;
+use futures::stream::{BoxStream, StreamExt};
+use futures::Stream;
+
+/// A single server-sent event as emitted by upstream LLM APIs.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct SseEvent {
+    pub event: Option<String>,
+    pub data: String,
+}
+
+/// Splits an upstream `text/event-stream` response into events.
+pub fn sse_events(response: reqwest::Response) -> impl Stream<Item = Result<SseEvent>> + Send {
+    sse_stream(response.bytes_stream())
+}
+
+/// Splits any `text/event-stream` body into events.
+pub fn sse_stream<S, E>(bytes: S) -> impl Stream<Item = Result<SseEvent>> + Send
+where
+    S: Stream<Item = std::result::Result<Bytes, E>> + Send + 'static,
+    E: Into<anyhow::Error>,
+{
+    let bytes: BoxStream<'static, Result<Bytes>> =
+        bytes.map(|chunk| chunk.map_err(Into::into)).boxed();
+
+    futures::stream::unfold(
+        (bytes, Vec::<u8>::new(), false),
+        |(mut bytes, mut buffer, mut done)| async move {
+            loop {
+                if let Some(event) = take_event(&mut buffer, done) {
+                    return Some((Ok(event), (bytes, buffer, done)));
+                }
+                if done {
+                    return None;
+                }
+                match bytes.next().await {
+                    Some(Ok(chunk)) => {
+                        buffer.extend(chunk.iter().filter(|b| **b != b'\r'));
+                    }
+                    Some(Err(e)) => {
+                        return Some((Err(e), (bytes, Vec::new(), true)));
+                    }
+                    None => done = true,
+                }
+            }
+        },
+    )
+}
+
+/// Pops the next complete event from `buffer`. When `flush` is set the
+/// remainder of the buffer is treated as a final event.
+fn take_event(buffer: &mut Vec<u8>, flush: bool) -> Option<SseEvent> {
+    loop {
+        let raw = match buffer.windows(2).position(|w| w == b"\n\n") {
+            Some(end_pos) => {
+                let raw: Vec<u8> = buffer.drain(..end_pos + 2).collect();
+                raw
+            }
+            None if flush && !buffer.is_empty() => std::mem::take(buffer),
+            None => return None,
+        };
+
+        if let Some(event) = parse_event(&String::from_utf8_lossy(&raw)) {
+            return Some(event);
+        }
+    }
+}
+
+fn parse_event(raw: &str) -> Option<SseEvent> {
+    let mut event = SseEvent::default();
+    let mut data_lines = vec![];
+
+    for line in raw.lines() {
+        if line.is_empty() || line.starts_with(':') {
+            continue;
+        }
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line, ""),
+        };
+        match field {
+            "event" => event.event = Some(value.to_string()),
+            "data" => data_lines.push(value),
+            _ => {}
+        }
+    }
+
+    if data_lines.is_empty() {
+        return None;
+    }
+    event.data = data_lines.join("\n");
+    Some(event)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collect(chunks: Vec<std::result::Result<Bytes, std::io::Error>>) -> Vec<Result<SseEvent>> {
+        futures::executor::block_on(sse_stream(futures::stream::iter(chunks)).collect())
+    }
+
+    fn events(chunks: &[&'static str]) -> Vec<SseEvent> {
+        let chunks = chunks
+            .iter()
+            .map(|chunk| Ok(Bytes::from_static(chunk.as_bytes())))
+            .collect();
+        collect(chunks).into_iter().map(Result::unwrap).collect()
+    }
+
+    fn event(event: Option<&str>, data: &str) -> SseEvent {
+        SseEvent {
+            event: event.map(str::to_string),
+            data: data.to_string(),
+        }
+    }
+
+    #[test]
+    fn joins_events_split_across_chunks() {
+        let events = events(&[
+            "event: message_start\r\nda",
+            "ta: {\"a\":1}\r\n\r\nevent: ping\n",
+            "data: {}\n\n",
+        ]);
+        assert_eq!(
+            events,
+            vec![
+                event(Some("message_start"), "{\"a\":1}"),
+                event(Some("ping"), "{}"),
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_comments_and_events_without_data() {
+        let events = events(&[": keep-alive\n\nevent: ping\n\ndata:{\"b\":2}\n\n"]);
+        assert_eq!(events, vec![event(None, "{\"b\":2}")]);
+    }
+
+    #[test]
+    fn joins_data_lines_and_flushes_the_last_event() {
+        let events = events(&["data: first\ndata: second\n\ndata: [DONE]"]);
+        assert_eq!(
+            events,
+            vec![event(None, "first\nsecond"), event(None, "[DONE]")]
+        );
+    }
+
+    #[test]
+    fn ends_after_a_body_error() {
+        let chunks = vec![
+            Ok(Bytes::from_static(b"data: 1\n\n")),
+            Err(std::io::Error::other("connection reset")),
+            Ok(Bytes::from_static(b"data: 2\n\n")),
+        ];
+        let events = collect(chunks);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].as_ref().unwrap().data, "1");
+        assert!(events[1].is_err());
+    }
+}
@@ -1,6 +1,43 @@
 use crate::types::*;
 use anyhow::Result;
+use async_trait::async_trait;
+use futures::StreamExt;
 
-pub trait ChatTrait {
+/// Object safe, so providers can be built at runtime from the registry.
+/// Built providers also report their models through `LLMConfig`.
+#[async_trait]
+pub trait ChatTrait: LLMConfig {
     async fn chat(&self, request: OaiChatCompletionRequest) -> Result<OaiChatCompletionResponse>;
+
+    /// Streams the completion as OpenAI chunks. Providers that cannot stream
+    /// fall back to replaying the full response as a simulated stream.
+    async fn chat_stream(
+        &self,
+        request: OaiChatCompletionRequest,
+    ) -> Result<OaiChatCompletionStream> {
+        let response = self.chat(request).await?;
+        Ok(simulated_stream(response))
+    }
+}
+
+pub trait EmbeddingsTrait {
+    async fn embeddings(&self, request: OaiEmbeddingRequest) -> Result<OaiEmbeddingResponse>;
+}
+
+pub fn simulated_stream(response: OaiChatCompletionResponse) -> OaiChatCompletionStream {
+    Box::pin(futures::stream::iter(
+        response.into_chunks().into_iter().map(Ok),
+    ))
+}
+
+/// Waits for the first chunk, errors such as a bad status often only surface
+/// there and should still be retried or fall back.
+pub async fn start_stream(mut stream: OaiChatCompletionStream) -> Result<OaiChatCompletionStream> {
+    match stream.next().await {
+        Some(Err(e)) => Err(e),
+        Some(Ok(chunk)) => Ok(Box::pin(
+            futures::stream::once(async { Ok(chunk) }).chain(stream),
+        )),
+        None => Ok(stream),
+    }
 }
@@ -0,0 +1,265 @@
+use super::claude::{
+    self, ClaudeCompletionRequest, ClaudeCompletionResponse, ClaudeStreamEvent, ClaudeStreamState,
+};
+use super::gemini::{GeminiRequest, GeminiResponse, GeminiStreamState};
+use super::sse::sse_events;
+use super::traits::ChatTrait;
+use crate::error::Error;
+use crate::firestore::VertexConfig;
+use crate::google_auth::GoogleTokenCache;
+use crate::retry::Retrier;
+use crate::types::LLMConfig;
+use crate::types::*;
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::StreamExt;
+use serde::Serialize;
+use std::sync::Arc;
+
+/// Google Cloud's Vertex AI, serving Gemini and Anthropic's Claude models.
+/// Requests are authenticated with the customer's service account.
+pub struct Vertex {
+    vertex: VertexConfig,
+    tokens: Arc<GoogleTokenCache>,
+    retrier: Retrier,
+    http_client: reqwest::Client,
+}
+
+impl LLMConfig for Vertex {
+    fn get_api_key(&self) -> String {
+        self.vertex.service_account.client_email.clone()
+    }
+
+    fn set_api_key(&mut self, _api_key: &str) {}
+
+    fn get_base_url(&self) -> String {
+        // the global endpoint has no region prefix
+        match self.vertex.region.as_str() {
+            "global" => "https://aiplatform.googleapis.com".to_string(),
+            region => format!("https://{}-aiplatform.googleapis.com", region),
+        }
+    }
+
+    fn get_name(&self) -> String {
+        "Vertex AI".to_string()
+    }
+
+    fn get_default_model(&self) -> String {
+        "gemini-1.5-pro".to_string()
+    }
+
+    // Model names are shared with the Gemini and Claude APIs, which they
+    // resolve to, so Vertex is only used when selected.
+    fn get_models(&self) -> Vec<String> {
+        vec![]
+    }
+
+    fn get_model_prefixes(&self) -> Vec<String> {
+        vec![]
+    }
+}
+
+impl Vertex {
+    /// Sends with the pooled `http_client` and shares minted access tokens
+    /// across requests through `tokens`.
+    pub fn new(http_client: reqwest::Client, tokens: Arc<GoogleTokenCache>) -> Self {
+        Self {
+            vertex: VertexConfig::default(),
+            tokens,
+            retrier: Retrier::default(),
+            http_client,
+        }
+    }
+
+    pub fn with_vertex(mut self, vertex: VertexConfig) -> Self {
+        self.vertex = vertex;
+        self
+    }
+
+    pub fn with_retrier(mut self, retrier: Retrier) -> Self {
+        self.retrier = retrier;
+        self
+    }
+
+    /// Calls `method` on a model of `publisher` (google or anthropic), e.g.
+    /// `.../publishers/google/models/gemini-1.5-pro:generateContent`.
+    async fn send<T: Serialize>(
+        &self,
+        publisher: &str,
+        model: &str,
+        method: &str,
+        body: &T,
+    ) -> Result<reqwest::Response> {
+        let access_token = self
+            .tokens
+            .access_token(&self.vertex.service_account, &self.retrier)
+            .await?;
+        let url = format!(
+            "{url}/v1/projects/{project}/locations/{region}/publishers/{publisher}/models/{model}:{method}",
+            url = self.get_base_url(),
+            project = self.vertex.project_id(),
+            region = self.vertex.region,
+        );
+
+        let http_client = &self.http_client;
+        let http_response = self
+            .retrier
+            .execute(|| {
+                let request = http_client.post(&url).bearer_auth(&access_token).json(body);
+                if method == "streamGenerateContent" {
+                    request.query(&[("alt", "sse")]).send()
+                } else {
+                    request.send()
+                }
+            })
+            .await?;
+
+        if !http_response.status().is_success() {
+            let status = http_response.status().as_u16();
+            let error_text = http_response
+                .text()
+                .await
+                .unwrap_or_else(|_| "Failed to read response text".to_string());
+
+            tracing::error!(
+                status = status,
+                error = error_text,
+                "Failed to make completion request to Vertex AI",
+            );
+            return Err(Error::UpstreamError {
+                status,
+                message: format!(
+                    "Failed to make completion request to Vertex AI: {}",
+                    error_text
+                ),
+            }
+            .into());
+        }
+        Ok(http_response)
+    }
+}
+
+/// Claude models are served by Anthropic, everything else by Google.
+fn is_anthropic(model: &str) -> bool {
+    model.starts_with("claude-")
+}
+
+#[async_trait]
+impl ChatTrait for Vertex {
+    async fn chat(&self, request: OaiChatCompletionRequest) -> Result<OaiChatCompletionResponse> {
+        let mut model = request.model.clone();
+        if model.is_empty() {
+            model = self.get_default_model();
+        }
+
+        if is_anthropic(&model) {
+            let mut claude_request = ClaudeCompletionRequest::try_from(request)?;
+            claude_request.stream = None;
+            let body = claude::platform_body(&claude_request, "vertex-2023-10-16")?;
+            let http_response = self.send("anthropic", &model, "rawPredict", &body).await?;
+            let result = http_response.json::<ClaudeCompletionResponse>().await?;
+            return Ok(result.into());
+        }
+
+        let gemini_request = GeminiRequest::try_from(request)?;
+        let http_response = self
+            .send("google", &model, "generateContent", &gemini_request)
+            .await?;
+        let result = http_response.json::<GeminiResponse>().await?;
+        if let Some(error) = result.error {
+            return Err(error.into_error());
+        }
+        let mut response = OaiChatCompletionResponse::from(result);
+        if response.model.is_empty() {
+            response.model = model;
+        }
+        Ok(response)
+    }
+
+    async fn chat_stream(
+        &self,
+        request: OaiChatCompletionRequest,
+    ) -> Result<OaiChatCompletionStream> {
+        let mut model = request.model.clone();
+        if model.is_empty() {
+            model = self.get_default_model();
+        }
+
+        if is_anthropic(&model) {
+            let mut claude_request = ClaudeCompletionRequest::try_from(request)?;
+            claude_request.stream = Some(true);
+            let body = claude::platform_body(&claude_request, "vertex-2023-10-16")?;
+            let http_response = self
+                .send("anthropic", &model, "streamRawPredict", &body)
+                .await?;
+
+            let mut state = ClaudeStreamState::new();
+            let stream = sse_events(http_response).filter_map(move |event| {
+                let chunk = event
+                    .and_then(|event| Ok(serde_json::from_str::<ClaudeStreamEvent>(&event.data)?))
+                    .map_or_else(|e| Some(Err(e)), |event| state.handle(event));
+                futures::future::ready(chunk)
+            });
+            return Ok(Box::pin(stream));
+        }
+
+        let gemini_request = GeminiRequest::try_from(request)?;
+        let http_response = self
+            .send("google", &model, "streamGenerateContent", &gemini_request)
+            .await?;
+
+        let mut state = GeminiStreamState::new(model);
+        let stream = sse_events(http_response).filter_map(move |event| {
+            let chunk = event
+                .and_then(|event| Ok(serde_json::from_str::<GeminiResponse>(&event.data)?))
+                .map_or_else(|e| Some(Err(e)), |response| state.handle(response));
+            futures::future::ready(chunk)
+        });
+        Ok(Box::pin(stream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn vertex(config: serde_json::Value) -> Vertex {
+        let http_client = reqwest::Client::new();
+        let tokens = Arc::new(GoogleTokenCache::new(http_client.clone()));
+        Vertex::new(http_client, tokens).with_vertex(serde_json::from_value(config).unwrap())
+    }
+
+    #[test]
+    fn calls_the_regional_or_global_endpoint() {
+        let service_account = json!({
+            "client_email": "gateway@project.iam.gserviceaccount.com",
+            "private_key": "pem",
+            "project_id": "key-project",
+        });
+        let regional = vertex(json!({ "service_account": service_account }));
+        assert_eq!(
+            regional.get_base_url(),
+            "https://us-central1-aiplatform.googleapis.com"
+        );
+        assert_eq!(regional.vertex.project_id(), "key-project");
+        assert_eq!(
+            regional.get_api_key(),
+            "gateway@project.iam.gserviceaccount.com"
+        );
+
+        let global = vertex(json!({
+            "service_account": service_account.to_string(),
+            "project_id": "other-project",
+            "region": "global",
+        }));
+        assert_eq!(global.get_base_url(), "https://aiplatform.googleapis.com");
+        assert_eq!(global.vertex.project_id(), "other-project");
+    }
+
+    #[test]
+    fn sends_claude_models_to_anthropic() {
+        assert!(is_anthropic("claude-3-5-sonnet-v2@20241022"));
+        assert!(!is_anthropic("gemini-1.5-pro"));
+    }
+}
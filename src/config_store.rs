@@ -0,0 +1,299 @@
+use crate::firestore::{CustomerConfig, Rollout};
+use crate::request_logs::RequestLog;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+/// How often the config file is checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where customer configs, the felafax token to user mapping and rollouts
+/// come from, and where request logs go.
+#[async_trait]
+pub trait ConfigStore: Send + Sync {
+    /// The config of the customer owning `felafax_token`.
+    async fn get_customer_configs(
+        &self,
+        felafax_token: &str,
+    ) -> Result<Option<Arc<CustomerConfig>>>;
+
+    async fn get_user_id(&self, felafax_token: &str) -> Result<Option<String>>;
+
+    async fn get_roll_out_percentage(&self, user_id: &str, rollout_id: &str)
+        -> Result<Option<i64>>;
+
+    async fn insert_request_log(&self, request_log: &RequestLog) -> Result<()>;
+}
+
+/// Contents of a config file, the same documents Firestore holds.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct FileConfig {
+    /// Customer configs by felafax token.
+    pub configs: HashMap<String, Arc<CustomerConfig>>,
+    /// User ids by felafax token.
+    pub users: HashMap<String, String>,
+    /// Rollouts by user id.
+    pub rollouts: HashMap<String, Vec<Rollout>>,
+}
+
+impl FileConfig {
+    /// Parses TOML for `.toml` files and YAML, or JSON, for anything else.
+    pub fn parse(path: &Path, contents: &str) -> Result<Self> {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => Ok(toml::from_str(contents)?),
+            _ => Ok(serde_yaml::from_str(contents)?),
+        }
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::parse(path, &contents).with_context(|| format!("Failed to parse {}", path.display()))
+    }
+}
+
+/// Configs from a local YAML or TOML file, for running without GCP. The file
+/// is reloaded when it changes; a file that fails to parse is reported and
+/// the previous config kept. Request logs are not stored.
+#[derive(Debug, Default)]
+pub struct FileConfigStore {
+    path: PathBuf,
+    config: RwLock<FileConfig>,
+}
+
+impl FileConfigStore {
+    pub fn new(path: impl Into<PathBuf>, config: FileConfig) -> Self {
+        Self {
+            path: path.into(),
+            config: RwLock::new(config),
+        }
+    }
+
+    /// Reads the file and starts watching it for changes.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Arc<Self>> {
+        let path = path.into();
+        // taken before reading, so a write racing the read is picked up
+        let last_modified = modified(&path);
+        let store = Arc::new(Self::new(&path, FileConfig::read(&path)?));
+        store.watch(last_modified);
+        Ok(store)
+    }
+
+    fn watch(self: &Arc<Self>, mut last_modified: Option<SystemTime>) {
+        let store = Arc::downgrade(self);
+        let path = self.path.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(RELOAD_INTERVAL).await;
+                let Some(store) = store.upgrade() else {
+                    return;
+                };
+                let modified = modified(&path);
+                if modified == last_modified {
+                    continue;
+                }
+                last_modified = modified;
+                match FileConfig::read(&path) {
+                    Ok(config) => {
+                        tracing::info!(path = %path.display(), "Reloaded configs");
+                        *store.config.write().unwrap() = config;
+                    }
+                    Err(e) => {
+                        tracing::warn!(path = %path.display(), error = ?e, "Keeping previous configs")
+                    }
+                }
+            }
+        });
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+#[async_trait]
+impl ConfigStore for FileConfigStore {
+    async fn get_customer_configs(
+        &self,
+        felafax_token: &str,
+    ) -> Result<Option<Arc<CustomerConfig>>> {
+        Ok(self
+            .config
+            .read()
+            .unwrap()
+            .configs
+            .get(felafax_token)
+            .cloned())
+    }
+
+    async fn get_user_id(&self, felafax_token: &str) -> Result<Option<String>> {
+        Ok(self
+            .config
+            .read()
+            .unwrap()
+            .users
+            .get(felafax_token)
+            .cloned())
+    }
+
+    async fn get_roll_out_percentage(
+        &self,
+        user_id: &str,
+        rollout_id: &str,
+    ) -> Result<Option<i64>> {
+        let config = self.config.read().unwrap();
+        let rollout = config
+            .rollouts
+            .get(user_id)
+            .and_then(|rollouts| rollouts.iter().find(|r| r.rollout_id == rollout_id));
+        Ok(rollout.map(|rollout| rollout.rollout_percentage as i64))
+    }
+
+    async fn insert_request_log(&self, _request_log: &RequestLog) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Write;
+
+    const YAML: &str = r#"
+configs:
+  tok-1:
+    selected_llm_name: openai
+    selected_llm_model: gpt-4o
+    llm_configs:
+      openai:
+        api_key: sk-1
+users:
+  tok-1: user-1
+rollouts:
+  user-1:
+    - rollout_id: new-router
+      rollout_name: New router
+      rollout_percentage: 25.0
+      created_date: 1718000000
+"#;
+
+    const TOML: &str = r#"
+[configs.tok-1]
+selected_llm_name = "claude"
+selected_llm_model = "claude-3-5-sonnet-20240620"
+
+[configs.tok-1.llm_configs.claude]
+api_key = "sk-ant-1"
+
+[users]
+tok-1 = "user-1"
+"#;
+
+    /// A config file of its own for each test, removed when dropped.
+    struct TempConfig(PathBuf);
+
+    impl TempConfig {
+        fn new(name: &str, contents: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "felafax-config-{}-{}",
+                std::process::id(),
+                name
+            ));
+            let config = Self(path);
+            config.write(contents);
+            config
+        }
+
+        /// Rewrites the file and moves its modification time forward, so the
+        /// change is seen even on filesystems with coarse timestamps.
+        fn write(&self, contents: &str) {
+            let mut file = File::create(&self.0).unwrap();
+            file.write_all(contents.as_bytes()).unwrap();
+            let modified = SystemTime::now() + Duration::from_secs(10);
+            file.set_modified(modified).unwrap();
+        }
+    }
+
+    impl Drop for TempConfig {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    async fn resolve(store: &FileConfigStore, felafax_token: &str) -> Option<Arc<CustomerConfig>> {
+        let user_id = store.get_user_id(felafax_token).await.unwrap()?;
+        assert_eq!(user_id, "user-1");
+        store.get_customer_configs(felafax_token).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn resolves_tokens_from_yaml() {
+        let file = TempConfig::new("resolve.yaml", YAML);
+        let store = FileConfigStore::open(&file.0).unwrap();
+
+        let config = resolve(&store, "tok-1").await.unwrap();
+        assert_eq!(config.selected_llm_name, "openai");
+        assert_eq!(config.llm_configs["openai"].api_key, "sk-1");
+        assert_eq!(
+            store
+                .get_roll_out_percentage("user-1", "new-router")
+                .await
+                .unwrap(),
+            Some(25)
+        );
+        assert!(store.get_user_id("tok-2").await.unwrap().is_none());
+        assert!(store.get_customer_configs("tok-2").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn resolves_tokens_from_toml() {
+        let file = TempConfig::new("resolve.toml", TOML);
+        let store = FileConfigStore::open(&file.0).unwrap();
+
+        let config = resolve(&store, "tok-1").await.unwrap();
+        assert_eq!(config.selected_llm_name, "claude");
+        assert_eq!(config.llm_configs["claude"].api_key, "sk-ant-1");
+        assert!(store
+            .get_roll_out_percentage("user-1", "new-router")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn reloads_the_file_when_it_changes() {
+        let file = TempConfig::new("reload.yaml", YAML);
+        let store = FileConfigStore::open(&file.0).unwrap();
+        assert_eq!(
+            resolve(&store, "tok-1").await.unwrap().selected_llm_name,
+            "openai"
+        );
+
+        file.write(&YAML.replace("selected_llm_name: openai", "selected_llm_name: claude"));
+        let mut reloaded = false;
+        for _ in 0..50 {
+            tokio::time::sleep(RELOAD_INTERVAL / 10).await;
+            if resolve(&store, "tok-1").await.unwrap().selected_llm_name == "claude" {
+                reloaded = true;
+                break;
+            }
+        }
+        assert!(reloaded, "the rewritten file was not reloaded");
+
+        // a file that no longer parses leaves the last good configs in place
+        file.write("configs: [");
+        tokio::time::sleep(RELOAD_INTERVAL * 2).await;
+        assert_eq!(
+            resolve(&store, "tok-1").await.unwrap().selected_llm_name,
+            "claude"
+        );
+    }
+}
@@ -1,5 +1,5 @@
 //! Errors originating from API calls, parsing responses, and reading-or-writing to the file system.
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -25,10 +25,13 @@ pub enum Error {
     /// or when builder fails to build request before making API call
     #[error("invalid args: {0}")]
     InvalidArgument(String),
+    /// Upstream LLM API responded with a non-success status
+    #[error("{message}")]
+    UpstreamError { status: u16, message: String },
 }
 
 /// OpenAI API returns error object on failure
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ApiError {
     pub message: String,
     pub r#type: Option<String>,
@@ -36,6 +39,97 @@ pub struct ApiError {
     pub code: Option<String>,
 }
 
+/// Whether a failed upstream call is worth trying again, possibly against
+/// another LLM: rate limits, 5xx responses, timeouts and connection errors.
+pub fn is_retryable(error: &anyhow::Error) -> bool {
+    if let Some(error) = error.downcast_ref::<Error>() {
+        return match error {
+            Error::UpstreamError { status, .. } => is_retryable_status(*status),
+            Error::Reqwest(e) => is_retryable_reqwest(e),
+            _ => false,
+        };
+    }
+    if let Some(e) = error.downcast_ref::<reqwest::Error>() {
+        return is_retryable_reqwest(e);
+    }
+    if let Some(error) = error.downcast_ref::<async_openai::error::OpenAIError>() {
+        return match error {
+            async_openai::error::OpenAIError::Reqwest(e) => is_retryable_reqwest(e),
+            async_openai::error::OpenAIError::ApiError(e) => {
+                e.r#type.as_deref() == Some("server_error")
+                    || e.code.as_deref() == Some("rate_limit_exceeded")
+            }
+            async_openai::error::OpenAIError::StreamError(message) => {
+                match stream_error_status(message) {
+                    Some(status) => is_retryable_status(status),
+                    None => true,
+                }
+            }
+            _ => false,
+        };
+    }
+    false
+}
+
+/// Whether the upstream rejected the API key itself, because it is invalid,
+/// not allowed or rate limited, so another key may still succeed.
+pub fn is_key_error(error: &anyhow::Error) -> bool {
+    if let Some(async_openai::error::OpenAIError::ApiError(e)) =
+        error.downcast_ref::<async_openai::error::OpenAIError>()
+    {
+        return matches!(
+            e.code.as_deref(),
+            Some("invalid_api_key" | "rate_limit_exceeded")
+        ) || e.r#type.as_deref() == Some("insufficient_quota");
+    }
+    upstream_status(error).is_some_and(is_key_status)
+}
+
+/// The status the upstream responded with, when the error carries one.
+pub fn upstream_status(error: &anyhow::Error) -> Option<u16> {
+    if let Some(error) = error.downcast_ref::<Error>() {
+        return match error {
+            Error::UpstreamError { status, .. } => Some(*status),
+            Error::Reqwest(e) => e.status().map(|status| status.as_u16()),
+            _ => None,
+        };
+    }
+    if let Some(e) = error.downcast_ref::<reqwest::Error>() {
+        return e.status().map(|status| status.as_u16());
+    }
+    match error.downcast_ref::<async_openai::error::OpenAIError>()? {
+        async_openai::error::OpenAIError::Reqwest(e) => e.status().map(|status| status.as_u16()),
+        async_openai::error::OpenAIError::StreamError(message) => stream_error_status(message),
+        _ => None,
+    }
+}
+
+// async-openai only keeps the message of a failed stream, e.g.
+// "Invalid status code: 503 Service Unavailable" or "Transport error: .."
+fn stream_error_status(message: &str) -> Option<u16> {
+    message
+        .split("Invalid status code: ")
+        .nth(1)?
+        .get(..3)?
+        .parse()
+        .ok()
+}
+
+pub fn is_key_status(status: u16) -> bool {
+    matches!(status, 401 | 403 | 429)
+}
+
+pub fn is_retryable_status(status: u16) -> bool {
+    status == 429 || status >= 500
+}
+
+fn is_retryable_reqwest(e: &reqwest::Error) -> bool {
+    e.is_timeout()
+        || e.is_connect()
+        || e.status()
+            .is_some_and(|status| is_retryable_status(status.as_u16()))
+}
+
 pub(crate) fn map_deserialization_error(e: serde_json::Error, bytes: &[u8]) -> Error {
     tracing::error!(
         "failed deserialization of: {}",
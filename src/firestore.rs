@@ -1,23 +1,399 @@
+use crate::config_store::ConfigStore;
+use crate::google_auth::ServiceAccountKey;
 use crate::request_logs;
+use crate::retry::RetryPolicy;
 use anyhow::Result;
+use async_trait::async_trait;
 use firestore::*;
 use futures::StreamExt;
+use lru::LruCache;
 use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 const METADTA_COLLECTION_NAME: &'static str = "configs";
 const CUSTOMER_COLLECTION_NAME: &'static str = "users";
+const ROLLOUTS_COLLECTION_NAME: &str = "rollouts";
+const TOKEN_MAP_DOCUMENT_ID: &str = "metadata";
 
 pub struct Firestore {
     project_id: String,
     service_account_json_path: String,
     db: OnceCell<FirestoreDb>,
+    cache: Arc<FirestoreCache>,
+    listener: OnceCell<FirestoreListener<FirestoreDb, FirestoreMemListenStateStorage>>,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+struct CachedDocument<T> {
+    document: Arc<T>,
+    expires_at: Instant,
+}
+
+struct Entries<T> {
+    documents: LruCache<String, CachedDocument<T>>,
+    /// When each missing document's entry expires. Kept apart so that lookups
+    /// of unknown ids, e.g. random bearer tokens, can't evict documents.
+    missing: LruCache<String, Instant>,
+}
+
+/// Documents read from Firestore by id, least recently used ones evicted past
+/// `capacity`. Missing documents are cached too, for `negative_ttl` and up to
+/// `negative_capacity`, so unknown tokens don't cost a read each. Documents
+/// are shared, not copied, with every reader.
+pub struct DocumentCache<T> {
+    entries: Mutex<Entries<T>>,
+    /// Bumped on every invalidation, so reads racing a change aren't cached.
+    version: AtomicU64,
+    ttl: Duration,
+    negative_ttl: Duration,
+}
+
+impl<T> DocumentCache<T> {
+    pub fn new(
+        ttl: Duration,
+        negative_ttl: Duration,
+        capacity: usize,
+        negative_capacity: usize,
+    ) -> Self {
+        let at_least_one = |capacity| NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            entries: Mutex::new(Entries {
+                documents: LruCache::new(at_least_one(capacity)),
+                missing: LruCache::new(at_least_one(negative_capacity)),
+            }),
+            version: AtomicU64::new(0),
+            ttl,
+            negative_ttl,
+        }
+    }
+
+    /// `Some(None)` when the document is known not to exist.
+    pub fn get(&self, id: &str) -> Option<Option<Arc<T>>> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        match entries.documents.get(id) {
+            Some(cached) if cached.expires_at > now => return Some(Some(cached.document.clone())),
+            Some(_) => {
+                entries.documents.pop(id);
+            }
+            None => {}
+        }
+        match entries.missing.get(id) {
+            Some(expires_at) if *expires_at > now => Some(None),
+            Some(_) => {
+                entries.missing.pop(id);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Take before reading a document and pass to `insert`.
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::SeqCst)
+    }
+
+    /// Caches a document read at `version`, unless something was invalidated
+    /// since and it may already be stale.
+    pub fn insert(&self, id: &str, document: Option<Arc<T>>, version: u64) {
+        let ttl = if document.is_some() {
+            self.ttl
+        } else {
+            self.negative_ttl
+        };
+        if ttl.is_zero() {
+            return;
+        }
+        let expires_at = Instant::now() + ttl;
+        let mut entries = self.entries.lock().unwrap();
+        if self.version() != version {
+            return;
+        }
+        match document {
+            Some(document) => {
+                entries.missing.pop(id);
+                entries.documents.put(
+                    id.to_string(),
+                    CachedDocument {
+                        document,
+                        expires_at,
+                    },
+                );
+            }
+            None => {
+                entries.documents.pop(id);
+                entries.missing.put(id.to_string(), expires_at);
+            }
+        }
+    }
+
+    pub fn invalidate(&self, id: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        self.version.fetch_add(1, Ordering::SeqCst);
+        entries.documents.pop(id);
+        entries.missing.pop(id);
+    }
+
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        self.version.fetch_add(1, Ordering::SeqCst);
+        entries.documents.clear();
+        entries.missing.clear();
+    }
+}
+
+/// Customer configs, rollouts and the token map, read once and then served
+/// from memory. Entries are dropped as soon as Firestore reports a change to
+/// their document, the TTL only bounds staleness while the listener is down.
+pub struct FirestoreCache {
+    configs: DocumentCache<CustomerConfig>,
+    rollouts: DocumentCache<Vec<Rollout>>,
+    token_map: DocumentCache<FelafaxTokenToIdMap>,
+}
+
+impl FirestoreCache {
+    pub fn new(
+        ttl: Duration,
+        negative_ttl: Duration,
+        capacity: usize,
+        negative_capacity: usize,
+    ) -> Self {
+        Self {
+            configs: DocumentCache::new(ttl, negative_ttl, capacity, negative_capacity),
+            rollouts: DocumentCache::new(ttl, negative_ttl, capacity, negative_capacity),
+            // a single document
+            token_map: DocumentCache::new(ttl, negative_ttl, 1, 1),
+        }
+    }
+
+    /// Reads `FIRESTORE_CACHE_TTL_SECS` (default 300),
+    /// `FIRESTORE_NEGATIVE_CACHE_TTL_SECS` (default 30), 0 disabling caching,
+    /// and how many documents (`FIRESTORE_CACHE_CAPACITY`, default 10000) and
+    /// missing ones (`FIRESTORE_NEGATIVE_CACHE_CAPACITY`, default 1000) to keep.
+    pub fn from_env() -> Self {
+        let var = |name: &str, default: u64| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+        Self::new(
+            Duration::from_secs(var("FIRESTORE_CACHE_TTL_SECS", 300)),
+            Duration::from_secs(var("FIRESTORE_NEGATIVE_CACHE_TTL_SECS", 30)),
+            var("FIRESTORE_CACHE_CAPACITY", 10000) as usize,
+            var("FIRESTORE_NEGATIVE_CACHE_CAPACITY", 1000) as usize,
+        )
+    }
+
+    /// Drops the entry of a document, given its full resource name, e.g.
+    /// `projects/p/databases/(default)/documents/configs/{id}`.
+    pub fn invalidate(&self, document_name: &str) {
+        let mut segments = document_name.rsplit('/');
+        let (Some(id), Some(collection)) = (segments.next(), segments.next()) else {
+            return;
+        };
+        match collection {
+            METADTA_COLLECTION_NAME => self.configs.invalidate(id),
+            ROLLOUTS_COLLECTION_NAME => self.rollouts.invalidate(id),
+            CUSTOMER_COLLECTION_NAME if id == TOKEN_MAP_DOCUMENT_ID => {
+                self.token_map.invalidate(id)
+            }
+            _ => {}
+        }
+    }
+
+    pub fn clear(&self) {
+        self.configs.clear();
+        self.rollouts.clear();
+        self.token_map.clear();
+    }
+
+    fn handle(&self, event: FirestoreListenEvent) {
+        match event {
+            FirestoreListenEvent::DocumentChange(change) => {
+                if let Some(document) = change.document {
+                    self.invalidate(&document.name);
+                }
+            }
+            FirestoreListenEvent::DocumentDelete(delete) => self.invalidate(&delete.document),
+            FirestoreListenEvent::DocumentRemove(remove) => self.invalidate(&remove.document),
+            // some documents may be gone, which ones is unknown
+            FirestoreListenEvent::Filter(_) => self.clear(),
+            FirestoreListenEvent::TargetChange(_) => {}
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct CustomerLLMConfig {
+    #[serde(default)]
     pub api_key: String,
+    /// Extra keys to balance requests across, alongside `api_key`.
+    #[serde(default)]
+    pub api_keys: Vec<WeightedApiKey>,
+    #[serde(default)]
+    pub key_selection: KeySelection,
+    /// Overrides the LLM's API URL, e.g. a local OpenAI-compatible server.
+    #[serde(default)]
+    pub base_url: Option<String>,
+    /// AWS credentials, for Bedrock.
+    #[serde(default)]
+    pub aws: Option<AwsConfig>,
+    /// Azure OpenAI resource, for Azure.
+    #[serde(default)]
+    pub azure: Option<AzureConfig>,
+    /// Google Cloud project and service account, for Vertex AI.
+    #[serde(default)]
+    pub vertex: Option<VertexConfig>,
+    /// The provider serving this config when it isn't the LLM name, e.g.
+    /// `openai_compatible` for an endpoint named `my-vllm`.
+    #[serde(default)]
+    pub provider: Option<String>,
+    /// Extra headers sent with every request, for OpenAI-compatible endpoints.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Models this config serves, requests for them are routed to it. Other
+    /// models are rejected by OpenAI-compatible endpoints.
+    #[serde(default)]
+    pub models: Vec<String>,
+}
+
+/// Provider of LLM configs for servers speaking the OpenAI protocol, such as
+/// vLLM, llama.cpp, Groq or Together.
+pub const OPENAI_COMPATIBLE: &str = "openai_compatible";
+
+impl CustomerLLMConfig {
+    /// The provider serving the config stored under `llm_name`.
+    pub fn provider<'a>(&'a self, llm_name: &'a str) -> &'a str {
+        self.provider.as_deref().unwrap_or(llm_name)
+    }
+
+    /// Where the keys are sent: the `base_url`, or the provider for hosted
+    /// APIs. Keys are balanced and cooled down per endpoint.
+    pub fn endpoint<'a>(&'a self, llm_name: &'a str) -> &'a str {
+        self.base_url
+            .as_deref()
+            .unwrap_or_else(|| self.provider(llm_name))
+    }
+
+    /// All configured keys, `api_key` first with a weight of 1.
+    pub fn keys(&self, llm_name: &str) -> Vec<WeightedApiKey> {
+        let mut keys = vec![];
+        if !self.api_key.is_empty() {
+            keys.push(WeightedApiKey {
+                key: self.api_key.clone(),
+                weight: 1,
+            });
+        }
+        keys.extend(self.api_keys.iter().cloned());
+        // Bedrock has no api key, its access key id stands in for one
+        if let Some(aws) = self.aws.as_ref().filter(|_| keys.is_empty()) {
+            keys.push(WeightedApiKey {
+                key: aws.access_key_id.clone(),
+                weight: 1,
+            });
+        }
+        // and neither has Vertex AI, its service account does
+        if let Some(vertex) = self.vertex.as_ref().filter(|_| keys.is_empty()) {
+            keys.push(WeightedApiKey {
+                key: vertex.service_account.client_email.clone(),
+                weight: 1,
+            });
+        }
+        // local servers often take no key at all, an empty one stands in
+        if matches!(self.provider(llm_name), OPENAI_COMPATIBLE | "ollama") && keys.is_empty() {
+            keys.push(WeightedApiKey {
+                key: "".to_string(),
+                weight: 1,
+            });
+        }
+        keys
+    }
+}
+
+/// AWS access keys and the region to sign requests for.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+pub struct AwsConfig {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    #[serde(default)]
+    pub session_token: Option<String>,
+    pub region: String,
+}
+
+/// An Azure OpenAI resource and the deployments serving each model.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+pub struct AzureConfig {
+    /// e.g. `https://my-resource.openai.azure.com`
+    pub endpoint: String,
+    #[serde(default = "default_azure_api_version")]
+    pub api_version: String,
+    /// Model name to deployment name. Models without an entry are assumed to
+    /// be deployed under their own name.
+    #[serde(default)]
+    pub deployments: HashMap<String, String>,
+}
+
+impl AzureConfig {
+    pub fn deployment(&self, model: &str) -> String {
+        self.deployments
+            .get(model)
+            .cloned()
+            .unwrap_or_else(|| model.to_string())
+    }
+}
+
+fn default_azure_api_version() -> String {
+    "2024-10-21".to_string()
+}
+
+/// Where and as whom to call Vertex AI.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+pub struct VertexConfig {
+    /// The service account's JSON key, as an object or a string.
+    #[serde(deserialize_with = "ServiceAccountKey::deserialize_json")]
+    pub service_account: ServiceAccountKey,
+    /// Defaults to the service account's project.
+    #[serde(default)]
+    pub project_id: Option<String>,
+    /// e.g. `us-east5`, or `global`.
+    #[serde(default = "default_vertex_region")]
+    pub region: String,
+}
+
+impl VertexConfig {
+    pub fn project_id(&self) -> &str {
+        self.project_id
+            .as_deref()
+            .unwrap_or(&self.service_account.project_id)
+    }
+}
+
+fn default_vertex_region() -> String {
+    "us-central1".to_string()
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct WeightedApiKey {
+    pub key: String,
+    #[serde(default = "default_key_weight")]
+    pub weight: u32,
+}
+
+fn default_key_weight() -> u32 {
+    1
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeySelection {
+    #[default]
+    WeightedRoundRobin,
+    LeastLoaded,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -25,6 +401,67 @@ pub struct CustomerConfig {
     pub selected_llm_name: String,
     pub selected_llm_model: String,
     pub llm_configs: HashMap<String, CustomerLLMConfig>,
+    /// LLMs to try, in order, when the selected one fails with a retryable error.
+    #[serde(default)]
+    pub fallbacks: Vec<FallbackConfig>,
+    /// Overrides the gateway's retry policy for this customer.
+    #[serde(default)]
+    pub retry_policy: Option<RetryPolicy>,
+    /// Serves repeated identical translate requests from the response cache.
+    #[serde(default)]
+    pub cache_responses: bool,
+    /// Serves answers to similar enough prompts from the semantic cache.
+    #[serde(default)]
+    pub semantic_cache: Option<SemanticCacheConfig>,
+    /// Where proxy mode forwards requests. `azure` uses the `azure` LLM config.
+    #[serde(default)]
+    pub proxy_upstream: ProxyUpstream,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyUpstream {
+    #[default]
+    OpenAI,
+    Azure,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SemanticCacheConfig {
+    /// Minimum cosine similarity between prompts for a cached answer to be used.
+    #[serde(default = "default_similarity_threshold")]
+    pub threshold: f32,
+    #[serde(default = "default_semantic_cache_ttl_secs")]
+    pub ttl_secs: u64,
+    /// LLM to embed prompts with, using its key from `llm_configs`.
+    #[serde(default = "default_embedding_llm_name")]
+    pub embedding_llm_name: String,
+    #[serde(default = "default_embedding_model")]
+    pub embedding_model: String,
+}
+
+fn default_similarity_threshold() -> f32 {
+    0.95
+}
+
+fn default_semantic_cache_ttl_secs() -> u64 {
+    3600
+}
+
+fn default_embedding_llm_name() -> String {
+    "openai".to_string()
+}
+
+fn default_embedding_model() -> String {
+    "text-embedding-3-small".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FallbackConfig {
+    pub llm_name: String,
+    /// Model to use instead of the LLM's default.
+    #[serde(default)]
+    pub model: Option<String>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -58,6 +495,8 @@ impl Firestore {
             project_id: project_id.to_string(),
             service_account_json_path: service_acccount_json_path.to_string(),
             db: OnceCell::new(),
+            cache: Arc::new(FirestoreCache::from_env()),
+            listener: OnceCell::new(),
         }
     }
 
@@ -65,36 +504,26 @@ impl Firestore {
         self.project_id.clone()
     }
 
-    pub async fn get_roll_out_percentage(
-        &self,
-        user_id: &str,
-        rollout_id: &str,
-    ) -> Result<Option<i64>> {
-        let rollouts = self.fetch_user_rollouts(user_id).await?;
-        println!("ROLLOUTS: {:?}", rollouts);
-        for rollout in rollouts {
-            if rollout.rollout_id == rollout_id {
-                return Ok(Some(rollout.rollout_percentage as i64));
-            }
+    async fn fetch_user_rollouts(&self, user_id: &str) -> Result<Arc<Vec<Rollout>>> {
+        if let Some(rollouts) = self.cache.rollouts.get(user_id) {
+            return Ok(rollouts.unwrap_or_default());
         }
-        Ok(None)
-    }
-
-    async fn fetch_user_rollouts(&self, user_id: &str) -> Result<Vec<Rollout>> {
+        let version = self.cache.rollouts.version();
         let user_rollouts: Option<UserRollouts> = self
             .get_client()
             .fluent()
             .select()
-            .by_id_in("rollouts")
+            .by_id_in(ROLLOUTS_COLLECTION_NAME)
             .obj()
             .one(user_id)
             .await?;
+        let rollouts = user_rollouts.map(|ur| Arc::new(ur.rollouts));
+        self.cache
+            .rollouts
+            .insert(user_id, rollouts.clone(), version);
 
         // 2. Extract rollouts or return an empty vector if not found
-        match user_rollouts {
-            Some(ur) => Ok(ur.rollouts),
-            None => Ok(Vec::new()),
-        }
+        Ok(rollouts.unwrap_or_default())
     }
 
     //pub async fn get_roll_outs(&self, user_id: &str) -> Result<Vec<Rollout>> {
@@ -144,31 +573,23 @@ impl Firestore {
     //    Ok(rollouts)
     //}
 
-    pub async fn get_user_id(&self, felafax_token: &str) -> Result<Option<String>> {
-        let id_to_user_map = self.get_id_to_user_map().await?;
-        println!("ID_TO_USER_MAP: {:?}", id_to_user_map);
-        match id_to_user_map {
-            Some(doc) => {
-                if doc.felafax_token_to_id_map.contains_key(felafax_token) {
-                    let user_id = doc.felafax_token_to_id_map.get(felafax_token);
-                    Ok(user_id.cloned())
-                } else {
-                    Ok(None)
-                }
-            }
-            None => Ok(None),
+    async fn get_id_to_user_map(&self) -> Result<Option<Arc<FelafaxTokenToIdMap>>> {
+        if let Some(doc) = self.cache.token_map.get(TOKEN_MAP_DOCUMENT_ID) {
+            return Ok(doc);
         }
-    }
-
-    async fn get_id_to_user_map(&self) -> Result<Option<FelafaxTokenToIdMap>> {
+        let version = self.cache.token_map.version();
         let doc: Option<FelafaxTokenToIdMap> = self
             .get_client()
             .fluent()
             .select()
             .by_id_in(CUSTOMER_COLLECTION_NAME)
             .obj()
-            .one("metadata")
+            .one(TOKEN_MAP_DOCUMENT_ID)
             .await?;
+        let doc = doc.map(Arc::new);
+        self.cache
+            .token_map
+            .insert(TOKEN_MAP_DOCUMENT_ID, doc.clone(), version);
         Ok(doc)
     }
 
@@ -180,23 +601,54 @@ impl Firestore {
         )
         .await?;
         self.db.set(db.clone()).unwrap();
+        self.listen(&db).await;
         Ok(())
     }
 
-    pub fn get_client(&self) -> &firestore::FirestoreDb {
-        self.db.get().unwrap()
+    /// Invalidates cached documents as they change. Without the listener the
+    /// cache still works, entries just live until their TTL.
+    async fn listen(&self, db: &FirestoreDb) {
+        let result: Result<_> = async {
+            let mut listener = db
+                .create_listener(FirestoreMemListenStateStorage::new())
+                .await?;
+            db.fluent()
+                .select()
+                .from(METADTA_COLLECTION_NAME)
+                .listen()
+                .add_target(FirestoreListenerTarget::new(1), &mut listener)?;
+            db.fluent()
+                .select()
+                .from(ROLLOUTS_COLLECTION_NAME)
+                .listen()
+                .add_target(FirestoreListenerTarget::new(2), &mut listener)?;
+            db.fluent()
+                .select()
+                .by_id_in(CUSTOMER_COLLECTION_NAME)
+                .batch_listen([TOKEN_MAP_DOCUMENT_ID])
+                .add_target(FirestoreListenerTarget::new(3), &mut listener)?;
+
+            let cache = self.cache.clone();
+            listener
+                .start(move |event| {
+                    cache.handle(event);
+                    async { Ok(()) }
+                })
+                .await?;
+            Ok(listener)
+        }
+        .await;
+
+        match result {
+            Ok(listener) => {
+                let _ = self.listener.set(listener);
+            }
+            Err(e) => eprintln!("Failed to listen for Firestore changes: {:?}", e),
+        }
     }
 
-    pub async fn get_customer_configs(&self, document_id: &str) -> Result<Option<CustomerConfig>> {
-        let doc: Option<CustomerConfig> = self
-            .get_client()
-            .fluent()
-            .select()
-            .by_id_in(METADTA_COLLECTION_NAME)
-            .obj()
-            .one(document_id)
-            .await?;
-        Ok(doc)
+    pub fn get_client(&self) -> &firestore::FirestoreDb {
+        self.db.get().unwrap()
     }
 
     pub async fn list_all_collections(&self) -> Result<Vec<String>> {
@@ -210,8 +662,49 @@ impl Firestore {
             .collection_ids;
         Ok(doc)
     }
+}
+
+#[async_trait]
+impl ConfigStore for Firestore {
+    async fn get_roll_out_percentage(
+        &self,
+        user_id: &str,
+        rollout_id: &str,
+    ) -> Result<Option<i64>> {
+        let rollouts = self.fetch_user_rollouts(user_id).await?;
+        println!("ROLLOUTS: {:?}", rollouts);
+        for rollout in rollouts.iter() {
+            if rollout.rollout_id == rollout_id {
+                return Ok(Some(rollout.rollout_percentage as i64));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn get_user_id(&self, felafax_token: &str) -> Result<Option<String>> {
+        let id_to_user_map = self.get_id_to_user_map().await?;
+        Ok(id_to_user_map.and_then(|doc| doc.felafax_token_to_id_map.get(felafax_token).cloned()))
+    }
+
+    async fn get_customer_configs(&self, document_id: &str) -> Result<Option<Arc<CustomerConfig>>> {
+        if let Some(doc) = self.cache.configs.get(document_id) {
+            return Ok(doc);
+        }
+        let version = self.cache.configs.version();
+        let doc: Option<CustomerConfig> = self
+            .get_client()
+            .fluent()
+            .select()
+            .by_id_in(METADTA_COLLECTION_NAME)
+            .obj()
+            .one(document_id)
+            .await?;
+        let doc = doc.map(Arc::new);
+        self.cache.configs.insert(document_id, doc.clone(), version);
+        Ok(doc)
+    }
 
-    pub async fn insert_request_log(&self, request_logs: &request_logs::RequestLog) -> Result<()> {
+    async fn insert_request_log(&self, request_logs: &request_logs::RequestLog) -> Result<()> {
         let db = self.get_client();
 
         // Create a reference to the customer's document
@@ -223,9 +716,98 @@ impl Firestore {
             .document_id(&request_logs.request_id)
             .parent(&customer_doc_ref)
             .object(request_logs)
-            .execute()
+            .execute::<()>()
             .await?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(negative_capacity: usize) -> DocumentCache<String> {
+        DocumentCache::new(
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            2,
+            negative_capacity,
+        )
+    }
+
+    #[test]
+    fn caches_documents_and_missing_ones() {
+        let cache = cache(2);
+        assert_eq!(cache.get("a"), None);
+        cache.insert("a", Some(Arc::new("config".to_string())), cache.version());
+        cache.insert("b", None, cache.version());
+        assert_eq!(cache.get("a").unwrap().unwrap().as_str(), "config");
+        assert_eq!(cache.get("b"), Some(None));
+    }
+
+    #[test]
+    fn unknown_ids_dont_evict_documents() {
+        let cache = cache(2);
+        cache.insert("a", Some(Arc::new("config".to_string())), cache.version());
+        for token in 0..100 {
+            cache.insert(&token.to_string(), None, cache.version());
+        }
+        assert!(cache.get("a").unwrap().is_some());
+        // only the latest unknown ids are remembered
+        assert_eq!(cache.entries.lock().unwrap().missing.len(), 2);
+        assert_eq!(cache.get("0"), None);
+        assert_eq!(cache.get("99"), Some(None));
+    }
+
+    #[test]
+    fn evicts_least_recently_used_documents() {
+        let cache = cache(2);
+        for id in ["a", "b"] {
+            cache.insert(id, Some(Arc::new(id.to_string())), cache.version());
+        }
+        cache.get("a");
+        cache.insert("c", Some(Arc::new("c".to_string())), cache.version());
+        assert!(cache.get("a").is_some());
+        assert_eq!(cache.get("b"), None);
+        assert!(cache.get("c").is_some());
+    }
+
+    #[test]
+    fn skips_reads_racing_an_invalidation() {
+        let cache = cache(2);
+        let version = cache.version();
+        cache.invalidate("a");
+        cache.insert("a", Some(Arc::new("stale".to_string())), version);
+        assert_eq!(cache.get("a"), None);
+    }
+
+    #[test]
+    fn named_endpoints_use_their_provider_and_base_url() {
+        let config: CustomerLLMConfig = serde_json::from_value(serde_json::json!({
+            "provider": "openai_compatible",
+            "base_url": "http://localhost:8000/v1",
+        }))
+        .unwrap();
+        assert_eq!(config.provider("my-vllm"), OPENAI_COMPATIBLE);
+        assert_eq!(config.endpoint("my-vllm"), "http://localhost:8000/v1");
+        // a keyless local server still gets a key to lease
+        let keys = config.keys("my-vllm");
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].key, "");
+
+        let hosted: CustomerLLMConfig = serde_json::from_value(serde_json::json!({
+            "api_key": "sk-1",
+            "api_keys": [{"key": "sk-2", "weight": 3}],
+        }))
+        .unwrap();
+        assert_eq!(hosted.provider("openai"), "openai");
+        assert_eq!(hosted.endpoint("openai"), "openai");
+        let keys: Vec<_> = hosted
+            .keys("openai")
+            .into_iter()
+            .map(|key| (key.key, key.weight))
+            .collect();
+        assert_eq!(keys, [("sk-1".to_string(), 1), ("sk-2".to_string(), 3)]);
+    }
+}
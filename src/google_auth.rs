@@ -0,0 +1,234 @@
+use crate::error::Error;
+use crate::retry::Retrier;
+use anyhow::Result;
+use chrono::Utc;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const CLOUD_PLATFORM_SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+const DEFAULT_TOKEN_URI: &str = "https://oauth2.googleapis.com/token";
+/// Tokens are refreshed this long before they expire.
+const REFRESH_MARGIN: Duration = Duration::from_secs(300);
+
+/// The parts of a Google service-account JSON key needed to mint tokens.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ServiceAccountKey {
+    pub client_email: String,
+    pub private_key: String,
+    #[serde(default)]
+    pub private_key_id: String,
+    #[serde(default)]
+    pub project_id: String,
+    #[serde(default)]
+    pub token_uri: Option<String>,
+}
+
+impl ServiceAccountKey {
+    /// Accepts the key file's JSON either as an object or pasted as a string.
+    pub fn deserialize_json<'de, D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum KeyOrJson {
+            Key(ServiceAccountKey),
+            Json(String),
+        }
+        match KeyOrJson::deserialize(deserializer)? {
+            KeyOrJson::Key(key) => Ok(key),
+            KeyOrJson::Json(json) => serde_json::from_str(&json).map_err(serde::de::Error::custom),
+        }
+    }
+
+    fn cache_key(&self) -> String {
+        format!("{}/{}", self.client_email, self.private_key_id)
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Claims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: i64,
+    exp: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+#[derive(Debug, Clone)]
+struct CachedToken {
+    access_token: String,
+    expires_at: Instant,
+}
+
+/// OAuth access tokens minted from customers' service accounts, reused until
+/// shortly before they expire. Shared by every request, keyed by account.
+#[derive(Debug)]
+pub struct GoogleTokenCache {
+    tokens: Mutex<HashMap<String, CachedToken>>,
+    http_client: reqwest::Client,
+}
+
+impl GoogleTokenCache {
+    /// Mints tokens with the pooled `http_client`.
+    pub fn new(http_client: reqwest::Client) -> Self {
+        Self {
+            tokens: Mutex::new(HashMap::new()),
+            http_client,
+        }
+    }
+
+    /// A cloud-platform scoped access token for `key`, minted with a signed
+    /// JWT assertion when there is no fresh one cached.
+    pub async fn access_token(&self, key: &ServiceAccountKey, retrier: &Retrier) -> Result<String> {
+        let cache_key = key.cache_key();
+        if let Some(token) = self.tokens.lock().unwrap().get(&cache_key) {
+            if token.expires_at > Instant::now() + REFRESH_MARGIN {
+                return Ok(token.access_token.clone());
+            }
+        }
+
+        let token = mint(key, retrier, &self.http_client).await?;
+        let access_token = token.access_token.clone();
+        self.tokens.lock().unwrap().insert(cache_key, token);
+        Ok(access_token)
+    }
+}
+
+async fn mint(
+    key: &ServiceAccountKey,
+    retrier: &Retrier,
+    http_client: &reqwest::Client,
+) -> Result<CachedToken> {
+    let token_uri = key.token_uri.as_deref().unwrap_or(DEFAULT_TOKEN_URI);
+    let now = Utc::now().timestamp();
+    let claims = Claims {
+        iss: &key.client_email,
+        scope: CLOUD_PLATFORM_SCOPE,
+        aud: token_uri,
+        iat: now,
+        exp: now + 3600,
+    };
+    let mut header = Header::new(Algorithm::RS256);
+    header.kid = Some(key.private_key_id.clone()).filter(|kid| !kid.is_empty());
+    let encoding_key = EncodingKey::from_rsa_pem(key.private_key.as_bytes()).map_err(|e| {
+        Error::InvalidArgument(format!("Invalid service account private key: {}", e))
+    })?;
+    let assertion = jsonwebtoken::encode(&header, &claims, &encoding_key)?;
+
+    let requested_at = Instant::now();
+    let http_response = retrier
+        .execute(|| {
+            http_client
+                .post(token_uri)
+                .form(&[
+                    ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                    ("assertion", assertion.as_str()),
+                ])
+                .send()
+        })
+        .await?;
+
+    if !http_response.status().is_success() {
+        let status = http_response.status().as_u16();
+        let error_text = http_response
+            .text()
+            .await
+            .unwrap_or_else(|_| "Failed to read response text".to_string());
+        tracing::error!(
+            status = status,
+            error = error_text,
+            "Failed to mint Google access token",
+        );
+        // a rejected service account is the customer's config, not ours
+        return Err(Error::UpstreamError {
+            status: if status == 400 { 401 } else { status },
+            message: format!("Failed to mint Google access token: {}", error_text),
+        }
+        .into());
+    }
+    let token = http_response.json::<TokenResponse>().await?;
+    Ok(CachedToken {
+        access_token: token.access_token,
+        expires_at: requested_at + Duration::from_secs(token.expires_in),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn key() -> ServiceAccountKey {
+        ServiceAccountKey {
+            client_email: "gateway@project.iam.gserviceaccount.com".to_string(),
+            private_key: "not a key".to_string(),
+            private_key_id: "kid-1".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn cache_token(cache: &GoogleTokenCache, key: &ServiceAccountKey, expires_in: Duration) {
+        cache.tokens.lock().unwrap().insert(
+            key.cache_key(),
+            CachedToken {
+                access_token: "ya29.cached".to_string(),
+                expires_at: Instant::now() + expires_in,
+            },
+        );
+    }
+
+    #[test]
+    fn reads_keys_as_objects_or_strings() {
+        #[derive(Deserialize)]
+        struct Config {
+            #[serde(deserialize_with = "ServiceAccountKey::deserialize_json")]
+            service_account: ServiceAccountKey,
+        }
+
+        let object = json!({"client_email": "a@b.com", "private_key": "pem", "project_id": "p"});
+        let as_object: Config =
+            serde_json::from_value(json!({ "service_account": object })).unwrap();
+        let as_string: Config =
+            serde_json::from_value(json!({ "service_account": object.to_string() })).unwrap();
+        assert_eq!(as_object.service_account, as_string.service_account);
+        assert_eq!(as_object.service_account.project_id, "p");
+
+        let broken = serde_json::from_value::<Config>(json!({ "service_account": "{" }));
+        assert!(broken.is_err());
+    }
+
+    #[tokio::test]
+    async fn reuses_fresh_tokens() {
+        let cache = GoogleTokenCache::new(reqwest::Client::new());
+        let key = key();
+        cache_token(&cache, &key, Duration::from_secs(3600));
+        let token = cache.access_token(&key, &Retrier::default()).await.unwrap();
+        assert_eq!(token, "ya29.cached");
+    }
+
+    #[tokio::test]
+    async fn mints_tokens_about_to_expire() {
+        let cache = GoogleTokenCache::new(reqwest::Client::new());
+        let key = key();
+        cache_token(&cache, &key, REFRESH_MARGIN / 2);
+        // minting starts with signing, which this key can't do
+        let error = cache
+            .access_token(&key, &Retrier::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<Error>(),
+            Some(Error::InvalidArgument(_))
+        ));
+    }
+}
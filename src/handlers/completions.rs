@@ -0,0 +1,107 @@
+use crate::client::sse::sse_stream;
+use crate::handlers::translate::{self, api_error};
+use crate::types::{
+    OaiChatCompletionChunk, OaiChatCompletionRequest, OaiChatCompletionResponse,
+    OaiCompletionRequest, OaiCompletionResponse,
+};
+use crate::BackendConfigs;
+use anyhow::Result;
+use axum::{
+    body::Body,
+    http::{
+        header::{HeaderMap, CONTENT_LENGTH},
+        StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Json,
+};
+use bytes::Bytes;
+use futures::StreamExt;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// Serves the legacy completions API by sending the prompt through
+/// [`translate::chat_completion`] as a single user message and reshaping
+/// the result.
+pub async fn completions(
+    headers: HeaderMap,
+    backend_configs: Arc<BackendConfigs>,
+    payload: Value,
+) -> Result<Response> {
+    let request: OaiCompletionRequest = match serde_json::from_value(payload) {
+        Ok(request) => request,
+        Err(e) => {
+            return Ok(bad_request(format!(
+                "Error while parsing request. Maybe it's not following OpenAI spec\nError: {}",
+                e
+            )))
+        }
+    };
+    let echo = request
+        .echo
+        .unwrap_or(false)
+        .then(|| request.prompt_text().unwrap_or_default());
+    let stream = request.stream.unwrap_or(false);
+    let chat_request = match OaiChatCompletionRequest::try_from(request) {
+        Ok(chat_request) => chat_request,
+        Err(e) => return Ok(bad_request(e.to_string())),
+    };
+
+    let response = translate::chat_completion(
+        headers,
+        backend_configs,
+        serde_json::to_value(chat_request)?,
+    )
+    .await?;
+    // errors are already OpenAI shaped
+    if !response.status().is_success() {
+        return Ok(response);
+    }
+
+    let (mut parts, body) = response.into_parts();
+    parts.headers.remove(CONTENT_LENGTH);
+
+    if stream {
+        let mut echoed = HashSet::new();
+        let events = sse_stream(body.into_data_stream()).map(move |event| {
+            let data = event?.data;
+            let data = match serde_json::from_str::<OaiChatCompletionChunk>(&data) {
+                Ok(chunk) => {
+                    let mut completion = OaiCompletionResponse::from(chunk);
+                    if let Some(prompt) = &echo {
+                        for choice in &mut completion.choices {
+                            if echoed.insert(choice.index) {
+                                choice.text.insert_str(0, prompt);
+                            }
+                        }
+                    }
+                    serde_json::to_string(&completion)?
+                }
+                // [DONE] and error events pass through
+                Err(_) => data,
+            };
+            Ok::<Bytes, anyhow::Error>(Bytes::from(format!("data: {}\n\n", data)))
+        });
+        return Ok(Response::from_parts(parts, Body::from_stream(events)));
+    }
+
+    let bytes = axum::body::to_bytes(body, usize::MAX).await?;
+    let chat_response: OaiChatCompletionResponse = serde_json::from_slice(&bytes)?;
+    let mut completion = OaiCompletionResponse::from(chat_response);
+    if let Some(prompt) = echo {
+        completion.echo(&prompt);
+    }
+    Ok(Response::from_parts(
+        parts,
+        Body::from(serde_json::to_vec(&completion)?),
+    ))
+}
+
+fn bad_request(message: String) -> Response {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(json!({ "error": api_error(StatusCode::BAD_REQUEST, message) })),
+    )
+        .into_response()
+}
@@ -0,0 +1,179 @@
+use crate::client::traits::*;
+use crate::client::*;
+use crate::error::Error;
+use crate::firestore::{CustomerLLMConfig, OPENAI_COMPATIBLE};
+use crate::handlers::translate::{error_status_code, log_and_respond, LogEntry};
+use crate::retry::Retrier;
+use crate::types::{OaiEmbeddingRequest, OaiEmbeddingResponse};
+use crate::utils;
+use crate::BackendConfigs;
+use anyhow::Result;
+use axum::{
+    http::{header::HeaderMap, StatusCode},
+    response::Response,
+};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Sends an embeddings request to one of the LLMs that can embed.
+pub async fn create_embeddings(
+    llm_name: &str,
+    api_key: &str,
+    llm_config: &CustomerLLMConfig,
+    retrier: &Retrier,
+    http_client: &reqwest::Client,
+    request: OaiEmbeddingRequest,
+) -> Result<OaiEmbeddingResponse> {
+    match llm_config.provider(llm_name) {
+        "openai" => {
+            openai::OpenAI::new(http_client.clone())
+                .with_api_key(api_key)
+                .with_base_url(llm_config.base_url.clone())
+                .with_retrier(retrier.clone())
+                .embeddings(request)
+                .await
+        }
+        "jamba" => {
+            mamba::Mamba::new(http_client.clone())
+                .with_api_key(api_key)
+                .with_retrier(retrier.clone())
+                .embeddings(request)
+                .await
+        }
+        "cohere" => {
+            cohere::Cohere::new(http_client.clone())
+                .with_api_key(api_key)
+                .with_retrier(retrier.clone())
+                .embeddings(request)
+                .await
+        }
+        OPENAI_COMPATIBLE => {
+            openai_compatible::OpenAICompatible::new(http_client.clone())
+                .with_api_key(api_key)
+                .with_base_url(llm_config.base_url.as_deref().unwrap_or_default())
+                .with_headers(llm_config.headers.clone())
+                .with_models(llm_config.models.clone())
+                .with_retrier(retrier.clone())
+                .embeddings(request)
+                .await
+        }
+        _ => Err(Error::InvalidArgument(format!(
+            "LLM '{}' does not support embeddings. Supported LLMs are: openai, jamba, cohere, openai_compatible",
+            llm_name
+        ))
+        .into()),
+    }
+}
+
+pub async fn embeddings(
+    headers: HeaderMap,
+    backend_configs: Arc<BackendConfigs>,
+    payload: Value,
+) -> Result<Response> {
+    let started = Instant::now();
+    let Some(felafax_token) = utils::extract_bearer_token(&headers) else {
+        let entry = LogEntry::rejected("", "Unauthorized: Missing or invalid token.".to_string());
+        return log_and_respond(&backend_configs, StatusCode::UNAUTHORIZED, entry).await;
+    };
+
+    let Ok(Some(customer_config)) = backend_configs
+        .config_store
+        .get_customer_configs(&felafax_token)
+        .await
+    else {
+        let entry = LogEntry::rejected(&felafax_token, "Invalid felafax token".to_string());
+        return log_and_respond(&backend_configs, StatusCode::UNAUTHORIZED, entry).await;
+    };
+
+    let request: OaiEmbeddingRequest = match serde_json::from_value(payload) {
+        Ok(request) => request,
+        Err(e) => {
+            let entry = LogEntry::rejected(
+                &felafax_token,
+                format!(
+                    "Error while parsing request. Maybe it's not following OpenAI spec\nError: {}",
+                    e
+                ),
+            );
+            return log_and_respond(&backend_configs, StatusCode::BAD_REQUEST, entry).await;
+        }
+    };
+
+    let llm_name = backend_configs
+        .model_registry
+        .get_customer_embedding_llm_name(&request.model, &customer_config);
+    let llm_config = llm_name
+        .as_ref()
+        .and_then(|llm_name| customer_config.llm_configs.get(llm_name));
+    let lease = llm_name
+        .as_ref()
+        .zip(llm_config)
+        .and_then(|(llm_name, llm_config)| {
+            backend_configs.key_pool.select(
+                llm_config.endpoint(llm_name),
+                &llm_config.keys(llm_name),
+                llm_config.key_selection,
+                &[],
+            )
+        });
+    let (Some(llm_name), Some(llm_config), Some(lease)) = (llm_name, llm_config, lease) else {
+        let entry = LogEntry {
+            request: Some(&request),
+            ..LogEntry::rejected(
+                &felafax_token,
+                format!(
+                    "No configured LLM serves embedding model '{}'",
+                    request.model
+                ),
+            )
+        };
+        return log_and_respond(&backend_configs, StatusCode::BAD_REQUEST, entry).await;
+    };
+
+    let retrier = Retrier::new(
+        customer_config
+            .retry_policy
+            .unwrap_or(backend_configs.retry_policy),
+    );
+    let result = create_embeddings(
+        &llm_name,
+        lease.key(),
+        llm_config,
+        &retrier,
+        &backend_configs.http_client,
+        request.clone(),
+    )
+    .await;
+    if let Err(e) = &result {
+        if crate::error::is_key_error(e) {
+            lease.cool_down();
+        }
+    }
+
+    let mut entry = LogEntry {
+        felafax_token: &felafax_token,
+        request: Some(&request),
+        llm_name: Some(&llm_name),
+        latency: started.elapsed().as_millis() as u32,
+        metadata: Some(HashMap::from([(
+            "key_fingerprint".to_string(),
+            lease.fingerprint(),
+        )])),
+        ..Default::default()
+    };
+    match result {
+        Ok(mut response) => {
+            if request.encoding_format.as_deref() == Some("base64") {
+                response.encode_base64();
+            }
+            entry.response = Some(&response);
+            log_and_respond(&backend_configs, StatusCode::OK, entry).await
+        }
+        Err(e) => {
+            entry.error = Some(e.to_string());
+            log_and_respond(&backend_configs, error_status_code(&e), entry).await
+        }
+    }
+}
@@ -49,13 +49,13 @@ impl Experiment {
     }
 
     async fn get_roll_out_percentage(&self, felafax_proxy: FelafaxProxy) -> Result<f64> {
-        let firebase_client = &self.backend_configs.firebase;
+        let config_store = &self.backend_configs.config_store;
 
         if let (Some(token), Some(rollout_id)) =
             (&felafax_proxy.felafax_token, &felafax_proxy.rollout_id)
         {
-            if let Some(user_id) = firebase_client.get_user_id(token).await? {
-                if let Some(percentage) = firebase_client
+            if let Some(user_id) = config_store.get_user_id(token).await? {
+                if let Some(percentage) = config_store
                     .get_roll_out_percentage(&user_id, rollout_id)
                     .await?
                 {
@@ -1,7 +1,13 @@
+pub mod completions;
+pub mod embeddings;
 pub mod experiment;
+pub mod models;
 pub mod openai_proxy;
 pub mod translate;
 
+pub use completions::*;
+pub use embeddings::*;
 pub use experiment::*;
+pub use models::*;
 pub use openai_proxy::*;
 pub use translate::*;
@@ -0,0 +1,78 @@
+use crate::client::{customer_models, HOT_SWAP_MODEL};
+use crate::handlers::translate::api_error;
+use crate::types::{OaiModel, OaiModelList};
+use crate::utils;
+use crate::BackendConfigs;
+use anyhow::Result;
+use axum::{
+    http::{
+        header::{HeaderMap, CACHE_CONTROL},
+        StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+use std::sync::Arc;
+
+/// Lists the models the caller's config can reach, OpenAI style.
+pub async fn list_models(
+    headers: HeaderMap,
+    backend_configs: Arc<BackendConfigs>,
+) -> Result<Response> {
+    let Some(felafax_token) = utils::extract_bearer_token(&headers) else {
+        return Ok(unauthorized("Unauthorized: Missing or invalid token."));
+    };
+    let Ok(Some(customer_config)) = backend_configs
+        .config_store
+        .get_customer_configs(&felafax_token)
+        .await
+    else {
+        return Ok(unauthorized("Invalid felafax token"));
+    };
+
+    let registry = &backend_configs.model_registry;
+    let model = |id: &str, owned_by: &str| OaiModel {
+        id: id.to_string(),
+        object: "model".to_string(),
+        created: registry.created,
+        owned_by: owned_by.to_string(),
+    };
+    let customer_models = backend_configs.customer_models.get_or_insert_with(
+        &felafax_token,
+        &customer_config,
+        || {
+            customer_models(
+                &customer_config,
+                &backend_configs.providers,
+                &backend_configs.google_tokens,
+                &backend_configs.http_client,
+            )
+        },
+    );
+    let mut data = vec![model(HOT_SWAP_MODEL, "felafax")];
+    data.extend(
+        customer_models
+            .iter()
+            .map(|(llm_name, id)| model(id, llm_name)),
+    );
+
+    let models = OaiModelList {
+        object: "list".to_string(),
+        data,
+    };
+    Ok((
+        StatusCode::OK,
+        [(CACHE_CONTROL, "private, max-age=300")],
+        Json(models),
+    )
+        .into_response())
+}
+
+fn unauthorized(message: &str) -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        Json(json!({ "error": api_error(StatusCode::UNAUTHORIZED, message.to_string()) })),
+    )
+        .into_response()
+}
@@ -15,10 +15,13 @@ use futures::stream::StreamExt;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::mpsc;
 use uuid::Uuid;
 
+use crate::firestore::{AzureConfig, ProxyUpstream};
+use crate::retry::Retrier;
 use crate::{handlers::experiment, request_logs, utils, BackendConfigs};
 
 #[derive(Builder, Default)]
@@ -29,6 +32,7 @@ pub struct Proxy {
     bearer_token: Option<String>,
     headers: Option<HeaderMap>,
     felafax_token: Option<String>,
+    retrier: Retrier,
 }
 
 pub async fn openai_proxy(
@@ -50,10 +54,29 @@ pub async fn openai_proxy(
     let mut payload = payload;
     let experiment = experiment::Experiment::new(backend_configs.clone());
     let felafax_proxy = experiment.extract_felafax_proxy(&headers);
+    let mut retry_policy = backend_configs.retry_policy;
+    let mut azure: Option<AzureConfig> = None;
 
     match felafax_proxy {
         Ok(Some(felafax_proxy)) => {
-            proxy_instance.felafax_token(felafax_proxy.felafax_token.unwrap_or_default());
+            let felafax_token = felafax_proxy.felafax_token.unwrap_or_default();
+            if let Ok(Some(customer_config)) = backend_configs
+                .config_store
+                .get_customer_configs(&felafax_token)
+                .await
+            {
+                retry_policy = customer_config.retry_policy.unwrap_or(retry_policy);
+                if customer_config.proxy_upstream == ProxyUpstream::Azure {
+                    azure = customer_config
+                        .llm_configs
+                        .get("azure")
+                        .and_then(|llm_config| llm_config.azure.clone());
+                    if azure.is_none() {
+                        return Ok(missing_azure_config_response());
+                    }
+                }
+            }
+            proxy_instance.felafax_token(felafax_token);
         }
         Ok(None) => {
             // user is not authorised to use proxy if felafax_token is missing.
@@ -75,22 +98,42 @@ pub async fn openai_proxy(
         }
     }
 
+    let client = backend_configs.http_client.clone();
+
     // construct logging object
     let proxy_instance = proxy_instance
         .bearer_token(&bearer_token)
         .request(payload.clone())
         .backend_configs(backend_configs)
         .headers(headers)
+        .retrier(Retrier::new(retry_policy))
         .build()?;
 
-    let url = construct_url(&original_uri)?;
+    let url = match &azure {
+        Some(azure) => construct_azure_url(azure, &original_uri, &payload)?,
+        None => construct_url(&original_uri)?,
+    };
     println!("Url: {:?}", &url.to_string());
 
-    let client = Client::new();
-    let request = build_request(&client, method, url, &bearer_token, &payload)?;
+    let request = build_request(
+        &client,
+        method,
+        url,
+        &bearer_token,
+        azure.is_some(),
+        &payload,
+    )?;
 
     let is_stream = payload["stream"].as_bool().unwrap_or(false);
-    let response = client.execute(request).await?;
+    let response = proxy_instance
+        .retrier
+        .execute(|| async {
+            let request = request
+                .try_clone()
+                .ok_or_else(|| anyhow::anyhow!("Request body can't be retried"))?;
+            anyhow::Ok(client.execute(request).await?)
+        })
+        .await?;
 
     println!("Response: {:?}", response);
 
@@ -113,16 +156,48 @@ fn unauthorized_response() -> Response {
         .into_response()
 }
 
+fn missing_azure_config_response() -> Response {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(json!({"error": "The azure proxy upstream needs an `azure` LLM config"})),
+    )
+        .into_response()
+}
+
 fn construct_url(original_uri: &Uri) -> Result<url::Url> {
     let base_url = url::Url::parse("https://api.openai.com/")?;
     Ok(base_url.join(&original_uri.to_string())?)
 }
 
+/// Maps `/v1/{path}` to the deployment serving the payload's model, e.g.
+/// `{endpoint}/openai/deployments/{deployment}/chat/completions`. Requests
+/// without a model, like `/v1/models`, go to `{endpoint}/openai/{path}`.
+fn construct_azure_url(
+    azure: &AzureConfig,
+    original_uri: &Uri,
+    payload: &Value,
+) -> Result<url::Url> {
+    let path = original_uri.path().trim_start_matches('/');
+    let path = path.strip_prefix("v1/").unwrap_or(path);
+    let path = match payload["model"].as_str() {
+        Some(model) => format!("openai/deployments/{}/{}", azure.deployment(model), path),
+        None => format!("openai/{}", path),
+    };
+
+    let base_url = url::Url::parse(&format!("{}/", azure.endpoint.trim_end_matches('/')))?;
+    let mut url = base_url.join(&path)?;
+    url.set_query(original_uri.query());
+    url.query_pairs_mut()
+        .append_pair("api-version", &azure.api_version);
+    Ok(url)
+}
+
 fn build_request(
     client: &Client,
     method: Method,
     url: url::Url,
     bearer_token: &str,
+    azure: bool,
     payload: &Value,
 ) -> Result<reqwest::Request> {
     let mut request = match method {
@@ -133,9 +208,12 @@ fn build_request(
         _ => return Err(anyhow::anyhow!("Method not allowed")),
     };
 
-    request = request
-        .header("Authorization", format!("Bearer {}", bearer_token))
-        .json(payload);
+    // Azure takes the key in its own header
+    request = match azure {
+        true => request.header("api-key", bearer_token),
+        false => request.header("Authorization", format!("Bearer {}", bearer_token)),
+    }
+    .json(payload);
 
     if payload["stream"].as_bool().unwrap_or(false) {
         request = request.header(CONTENT_TYPE, "text/event-stream");
@@ -215,12 +293,17 @@ async fn log_stats(
             request_logs.error(error);
         }
 
+        request_logs.metadata(HashMap::from([(
+            "upstream_attempts".to_string(),
+            proxy.retrier.attempts().to_string(),
+        )]));
+
         let request_logs = request_logs.build().unwrap();
         if let Some(backend_configs) = &proxy.backend_configs {
             let clickhouse_client = backend_configs.clickhouse.clone();
-            let firebase_client = backend_configs.firebase.clone();
+            let config_store = backend_configs.config_store.clone();
             request_logs
-                .log(&clickhouse_client, &firebase_client)
+                .log(clickhouse_client.as_ref(), &config_store)
                 .await
                 .unwrap_or_else(|e| eprintln!("Failed to log request: {:?}", e));
         }
@@ -385,3 +468,56 @@ pub struct Message {
     pub role: String,
     pub content: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn azure() -> AzureConfig {
+        AzureConfig {
+            endpoint: "https://my-resource.openai.azure.com/".to_string(),
+            api_version: "2024-10-21".to_string(),
+            deployments: HashMap::from([("gpt-4o".to_string(), "prod-gpt4o".to_string())]),
+        }
+    }
+
+    fn azure_url(uri: &str, payload: Value) -> String {
+        let uri: Uri = uri.parse().unwrap();
+        construct_azure_url(&azure(), &uri, &payload)
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn routes_models_to_their_azure_deployment() {
+        assert_eq!(
+            azure_url("/v1/chat/completions", json!({"model": "gpt-4o"})),
+            "https://my-resource.openai.azure.com/openai/deployments/prod-gpt4o/chat/completions?api-version=2024-10-21"
+        );
+        // models without an entry are deployed under their own name
+        assert_eq!(
+            azure_url("/v1/embeddings", json!({"model": "text-embedding-3-small"})),
+            "https://my-resource.openai.azure.com/openai/deployments/text-embedding-3-small/embeddings?api-version=2024-10-21"
+        );
+        assert_eq!(
+            azure_url("/v1/models?limit=5", Value::Null),
+            "https://my-resource.openai.azure.com/openai/models?limit=5&api-version=2024-10-21"
+        );
+    }
+
+    #[test]
+    fn sends_the_key_in_the_upstreams_header() {
+        let client = Client::new();
+        let url = url::Url::parse("https://example.com/").unwrap();
+        let payload = json!({"model": "gpt-4o"});
+
+        let request =
+            build_request(&client, Method::POST, url.clone(), "sk-1", true, &payload).unwrap();
+        assert_eq!(request.headers()["api-key"], "sk-1");
+        assert!(request.headers().get("authorization").is_none());
+
+        let request = build_request(&client, Method::POST, url, "sk-1", false, &payload).unwrap();
+        assert_eq!(request.headers()["authorization"], "Bearer sk-1");
+        assert!(request.headers().get("api-key").is_none());
+    }
+}
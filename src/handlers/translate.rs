@@ -1,248 +1,1220 @@
-use crate::clickhouse;
-use crate::client::traits::*;
+use crate::cache::{cache_key, CACHE_HEADER};
 use crate::client::*;
-use crate::firestore;
-use crate::request_logs;
-use crate::types::{OaiChatCompletionRequest, OaiChatCompletionResponse};
+use crate::error::{is_key_error, is_retryable, upstream_status, ApiError, Error};
+use crate::firestore::{
+    CustomerConfig, CustomerLLMConfig, KeySelection, SemanticCacheConfig, WeightedApiKey,
+};
+use crate::google_auth::GoogleTokenCache;
+use crate::handlers::embeddings::create_embeddings;
+use crate::key_pool::{KeyLease, KeyPool};
+use crate::request_logs::{self, Loggable};
+use crate::retry::Retrier;
+use crate::semantic_cache::{last_user_text, semantic_scope, PendingEntry, SIMILARITY_HEADER};
+use crate::types::{
+    OaiChatCompletionChunk, OaiChatCompletionRequest, OaiChatCompletionResponse,
+    OaiChatCompletionStream, OaiEmbeddingInput, OaiEmbeddingRequest, OaiEmbeddingVector,
+};
 use crate::utils;
 use crate::BackendConfigs;
 use anyhow::Result;
-use axum::{http::header::HeaderMap, http::StatusCode};
+use axum::{
+    body::Body,
+    http::{
+        header::{HeaderMap, HeaderValue, CONTENT_TYPE},
+        StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Json,
+};
+use bytes::Bytes;
 use chrono::Utc;
+use futures::stream::StreamExt;
+use serde::Serialize;
 use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::future::Future;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
 use uuid::Uuid;
 
+/// What to record about a request. Fields left out are logged as unknown.
+#[derive(Default)]
+pub(crate) struct LogEntry<'a> {
+    pub felafax_token: &'a str,
+    pub request: Option<&'a dyn Loggable>,
+    pub response: Option<&'a dyn Loggable>,
+    pub llm_name: Option<&'a str>,
+    /// Milliseconds since the request came in.
+    pub latency: u32,
+    pub error: Option<String>,
+    pub metadata: Option<HashMap<String, String>>,
+}
+
+impl<'a> LogEntry<'a> {
+    /// A request turned away before it reached an LLM.
+    pub fn rejected(felafax_token: &'a str, error: String) -> Self {
+        Self {
+            felafax_token,
+            error: Some(error),
+            ..Default::default()
+        }
+    }
+}
+
 async fn log_stats(
-    clickhouse_client: Arc<clickhouse::Clickhouse>,
-    firebase_client: Arc<firestore::Firestore>,
+    backend_configs: &BackendConfigs,
     status_code: StatusCode,
-    felafax_token: &str,
-    request: Option<&OaiChatCompletionRequest>,
-    response: Option<&OaiChatCompletionResponse>,
-    llm_name: Option<&str>,
-    latency: u32,
-    error: Option<String>,
+    entry: LogEntry<'_>,
 ) -> Result<()> {
-    let clickhouse_client = clickhouse_client.clone();
+    let clickhouse_client = backend_configs.clickhouse.clone();
+    let config_store = backend_configs.config_store.clone();
     let mut request_logs = request_logs::RequestLogBuilder::default();
     request_logs.customer_id(Uuid::new_v4().to_string());
     request_logs.request_id(Uuid::new_v4().to_string());
     request_logs.timestamp(Utc::now().timestamp());
 
-    request_logs.customer_id(felafax_token);
+    request_logs.customer_id(entry.felafax_token);
+    request_logs.http_status(status_code.as_u16());
 
-    if let Some(request) = request {
-        request_logs.request(serde_json::to_string(&request)?);
+    if let Some(request) = entry.request {
+        request_logs.request(request.to_json()?.to_string());
     }
 
-    if let Some(llm_name) = llm_name {
+    if let Some(llm_name) = entry.llm_name {
         request_logs.llm_name(llm_name.to_string());
     }
 
-    if let Some(response) = response {
-        request_logs.response(serde_json::to_string(&response)?);
-
-        request_logs.llm_model(serde_json::to_string(&response.model)?);
-        if let Some(usage) = &response.usage {
-            request_logs.prompt_tokens(usage.prompt_tokens);
-            request_logs.completion_tokens(usage.completion_tokens);
-            request_logs.total_tokens(usage.total_tokens);
-        }
+    if let Some(response) = entry.response {
+        response.log_response(&mut request_logs)?;
     }
-    request_logs.total_latency(latency);
+    request_logs.total_latency(entry.latency);
 
-    if let Some(error) = error {
+    if let Some(error) = entry.error {
         request_logs.error(error);
     }
 
+    if let Some(metadata) = entry.metadata {
+        request_logs.metadata(metadata);
+    }
+
     let request_logs = request_logs.build().unwrap();
 
     // log in background
     tokio::task::spawn(async move {
         request_logs
-            .log(&clickhouse_client, &firebase_client)
+            .log(clickhouse_client.as_ref(), &config_store)
             .await
             .unwrap_or_else(|e| eprintln!("Failed to log request: {:?}", e));
     });
     Ok(())
 }
 
-async fn log_and_respond(
-    clickhouse_client: Arc<clickhouse::Clickhouse>,
-    firebase: Arc<firestore::Firestore>,
+/// Logs the request in the background and responds with the entry's
+/// response, or its error shaped the way OpenAI reports errors.
+pub(crate) async fn log_and_respond(
+    backend_configs: &BackendConfigs,
     status_code: StatusCode,
+    entry: LogEntry<'_>,
+) -> Result<Response> {
+    let error = entry.error.clone();
+    let response = entry.response;
+    let _ = log_stats(backend_configs, status_code, entry).await;
+
+    if let Some(error) = error {
+        Ok((
+            status_code,
+            Json(json!({ "error": api_error(status_code, error) })),
+        )
+            .into_response())
+    } else {
+        let body = match response {
+            Some(response) => response.to_json()?,
+            None => Value::Null,
+        };
+        Ok((status_code, Json(body)).into_response())
+    }
+}
+
+/// Shapes an error the way the OpenAI API reports it.
+pub(crate) fn api_error(status_code: StatusCode, message: String) -> ApiError {
+    let error_type = match status_code {
+        StatusCode::UNAUTHORIZED => "authentication_error",
+        StatusCode::TOO_MANY_REQUESTS => "rate_limit_error",
+        status_code if status_code.is_client_error() => "invalid_request_error",
+        _ => "api_error",
+    };
+    ApiError {
+        message,
+        r#type: Some(error_type.to_string()),
+        param: None,
+        code: None,
+    }
+}
+
+/// Requests that a provider cannot represent are the caller's fault.
+pub(crate) fn error_status_code(error: &anyhow::Error) -> StatusCode {
+    match error.downcast_ref::<Error>() {
+        Some(Error::InvalidArgument(_)) => StatusCode::BAD_REQUEST,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// What to record once a streamed response has finished.
+struct StreamLog {
+    /// When the request came in.
+    started: Instant,
+    felafax_token: String,
+    request: OaiChatCompletionRequest,
+    llm_name: String,
+    metadata: Option<HashMap<String, String>>,
+    /// Held until the stream ends so the key counts as in flight.
+    lease: Option<KeyLease>,
+    cache_key: Option<String>,
+    semantic_entry: Option<PendingEntry>,
+}
+
+fn stream_and_log(
+    backend_configs: Arc<BackendConfigs>,
+    stream_log: StreamLog,
+    stream: OaiChatCompletionStream,
+) -> Result<Response> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let body = stream
+        .map(move |result| {
+            let data = match result {
+                Ok(chunk) => {
+                    let data = serde_json::to_string(&chunk)?;
+                    let _ = tx.send(Ok(chunk));
+                    data
+                }
+                Err(e) => {
+                    // the response is already a 200, the status is for the log
+                    let status_code = upstream_status(&e)
+                        .and_then(|status| StatusCode::from_u16(status).ok())
+                        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+                    let _ = tx.send(Err((status_code, e.to_string())));
+                    json!({ "error": api_error(status_code, e.to_string()) }).to_string()
+                }
+            };
+            Ok::<Bytes, serde_json::Error>(Bytes::from(format!("data: {}\n\n", data)))
+        })
+        .chain(futures::stream::once(async {
+            Ok(Bytes::from_static(b"data: [DONE]\n\n"))
+        }));
+
+    tokio::spawn(process_background_streaming(
+        backend_configs,
+        stream_log,
+        rx,
+    ));
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "text/event-stream")
+        .body(Body::from_stream(body))?)
+}
+
+async fn process_background_streaming(
+    backend_configs: Arc<BackendConfigs>,
+    stream_log: StreamLog,
+    mut rx: mpsc::UnboundedReceiver<
+        std::result::Result<OaiChatCompletionChunk, (StatusCode, String)>,
+    >,
+) {
+    let mut accumulated_response = OaiChatCompletionResponse::default();
+    let mut error = None;
+    let mut first_chunk_latency = None;
+
+    while let Some(result) = rx.recv().await {
+        match result {
+            Ok(chunk) => {
+                first_chunk_latency.get_or_insert_with(|| stream_log.started.elapsed());
+                accumulated_response.accumulate(&chunk)
+            }
+            Err(e) => error = Some(e),
+        }
+    }
+    let latency = stream_log.started.elapsed().as_millis() as u32;
+    let mut metadata = stream_log.metadata;
+    if let Some(first_chunk_latency) = first_chunk_latency {
+        metadata.get_or_insert_with(HashMap::new).insert(
+            "time_to_first_chunk_ms".to_string(),
+            first_chunk_latency.as_millis().to_string(),
+        );
+    }
+
+    let (status_code, error) = match error {
+        Some((status_code, error)) => (status_code, Some(error)),
+        None => (StatusCode::OK, None),
+    };
+    if error.is_none() && is_complete(&accumulated_response) {
+        if let Some(cache_key) = stream_log.cache_key {
+            backend_configs
+                .response_cache
+                .insert(cache_key, accumulated_response.clone());
+        }
+        if let Some(entry) = stream_log.semantic_entry {
+            let request = &stream_log.request;
+            let scope = semantic_scope(&stream_log.llm_name, &request.model, request);
+            backend_configs
+                .semantic_cache
+                .insert(entry, scope, accumulated_response.clone());
+        }
+    }
+    let entry = LogEntry {
+        felafax_token: &stream_log.felafax_token,
+        request: Some(&stream_log.request),
+        response: Some(&accumulated_response),
+        llm_name: Some(&stream_log.llm_name),
+        latency,
+        error,
+        metadata,
+    };
+    let _ = log_stats(&backend_configs, status_code, entry).await;
+}
+
+/// Whether every choice finished, so a stream the client dropped halfway
+/// isn't cached.
+fn is_complete(response: &OaiChatCompletionResponse) -> bool {
+    !response.choices.is_empty()
+        && response
+            .choices
+            .iter()
+            .all(|choice| choice.finish_reason.is_some())
+}
+
+fn with_cache_header(response: Result<Response>, status: &'static str) -> Result<Response> {
+    let mut response = response?;
+    response
+        .headers_mut()
+        .insert(CACHE_HEADER, HeaderValue::from_static(status));
+    Ok(response)
+}
+
+/// Serves a cached response, replaying it as chunks for streaming requests.
+/// Semantic cache hits carry the prompts' similarity.
+async fn respond_from_cache(
+    backend_configs: Arc<BackendConfigs>,
+    started: Instant,
+    felafax_token: String,
+    request: OaiChatCompletionRequest,
+    llm_name: String,
+    cached: OaiChatCompletionResponse,
+    similarity: Option<f32>,
+) -> Result<Response> {
+    let status = match similarity {
+        Some(_) => "semantic_hit",
+        None => "hit",
+    };
+    let mut metadata = cache_metadata(None, status).unwrap_or_default();
+    if let Some(similarity) = similarity {
+        metadata.insert("similarity".to_string(), similarity.to_string());
+    }
+
+    let response = if request.stream.unwrap_or(false) {
+        let chunks = cached.into_chunks().into_iter().map(Ok);
+        let stream_log = StreamLog {
+            started,
+            felafax_token,
+            request,
+            llm_name,
+            metadata: Some(metadata),
+            lease: None,
+            cache_key: None,
+            semantic_entry: None,
+        };
+        stream_and_log(
+            backend_configs,
+            stream_log,
+            Box::pin(futures::stream::iter(chunks)),
+        )
+    } else {
+        let entry = LogEntry {
+            felafax_token: &felafax_token,
+            request: Some(&request),
+            response: Some(&cached),
+            llm_name: Some(&llm_name),
+            latency: started.elapsed().as_millis() as u32,
+            error: None,
+            metadata: Some(metadata),
+        };
+        log_and_respond(&backend_configs, StatusCode::OK, entry).await
+    };
+
+    let mut response = with_cache_header(response, "hit")?;
+    if let Some(similarity) = similarity {
+        if let Ok(similarity) = HeaderValue::from_str(&format!("{:.4}", similarity)) {
+            response.headers_mut().insert(SIMILARITY_HEADER, similarity);
+        }
+    }
+    Ok(response)
+}
+
+/// Embeds the request's last user message with the customer's embeddings
+/// LLM. Returns `None` when there is nothing to match on.
+async fn embed_for_cache(
+    backend_configs: &BackendConfigs,
+    customer_config: &CustomerConfig,
+    semantic_config: &SemanticCacheConfig,
     felafax_token: &str,
-    request: Option<&OaiChatCompletionRequest>,
-    response: Option<&OaiChatCompletionResponse>,
-    llm_name: Option<&str>,
-    latency: u32,
-    error: Option<String>,
-) -> Result<(StatusCode, Value)> {
-    let _ = log_stats(
-        clickhouse_client.clone(),
-        firebase.clone(),
-        status_code,
-        felafax_token,
-        request,
-        response,
+    request: &OaiChatCompletionRequest,
+) -> Result<Option<PendingEntry>> {
+    let Some(text) = last_user_text(request) else {
+        return Ok(None);
+    };
+    let llm_name = &semantic_config.embedding_llm_name;
+    let llm_config = customer_config
+        .llm_configs
+        .get(llm_name)
+        .ok_or_else(|| Error::InvalidArgument(format!("No config found for LLM '{}'", llm_name)))?;
+    let lease = backend_configs
+        .key_pool
+        .select(
+            llm_config.endpoint(llm_name),
+            &llm_config.keys(llm_name),
+            llm_config.key_selection,
+            &[],
+        )
+        .ok_or_else(|| {
+            Error::InvalidArgument(format!("No API key configured for LLM '{}'", llm_name))
+        })?;
+
+    let request = OaiEmbeddingRequest {
+        model: semantic_config.embedding_model.clone(),
+        input: OaiEmbeddingInput::String(text),
+        ..Default::default()
+    };
+    let retrier = Retrier::new(
+        customer_config
+            .retry_policy
+            .unwrap_or(backend_configs.retry_policy),
+    );
+    let response = create_embeddings(
         llm_name,
-        latency,
-        error.clone(),
+        lease.key(),
+        llm_config,
+        &retrier,
+        &backend_configs.http_client,
+        request,
     )
-    .await;
+    .await?;
+    let Some(OaiEmbeddingVector::Float(embedding)) = response
+        .data
+        .into_iter()
+        .next()
+        .map(|embedding| embedding.embedding)
+    else {
+        return Err(anyhow::anyhow!("{} returned no embedding", llm_name));
+    };
+    Ok(Some(PendingEntry::new(
+        felafax_token,
+        embedding,
+        Duration::from_secs(semantic_config.ttl_secs),
+    )))
+}
 
-    if let Some(error) = error {
-        Ok((status_code, json!({ "error": error })))
-    } else {
-        Ok((status_code, serde_json::to_value(response.clone())?))
+fn cache_metadata(
+    metadata: Option<HashMap<String, String>>,
+    status: &str,
+) -> Option<HashMap<String, String>> {
+    let mut metadata = metadata.unwrap_or_default();
+    metadata.insert("cache".to_string(), status.to_string());
+    Some(metadata)
+}
+
+/// An LLM to send the request to, along with the customer's keys for it.
+#[derive(Debug, Clone, PartialEq)]
+struct LlmRoute {
+    llm_name: String,
+    /// Usually the LLM name, `openai_compatible` for custom endpoints.
+    provider: String,
+    model: String,
+    keys: Vec<WeightedApiKey>,
+    key_selection: KeySelection,
+    llm_config: CustomerLLMConfig,
+}
+
+impl LlmRoute {
+    fn new(llm_name: String, model: String, llm_config: &CustomerLLMConfig) -> Self {
+        let provider = llm_config.provider(&llm_name).to_string();
+        let mut keys: Vec<WeightedApiKey> = vec![];
+        for key in llm_config.keys(&llm_name) {
+            if key.weight > 0 && !keys.iter().any(|k| k.key == key.key) {
+                keys.push(key);
+            }
+        }
+        Self {
+            provider,
+            llm_name,
+            model,
+            keys,
+            key_selection: llm_config.key_selection,
+            llm_config: llm_config.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct FailedAttempt {
+    llm_name: String,
+    model: String,
+    key_fingerprint: String,
+    error: String,
+}
+
+/// The requested LLM followed by the customer's fallbacks. Fallbacks without
+/// a config, a registered provider or keys are skipped.
+fn llm_routes(
+    providers: &ProviderRegistry,
+    customer_config: &CustomerConfig,
+    llm_name: String,
+    model: String,
+) -> Result<Vec<LlmRoute>> {
+    let llm_config = customer_config
+        .llm_configs
+        .get(&llm_name)
+        .ok_or_else(|| Error::InvalidArgument(format!("No config found for LLM '{}'", llm_name)))?;
+    let route = LlmRoute::new(llm_name, model, llm_config);
+    if !providers.contains(&route.provider) {
+        return Err(Error::InvalidArgument(format!(
+            "Unknown provider '{}' for LLM '{}'. Supported providers are: {}",
+            route.provider,
+            route.llm_name,
+            providers.names().join(", ")
+        ))
+        .into());
+    }
+    if route.keys.is_empty() {
+        return Err(Error::InvalidArgument(format!(
+            "No API key configured for LLM '{}'",
+            route.llm_name
+        ))
+        .into());
+    }
+    let mut routes = vec![route];
+
+    for fallback in &customer_config.fallbacks {
+        let Some(llm_config) = customer_config.llm_configs.get(&fallback.llm_name) else {
+            tracing::warn!(
+                llm_name = fallback.llm_name.as_str(),
+                "Skipping fallback with no config"
+            );
+            continue;
+        };
+        let route = LlmRoute::new(
+            fallback.llm_name.clone(),
+            fallback.model.clone().unwrap_or_default(),
+            llm_config,
+        );
+        if !providers.contains(&route.provider) {
+            tracing::warn!(
+                llm_name = route.llm_name.as_str(),
+                provider = route.provider.as_str(),
+                "Skipping fallback with an unknown provider"
+            );
+            continue;
+        }
+        if !route.keys.is_empty() && !routes.contains(&route) {
+            routes.push(route);
+        }
+    }
+    Ok(routes)
+}
+
+/// The last route [`call_with_fallbacks`] tried, the request sent to it, its
+/// result, the key it used and the attempts that failed before it.
+type FallbackOutcome<T> = (
+    LlmRoute,
+    OaiChatCompletionRequest,
+    Result<T>,
+    Option<KeyLease>,
+    Vec<FailedAttempt>,
+);
+
+/// Tries each route in turn until one succeeds or fails with an error that
+/// is not retryable. Within a route, a key that is rate limited or rejected
+/// is cooled down and the next key is tried. Fails without routes to try.
+async fn call_with_fallbacks<T, F, Fut>(
+    key_pool: &Arc<KeyPool>,
+    retrier: &Retrier,
+    routes: Vec<LlmRoute>,
+    request: &OaiChatCompletionRequest,
+    call: F,
+) -> Result<FallbackOutcome<T>>
+where
+    F: Fn(LlmRoute, String, Retrier, OaiChatCompletionRequest) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut failed_attempts = vec![];
+    let mut routes = routes.into_iter().peekable();
+    loop {
+        let Some(route) = routes.next() else {
+            return Err(Error::InvalidArgument("No LLM to send the request to".to_string()).into());
+        };
+        let mut request = request.clone();
+        request.model = route.model.clone();
+
+        let mut tried_keys = vec![];
+        let (result, lease) = loop {
+            let Some(lease) = key_pool.select(
+                route.llm_config.endpoint(&route.llm_name),
+                &route.keys,
+                route.key_selection,
+                &tried_keys,
+            ) else {
+                let error = Error::InvalidArgument(format!(
+                    "No API key configured for LLM '{}'",
+                    route.llm_name
+                ));
+                break (Err(error.into()), None);
+            };
+
+            // leave rate limited keys to the pool rather than retrying them
+            let rotate_keys = tried_keys.len() + 1 < route.keys.len();
+            let retrier = retrier.clone().with_key_rotation(rotate_keys);
+            match call(
+                route.clone(),
+                lease.key().to_string(),
+                retrier,
+                request.clone(),
+            )
+            .await
+            {
+                Err(e) if is_key_error(&e) => {
+                    lease.cool_down();
+                    tried_keys.push(lease.key().to_string());
+                    if tried_keys.len() == route.keys.len() {
+                        break (Err(e), Some(lease));
+                    }
+                    tracing::warn!(
+                        llm_name = route.llm_name.as_str(),
+                        key_fingerprint = lease.fingerprint().as_str(),
+                        status = error_status_code(&e).as_u16(),
+                        error = %e,
+                        "Key failed, trying next key"
+                    );
+                    failed_attempts.push(FailedAttempt {
+                        llm_name: route.llm_name.clone(),
+                        model: route.model.clone(),
+                        key_fingerprint: lease.fingerprint(),
+                        error: e.to_string(),
+                    });
+                }
+                result => break (result, Some(lease)),
+            }
+        };
+
+        match result {
+            Err(e) if (is_retryable(&e) || is_key_error(&e)) && routes.peek().is_some() => {
+                tracing::warn!(
+                    llm_name = route.llm_name.as_str(),
+                    model = route.model.as_str(),
+                    status = error_status_code(&e).as_u16(),
+                    error = %e,
+                    "LLM failed, trying next fallback"
+                );
+                failed_attempts.push(FailedAttempt {
+                    llm_name: route.llm_name,
+                    model: route.model,
+                    key_fingerprint: lease.map(|lease| lease.fingerprint()).unwrap_or_default(),
+                    error: e.to_string(),
+                });
+            }
+            result => return Ok((route, request, result, lease, failed_attempts)),
+        }
+    }
+}
+
+fn attempts_metadata(
+    failed_attempts: &[FailedAttempt],
+    retrier: &Retrier,
+    lease: Option<&KeyLease>,
+) -> Option<HashMap<String, String>> {
+    let mut metadata = HashMap::new();
+    if !failed_attempts.is_empty() {
+        metadata.insert(
+            "failed_attempts".to_string(),
+            serde_json::to_string(failed_attempts).unwrap_or_default(),
+        );
+    }
+    if retrier.attempts() > 0 {
+        metadata.insert(
+            "upstream_attempts".to_string(),
+            retrier.attempts().to_string(),
+        );
+    }
+    if let Some(lease) = lease {
+        metadata.insert("key_fingerprint".to_string(), lease.fingerprint());
     }
+    if metadata.is_empty() {
+        return None;
+    }
+    Some(metadata)
+}
+
+/// Builds the client for a route and key from the provider's factory.
+fn provider(
+    providers: &ProviderRegistry,
+    route: &LlmRoute,
+    api_key: &str,
+    retrier: &Retrier,
+    google_tokens: &Arc<GoogleTokenCache>,
+    http_client: &reqwest::Client,
+) -> Result<Provider> {
+    providers.build(
+        &route.provider,
+        &ProviderContext {
+            api_key,
+            llm_config: &route.llm_config,
+            retrier,
+            google_tokens,
+            http_client,
+        },
+    )
+}
+
+/// Whether a request reads and writes the caches, from the customer's config
+/// and the `x-felafax-cache` header.
+#[derive(Debug, Clone, Copy)]
+struct CacheMode {
+    /// Read and write the exact-match response cache.
+    response_cache: bool,
+    /// Skip cache lookups altogether.
+    bypass: bool,
+}
+
+impl CacheMode {
+    fn new(headers: &HeaderMap, customer_config: &CustomerConfig) -> Self {
+        let cache_header = headers
+            .get(CACHE_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_lowercase());
+        Self {
+            response_cache: customer_config.cache_responses
+                || cache_header.as_deref() == Some("true"),
+            bypass: cache_header.as_deref() == Some("bypass"),
+        }
+    }
+
+    /// Marks a response that went upstream as a cache miss.
+    fn miss(&self, response: Result<Response>) -> Result<Response> {
+        if self.response_cache {
+            with_cache_header(response, "miss")
+        } else {
+            response
+        }
+    }
+}
+
+enum CacheLookup {
+    /// A cached answer, with the prompts' similarity for semantic hits.
+    Hit(OaiChatCompletionResponse, Option<f32>),
+    /// Nothing cached. Holds the prompt's embedding when the answer should
+    /// go to the semantic cache.
+    Miss(Option<PendingEntry>),
+}
+
+/// Looks the request up in the response cache, then the semantic cache.
+/// Both are keyed by the first route, the one the request asked for.
+async fn lookup_caches(
+    backend_configs: &BackendConfigs,
+    customer_config: &CustomerConfig,
+    felafax_token: &str,
+    request: &OaiChatCompletionRequest,
+    primary: &LlmRoute,
+    cache_mode: CacheMode,
+) -> CacheLookup {
+    if cache_mode.bypass {
+        return CacheLookup::Miss(None);
+    }
+    if cache_mode.response_cache {
+        let cache_key = cache_key(felafax_token, &primary.llm_name, &primary.model, request);
+        if let Some(cached) = backend_configs.response_cache.get(&cache_key) {
+            return CacheLookup::Hit(cached, None);
+        }
+    }
+
+    let Some(semantic_config) = customer_config.semantic_cache.as_ref() else {
+        return CacheLookup::Miss(None);
+    };
+    match embed_for_cache(
+        backend_configs,
+        customer_config,
+        semantic_config,
+        felafax_token,
+        request,
+    )
+    .await
+    {
+        Ok(Some(entry)) => {
+            let scope = semantic_scope(&primary.llm_name, &primary.model, request);
+            match backend_configs
+                .semantic_cache
+                .lookup(&entry, &scope, semantic_config.threshold)
+            {
+                Some((cached, similarity)) => CacheLookup::Hit(cached, Some(similarity)),
+                None => CacheLookup::Miss(Some(entry)),
+            }
+        }
+        Ok(None) => CacheLookup::Miss(None),
+        Err(e) => {
+            tracing::error!(error = ?e, "Semantic cache lookup failed");
+            CacheLookup::Miss(None)
+        }
+    }
+}
+
+/// A translate request that passed auth and routing and missed the caches,
+/// on its way upstream.
+struct UpstreamCall {
+    backend_configs: Arc<BackendConfigs>,
+    started: Instant,
+    felafax_token: String,
+    request: OaiChatCompletionRequest,
+    routes: Vec<LlmRoute>,
+    retrier: Retrier,
+    cache_mode: CacheMode,
+    semantic_entry: Option<PendingEntry>,
 }
 
 pub async fn chat_completion(
     headers: HeaderMap,
     backend_configs: Arc<BackendConfigs>,
     payload: Value,
-) -> Result<(StatusCode, Value)> {
-    let felafax_token = match utils::extract_bearer_token(&headers) {
-        Some(token) => token,
-        None => {
-            return log_and_respond(
-                backend_configs.clickhouse.clone(),
-                backend_configs.firebase.clone(),
-                StatusCode::UNAUTHORIZED,
-                "",
-                None,
-                None,
-                None,
-                0,
-                Some("Unauthorized: Missing or invalid token.".to_string()),
-            )
-            .await
-        }
+) -> Result<Response> {
+    let started = Instant::now();
+    let Some(felafax_token) = utils::extract_bearer_token(&headers) else {
+        let entry = LogEntry::rejected("", "Unauthorized: Missing or invalid token.".to_string());
+        return log_and_respond(&backend_configs, StatusCode::UNAUTHORIZED, entry).await;
     };
 
-    let customer_config = match backend_configs
-        .firebase
+    let Ok(Some(customer_config)) = backend_configs
+        .config_store
         .get_customer_configs(&felafax_token)
         .await
-    {
-        Ok(Some(config)) => config,
-        _ => {
-            return log_and_respond(
-                backend_configs.clickhouse.clone(),
-                backend_configs.firebase.clone(),
-                StatusCode::UNAUTHORIZED,
-                &felafax_token,
-                None,
-                None,
-                None,
-                0,
-                Some("Invalid felafax token".to_string()),
-            )
-            .await
-        }
+    else {
+        let entry = LogEntry::rejected(&felafax_token, "Invalid felafax token".to_string());
+        return log_and_respond(&backend_configs, StatusCode::UNAUTHORIZED, entry).await;
     };
 
     let request: OaiChatCompletionRequest = match serde_json::from_value(payload) {
         Ok(req) => req,
         Err(e) => {
-            return log_and_respond(
-                backend_configs.clickhouse.clone(),
-                backend_configs.firebase.clone(),
-                StatusCode::BAD_REQUEST,
+            let entry = LogEntry::rejected(
                 &felafax_token,
-                None,
-                None,
-                None,
-                0,
-                Some(format!(
+                format!(
                     "Error while parsing request. Maybe it's not following OpenAI spec\nError: {}",
-                    e.to_string()
-                )),
-            )
-            .await
+                    e
+                ),
+            );
+            return log_and_respond(&backend_configs, StatusCode::BAD_REQUEST, entry).await;
         }
     };
 
-    let llm_response = match customer_config.selected_llm_name.as_str() {
-        "claude" => {
-            let api_key = customer_config
-                .llm_configs
-                .get("claude")
-                .unwrap()
-                .api_key
-                .clone();
-            let llm_client = claude::Claude::new().with_api_key(api_key.as_str());
-
-            llm_client.chat(request.clone()).await
-        }
-        "openai" => {
-            let api_key = customer_config
-                .llm_configs
-                .get("openai")
-                .unwrap()
-                .api_key
-                .clone();
-            let llm_client = openai::OpenAI::new().with_api_key(api_key.as_str());
-
-            llm_client.chat(request.clone()).await
-        }
-        "jamba" => {
-            let api_key = customer_config
-                .llm_configs
-                .get("jamba")
-                .unwrap()
-                .api_key
-                .clone();
-            let llm_client = mamba::Mamba::new().with_api_key(api_key.as_str());
-
-            llm_client.chat(request.clone()).await
-        }
-        _ => {
-            return log_and_respond(
-                backend_configs.clickhouse.clone(),
-                backend_configs.firebase.clone(),
-                StatusCode::BAD_REQUEST,
+    let Some((llm_name, model)) = backend_configs
+        .model_registry
+        .resolve(&request.model, &customer_config)
+    else {
+        let entry = LogEntry {
+            request: Some(&request),
+            ..LogEntry::rejected(
                 &felafax_token,
-                Some(&request),
-                None,
-                None,
-                0,
-                Some("Invalid LLM name. Supported LLMs are: mamba, openai, claude".to_string()),
+                format!(
+                    "Unknown model '{}'. Pass a supported model or '{}'",
+                    request.model, HOT_SWAP_MODEL
+                ),
+            )
+        };
+        return log_and_respond(&backend_configs, StatusCode::BAD_REQUEST, entry).await;
+    };
+
+    let routes = match llm_routes(
+        &backend_configs.providers,
+        &customer_config,
+        llm_name.clone(),
+        model,
+    ) {
+        Ok(routes) => routes,
+        Err(e) => {
+            let entry = LogEntry {
+                request: Some(&request),
+                llm_name: Some(&llm_name),
+                ..LogEntry::rejected(&felafax_token, e.to_string())
+            };
+            return log_and_respond(&backend_configs, StatusCode::BAD_REQUEST, entry).await;
+        }
+    };
+
+    let cache_mode = CacheMode::new(&headers, &customer_config);
+    let primary = &routes[0];
+    let semantic_entry = match lookup_caches(
+        &backend_configs,
+        &customer_config,
+        &felafax_token,
+        &request,
+        primary,
+        cache_mode,
+    )
+    .await
+    {
+        CacheLookup::Hit(cached, similarity) => {
+            let llm_name = primary.llm_name.clone();
+            return respond_from_cache(
+                backend_configs,
+                started,
+                felafax_token,
+                request,
+                llm_name,
+                cached,
+                similarity,
             )
+            .await;
+        }
+        CacheLookup::Miss(semantic_entry) => semantic_entry,
+    };
+
+    let call = UpstreamCall {
+        retrier: Retrier::new(
+            customer_config
+                .retry_policy
+                .unwrap_or(backend_configs.retry_policy),
+        ),
+        backend_configs,
+        started,
+        felafax_token,
+        request,
+        routes,
+        cache_mode,
+        semantic_entry,
+    };
+    if call.request.stream.unwrap_or(false) {
+        stream_upstream(call).await
+    } else {
+        call_upstream(call).await
+    }
+}
+
+/// Streams the first route that starts a stream, logging and caching the
+/// response once it has been sent.
+async fn stream_upstream(call: UpstreamCall) -> Result<Response> {
+    let UpstreamCall {
+        backend_configs,
+        started,
+        felafax_token,
+        request,
+        routes,
+        retrier,
+        cache_mode,
+        semantic_entry,
+    } = call;
+    let providers = &backend_configs.providers;
+    let google_tokens = &backend_configs.google_tokens;
+    let http_client = &backend_configs.http_client;
+
+    let outcome = call_with_fallbacks(
+        &backend_configs.key_pool,
+        &retrier,
+        routes,
+        &request,
+        |route, api_key, retrier, request| async move {
+            let provider = provider(
+                providers,
+                &route,
+                &api_key,
+                &retrier,
+                google_tokens,
+                http_client,
+            )?;
+            start_stream(provider.chat_stream(request).await?).await
+        },
+    )
+    .await;
+    let (route, request, llm_stream, lease, failed_attempts) = match outcome {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            let entry = LogEntry {
+                request: Some(&request),
+                ..LogEntry::rejected(&felafax_token, e.to_string())
+            };
+            return log_and_respond(&backend_configs, error_status_code(&e), entry).await;
+        }
+    };
+    let mut metadata = attempts_metadata(&failed_attempts, &retrier, lease.as_ref());
+    if cache_mode.response_cache {
+        metadata = cache_metadata(metadata, "miss");
+    }
+
+    match llm_stream {
+        Ok(stream) => {
+            let cache_key = cache_mode
+                .response_cache
+                .then(|| cache_key(&felafax_token, &route.llm_name, &route.model, &request));
+            let stream_log = StreamLog {
+                started,
+                felafax_token,
+                request,
+                llm_name: route.llm_name,
+                metadata,
+                lease,
+                cache_key,
+                semantic_entry,
+            };
+            let response = stream_and_log(backend_configs.clone(), stream_log, stream);
+            cache_mode.miss(response)
+        }
+        Err(e) => {
+            let entry = LogEntry {
+                felafax_token: &felafax_token,
+                request: Some(&request),
+                response: None,
+                llm_name: Some(&route.llm_name),
+                latency: started.elapsed().as_millis() as u32,
+                error: Some(e.to_string()),
+                metadata,
+            };
+            log_and_respond(&backend_configs, error_status_code(&e), entry).await
+        }
+    }
+}
+
+/// Sends the request down its routes and answers with the first result that
+/// isn't retried elsewhere, caching complete responses.
+async fn call_upstream(call: UpstreamCall) -> Result<Response> {
+    let UpstreamCall {
+        backend_configs,
+        started,
+        felafax_token,
+        request,
+        routes,
+        retrier,
+        cache_mode,
+        semantic_entry,
+    } = call;
+    let providers = &backend_configs.providers;
+    let google_tokens = &backend_configs.google_tokens;
+    let http_client = &backend_configs.http_client;
+
+    let outcome = call_with_fallbacks(
+        &backend_configs.key_pool,
+        &retrier,
+        routes,
+        &request,
+        |route, api_key, retrier, request| async move {
+            provider(
+                providers,
+                &route,
+                &api_key,
+                &retrier,
+                google_tokens,
+                http_client,
+            )?
+            .chat(request)
             .await
+        },
+    )
+    .await;
+    let (route, request, llm_response, lease, failed_attempts) = match outcome {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            let entry = LogEntry {
+                request: Some(&request),
+                ..LogEntry::rejected(&felafax_token, e.to_string())
+            };
+            return log_and_respond(&backend_configs, error_status_code(&e), entry).await;
         }
     };
+    let mut metadata = attempts_metadata(&failed_attempts, &retrier, lease.as_ref());
+    if cache_mode.response_cache {
+        metadata = cache_metadata(metadata, "miss");
+    }
 
+    let mut entry = LogEntry {
+        felafax_token: &felafax_token,
+        request: Some(&request),
+        llm_name: Some(&route.llm_name),
+        latency: started.elapsed().as_millis() as u32,
+        metadata,
+        ..Default::default()
+    };
     match llm_response {
         Ok(response) => {
-            log_and_respond(
-                backend_configs.clickhouse.clone(),
-                backend_configs.firebase.clone(),
-                StatusCode::OK,
-                &felafax_token,
-                Some(&request),
-                Some(&response),
-                Some(&customer_config.selected_llm_name),
-                0,
-                None,
-            )
-            .await
+            if cache_mode.response_cache && is_complete(&response) {
+                backend_configs.response_cache.insert(
+                    cache_key(&felafax_token, &route.llm_name, &route.model, &request),
+                    response.clone(),
+                );
+            }
+            if let Some(semantic_entry) = semantic_entry.filter(|_| is_complete(&response)) {
+                let scope = semantic_scope(&route.llm_name, &route.model, &request);
+                backend_configs
+                    .semantic_cache
+                    .insert(semantic_entry, scope, response.clone());
+            }
+            entry.response = Some(&response);
+            let response = log_and_respond(&backend_configs, StatusCode::OK, entry).await;
+            cache_mode.miss(response)
         }
         Err(e) => {
-            log_and_respond(
-                backend_configs.clickhouse.clone(),
-                backend_configs.firebase.clone(),
-                StatusCode::INTERNAL_SERVER_ERROR,
-                &felafax_token,
-                Some(&request),
-                None,
-                Some(&customer_config.selected_llm_name),
-                0,
-                Some(e.to_string()),
-            )
-            .await
+            entry.error = Some(e.to_string());
+            log_and_respond(&backend_configs, error_status_code(&e), entry).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_pool::fingerprint;
+
+    fn customer_config() -> CustomerConfig {
+        serde_json::from_value(json!({
+            "selected_llm_name": "openai",
+            "selected_llm_model": "gpt-4o",
+            "llm_configs": {
+                "openai": {"api_key": "sk-1", "api_keys": [{"key": "sk-2"}]},
+                "claude": {"api_key": "sk-ant-1"},
+                "jamba": {},
+                "mystery": {"provider": "mystery", "api_key": "key"},
+            },
+            "fallbacks": [
+                {"llm_name": "missing"},
+                {"llm_name": "mystery"},
+                {"llm_name": "jamba"},
+                {"llm_name": "claude", "model": "claude-3-5-sonnet-20240620"},
+                {"llm_name": "claude", "model": "claude-3-5-sonnet-20240620"},
+            ],
+        }))
+        .unwrap()
+    }
+
+    fn routes() -> Vec<LlmRoute> {
+        llm_routes(
+            &ProviderRegistry::new(),
+            &customer_config(),
+            "openai".to_string(),
+            "gpt-4o".to_string(),
+        )
+        .unwrap()
+    }
+
+    fn request() -> OaiChatCompletionRequest {
+        serde_json::from_value(
+            json!({"model": "gpt-4o", "messages": [{"role": "user", "content": "Hi"}]}),
+        )
+        .unwrap()
+    }
+
+    fn upstream_error(status: u16) -> anyhow::Error {
+        Error::UpstreamError {
+            status,
+            message: format!("upstream returned {}", status),
         }
+        .into()
+    }
+
+    #[test]
+    fn skips_fallbacks_that_cannot_be_called() {
+        let routes: Vec<(String, String)> = routes()
+            .into_iter()
+            .map(|route| (route.llm_name, route.model))
+            .collect();
+        assert_eq!(
+            routes,
+            vec![
+                ("openai".to_string(), "gpt-4o".to_string()),
+                (
+                    "claude".to_string(),
+                    "claude-3-5-sonnet-20240620".to_string()
+                ),
+            ]
+        );
+
+        let keyless = llm_routes(
+            &ProviderRegistry::new(),
+            &customer_config(),
+            "jamba".to_string(),
+            String::new(),
+        );
+        assert!(keyless.is_err());
+    }
+
+    #[tokio::test]
+    async fn falls_back_on_retryable_errors() {
+        let key_pool = Arc::new(KeyPool::new());
+        let (route, request, result, lease, failed_attempts) = call_with_fallbacks(
+            &key_pool,
+            &Retrier::default(),
+            routes(),
+            &request(),
+            |route, _, _, request| async move {
+                match route.llm_name.as_str() {
+                    "openai" => Err(upstream_error(503)),
+                    _ => Ok(request.model),
+                }
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(route.llm_name, "claude");
+        assert_eq!(request.model, "claude-3-5-sonnet-20240620");
+        assert_eq!(result.unwrap(), "claude-3-5-sonnet-20240620");
+        assert_eq!(lease.unwrap().key(), "sk-ant-1");
+        assert_eq!(failed_attempts.len(), 1);
+        assert_eq!(failed_attempts[0].llm_name, "openai");
+    }
+
+    #[tokio::test]
+    async fn tries_the_next_key_before_falling_back() {
+        let key_pool = Arc::new(KeyPool::new());
+        let (route, _, result, lease, failed_attempts) = call_with_fallbacks(
+            &key_pool,
+            &Retrier::default(),
+            routes(),
+            &request(),
+            |_, api_key, _, _| async move {
+                match api_key.as_str() {
+                    "sk-1" => Err(upstream_error(429)),
+                    _ => Ok(api_key),
+                }
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(route.llm_name, "openai");
+        assert_eq!(result.unwrap(), "sk-2");
+        assert_eq!(lease.unwrap().key(), "sk-2");
+        assert_eq!(failed_attempts[0].key_fingerprint, fingerprint("sk-1"));
+    }
+
+    #[tokio::test]
+    async fn stops_at_errors_that_are_not_retryable() {
+        let key_pool = Arc::new(KeyPool::new());
+        let (route, _, result, _, failed_attempts) = call_with_fallbacks(
+            &key_pool,
+            &Retrier::default(),
+            routes(),
+            &request(),
+            |_, _, _, _| async move { Err::<(), _>(upstream_error(400)) },
+        )
+        .await
+        .unwrap();
+        assert_eq!(route.llm_name, "openai");
+        assert!(result.is_err());
+        assert!(failed_attempts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn fails_without_routes() {
+        let outcome = call_with_fallbacks(
+            &Arc::new(KeyPool::new()),
+            &Retrier::default(),
+            vec![],
+            &request(),
+            |_, _, _, _| async move { Ok(()) },
+        )
+        .await;
+        let error = outcome.err().unwrap();
+        assert_eq!(error_status_code(&error), StatusCode::BAD_REQUEST);
     }
 }
@@ -0,0 +1,94 @@
+use anyhow::Result;
+use std::time::Duration;
+
+/// Settings of the HTTP client shared by every upstream call. One client is
+/// built at startup so connections and TLS sessions are reused across
+/// requests.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpClientConfig {
+    /// Idle connections kept open per upstream host.
+    pub pool_max_idle_per_host: usize,
+    /// How long an idle connection is kept open.
+    pub pool_idle_timeout_secs: u64,
+    /// TCP keep-alive interval, 0 disables it.
+    pub tcp_keepalive_secs: u64,
+    /// Time allowed to connect to an upstream, 0 waits indefinitely.
+    pub connect_timeout_secs: u64,
+    /// Speak HTTP/2 without negotiating it. HTTPS upstreams negotiate HTTP/2
+    /// anyway, this is for plain-text upstreams that only speak HTTP/2.
+    pub http2_prior_knowledge: bool,
+    /// Interval of HTTP/2 pings keeping connections alive, 0 disables them.
+    pub http2_keep_alive_interval_secs: u64,
+    /// Proxy for all upstream traffic, e.g. `http://proxy.internal:3128`.
+    /// Without one, `HTTP_PROXY` and `HTTPS_PROXY` are honoured.
+    pub proxy: Option<String>,
+}
+
+impl Default for HttpClientConfig {
+    fn default() -> Self {
+        Self {
+            pool_max_idle_per_host: 32,
+            pool_idle_timeout_secs: 90,
+            tcp_keepalive_secs: 60,
+            connect_timeout_secs: 10,
+            http2_prior_knowledge: false,
+            http2_keep_alive_interval_secs: 30,
+            proxy: None,
+        }
+    }
+}
+
+impl HttpClientConfig {
+    /// Reads `HTTP_POOL_MAX_IDLE_PER_HOST`, `HTTP_POOL_IDLE_TIMEOUT_SECS`,
+    /// `HTTP_TCP_KEEPALIVE_SECS`, `HTTP_CONNECT_TIMEOUT_SECS`,
+    /// `HTTP2_PRIOR_KNOWLEDGE`, `HTTP2_KEEP_ALIVE_INTERVAL_SECS` and
+    /// `UPSTREAM_PROXY_URL`, falling back to the defaults.
+    pub fn from_env() -> Self {
+        fn var<T: std::str::FromStr>(name: &str) -> Option<T> {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+        }
+
+        let default = Self::default();
+        Self {
+            pool_max_idle_per_host: var("HTTP_POOL_MAX_IDLE_PER_HOST")
+                .unwrap_or(default.pool_max_idle_per_host),
+            pool_idle_timeout_secs: var("HTTP_POOL_IDLE_TIMEOUT_SECS")
+                .unwrap_or(default.pool_idle_timeout_secs),
+            tcp_keepalive_secs: var("HTTP_TCP_KEEPALIVE_SECS")
+                .unwrap_or(default.tcp_keepalive_secs),
+            connect_timeout_secs: var("HTTP_CONNECT_TIMEOUT_SECS")
+                .unwrap_or(default.connect_timeout_secs),
+            http2_prior_knowledge: var("HTTP2_PRIOR_KNOWLEDGE")
+                .unwrap_or(default.http2_prior_knowledge),
+            http2_keep_alive_interval_secs: var("HTTP2_KEEP_ALIVE_INTERVAL_SECS")
+                .unwrap_or(default.http2_keep_alive_interval_secs),
+            proxy: std::env::var("UPSTREAM_PROXY_URL")
+                .ok()
+                .filter(|proxy| !proxy.is_empty()),
+        }
+    }
+
+    pub fn build(&self) -> Result<reqwest::Client> {
+        let seconds = |secs: u64| Some(secs).filter(|&secs| secs > 0).map(Duration::from_secs);
+
+        let mut builder = reqwest::Client::builder()
+            .pool_max_idle_per_host(self.pool_max_idle_per_host)
+            .pool_idle_timeout(seconds(self.pool_idle_timeout_secs))
+            .tcp_keepalive(seconds(self.tcp_keepalive_secs))
+            .http2_keep_alive_interval(seconds(self.http2_keep_alive_interval_secs))
+            .http2_keep_alive_while_idle(true)
+            .http2_adaptive_window(true);
+        if let Some(timeout) = seconds(self.connect_timeout_secs) {
+            builder = builder.connect_timeout(timeout);
+        }
+        if self.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+        }
+        Ok(builder.build()?)
+    }
+}
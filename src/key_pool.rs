@@ -0,0 +1,303 @@
+use crate::firestore::{KeySelection, WeightedApiKey};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a key that was rate limited or rejected stays out of rotation.
+pub const KEY_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// How long a key goes unused before its state is dropped.
+const KEY_IDLE_TTL: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Default)]
+struct KeyState {
+    /// Smooth weighted round-robin counter.
+    current_weight: i64,
+    in_flight: u32,
+    cooldown_until: Option<Instant>,
+    last_used: Option<Instant>,
+}
+
+impl KeyState {
+    fn is_idle(&self, now: Instant) -> bool {
+        self.in_flight == 0
+            && self.cooldown_until.is_none_or(|until| until <= now)
+            && self
+                .last_used
+                .is_none_or(|last_used| now.duration_since(last_used) >= KEY_IDLE_TTL)
+    }
+}
+
+#[derive(Debug, Default)]
+struct KeyStates {
+    /// Keys by the endpoint they are sent to.
+    endpoints: HashMap<String, HashMap<String, KeyState>>,
+    last_evicted: Option<Instant>,
+}
+
+impl KeyStates {
+    /// Drops keys that are idle and not cooling down, at most once per
+    /// [`KEY_COOLDOWN`], so keys that are no longer configured don't pile up.
+    fn evict_idle(&mut self, now: Instant) {
+        if self
+            .last_evicted
+            .is_some_and(|last_evicted| now.duration_since(last_evicted) < KEY_COOLDOWN)
+        {
+            return;
+        }
+        self.last_evicted = Some(now);
+        self.endpoints.retain(|_, keys| {
+            keys.retain(|_, state| !state.is_idle(now));
+            !keys.is_empty()
+        });
+    }
+
+    fn get_mut(&mut self, endpoint: &str, key: &str) -> Option<&mut KeyState> {
+        self.endpoints.get_mut(endpoint)?.get_mut(key)
+    }
+}
+
+fn state<'a>(keys: &'a mut HashMap<String, KeyState>, key: &str, now: Instant) -> &'a mut KeyState {
+    let state = keys.entry(key.to_string()).or_default();
+    state.last_used = Some(now);
+    state
+}
+
+/// Balances requests across the API keys customers configure for a provider.
+/// State is kept per endpoint and key, so it is shared by every customer
+/// sending that key to the same endpoint, but a keyless local server isn't
+/// confused with another customer's.
+#[derive(Debug, Default)]
+pub struct KeyPool {
+    keys: Mutex<KeyStates>,
+}
+
+impl KeyPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Picks a key for `endpoint` that is not cooling down, skipping
+    /// `exclude`. When every remaining key is cooling down, the one that
+    /// recovers first is used.
+    pub fn select(
+        self: &Arc<Self>,
+        endpoint: &str,
+        keys: &[WeightedApiKey],
+        selection: KeySelection,
+        exclude: &[String],
+    ) -> Option<KeyLease> {
+        let mut pool = self.keys.lock().unwrap();
+        let now = Instant::now();
+        pool.evict_idle(now);
+        let states = pool.endpoints.entry(endpoint.to_string()).or_default();
+
+        let candidates: Vec<&WeightedApiKey> = keys
+            .iter()
+            .filter(|key| key.weight > 0 && !exclude.contains(&key.key))
+            .collect();
+        let available: Vec<&WeightedApiKey> = candidates
+            .iter()
+            .copied()
+            .filter(|key| {
+                states
+                    .get(&key.key)
+                    .and_then(|state| state.cooldown_until)
+                    .is_none_or(|until| until <= now)
+            })
+            .collect();
+
+        let key = if available.is_empty() {
+            candidates
+                .into_iter()
+                .min_by_key(|key| states.get(&key.key).and_then(|state| state.cooldown_until))?
+        } else {
+            match selection {
+                KeySelection::WeightedRoundRobin => {
+                    let total: i64 = available.iter().map(|key| key.weight as i64).sum();
+                    let mut selected = available[0];
+                    let mut selected_weight = i64::MIN;
+                    for key in &available {
+                        let state = state(states, &key.key, now);
+                        state.current_weight += key.weight as i64;
+                        if state.current_weight > selected_weight {
+                            selected = key;
+                            selected_weight = state.current_weight;
+                        }
+                    }
+                    state(states, &selected.key, now).current_weight -= total;
+                    selected
+                }
+                KeySelection::LeastLoaded => available.iter().copied().min_by(|a, b| {
+                    let load = |key: &WeightedApiKey| {
+                        states.get(&key.key).map_or(0, |state| state.in_flight) as f64
+                            / key.weight as f64
+                    };
+                    load(a).total_cmp(&load(b))
+                })?,
+            }
+        };
+
+        state(states, &key.key, now).in_flight += 1;
+        Some(KeyLease {
+            pool: self.clone(),
+            endpoint: endpoint.to_string(),
+            key: key.key.clone(),
+        })
+    }
+
+    /// Takes a key out of rotation for [`KEY_COOLDOWN`].
+    pub fn cool_down(&self, endpoint: &str, key: &str) {
+        tracing::warn!(
+            endpoint,
+            key_fingerprint = fingerprint(key).as_str(),
+            cooldown_secs = KEY_COOLDOWN.as_secs(),
+            "Cooling down API key"
+        );
+        let now = Instant::now();
+        let mut pool = self.keys.lock().unwrap();
+        let keys = pool.endpoints.entry(endpoint.to_string()).or_default();
+        state(keys, key, now).cooldown_until = Some(now + KEY_COOLDOWN);
+    }
+}
+
+/// A key picked for a request. It counts as in flight until dropped.
+#[derive(Debug)]
+pub struct KeyLease {
+    pool: Arc<KeyPool>,
+    endpoint: String,
+    key: String,
+}
+
+impl KeyLease {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.key)
+    }
+
+    pub fn cool_down(&self) {
+        self.pool.cool_down(&self.endpoint, &self.key);
+    }
+}
+
+impl Drop for KeyLease {
+    fn drop(&mut self) {
+        if let Some(state) = self
+            .pool
+            .keys
+            .lock()
+            .unwrap()
+            .get_mut(&self.endpoint, &self.key)
+        {
+            state.in_flight = state.in_flight.saturating_sub(1);
+        }
+    }
+}
+
+/// Identifies a key in logs without revealing it, e.g. `sk-...wxyz`.
+pub fn fingerprint(key: &str) -> String {
+    let chars: Vec<char> = key.chars().collect();
+    if chars.len() < 12 {
+        return "...".to_string();
+    }
+    let prefix: String = chars[..3].iter().collect();
+    let suffix: String = chars[chars.len() - 4..].iter().collect();
+    format!("{}...{}", prefix, suffix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(weights: &[(&str, u32)]) -> Vec<WeightedApiKey> {
+        weights
+            .iter()
+            .map(|(key, weight)| WeightedApiKey {
+                key: key.to_string(),
+                weight: *weight,
+            })
+            .collect()
+    }
+
+    fn pick(pool: &Arc<KeyPool>, keys: &[WeightedApiKey], selection: KeySelection) -> String {
+        let lease = pool.select("openai", keys, selection, &[]).unwrap();
+        lease.key().to_string()
+    }
+
+    #[test]
+    fn spreads_requests_by_weight() {
+        let pool = Arc::new(KeyPool::new());
+        let keys = keys(&[("a", 2), ("b", 1), ("unused", 0)]);
+        let picks: Vec<String> = (0..6)
+            .map(|_| pick(&pool, &keys, KeySelection::WeightedRoundRobin))
+            .collect();
+        // smooth round-robin interleaves rather than sending bursts
+        assert_eq!(picks, ["a", "b", "a", "a", "b", "a"]);
+    }
+
+    #[test]
+    fn picks_the_least_loaded_key() {
+        let pool = Arc::new(KeyPool::new());
+        let keys = keys(&[("a", 1), ("b", 1)]);
+        let first = pool
+            .select("openai", &keys, KeySelection::LeastLoaded, &[])
+            .unwrap();
+        let second = pool
+            .select("openai", &keys, KeySelection::LeastLoaded, &[])
+            .unwrap();
+        assert_ne!(first.key(), second.key());
+
+        // a finished request no longer counts
+        let freed = first.key().to_string();
+        drop(first);
+        assert_eq!(pick(&pool, &keys, KeySelection::LeastLoaded), freed);
+    }
+
+    #[test]
+    fn skips_keys_that_are_cooling_down() {
+        let pool = Arc::new(KeyPool::new());
+        let keys = keys(&[("a", 5), ("b", 1)]);
+        pool.cool_down("openai", "a");
+        for _ in 0..3 {
+            assert_eq!(pick(&pool, &keys, KeySelection::WeightedRoundRobin), "b");
+        }
+
+        // cooldowns are per endpoint
+        let lease = pool
+            .select("azure", &keys, KeySelection::WeightedRoundRobin, &[])
+            .unwrap();
+        assert_eq!(lease.key(), "a");
+
+        // with every key cooling down, the one that recovers first is used
+        pool.cool_down("openai", "b");
+        assert_eq!(pick(&pool, &keys, KeySelection::WeightedRoundRobin), "a");
+    }
+
+    #[test]
+    fn skips_excluded_keys() {
+        let pool = Arc::new(KeyPool::new());
+        let keys = keys(&[("a", 1), ("b", 1)]);
+        let exclude = ["a".to_string(), "b".to_string()];
+        let lease = pool
+            .select(
+                "openai",
+                &keys,
+                KeySelection::WeightedRoundRobin,
+                &exclude[..1],
+            )
+            .unwrap();
+        assert_eq!(lease.key(), "b");
+        assert!(pool
+            .select("openai", &keys, KeySelection::WeightedRoundRobin, &exclude)
+            .is_none());
+    }
+
+    #[test]
+    fn fingerprints_hide_the_key() {
+        assert_eq!(fingerprint("sk-proj-abcdefghwxyz"), "sk-...wxyz");
+        assert_eq!(fingerprint("short"), "...");
+    }
+}
@@ -4,12 +4,19 @@
 #![allow(async_fn_in_trait)]
 #![allow(deprecated)]
 
+pub mod cache;
 pub mod clickhouse;
 pub mod client;
+pub mod config_store;
 pub mod error;
 pub mod firestore;
+pub mod google_auth;
 pub mod handlers;
+pub mod http;
+pub mod key_pool;
 pub mod request_logs;
+pub mod retry;
+pub mod semantic_cache;
 pub mod types;
 pub mod utils;
 
@@ -22,8 +29,19 @@ use std::sync::Arc;
 
 #[derive(Clone)]
 pub struct BackendConfigs {
-    firebase: Arc<firestore::Firestore>,
-    clickhouse: Arc<clickhouse::Clickhouse>,
+    config_store: Arc<dyn config_store::ConfigStore>,
+    /// Set when the `CLICKHOUSE_*` variables are.
+    clickhouse: Option<Arc<clickhouse::Clickhouse>>,
+    model_registry: Arc<client::ModelRegistry>,
+    /// `/translate/v1/models` lists, per token and config.
+    customer_models: Arc<client::CustomerModelsCache>,
+    providers: Arc<client::ProviderRegistry>,
+    retry_policy: retry::RetryPolicy,
+    key_pool: Arc<key_pool::KeyPool>,
+    response_cache: Arc<cache::ResponseCache>,
+    semantic_cache: Arc<semantic_cache::SemanticCache>,
+    google_tokens: Arc<google_auth::GoogleTokenCache>,
+    http_client: reqwest::Client,
 }
 
 async fn hello() -> &'static str {
@@ -35,14 +53,53 @@ pub async fn translate_chat_completion(
     State(backend_configs): State<Arc<BackendConfigs>>,
     Json(payload): Json<Value>,
 ) -> impl IntoResponse {
-    let result = handlers::translate::chat_completion(headers, backend_configs, payload).await;
-    if result.is_ok() {
-        let (status_code, value) = result.unwrap();
-        let response = Json(value);
-        (status_code, response)
-    } else {
-        let status_code = StatusCode::INTERNAL_SERVER_ERROR;
-        (status_code, Json(json!("Internal server error")))
+    match handlers::translate::chat_completion(headers, backend_configs, payload).await {
+        Ok(response) => response,
+        Err(_) => {
+            let status_code = StatusCode::INTERNAL_SERVER_ERROR;
+            (status_code, Json(json!("Internal server error"))).into_response()
+        }
+    }
+}
+
+pub async fn translate_completions(
+    headers: HeaderMap,
+    State(backend_configs): State<Arc<BackendConfigs>>,
+    Json(payload): Json<Value>,
+) -> impl IntoResponse {
+    match handlers::completions::completions(headers, backend_configs, payload).await {
+        Ok(response) => response,
+        Err(_) => {
+            let status_code = StatusCode::INTERNAL_SERVER_ERROR;
+            (status_code, Json(json!("Internal server error"))).into_response()
+        }
+    }
+}
+
+pub async fn translate_embeddings(
+    headers: HeaderMap,
+    State(backend_configs): State<Arc<BackendConfigs>>,
+    Json(payload): Json<Value>,
+) -> impl IntoResponse {
+    match handlers::embeddings::embeddings(headers, backend_configs, payload).await {
+        Ok(response) => response,
+        Err(_) => {
+            let status_code = StatusCode::INTERNAL_SERVER_ERROR;
+            (status_code, Json(json!("Internal server error"))).into_response()
+        }
+    }
+}
+
+pub async fn translate_models(
+    headers: HeaderMap,
+    State(backend_configs): State<Arc<BackendConfigs>>,
+) -> impl IntoResponse {
+    match handlers::models::list_models(headers, backend_configs).await {
+        Ok(response) => response,
+        Err(_) => {
+            let status_code = StatusCode::INTERNAL_SERVER_ERROR;
+            (status_code, Json(json!("Internal server error"))).into_response()
+        }
     }
 }
 
@@ -69,50 +126,80 @@ pub async fn proxy(
     }
 }
 
-#[tokio::main]
-async fn main() {
-    // Load environment variables
-    dotenv::dotenv().ok();
+/// Configs come from `CONFIG_FILE` when set, otherwise from Firestore when
+/// its credentials are. Without either, only proxy mode works.
+async fn init_config_store() -> Arc<dyn config_store::ConfigStore> {
+    if let Ok(config_file) = std::env::var("CONFIG_FILE") {
+        let store = config_store::FileConfigStore::open(&config_file)
+            .unwrap_or_else(|e| panic!("Failed to load {}: {:?}", config_file, e));
+        println!("Using configs from {}", config_file);
+        return store;
+    }
 
     // Firebase init
-    let firebase_key = std::env::var("FIREBASE_SERVICE_ACCOUNT_KEY").unwrap_or_else(|_| {
-        std::env::var("GOOGLE_APPLICATION_CREDENTIALS").unwrap_or_else(|_| {
-            panic!("Error: FIREBASE_SERVICE_ACCOUNT_KEY or GOOGLE_APPLICATION_CREDENTIALS not found in environment.")
-        })
-    });
-
-    let firebase = firestore::Firestore::new(
-        &std::env::var("FIREBASE_PROJECT_ID")
-            .expect("Error: FIREBASE_PROJECT_ID not found in environment."),
-        &firebase_key,
-    );
+    let firebase_key = std::env::var("FIREBASE_SERVICE_ACCOUNT_KEY")
+        .or_else(|_| std::env::var("GOOGLE_APPLICATION_CREDENTIALS"));
+    let (Ok(firebase_key), Ok(project_id)) = (firebase_key, std::env::var("FIREBASE_PROJECT_ID"))
+    else {
+        eprintln!(
+            "Warning: neither CONFIG_FILE nor FIREBASE_PROJECT_ID and FIREBASE_SERVICE_ACCOUNT_KEY are set, translate requests will be rejected."
+        );
+        return Arc::new(config_store::FileConfigStore::default());
+    };
+
+    let firebase = firestore::Firestore::new(&project_id, &firebase_key);
     firebase
         .init()
         .await
         .unwrap_or_else(|e| panic!("Failed to initialise firestore: {:?}", e));
+    Arc::new(firebase)
+}
+
+/// ClickHouse is optional, the gateway runs without it unless every
+/// `CLICKHOUSE_*` variable is set.
+fn init_clickhouse() -> Option<Arc<clickhouse::Clickhouse>> {
+    let (Ok(url), Ok(username), Ok(password), Ok(database)) = (
+        std::env::var("CLICKHOUSE_URL"),
+        std::env::var("CLICKHOUSE_USERNAME"),
+        std::env::var("CLICKHOUSE_PASSWORD"),
+        std::env::var("CLICKHOUSE_DATABASE"),
+    ) else {
+        eprintln!(
+            "Warning: CLICKHOUSE_URL, CLICKHOUSE_USERNAME, CLICKHOUSE_PASSWORD and CLICKHOUSE_DATABASE are not all set, running without ClickHouse."
+        );
+        return None;
+    };
+    Some(Arc::new(clickhouse::Clickhouse::new(
+        &url, &username, &password, &database,
+    )))
+}
+
+#[tokio::main]
+async fn main() {
+    // Load environment variables
+    dotenv::dotenv().ok();
+
+    let config_store = init_config_store().await;
+
+    let clickhouse_client = init_clickhouse();
 
-    let firebase = Arc::new(firebase);
-
-    // Init clickhouse
-    let click_house_url =
-        std::env::var("CLICKHOUSE_URL").expect("Error: CLICKHOUSE_URL not found in environment.");
-    let clickhouse_username = std::env::var("CLICKHOUSE_USERNAME")
-        .expect("Error: CLICKHOUSE_USERNAME not found in environment.");
-    let clickhouse_password = std::env::var("CLICKHOUSE_PASSWORD")
-        .expect("Error: CLICKHOUSE_PASSWORD not found in environment.");
-    let clickhouse_database = std::env::var("CLICKHOUSE_DATABASE")
-        .expect("Error: CLICKHOUSE_DATABASE not found in environment.");
-
-    let clickhouse_client = Arc::new(clickhouse::Clickhouse::new(
-        &click_house_url,
-        &clickhouse_username,
-        &clickhouse_password,
-        &clickhouse_database,
-    ));
+    // One pooled client for every upstream call
+    let http_client = http::HttpClientConfig::from_env()
+        .build()
+        .unwrap_or_else(|e| panic!("Failed to build the HTTP client: {:?}", e));
 
     let backend_configs = BackendConfigs {
-        firebase,
+        config_store,
         clickhouse: clickhouse_client,
+        model_registry: Arc::new(client::ModelRegistry::new(&http_client)),
+        customer_models: Arc::new(client::CustomerModelsCache::default()),
+        providers: Arc::new(client::ProviderRegistry::new()),
+        retry_policy: retry::RetryPolicy::from_env(),
+        key_pool: Arc::new(key_pool::KeyPool::new()),
+        response_cache: Arc::new(cache::ResponseCache::from_env()),
+        semantic_cache: Arc::new(semantic_cache::SemanticCache::new()),
+        google_tokens: Arc::new(google_auth::GoogleTokenCache::new(http_client.clone())),
+        http_client,
     };
     let backend_configs = Arc::new(backend_configs);
 
@@ -122,6 +209,9 @@ async fn main() {
             "/translate/v1/chat/completions",
             post(translate_chat_completion),
         )
+        .route("/translate/v1/completions", post(translate_completions))
+        .route("/translate/v1/embeddings", post(translate_embeddings))
+        .route("/translate/v1/models", get(translate_models))
         .fallback(any(proxy))
         .with_state(backend_configs);
 
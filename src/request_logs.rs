@@ -1,9 +1,13 @@
 use crate::clickhouse as cl;
-use crate::firestore;
+use crate::config_store::ConfigStore;
+use crate::types::{
+    OaiChatCompletionRequest, OaiChatCompletionResponse, OaiEmbeddingRequest, OaiEmbeddingResponse,
+};
 use anyhow::Result;
 use clickhouse::Row;
 use derive_builder::Builder;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::collections::HashMap;
 use std::sync::Arc;
 
@@ -55,8 +59,8 @@ pub struct RequestLog {
 impl RequestLog {
     pub async fn log(
         &self,
-        client: &Arc<cl::Clickhouse>,
-        firestore: &Arc<firestore::Firestore>,
+        client: Option<&Arc<cl::Clickhouse>>,
+        config_store: &Arc<dyn ConfigStore>,
     ) -> Result<()> {
         println!("Logging request: {:?}", self);
         // TODO: move ot clickhouse or postgres
@@ -66,10 +70,67 @@ impl RequestLog {
             eprintln!("Customer ID is empty, skipping logging");
         }
 
-        firestore
+        config_store
             .insert_request_log(&self.clone())
             .await
             .unwrap_or_else(|e| eprintln!("Failed to log request: {:?}", e));
         Ok(())
     }
 }
+
+/// Request and response bodies, as returned to callers and recorded in logs.
+pub trait Loggable: Send + Sync {
+    fn to_json(&self) -> Result<Value>;
+
+    /// Records the body as the response of `request_log`.
+    fn log_response(&self, request_log: &mut RequestLogBuilder) -> Result<()> {
+        request_log.response(self.to_json()?.to_string());
+        Ok(())
+    }
+}
+
+impl Loggable for OaiChatCompletionRequest {
+    fn to_json(&self) -> Result<Value> {
+        Ok(serde_json::to_value(self)?)
+    }
+}
+
+impl Loggable for OaiChatCompletionResponse {
+    fn to_json(&self) -> Result<Value> {
+        Ok(serde_json::to_value(self)?)
+    }
+
+    fn log_response(&self, request_log: &mut RequestLogBuilder) -> Result<()> {
+        request_log
+            .response(serde_json::to_string(self)?)
+            .llm_model(serde_json::to_string(&self.model)?);
+        if let Some(usage) = &self.usage {
+            request_log
+                .prompt_tokens(usage.prompt_tokens)
+                .completion_tokens(usage.completion_tokens)
+                .total_tokens(usage.total_tokens);
+        }
+        Ok(())
+    }
+}
+
+impl Loggable for OaiEmbeddingRequest {
+    fn to_json(&self) -> Result<Value> {
+        Ok(serde_json::to_value(self)?)
+    }
+}
+
+impl Loggable for OaiEmbeddingResponse {
+    fn to_json(&self) -> Result<Value> {
+        Ok(serde_json::to_value(self)?)
+    }
+
+    // vectors are too big to keep, only log what was billed
+    fn log_response(&self, request_log: &mut RequestLogBuilder) -> Result<()> {
+        request_log
+            .llm_model(self.model.clone())
+            .prompt_tokens(self.usage.prompt_tokens)
+            .total_tokens(self.usage.total_tokens);
+        Ok(())
+    }
+}
@@ -0,0 +1,310 @@
+use crate::error::{is_key_error, is_key_status, is_retryable, is_retryable_status};
+use anyhow::Result;
+use rand::Rng;
+use reqwest::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How upstream calls are retried on 429s, 5xx responses, timeouts and
+/// connection errors.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Total attempts, including the first one.
+    pub max_attempts: u32,
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+    /// Fraction of the delay that is randomised, between 0.0 and 1.0.
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay_ms: 500,
+            max_delay_ms: 10_000,
+            jitter: 0.2,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Reads `RETRY_MAX_ATTEMPTS`, `RETRY_BASE_DELAY_MS`, `RETRY_MAX_DELAY_MS`
+    /// and `RETRY_JITTER`, falling back to the defaults.
+    pub fn from_env() -> Self {
+        fn var<T: std::str::FromStr>(name: &str) -> Option<T> {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+        }
+
+        let default = Self::default();
+        Self {
+            max_attempts: var("RETRY_MAX_ATTEMPTS").unwrap_or(default.max_attempts),
+            base_delay_ms: var("RETRY_BASE_DELAY_MS").unwrap_or(default.base_delay_ms),
+            max_delay_ms: var("RETRY_MAX_DELAY_MS").unwrap_or(default.max_delay_ms),
+            jitter: var("RETRY_JITTER").unwrap_or(default.jitter),
+        }
+    }
+
+    /// Exponential backoff for the given attempt (starting at 1) with jitter.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exponential = self
+            .base_delay_ms
+            .saturating_mul(2u64.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay_ms) as f64;
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let factor = 1.0 + rand::thread_rng().gen_range(-jitter..=jitter);
+        Duration::from_millis((exponential * factor) as u64)
+    }
+}
+
+/// Delay requested by the upstream through `retry-after-ms` or `retry-after`
+/// (seconds or an HTTP date).
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+
+    if let Some(millis) = header("retry-after-ms").and_then(|value| value.parse::<f64>().ok()) {
+        return Some(Duration::from_millis(millis.max(0.0) as u64));
+    }
+    let value = header("retry-after")?;
+    if let Ok(seconds) = value.parse::<f64>() {
+        return Some(Duration::from_millis((seconds.max(0.0) * 1000.0) as u64));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (date.with_timezone(&chrono::Utc) - chrono::Utc::now())
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+/// Turns off async-openai's own retries, which would otherwise retry 429s
+/// for up to 15 minutes regardless of the [`RetryPolicy`].
+pub fn no_backoff() -> backoff::ExponentialBackoff {
+    backoff::ExponentialBackoff {
+        max_elapsed_time: Some(Duration::ZERO),
+        ..Default::default()
+    }
+}
+
+/// Runs upstream requests under a [`RetryPolicy`]. Clones share the attempt
+/// counter, so one retrier can count every upstream call of a request.
+#[derive(Debug, Clone, Default)]
+pub struct Retrier {
+    policy: RetryPolicy,
+    attempts: Arc<AtomicU32>,
+    /// Whether another key can be tried, so key errors aren't retried.
+    rotate_keys: bool,
+}
+
+impl Retrier {
+    pub fn new(policy: RetryPolicy) -> Self {
+        Self {
+            policy,
+            attempts: Arc::new(AtomicU32::new(0)),
+            rotate_keys: false,
+        }
+    }
+
+    /// Returns 401, 403 and 429 responses straight away, so the key pool can
+    /// move on to another key instead of retrying a rate limited one.
+    pub fn with_key_rotation(mut self, rotate_keys: bool) -> Self {
+        self.rotate_keys = rotate_keys;
+        self
+    }
+
+    /// Number of upstream requests sent so far.
+    pub fn attempts(&self) -> u32 {
+        self.attempts.load(Ordering::Relaxed)
+    }
+
+    /// Sends a request until it gets a response that is not retryable or the
+    /// attempts run out. Only the response head is awaited, so a stream that
+    /// has started is never retried.
+    pub async fn execute<F, Fut, E>(&self, send: F) -> Result<reqwest::Response>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = std::result::Result<reqwest::Response, E>>,
+        E: Into<anyhow::Error>,
+    {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            self.attempts.fetch_add(1, Ordering::Relaxed);
+            let result = send().await.map_err(Into::into);
+            if attempt >= self.policy.max_attempts {
+                return result;
+            }
+
+            let delay = match &result {
+                Ok(response) if self.rotate_keys && is_key_status(response.status().as_u16()) => {
+                    return result
+                }
+                Ok(response) if is_retryable_status(response.status().as_u16()) => {
+                    match retry_after(response.headers()) {
+                        // don't hold the request for longer than we'd ever back off
+                        Some(delay) if delay > Duration::from_millis(self.policy.max_delay_ms) => {
+                            return result
+                        }
+                        Some(delay) => delay,
+                        None => self.policy.backoff(attempt),
+                    }
+                }
+                Err(e)
+                    if e.downcast_ref::<reqwest::Error>()
+                        .is_some_and(|e| e.is_timeout() || e.is_connect()) =>
+                {
+                    self.policy.backoff(attempt)
+                }
+                _ => return result,
+            };
+
+            let (upstream, status) = match &result {
+                Ok(response) => (response.url().host_str(), Some(response.status().as_u16())),
+                Err(e) => (
+                    e.downcast_ref::<reqwest::Error>()
+                        .and_then(|e| e.url())
+                        .and_then(|url| url.host_str()),
+                    None,
+                ),
+            };
+            tracing::warn!(
+                upstream,
+                attempt,
+                status,
+                delay_ms = delay.as_millis() as u64,
+                "Upstream attempt failed, retrying"
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Like [`Retrier::execute`] for calls that only report errors, such as
+    /// the OpenAI client's. Response headers aren't visible, so `retry-after`
+    /// isn't honoured.
+    pub async fn retry<F, Fut, T>(&self, call: F) -> Result<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            self.attempts.fetch_add(1, Ordering::Relaxed);
+            let result = call().await;
+            match &result {
+                Err(e) if self.rotate_keys && is_key_error(e) => return result,
+                Err(e) if attempt < self.policy.max_attempts && is_retryable(e) => {}
+                _ => return result,
+            }
+
+            let delay = self.policy.backoff(attempt);
+            if let Err(e) = &result {
+                tracing::warn!(
+                    attempt,
+                    delay_ms = delay.as_millis() as u64,
+                    error = %e,
+                    "Upstream attempt failed, retrying"
+                );
+            }
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use reqwest::header::HeaderValue;
+
+    fn headers(name: &'static str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay_ms: 1,
+            max_delay_ms: 4,
+            jitter: 0.0,
+        }
+    }
+
+    #[test]
+    fn reads_retry_after_headers() {
+        let millis = headers("retry-after-ms", "1500");
+        assert_eq!(retry_after(&millis), Some(Duration::from_millis(1500)));
+
+        let seconds = headers("retry-after", "2");
+        assert_eq!(retry_after(&seconds), Some(Duration::from_secs(2)));
+
+        let date = chrono::Utc::now() + chrono::Duration::seconds(30);
+        let date = headers("retry-after", &date.to_rfc2822());
+        let delay = retry_after(&date).unwrap();
+        assert!(delay > Duration::from_secs(25) && delay <= Duration::from_secs(30));
+
+        let past = headers("retry-after", "Wed, 21 Oct 2015 07:28:00 GMT");
+        assert_eq!(retry_after(&past), Some(Duration::ZERO));
+
+        assert_eq!(retry_after(&headers("retry-after", "soon")), None);
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_the_cap() {
+        let policy = policy();
+        let delays: Vec<Duration> = (1..=4).map(|attempt| policy.backoff(attempt)).collect();
+        assert_eq!(delays, [1, 2, 4, 4].map(Duration::from_millis).to_vec());
+
+        let jittered = RetryPolicy {
+            base_delay_ms: 1000,
+            max_delay_ms: 1000,
+            jitter: 0.2,
+            ..policy
+        };
+        for _ in 0..20 {
+            let delay = jittered.backoff(1);
+            assert!(delay >= Duration::from_millis(800) && delay <= Duration::from_millis(1200));
+        }
+    }
+
+    async fn attempts_until_done(retrier: &Retrier, status: u16) -> u32 {
+        let calls = AtomicU32::new(0);
+        let _ = retrier
+            .retry(|| async {
+                calls.fetch_add(1, Ordering::Relaxed);
+                Err::<(), _>(anyhow::Error::from(Error::UpstreamError {
+                    status,
+                    message: String::new(),
+                }))
+            })
+            .await;
+        calls.load(Ordering::Relaxed)
+    }
+
+    #[tokio::test]
+    async fn retries_retryable_errors_until_attempts_run_out() {
+        let retrier = Retrier::new(policy());
+        assert_eq!(attempts_until_done(&retrier, 503).await, 3);
+        assert_eq!(retrier.attempts(), 3);
+        assert_eq!(attempts_until_done(&Retrier::new(policy()), 400).await, 1);
+    }
+
+    #[tokio::test]
+    async fn leaves_key_errors_to_the_key_pool() {
+        let rotating = Retrier::new(policy()).with_key_rotation(true);
+        assert_eq!(attempts_until_done(&rotating, 429).await, 1);
+
+        // with no other key to move on to, a rate limit is waited out
+        let last_key = Retrier::new(policy()).with_key_rotation(false);
+        assert_eq!(attempts_until_done(&last_key, 429).await, 3);
+    }
+}
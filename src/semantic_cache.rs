@@ -0,0 +1,233 @@
+use crate::cache::cache_key;
+use crate::types::{OaiChatCompletionRequest, OaiChatCompletionResponse, OaiMessageContent};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Response header reporting how similar a served answer's prompt was.
+pub const SIMILARITY_HEADER: &str = "x-felafax-cache-similarity";
+
+const MAX_ENTRIES_PER_CUSTOMER: usize = 1000;
+
+struct Entry {
+    scope: String,
+    embedding: Vec<f32>,
+    response: OaiChatCompletionResponse,
+    expires_at: Instant,
+}
+
+/// The embedding of a request's last user message, kept until the response
+/// to it can be cached.
+#[derive(Debug, Clone)]
+pub struct PendingEntry {
+    customer_id: String,
+    embedding: Vec<f32>,
+    ttl: Duration,
+}
+
+impl PendingEntry {
+    pub fn new(customer_id: &str, embedding: Vec<f32>, ttl: Duration) -> Self {
+        Self {
+            customer_id: customer_id.to_string(),
+            embedding: normalize(embedding),
+            ttl,
+        }
+    }
+}
+
+/// In-process nearest-neighbour index of responses, one per customer.
+#[derive(Default)]
+pub struct SemanticCache {
+    indexes: Mutex<HashMap<String, VecDeque<Entry>>>,
+}
+
+impl SemanticCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The response whose prompt is most similar to `entry`, if it scores at
+    /// least `threshold`, along with the cosine similarity.
+    pub fn lookup(
+        &self,
+        entry: &PendingEntry,
+        scope: &str,
+        threshold: f32,
+    ) -> Option<(OaiChatCompletionResponse, f32)> {
+        let mut indexes = self.indexes.lock().unwrap();
+        let index = indexes.get_mut(&entry.customer_id)?;
+        let now = Instant::now();
+        index.retain(|cached| cached.expires_at > now);
+
+        index
+            .iter()
+            .filter(|cached| cached.scope == scope)
+            .map(|cached| (cached, dot(&cached.embedding, &entry.embedding)))
+            .filter(|(_, score)| *score >= threshold)
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(cached, score)| (cached.response.clone(), score))
+    }
+
+    pub fn insert(&self, entry: PendingEntry, scope: String, response: OaiChatCompletionResponse) {
+        let mut indexes = self.indexes.lock().unwrap();
+        let index = indexes.entry(entry.customer_id).or_default();
+        if index.len() >= MAX_ENTRIES_PER_CUSTOMER {
+            index.pop_front();
+        }
+        index.push_back(Entry {
+            scope,
+            embedding: entry.embedding,
+            response,
+            expires_at: Instant::now() + entry.ttl,
+        });
+    }
+}
+
+/// Text of the last user message, which is what gets embedded. Messages with
+/// images are never matched semantically.
+pub fn last_user_text(request: &OaiChatCompletionRequest) -> Option<String> {
+    let message = request
+        .messages
+        .iter()
+        .rev()
+        .find(|message| message.role == "user")?;
+    if message.content.has_images() || message.content.is_empty() {
+        return None;
+    }
+    Some(message.content.text())
+}
+
+/// Everything about a request except its last user message: only answers to
+/// the same conversation, LLM and parameters are candidates for a match.
+pub fn semantic_scope(llm_name: &str, model: &str, request: &OaiChatCompletionRequest) -> String {
+    let mut request = request.clone();
+    if let Some(message) = request
+        .messages
+        .iter_mut()
+        .rev()
+        .find(|message| message.role == "user")
+    {
+        message.content = OaiMessageContent::default();
+    }
+    cache_key("", llm_name, model, &request)
+}
+
+fn normalize(mut vector: Vec<f32>) -> Vec<f32> {
+    let norm = dot(&vector, &vector).sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|value| *value /= norm);
+    }
+    vector
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn request(value: serde_json::Value) -> OaiChatCompletionRequest {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn response(id: &str) -> OaiChatCompletionResponse {
+        OaiChatCompletionResponse {
+            id: id.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn pending(customer_id: &str, embedding: Vec<f32>) -> PendingEntry {
+        PendingEntry::new(customer_id, embedding, Duration::from_secs(60))
+    }
+
+    #[test]
+    fn returns_the_closest_match_above_the_threshold() {
+        let cache = SemanticCache::new();
+        cache.insert(pending("c", vec![1.0, 0.0]), "s".to_string(), response("x"));
+        cache.insert(pending("c", vec![0.0, 1.0]), "s".to_string(), response("y"));
+
+        let (matched, similarity) = cache
+            .lookup(&pending("c", vec![2.0, 0.2]), "s", 0.9)
+            .unwrap();
+        assert_eq!(matched.id, "x");
+        assert!(similarity > 0.99);
+        assert!(cache
+            .lookup(&pending("c", vec![1.0, 1.0]), "s", 0.9)
+            .is_none());
+    }
+
+    #[test]
+    fn matches_only_the_same_customer_and_scope() {
+        let cache = SemanticCache::new();
+        cache.insert(pending("c", vec![1.0, 0.0]), "s".to_string(), response("x"));
+        assert!(cache
+            .lookup(&pending("other", vec![1.0, 0.0]), "s", 0.9)
+            .is_none());
+        assert!(cache
+            .lookup(&pending("c", vec![1.0, 0.0]), "t", 0.9)
+            .is_none());
+    }
+
+    #[test]
+    fn drops_expired_entries() {
+        let cache = SemanticCache::new();
+        let entry = PendingEntry::new("c", vec![1.0, 0.0], Duration::ZERO);
+        cache.insert(entry, "s".to_string(), response("x"));
+        assert!(cache
+            .lookup(&pending("c", vec![1.0, 0.0]), "s", 0.9)
+            .is_none());
+    }
+
+    #[test]
+    fn scopes_ignore_only_the_last_user_message() {
+        let ask = |question: &str, temperature: f32| {
+            request(json!({
+                "model": "gpt-4o",
+                "temperature": temperature,
+                "messages": [
+                    {"role": "system", "content": "Be brief"},
+                    {"role": "user", "content": question}
+                ]
+            }))
+        };
+        let scope = semantic_scope("openai", "gpt-4o", &ask("Capital of France?", 0.0));
+        assert_eq!(
+            scope,
+            semantic_scope("openai", "gpt-4o", &ask("What is France's capital?", 0.0))
+        );
+        assert_ne!(
+            scope,
+            semantic_scope("openai", "gpt-4o", &ask("Capital of France?", 1.0))
+        );
+        assert_ne!(
+            scope,
+            semantic_scope("azure", "gpt-4o", &ask("Capital of France?", 0.0))
+        );
+    }
+
+    #[test]
+    fn embeds_the_last_user_text_only() {
+        let text = last_user_text(&request(json!({
+            "model": "gpt-4o",
+            "messages": [
+                {"role": "user", "content": "First"},
+                {"role": "assistant", "content": "Ok"},
+                {"role": "user", "content": "Second"}
+            ]
+        })));
+        assert_eq!(text.as_deref(), Some("Second"));
+
+        let with_image = request(json!({
+            "model": "gpt-4o",
+            "messages": [{"role": "user", "content": [
+                {"type": "text", "text": "What is this?"},
+                {"type": "image_url", "image_url": {"url": "https://example.com/cat.png"}}
+            ]}]
+        }));
+        assert_eq!(last_user_text(&with_image), None);
+    }
+}
@@ -1,6 +1,10 @@
+use crate::error::Error;
+use anyhow::Result;
 use derive_builder::Builder;
-use serde::{Deserialize, Serialize};
+use futures::Stream;
+use serde::{Deserialize, Deserializer, Serialize};
 use std::collections::HashMap;
+use std::pin::Pin;
 
 #[derive(Debug, Builder, Deserialize, Clone, PartialEq, Serialize, Default)]
 #[builder(setter(into, strip_option), default)]
@@ -33,7 +37,7 @@ pub struct OaiChatCompletionRequest {
     pub presence_penalty: Option<f32>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub response_format: Option<HashMap<String, String>>,
+    pub response_format: Option<serde_json::Value>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub seed: Option<i64>,
@@ -57,10 +61,10 @@ pub struct OaiChatCompletionRequest {
     pub top_p: Option<f32>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub tools: Option<Vec<String>>,
+    pub tools: Option<Vec<OaiTool>>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub tool_choice: Option<HashMap<String, String>>,
+    pub tool_choice: Option<OaiToolChoice>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub parallel_tool_calls: Option<bool>,
@@ -70,12 +74,201 @@ pub struct OaiChatCompletionRequest {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub function_call: Option<HashMap<String, String>>,
+
+    // Not OpenAI's: documents for Cohere to ground its answer in, e.g.
+    // `[{"title": "...", "snippet": "..."}]`. Other LLMs ignore them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub documents: Option<Vec<serde_json::Value>>,
 }
 
 #[derive(Debug, Deserialize, Clone, PartialEq, Serialize, Default)]
 pub struct OaiMessage {
     pub role: String,
-    pub content: String,
+
+    // assistant messages that only carry tool calls send `content: null`
+    #[serde(default, deserialize_with = "deserialize_null_default")]
+    pub content: OaiMessageContent,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<OaiToolCall>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+/// Message content is either plain text or a list of content parts.
+#[derive(Debug, Deserialize, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum OaiMessageContent {
+    Text(String),
+    Parts(Vec<OaiContentPart>),
+}
+
+impl Default for OaiMessageContent {
+    fn default() -> Self {
+        OaiMessageContent::Text(String::new())
+    }
+}
+
+impl From<String> for OaiMessageContent {
+    fn from(text: String) -> Self {
+        OaiMessageContent::Text(text)
+    }
+}
+
+impl OaiMessageContent {
+    /// Concatenated text of the message, image parts are skipped.
+    pub fn text(&self) -> String {
+        match self {
+            OaiMessageContent::Text(text) => text.clone(),
+            OaiMessageContent::Parts(parts) => parts
+                .iter()
+                .filter_map(|part| part.text.as_deref())
+                .collect::<Vec<&str>>()
+                .join("\n"),
+        }
+    }
+
+    /// Text of a message that can't carry images, e.g. a system prompt.
+    pub fn text_only(&self) -> Result<String, Error> {
+        self.check_parts()?;
+        if self.has_images() {
+            return Err(Error::InvalidArgument(
+                "image content is only supported in user messages".to_string(),
+            ));
+        }
+        Ok(self.text())
+    }
+
+    /// Rejects content parts other than text and image_url.
+    pub fn check_parts(&self) -> Result<(), Error> {
+        match self {
+            OaiMessageContent::Text(_) => Ok(()),
+            OaiMessageContent::Parts(parts) => match parts
+                .iter()
+                .find(|part| !matches!(part.part_type.as_str(), "text" | "image_url"))
+            {
+                Some(part) => Err(Error::InvalidArgument(format!(
+                    "content parts of type '{}' are not supported",
+                    part.part_type
+                ))),
+                None => Ok(()),
+            },
+        }
+    }
+
+    pub fn push_str(&mut self, text: &str) {
+        match self {
+            OaiMessageContent::Text(content) => content.push_str(text),
+            OaiMessageContent::Parts(parts) => parts.push(OaiContentPart {
+                part_type: "text".to_string(),
+                text: Some(text.to_string()),
+                image_url: None,
+            }),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        match self {
+            OaiMessageContent::Text(text) => text.is_empty(),
+            OaiMessageContent::Parts(parts) => parts.is_empty(),
+        }
+    }
+
+    pub fn has_images(&self) -> bool {
+        match self {
+            OaiMessageContent::Text(_) => false,
+            OaiMessageContent::Parts(parts) => parts.iter().any(|part| part.image_url.is_some()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Serialize, Default)]
+pub struct OaiContentPart {
+    // text or image_url
+    #[serde(rename = "type")]
+    pub part_type: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_url: Option<OaiImageUrl>,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Serialize, Default)]
+pub struct OaiImageUrl {
+    // http(s) url or a base64 data url
+    pub url: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+fn deserialize_null_default<'de, D, T>(deserializer: D) -> std::result::Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Serialize, Default)]
+pub struct OaiTool {
+    #[serde(rename = "type")]
+    pub tool_type: String,
+    pub function: OaiFunction,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Serialize, Default)]
+pub struct OaiFunction {
+    pub name: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<serde_json::Value>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strict: Option<bool>,
+}
+
+/// Either `"none"`, `"auto"`, `"required"` or a named function.
+#[derive(Debug, Deserialize, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum OaiToolChoice {
+    Mode(String),
+    Named(OaiNamedToolChoice),
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Serialize, Default)]
+pub struct OaiNamedToolChoice {
+    #[serde(rename = "type")]
+    pub tool_type: String,
+    pub function: OaiFunctionName,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Serialize, Default)]
+pub struct OaiFunctionName {
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Serialize, Default)]
+pub struct OaiToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub tool_type: String,
+    pub function: OaiFunctionCall,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Serialize, Default)]
+pub struct OaiFunctionCall {
+    pub name: String,
+    pub arguments: String,
 }
 
 #[derive(Debug, Builder, Deserialize, Clone, PartialEq, Serialize, Default)]
@@ -114,3 +307,250 @@ pub struct OaiUsage {
     pub completion_tokens: u32,
     pub total_tokens: u32,
 }
+
+/// Streamed chunk of a chat completion, `object` is always `chat.completion.chunk`.
+#[derive(Debug, Builder, Deserialize, Clone, PartialEq, Serialize, Default)]
+#[builder(setter(into, strip_option), default)]
+#[builder(pattern = "mutable")]
+#[builder(derive(Debug))]
+pub struct OaiChatCompletionChunk {
+    pub id: String,
+    pub object: String,
+    pub created: u64,
+    pub model: String,
+    pub system_fingerprint: Option<String>,
+    pub choices: Vec<OaiChunkChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<OaiUsage>,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Serialize, Default)]
+pub struct OaiChunkChoice {
+    pub index: u32,
+    pub delta: OaiDelta,
+    pub logprobs: Option<OaiLogprobs>,
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Serialize, Default)]
+pub struct OaiDelta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<OaiToolCallDelta>>,
+}
+
+/// Partial tool call, `arguments` arrive as fragments keyed by `index`.
+#[derive(Debug, Deserialize, Clone, PartialEq, Serialize, Default)]
+pub struct OaiToolCallDelta {
+    pub index: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub tool_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub function: Option<OaiFunctionCallDelta>,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Serialize, Default)]
+pub struct OaiFunctionCallDelta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arguments: Option<String>,
+}
+
+pub type OaiChatCompletionStream =
+    Pin<Box<dyn Stream<Item = Result<OaiChatCompletionChunk>> + Send>>;
+
+impl OaiChatCompletionResponse {
+    /// Folds a streamed chunk into this response, used to rebuild the full
+    /// completion once a stream has finished.
+    pub fn accumulate(&mut self, chunk: &OaiChatCompletionChunk) {
+        if self.id.is_empty() {
+            self.id = chunk.id.clone();
+            self.object = "chat.completion".to_string();
+            self.created = chunk.created;
+            self.model = chunk.model.clone();
+            self.system_fingerprint = chunk.system_fingerprint.clone();
+        }
+
+        for chunk_choice in &chunk.choices {
+            let choice = match self
+                .choices
+                .iter_mut()
+                .position(|c| c.index == chunk_choice.index)
+            {
+                Some(pos) => &mut self.choices[pos],
+                None => {
+                    self.choices.push(OaiChoice {
+                        index: chunk_choice.index,
+                        message: OaiMessage {
+                            role: "assistant".to_string(),
+                            ..Default::default()
+                        },
+                        logprobs: None,
+                        finish_reason: None,
+                    });
+                    self.choices.last_mut().unwrap()
+                }
+            };
+            if let Some(role) = &chunk_choice.delta.role {
+                choice.message.role = role.clone();
+            }
+            if let Some(content) = &chunk_choice.delta.content {
+                choice.message.content.push_str(content);
+            }
+            for tool_call_delta in chunk_choice.delta.tool_calls.iter().flatten() {
+                let tool_calls = choice.message.tool_calls.get_or_insert_with(Vec::new);
+                // calls are numbered from 0 in order, an index past the next
+                // one comes from a broken upstream and would grow the list
+                let index = tool_call_delta.index as usize;
+                if index == tool_calls.len() {
+                    tool_calls.push(OaiToolCall::default());
+                }
+                let Some(tool_call) = tool_calls.get_mut(index) else {
+                    tracing::warn!(index, "Skipping tool call delta past the next index");
+                    continue;
+                };
+                if let Some(id) = &tool_call_delta.id {
+                    tool_call.id = id.clone();
+                }
+                if let Some(tool_type) = &tool_call_delta.tool_type {
+                    tool_call.tool_type = tool_type.clone();
+                }
+                if let Some(function) = &tool_call_delta.function {
+                    if let Some(name) = &function.name {
+                        tool_call.function.name.push_str(name);
+                    }
+                    if let Some(arguments) = &function.arguments {
+                        tool_call.function.arguments.push_str(arguments);
+                    }
+                }
+            }
+            if chunk_choice.finish_reason.is_some() {
+                choice.finish_reason = chunk_choice.finish_reason.clone();
+            }
+        }
+
+        if chunk.usage.is_some() {
+            self.usage = chunk.usage.clone();
+        }
+    }
+
+    /// Splits a full completion into the chunks an upstream stream would have
+    /// produced: one content chunk per choice followed by a final chunk that
+    /// carries the finish reasons and usage.
+    pub fn into_chunks(self) -> Vec<OaiChatCompletionChunk> {
+        let chunk =
+            |choices: Vec<OaiChunkChoice>, usage: Option<OaiUsage>| OaiChatCompletionChunk {
+                id: self.id.clone(),
+                object: "chat.completion.chunk".to_string(),
+                created: self.created,
+                model: self.model.clone(),
+                system_fingerprint: self.system_fingerprint.clone(),
+                choices,
+                usage,
+            };
+
+        let mut chunks: Vec<OaiChatCompletionChunk> = self
+            .choices
+            .iter()
+            .map(|choice| {
+                chunk(
+                    vec![OaiChunkChoice {
+                        index: choice.index,
+                        delta: OaiDelta {
+                            role: Some(choice.message.role.clone()),
+                            content: Some(choice.message.content.text()),
+                            tool_calls: choice.message.tool_calls.as_ref().map(|tool_calls| {
+                                tool_calls
+                                    .iter()
+                                    .enumerate()
+                                    .map(|(index, tool_call)| OaiToolCallDelta {
+                                        index: index as u32,
+                                        id: Some(tool_call.id.clone()),
+                                        tool_type: Some(tool_call.tool_type.clone()),
+                                        function: Some(OaiFunctionCallDelta {
+                                            name: Some(tool_call.function.name.clone()),
+                                            arguments: Some(tool_call.function.arguments.clone()),
+                                        }),
+                                    })
+                                    .collect()
+                            }),
+                        },
+                        logprobs: choice.logprobs.clone(),
+                        finish_reason: None,
+                    }],
+                    None,
+                )
+            })
+            .collect();
+
+        let finish_choices = self
+            .choices
+            .iter()
+            .map(|choice| OaiChunkChoice {
+                index: choice.index,
+                delta: OaiDelta::default(),
+                logprobs: None,
+                finish_reason: choice.finish_reason.clone(),
+            })
+            .collect();
+        chunks.push(chunk(finish_choices, self.usage.clone()));
+        chunks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn chunk(tool_calls: serde_json::Value) -> OaiChatCompletionChunk {
+        serde_json::from_value(json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion.chunk",
+            "created": 1,
+            "model": "gpt-4o",
+            "system_fingerprint": null,
+            "choices": [{"index": 0, "delta": {"tool_calls": tool_calls}, "finish_reason": null}],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn accumulates_tool_call_fragments() {
+        let mut response = OaiChatCompletionResponse::default();
+        response.accumulate(&chunk(json!([
+            {"index": 0, "id": "call_1", "type": "function", "function": {"name": "get_weather", "arguments": "{\"city\":"}},
+        ])));
+        response.accumulate(&chunk(json!([
+            {"index": 0, "function": {"arguments": "\"Paris\"}"}},
+            {"index": 1, "id": "call_2", "type": "function", "function": {"name": "get_time", "arguments": "{}"}},
+        ])));
+
+        let tool_calls = response.choices[0].message.tool_calls.as_ref().unwrap();
+        assert_eq!(tool_calls.len(), 2);
+        assert_eq!(tool_calls[0].id, "call_1");
+        assert_eq!(tool_calls[0].function.arguments, "{\"city\":\"Paris\"}");
+        assert_eq!(tool_calls[1].function.name, "get_time");
+    }
+
+    #[test]
+    fn skips_tool_call_indices_past_the_next_one() {
+        let mut response = OaiChatCompletionResponse::default();
+        response.accumulate(&chunk(json!([
+            {"index": u32::MAX, "id": "call_1", "function": {"name": "get_weather"}},
+        ])));
+        response.accumulate(&chunk(json!([
+            {"index": 0, "id": "call_2", "function": {"name": "get_time"}},
+        ])));
+
+        let tool_calls = response.choices[0].message.tool_calls.as_ref().unwrap();
+        assert_eq!(tool_calls.len(), 1);
+        assert_eq!(tool_calls[0].id, "call_2");
+    }
+}
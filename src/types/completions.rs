@@ -0,0 +1,231 @@
+use crate::error::Error;
+use crate::types::{
+    OaiChatCompletionChunk, OaiChatCompletionRequest, OaiChatCompletionResponse, OaiMessage,
+    OaiUsage,
+};
+use derive_builder::Builder;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Legacy text completions request.
+#[derive(Debug, Builder, Deserialize, Clone, PartialEq, Serialize, Default)]
+#[builder(setter(into, strip_option), default)]
+#[builder(pattern = "mutable")]
+#[builder(derive(Debug))]
+pub struct OaiCompletionRequest {
+    pub model: String,
+
+    pub prompt: OaiPrompt,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suffix: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<u32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<u32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub echo: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<OaiStop>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub best_of: Option<u32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logit_bias: Option<HashMap<String, serde_json::Value>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum OaiPrompt {
+    String(String),
+    StringArray(Vec<String>),
+    Tokens(Vec<u32>),
+    TokensArray(Vec<Vec<u32>>),
+}
+
+impl Default for OaiPrompt {
+    fn default() -> Self {
+        OaiPrompt::String(String::new())
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum OaiStop {
+    String(String),
+    StringArray(Vec<String>),
+}
+
+/// Text completion response. Streamed chunks have the same shape.
+#[derive(Debug, Builder, Deserialize, Clone, PartialEq, Serialize, Default)]
+#[builder(setter(into, strip_option), default)]
+#[builder(pattern = "mutable")]
+#[builder(derive(Debug))]
+pub struct OaiCompletionResponse {
+    pub id: String,
+    pub object: String,
+    pub created: u64,
+    pub model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_fingerprint: Option<String>,
+    pub choices: Vec<OaiCompletionChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<OaiUsage>,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Serialize, Default)]
+pub struct OaiCompletionChoice {
+    pub text: String,
+    pub index: u32,
+    pub logprobs: Option<serde_json::Value>,
+    pub finish_reason: Option<String>,
+}
+
+impl OaiCompletionRequest {
+    /// The prompt as text. Only a single text prompt can become a chat.
+    pub fn prompt_text(&self) -> Result<String, Error> {
+        match &self.prompt {
+            OaiPrompt::String(prompt) => Ok(prompt.clone()),
+            OaiPrompt::StringArray(prompts) if prompts.len() == 1 => Ok(prompts[0].clone()),
+            OaiPrompt::StringArray(_) => Err(Error::InvalidArgument(
+                "Only a single prompt is supported".to_string(),
+            )),
+            OaiPrompt::Tokens(_) | OaiPrompt::TokensArray(_) => Err(Error::InvalidArgument(
+                "Token prompts are not supported, send the prompt as text".to_string(),
+            )),
+        }
+    }
+}
+
+impl TryFrom<OaiCompletionRequest> for OaiChatCompletionRequest {
+    type Error = Error;
+
+    fn try_from(value: OaiCompletionRequest) -> Result<Self, Self::Error> {
+        if value
+            .suffix
+            .as_deref()
+            .is_some_and(|suffix| !suffix.is_empty())
+        {
+            return Err(Error::InvalidArgument(
+                "`suffix` is not supported".to_string(),
+            ));
+        }
+        // chat models can't rank candidates, so only best_of == n works
+        if let Some(best_of) = value.best_of {
+            if best_of != value.n.unwrap_or(1) {
+                return Err(Error::InvalidArgument(
+                    "`best_of` must equal `n`".to_string(),
+                ));
+            }
+        }
+
+        let message = OaiMessage {
+            role: "user".to_string(),
+            content: value.prompt_text()?.into(),
+            ..Default::default()
+        };
+        Ok(OaiChatCompletionRequest {
+            model: value.model,
+            messages: vec![message],
+            frequency_penalty: value.frequency_penalty,
+            logit_bias: value.logit_bias,
+            max_tokens: value.max_tokens,
+            n: value.n,
+            presence_penalty: value.presence_penalty,
+            seed: value.seed,
+            stop: value.stop.map(|stop| match stop {
+                OaiStop::String(stop) => vec![stop],
+                OaiStop::StringArray(stop) => stop,
+            }),
+            stream: value.stream,
+            temperature: value.temperature,
+            top_p: value.top_p,
+            user: value.user,
+            ..Default::default()
+        })
+    }
+}
+
+impl From<OaiChatCompletionResponse> for OaiCompletionResponse {
+    fn from(value: OaiChatCompletionResponse) -> Self {
+        OaiCompletionResponse {
+            id: value.id,
+            object: "text_completion".to_string(),
+            created: value.created,
+            model: value.model,
+            system_fingerprint: value.system_fingerprint,
+            choices: value
+                .choices
+                .into_iter()
+                .map(|choice| OaiCompletionChoice {
+                    text: choice.message.content.text(),
+                    index: choice.index,
+                    logprobs: None,
+                    finish_reason: choice.finish_reason,
+                })
+                .collect(),
+            usage: value.usage,
+        }
+    }
+}
+
+impl From<OaiChatCompletionChunk> for OaiCompletionResponse {
+    fn from(value: OaiChatCompletionChunk) -> Self {
+        OaiCompletionResponse {
+            id: value.id,
+            object: "text_completion".to_string(),
+            created: value.created,
+            model: value.model,
+            system_fingerprint: value.system_fingerprint,
+            choices: value
+                .choices
+                .into_iter()
+                .map(|choice| OaiCompletionChoice {
+                    text: choice.delta.content.unwrap_or_default(),
+                    index: choice.index,
+                    logprobs: None,
+                    finish_reason: choice.finish_reason,
+                })
+                .collect(),
+            usage: value.usage,
+        }
+    }
+}
+
+impl OaiCompletionResponse {
+    /// Prepends the prompt to every choice, for `echo`.
+    pub fn echo(&mut self, prompt: &str) {
+        for choice in &mut self.choices {
+            choice.text.insert_str(0, prompt);
+        }
+    }
+}
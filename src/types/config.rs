@@ -8,4 +8,20 @@ pub trait LLMConfig {
     fn get_name(&self) -> String;
 
     fn get_default_model(&self) -> String;
+
+    /// Model ids served by this LLM.
+    fn get_models(&self) -> Vec<String>;
+
+    /// Prefixes that route unlisted model ids to this LLM.
+    fn get_model_prefixes(&self) -> Vec<String>;
+
+    /// Embedding model ids served by this LLM, kept apart from chat models.
+    fn get_embedding_models(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Prefixes that route unlisted embedding model ids to this LLM.
+    fn get_embedding_model_prefixes(&self) -> Vec<String> {
+        Vec::new()
+    }
 }
@@ -0,0 +1,131 @@
+use derive_builder::Builder;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Builder, Deserialize, Clone, PartialEq, Serialize, Default)]
+#[builder(setter(into, strip_option), default)]
+#[builder(pattern = "mutable")]
+#[builder(derive(Debug))]
+pub struct OaiEmbeddingRequest {
+    pub model: String,
+
+    pub input: OaiEmbeddingInput,
+
+    /// `float` (the default) or `base64`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding_format: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dimensions: Option<u32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+
+    /// Extension: what the inputs are embedded for, e.g. `search_query` (the
+    /// default) or `search_document`. Only Cohere and AI21 use it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input_type: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum OaiEmbeddingInput {
+    String(String),
+    StringArray(Vec<String>),
+    Tokens(Vec<u32>),
+    TokensArray(Vec<Vec<u32>>),
+}
+
+impl Default for OaiEmbeddingInput {
+    fn default() -> Self {
+        OaiEmbeddingInput::String(String::new())
+    }
+}
+
+impl OaiEmbeddingInput {
+    /// The inputs as text. Token inputs can only be sent to OpenAI.
+    pub fn texts(&self) -> Option<Vec<String>> {
+        match self {
+            OaiEmbeddingInput::String(text) => Some(vec![text.clone()]),
+            OaiEmbeddingInput::StringArray(texts) => Some(texts.clone()),
+            OaiEmbeddingInput::Tokens(_) | OaiEmbeddingInput::TokensArray(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Builder, Deserialize, Clone, PartialEq, Serialize, Default)]
+#[builder(setter(into, strip_option), default)]
+#[builder(pattern = "mutable")]
+#[builder(derive(Debug))]
+pub struct OaiEmbeddingResponse {
+    pub object: String,
+    pub data: Vec<OaiEmbedding>,
+    pub model: String,
+    pub usage: OaiEmbeddingUsage,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Serialize, Default)]
+pub struct OaiEmbedding {
+    pub object: String,
+    pub index: u32,
+    pub embedding: OaiEmbeddingVector,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum OaiEmbeddingVector {
+    Float(Vec<f32>),
+    Base64(String),
+}
+
+impl Default for OaiEmbeddingVector {
+    fn default() -> Self {
+        OaiEmbeddingVector::Float(vec![])
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Serialize, Default)]
+pub struct OaiEmbeddingUsage {
+    pub prompt_tokens: u32,
+    pub total_tokens: u32,
+}
+
+impl OaiEmbeddingResponse {
+    /// Builds an OpenAI-shaped response from one vector per input.
+    pub fn from_vectors(model: &str, vectors: Vec<Vec<f32>>, prompt_tokens: u32) -> Self {
+        Self {
+            object: "list".to_string(),
+            data: vectors
+                .into_iter()
+                .enumerate()
+                .map(|(index, vector)| OaiEmbedding {
+                    object: "embedding".to_string(),
+                    index: index as u32,
+                    embedding: OaiEmbeddingVector::Float(vector),
+                })
+                .collect(),
+            model: model.to_string(),
+            usage: OaiEmbeddingUsage {
+                prompt_tokens,
+                total_tokens: prompt_tokens,
+            },
+        }
+    }
+
+    /// Re-encodes float vectors as base64 little-endian `f32`s, the way
+    /// OpenAI does for `encoding_format: base64`.
+    pub fn encode_base64(&mut self) {
+        use base64::Engine;
+
+        for embedding in &mut self.data {
+            if let OaiEmbeddingVector::Float(vector) = &embedding.embedding {
+                let bytes: Vec<u8> = vector
+                    .iter()
+                    .flat_map(|value| value.to_le_bytes())
+                    .collect();
+                embedding.embedding = OaiEmbeddingVector::Base64(
+                    base64::engine::general_purpose::STANDARD.encode(bytes),
+                );
+            }
+        }
+    }
+}
@@ -1,5 +1,11 @@
 pub mod chat;
+pub mod completions;
 pub mod config;
+pub mod embeddings;
+pub mod models;
 
 pub use chat::*;
+pub use completions::*;
 pub use config::*;
+pub use embeddings::*;
+pub use models::*;
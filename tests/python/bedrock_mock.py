@@ -0,0 +1,179 @@
+"""Local stand-in for the Bedrock runtime API that checks SigV4 signatures.
+
+Run it, then point a customer's `bedrock` config at it:
+
+    "bedrock": {
+        "base_url": "http://127.0.0.1:8089",
+        "aws": {
+            "access_key_id": "AKIDEXAMPLE",
+            "secret_access_key": "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "region": "us-east-1"
+        }
+    }
+
+Requests whose signature doesn't match the one botocore computes get a 403,
+like Bedrock would. Needs `pip install botocore`.
+"""
+
+import base64
+import hashlib
+import json
+import os
+import struct
+import zlib
+from http.server import BaseHTTPRequestHandler, HTTPServer
+
+from botocore.auth import SigV4Auth
+from botocore.awsrequest import AWSRequest
+from botocore.credentials import Credentials
+
+PORT = int(os.environ.get("PORT", "8089"))
+REGION = os.environ.get("AWS_REGION", "us-east-1")
+CREDENTIALS = Credentials(
+    os.environ.get("AWS_ACCESS_KEY_ID", "AKIDEXAMPLE"),
+    os.environ.get("AWS_SECRET_ACCESS_KEY", "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY"),
+    os.environ.get("AWS_SESSION_TOKEN"),
+)
+
+USAGE = {"input_tokens": 12, "output_tokens": 6}
+
+
+def signature_error(method, path, headers, body):
+    """Why the request's signature is wrong, or None if it's valid."""
+    authorization = headers.get("authorization", "")
+    if not authorization.startswith("AWS4-HMAC-SHA256 "):
+        return "missing SigV4 authorization header"
+    fields = dict(
+        field.split("=", 1)
+        for field in authorization[len("AWS4-HMAC-SHA256 "):].split(", ")
+    )
+    if hashlib.sha256(body).hexdigest() != headers.get("x-amz-content-sha256"):
+        return "x-amz-content-sha256 does not match the body"
+
+    signed_headers = fields["SignedHeaders"].split(";")
+    request = AWSRequest(
+        method=method,
+        url=f"http://{headers['host']}{path}",
+        data=body,
+        headers={name: headers[name] for name in signed_headers},
+    )
+    request.context["timestamp"] = headers["x-amz-date"]
+    auth = SigV4Auth(CREDENTIALS, "bedrock", REGION)
+    canonical_request = auth.canonical_request(request)
+    string_to_sign = auth.string_to_sign(request, canonical_request)
+    expected = auth.signature(string_to_sign, request)
+
+    scope = f"{headers['x-amz-date'][:8]}/{REGION}/bedrock/aws4_request"
+    if fields["Credential"] != f"{CREDENTIALS.access_key}/{scope}":
+        return f"unexpected credential {fields['Credential']}"
+    if fields["Signature"] != expected:
+        return f"signature mismatch, canonical request was:\n{canonical_request}"
+    return None
+
+
+def event_message(event_type, payload):
+    """Encodes an application/vnd.amazon.eventstream message."""
+    headers = {
+        ":event-type": event_type,
+        ":content-type": "application/json",
+        ":message-type": "event",
+    }
+    raw_headers = b"".join(
+        bytes([len(name)]) + name.encode() + b"\x07" + struct.pack(">H", len(value)) + value.encode()
+        for name, value in headers.items()
+    )
+    payload = json.dumps(payload).encode()
+    prelude = struct.pack(">II", 16 + len(raw_headers) + len(payload), len(raw_headers))
+    message = prelude + struct.pack(">I", zlib.crc32(prelude)) + raw_headers + payload
+    return message + struct.pack(">I", zlib.crc32(message))
+
+
+def claude_events(model):
+    events = [
+        {"type": "message_start", "message": {
+            "id": "msg_mock", "type": "message", "role": "assistant", "content": [],
+            "model": model, "usage": {"input_tokens": USAGE["input_tokens"], "output_tokens": 1},
+        }},
+        {"type": "content_block_start", "index": 0, "content_block": {"type": "text", "text": ""}},
+        {"type": "content_block_delta", "index": 0, "delta": {"type": "text_delta", "text": "Hello from "}},
+        {"type": "content_block_delta", "index": 0, "delta": {"type": "text_delta", "text": "Bedrock"}},
+        {"type": "content_block_stop", "index": 0},
+        {"type": "message_delta", "delta": {"stop_reason": "end_turn"},
+         "usage": {"output_tokens": USAGE["output_tokens"]}},
+        {"type": "message_stop"},
+    ]
+    return [
+        event_message("chunk", {"bytes": base64.b64encode(json.dumps(event).encode()).decode()})
+        for event in events
+    ]
+
+
+def converse_events():
+    return [
+        event_message("messageStart", {"role": "assistant"}),
+        event_message("contentBlockDelta", {"contentBlockIndex": 0, "delta": {"text": "Hello from "}}),
+        event_message("contentBlockDelta", {"contentBlockIndex": 0, "delta": {"text": "Bedrock"}}),
+        event_message("contentBlockStop", {"contentBlockIndex": 0}),
+        event_message("messageStop", {"stopReason": "end_turn"}),
+        event_message("metadata", {
+            "usage": {"inputTokens": USAGE["input_tokens"], "outputTokens": USAGE["output_tokens"],
+                      "totalTokens": USAGE["input_tokens"] + USAGE["output_tokens"]},
+            "metrics": {"latencyMs": 10},
+        }),
+    ]
+
+
+class BedrockHandler(BaseHTTPRequestHandler):
+    def do_POST(self):
+        body = self.rfile.read(int(self.headers.get("content-length", 0)))
+        headers = {name.lower(): value for name, value in self.headers.items()}
+        error = signature_error("POST", self.path, headers, body)
+        if error:
+            print(error)
+            return self.respond(403, {"message": error})
+
+        # /model/{model id}/{action}, the model id is url encoded
+        _, _, model, action = self.path.split("/", 3)
+        model = model.replace("%3A", ":")
+        request = json.loads(body)
+        print(f"{action} {model}: {json.dumps(request)}")
+
+        if action == "invoke":
+            self.respond(200, {
+                "id": "msg_mock", "type": "message", "role": "assistant", "model": model,
+                "content": [{"type": "text", "text": "Hello from Bedrock"}],
+                "stop_reason": "end_turn", "usage": USAGE,
+            })
+        elif action == "converse":
+            self.respond(200, {
+                "output": {"message": {"role": "assistant", "content": [{"text": "Hello from Bedrock"}]}},
+                "stopReason": "end_turn",
+                "usage": {"inputTokens": USAGE["input_tokens"], "outputTokens": USAGE["output_tokens"],
+                          "totalTokens": USAGE["input_tokens"] + USAGE["output_tokens"]},
+            })
+        elif action in ("invoke-with-response-stream", "converse-stream"):
+            messages = claude_events(model) if action.startswith("invoke") else converse_events()
+            self.send_response(200)
+            self.send_header("content-type", "application/vnd.amazon.eventstream")
+            self.end_headers()
+            for message in messages:
+                # split messages across writes, like a real network would
+                half = len(message) // 2
+                for part in (message[:half], message[half:]):
+                    self.wfile.write(part)
+                    self.wfile.flush()
+        else:
+            self.respond(404, {"message": f"unknown action {action}"})
+
+    def respond(self, status, body):
+        body = json.dumps(body).encode()
+        self.send_response(status)
+        self.send_header("content-type", "application/json")
+        self.send_header("content-length", str(len(body)))
+        self.end_headers()
+        self.wfile.write(body)
+
+
+if __name__ == "__main__":
+    print(f"Bedrock mock listening on http://127.0.0.1:{PORT}")
+    HTTPServer(("127.0.0.1", PORT), BedrockHandler).serve_forever()
@@ -95,6 +95,10 @@ def write_csv(openai_results, felafax_results, filename):
             writer.writerow(['Felafax', i] + [felafax_result.get(metric) for metric in metrics])
 
 def main():
+    # Point OPENAI_URL at openai_mock.py to measure the gateway's own overhead
+    openai_url = os.environ.get('OPENAI_URL', "https://api.openai.com/v1/chat/completions")
+    felafax_url = os.environ.get('FELAFAX_URL', "https://felafax-proxy-sq5shdnepa-uc.a.run.app/v1/chat/completions")
+
     openai_api_key = os.environ.get('OPENAI_API_KEY')
     if not openai_api_key:
         raise ValueError("OPENAI_API_KEY environment variable is not set")
@@ -103,6 +107,10 @@ def main():
         "Content-Type": "application/json",
         "Authorization": f"Bearer {openai_api_key}"
     }
+    # translate mode authenticates with the Felafax token instead
+    felafax_headers = dict(headers)
+    if os.environ.get('FELAFAX_TOKEN'):
+        felafax_headers["Authorization"] = f"Bearer {os.environ['FELAFAX_TOKEN']}"
 
     data = {
         "model": "gpt-4o",
@@ -119,18 +127,15 @@ def main():
         "stream": True
     }
 
-    openai_url = "https://api.openai.com/v1/chat/completions"
-    felafax_url = "https://felafax-proxy-sq5shdnepa-uc.a.run.app/v1/chat/completions"
-
     openai_results = []
     felafax_results = []
 
     print("Running tests...")
-    runs = 20
+    runs = int(os.environ.get('RUNS', "20"))
     for i in range(runs):
         print(f"Test {i+1}/{runs}")
         openai_results.append(make_api_request(openai_url, headers, data))
-        felafax_results.append(make_api_request(felafax_url, headers, data))
+        felafax_results.append(make_api_request(felafax_url, felafax_headers, data))
 
     print("\nResults:")
     print_markdown_table(openai_results, felafax_results)
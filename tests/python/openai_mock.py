@@ -0,0 +1,112 @@
+"""Local stand-in for OpenAI's chat completions API that answers instantly.
+
+Latency measured through the gateway against it is the gateway's own
+overhead. Point a customer's config at it:
+
+    "mock": {
+        "provider": "openai_compatible",
+        "base_url": "https://127.0.0.1:8443/v1",
+        "models": ["gpt-4o"]
+    }
+
+and run `benchmark.py` with `OPENAI_URL=https://127.0.0.1:8443/v1/chat/completions`
+and `FELAFAX_URL=http://127.0.0.1:8000/translate/v1/chat/completions`.
+
+Serves HTTPS when `CERT_FILE` and `KEY_FILE` are set, e.g. a self-signed pair
+from `openssl req -x509 -newkey rsa:2048 -nodes -subj /CN=127.0.0.1
+-addext subjectAltName=IP:127.0.0.1 -keyout key.pem -out cert.pem`. Start the
+gateway with `SSL_CERT_FILE=cert.pem` so it trusts it. Connections are kept
+alive, like OpenAI's.
+"""
+
+import json
+import os
+import ssl
+import time
+from http.server import BaseHTTPRequestHandler, ThreadingHTTPServer
+
+PORT = int(os.environ.get("PORT", "8443"))
+CERT_FILE = os.environ.get("CERT_FILE")
+KEY_FILE = os.environ.get("KEY_FILE")
+WORDS = ["Felafax", " is", " a", " tasty", " gateway", "."]
+
+
+def chunk(model, delta, finish_reason=None, usage=None):
+    return {
+        "id": "chatcmpl-mock",
+        "object": "chat.completion.chunk",
+        "created": int(time.time()),
+        "model": model,
+        "choices": [{"index": 0, "delta": delta, "finish_reason": finish_reason}],
+        "usage": usage,
+    }
+
+
+class OpenAIHandler(BaseHTTPRequestHandler):
+    protocol_version = "HTTP/1.1"
+    # headers and body are written separately, don't wait on delayed ACKs
+    disable_nagle_algorithm = True
+
+    def log_message(self, format, *args):
+        pass
+
+    def do_POST(self):
+        length = int(self.headers.get("content-length", 0))
+        request = json.loads(self.rfile.read(length) or b"{}")
+        if not self.path.endswith("/chat/completions"):
+            self.respond(404, {"error": {"message": f"unknown path {self.path}"}})
+            return
+
+        model = request.get("model") or "mock-model"
+        usage = {"prompt_tokens": 12, "completion_tokens": len(WORDS), "total_tokens": 12 + len(WORDS)}
+        if not request.get("stream"):
+            self.respond(200, {
+                "id": "chatcmpl-mock",
+                "object": "chat.completion",
+                "created": int(time.time()),
+                "model": model,
+                "choices": [{
+                    "index": 0,
+                    "message": {"role": "assistant", "content": "".join(WORDS)},
+                    "finish_reason": "stop",
+                }],
+                "usage": usage,
+            })
+            return
+
+        self.send_response(200)
+        self.send_header("content-type", "text/event-stream")
+        self.send_header("transfer-encoding", "chunked")
+        self.end_headers()
+        chunks = [chunk(model, {"role": "assistant", "content": ""})]
+        chunks += [chunk(model, {"content": word}) for word in WORDS]
+        chunks.append(chunk(model, {}, "stop"))
+        chunks.append({**chunk(model, {}), "choices": [], "usage": usage})
+        for data in chunks:
+            self.write_chunk(f"data: {json.dumps(data)}\n\n".encode())
+        self.write_chunk(b"data: [DONE]\n\n")
+        self.write_chunk(b"")
+
+    def write_chunk(self, data):
+        self.wfile.write(f"{len(data):x}\r\n".encode() + data + b"\r\n")
+        self.wfile.flush()
+
+    def respond(self, status, body):
+        body = json.dumps(body).encode()
+        self.send_response(status)
+        self.send_header("content-type", "application/json")
+        self.send_header("content-length", str(len(body)))
+        self.end_headers()
+        self.wfile.write(body)
+
+
+if __name__ == "__main__":
+    server = ThreadingHTTPServer(("127.0.0.1", PORT), OpenAIHandler)
+    scheme = "http"
+    if CERT_FILE and KEY_FILE:
+        context = ssl.SSLContext(ssl.PROTOCOL_TLS_SERVER)
+        context.load_cert_chain(CERT_FILE, KEY_FILE)
+        server.socket = context.wrap_socket(server.socket, server_side=True)
+        scheme = "https"
+    print(f"OpenAI mock listening on {scheme}://127.0.0.1:{PORT}")
+    server.serve_forever()